@@ -0,0 +1,35 @@
+use crate::CallerIndexKey;
+use candid::Principal;
+use ic_stable_structures::Storable;
+use std::borrow::Cow;
+
+#[test]
+fn should_have_correct_length() {
+    let index_key = CallerIndexKey {
+        caller: Principal::from_slice(&[1, 2, 3]),
+        log_index: 23,
+    };
+    let bytes = index_key.to_bytes();
+    assert_eq!(bytes.len(), 38);
+    assert_eq!(
+        bytes,
+        hex::decode("0301020300000000000000000000000000000000000000000000000000000000000000000017")
+            .unwrap()
+    );
+}
+
+#[test]
+fn should_deserialize_correctly() {
+    let decoded =
+        hex::decode("0a000000000000000101010000000000000000000000000000000000000000000000000003b1")
+            .unwrap();
+    let index_key = CallerIndexKey::from_bytes(Cow::from(decoded));
+
+    assert_eq!(
+        index_key,
+        CallerIndexKey {
+            caller: Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]),
+            log_index: 945,
+        }
+    );
+}
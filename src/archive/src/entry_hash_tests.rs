@@ -0,0 +1,24 @@
+use crate::EntryHash;
+use ic_stable_structures::Storable;
+use std::borrow::Cow;
+
+#[test]
+fn should_chain_entry_to_hash() {
+    let hash = EntryHash::GENESIS.chain(b"entry");
+    assert_eq!(
+        hash.0.to_vec(),
+        hex::decode("2b9ab6d18a027b5596b6f95d34a143af27414921ac03b10e91b12d95c2ade707").unwrap()
+    );
+}
+
+#[test]
+fn should_have_correct_length() {
+    let hash = EntryHash::GENESIS.chain(&[]);
+    let bytes = hash.to_bytes();
+    assert_eq!(bytes.len(), 32);
+    assert_eq!(
+        bytes,
+        hex::decode("66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925").unwrap()
+    );
+    assert_eq!(EntryHash::from_bytes(Cow::from(bytes.to_vec())), hash);
+}
@@ -13,6 +13,10 @@
 //!   - Log Index
 //!   - Log Data
 //!   - Anchor Index
+//!   - Timestamp Index
+//!   - Caller Index
+//!   - Hash Chain
+//!   - Merkle Tree
 //! ----------------------------------------
 //! Unallocated space
 //! ```
@@ -36,14 +40,51 @@
 //! - prefix scan with anchor to retrieve entries by anchor
 //! - prefix scan with (anchor, timestamp) to narrow down on the time period for a specific anchor
 //! - prefix scan with (anchor, timestamp, log index) to do pagination (with the key of the first entry not included in the previous set)
+//!
+//! ### Timestamp Index
+//! The timestamp index is a [StableBTreeMap] of (timestamp, log index) -> () to efficiently retrieve
+//! the entries of all anchors within a time range. Like for the anchor index, the log index is part
+//! of the key to ensure uniqueness and the key of the first entry not included in a response is used
+//! for pagination.
+//!
+//! ### Caller Index
+//! The caller index is a [StableBTreeMap] of (caller, log index) -> () to efficiently retrieve the
+//! entries made by a given caller (see [EntryFilter]). To populate it, the caller is decoded from the
+//! entry on write (see [IndexedFields]).
+//!
+//! The timestamp and the caller index have been introduced after entries had already been archived.
+//! These entries are added to the indices by a timer after the upgrade (see [backfill_indices]).
+//! Until then, the entries not yet added to the caller index are found by scanning the log (see
+//! [get_filtered_entries]) and responses by time range indicate the entries missing from them (see
+//! [get_entries_by_time]).
+//!
+//! ### Hash Chain
+//! To make modifications of the archived data evident, every log entry is chained to its
+//! predecessor: the hash chain is a [StableBTreeMap] of log index -> SHA-256(previous hash || entry)
+//! (see [EntryHash::chain]). The hash chain allows verifying ranges of entries (see [verify_range]).
+//!
+//! ### Merkle Tree
+//! To prove individual entries with proofs of logarithmic size, the entries are also added to a
+//! Merkle tree as defined by RFC 9162 (section 2.1). The tree is a [StableBTreeMap] of
+//! (level, index) -> hash containing the roots of all the perfect subtrees (see [MerkleNodeKey]),
+//! from which the roots of all other subtrees are computed on demand (see [merkle_subtree_hash]).
+//!
+//! The certified data of the canister is SHA-256(length || hash chain head || Merkle root), where
+//! length is the number of entries in the hash chain and the Merkle tree as 8 bytes big endian
+//! (see [certified_hash]). This allows clients to check the entries they retrieve against it (see
+//! [get_entry_proof]).
+//!
+//! The hash chain and the Merkle tree have been introduced after entries had already been archived.
+//! As they have to be built in order, new entries are only added on write once the entries archived
+//! before have been added by a timer after the upgrade (see [backfill_hash_chain]).
 use candid::{candid_method, CandidType, Deserialize, Principal};
 use ic_cdk::api::call::CallResult;
 use ic_cdk::api::management_canister::main::{canister_status, CanisterIdRecord};
 use ic_cdk::api::stable::stable64_size;
-use ic_cdk::api::time;
+use ic_cdk::api::{data_certificate, set_certified_data, time};
 use ic_cdk::{call, caller, id, print, trap};
 use ic_cdk_macros::{init, post_upgrade, query, update};
-use ic_cdk_timers::set_timer_interval;
+use ic_cdk_timers::{set_timer, set_timer_interval};
 use ic_metrics_encoder::MetricsEncoder;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{
@@ -54,12 +95,22 @@ use internet_identity_interface::archive::types::*;
 use internet_identity_interface::http_gateway::{HttpRequest, HttpResponse};
 use internet_identity_interface::internet_identity::types::*;
 use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::ops::Range;
 use std::time::Duration;
 
 #[cfg(test)]
 mod anchor_index_key_tests;
+#[cfg(test)]
+mod caller_index_key_tests;
+#[cfg(test)]
+mod entry_hash_tests;
+#[cfg(test)]
+mod merkle_tree_tests;
+#[cfg(test)]
+mod timestamp_index_key_tests;
 
 /// We use restricted memory in order to ensure the separation between non-managed config memory (first page)
 /// and the managed memory for the archived data & indices.
@@ -69,6 +120,14 @@ type ConfigCell = StableCell<ConfigState, Memory>;
 /// Type of the index to efficiently retrieve entries by anchor.
 type LogIndex = u64;
 type AnchorIndex = StableBTreeMap<AnchorIndexKey, (), VirtualMemory<Memory>>;
+/// Type of the index to efficiently retrieve entries by time range.
+type TimestampIndex = StableBTreeMap<TimestampIndexKey, (), VirtualMemory<Memory>>;
+/// Type of the index to efficiently retrieve entries by caller.
+type CallerIndex = StableBTreeMap<CallerIndexKey, (), VirtualMemory<Memory>>;
+/// Type of the hash chain over all log entries.
+type HashChain = StableBTreeMap<LogIndex, EntryHash, VirtualMemory<Memory>>;
+/// Type of the Merkle tree over all log entries.
+type MerkleTree = StableBTreeMap<MerkleNodeKey, EntryHash, VirtualMemory<Memory>>;
 
 const GIB: u64 = 1 << 30;
 const WASM_PAGE_SIZE: u64 = 65536;
@@ -80,6 +139,21 @@ const MAX_WASM_PAGES: u64 = MAX_STABLE_MEMORY_SIZE / WASM_PAGE_SIZE;
 const LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(0);
 const LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(1);
 const ANCHOR_ACCESS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(2);
+const TIMESTAMP_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
+const CALLER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(4);
+const HASH_CHAIN_MEMORY_ID: MemoryId = MemoryId::new(5);
+const MERKLE_TREE_MEMORY_ID: MemoryId = MemoryId::new(6);
+
+/// Number of log entries added to an index (or the hash chain) per timer invocation when backfilling it.
+const INDEX_BACKFILL_BATCH_SIZE: u64 = 1_000;
+/// Maximum number of entries checked against a filter per call to bound the instructions used by
+/// filtered queries.
+const MAX_FILTERED_ENTRIES_PER_CALL: usize = 10_000;
+/// Maximum number of entries verified against the hash chain per call to bound the instructions
+/// used by [verify_range].
+const MAX_VERIFIED_ENTRIES_PER_CALL: u64 = 1_000;
+/// Maximum length of a principal in bytes.
+const MAX_PRINCIPAL_LENGTH: usize = 29;
 
 thread_local! {
     /// Static configuration of the archive set by init() or post_upgrade().
@@ -99,6 +173,26 @@ thread_local! {
         RefCell::new(StableBTreeMap::init(memory_manager.get(ANCHOR_ACCESS_INDEX_MEMORY_ID)))
     });
 
+    /// Index to efficiently retrieve entries by time range.
+    static TIMESTAMP_INDEX: RefCell<TimestampIndex> = with_memory_manager(|memory_manager| {
+        RefCell::new(StableBTreeMap::init(memory_manager.get(TIMESTAMP_INDEX_MEMORY_ID)))
+    });
+
+    /// Index to efficiently retrieve entries by caller.
+    static CALLER_INDEX: RefCell<CallerIndex> = with_memory_manager(|memory_manager| {
+        RefCell::new(StableBTreeMap::init(memory_manager.get(CALLER_INDEX_MEMORY_ID)))
+    });
+
+    /// Hash chain over all log entries.
+    static HASH_CHAIN: RefCell<HashChain> = with_memory_manager(|memory_manager| {
+        RefCell::new(StableBTreeMap::init(memory_manager.get(HASH_CHAIN_MEMORY_ID)))
+    });
+
+    /// Merkle tree over all log entries.
+    static MERKLE_TREE: RefCell<MerkleTree> = with_memory_manager(|memory_manager| {
+        RefCell::new(StableBTreeMap::init(memory_manager.get(MERKLE_TREE_MEMORY_ID)))
+    });
+
     /// Information about the calls the archive is making to II. Not persistent in stable memory.
     static CALL_INFO: RefCell<CallInfo> = RefCell::new(CallInfo::default());
 }
@@ -133,6 +227,26 @@ fn with_anchor_index_mut<R>(f: impl FnOnce(&mut AnchorIndex) -> R) -> R {
     ANCHOR_INDEX.with(|cell| f(&mut cell.borrow_mut()))
 }
 
+/// A helper function to access the timestamp-based index.
+fn with_timestamp_index_mut<R>(f: impl FnOnce(&mut TimestampIndex) -> R) -> R {
+    TIMESTAMP_INDEX.with(|cell| f(&mut cell.borrow_mut()))
+}
+
+/// A helper function to access the caller-based index.
+fn with_caller_index_mut<R>(f: impl FnOnce(&mut CallerIndex) -> R) -> R {
+    CALLER_INDEX.with(|cell| f(&mut cell.borrow_mut()))
+}
+
+/// A helper function to access the hash chain.
+fn with_hash_chain_mut<R>(f: impl FnOnce(&mut HashChain) -> R) -> R {
+    HASH_CHAIN.with(|cell| f(&mut cell.borrow_mut()))
+}
+
+/// A helper function to access the Merkle tree.
+fn with_merkle_tree_mut<R>(f: impl FnOnce(&mut MerkleTree) -> R) -> R {
+    MERKLE_TREE.with(|cell| f(&mut cell.borrow_mut()))
+}
+
 /// A helper function to access the call info.
 fn with_call_info<R>(f: impl FnOnce(&CallInfo) -> R) -> R {
     CALL_INFO.with(|cell| f(&cell.borrow_mut()))
@@ -173,6 +287,38 @@ struct ArchiveConfig {
     error_buffer_limit: Option<u16>,
     /// Highest sequence number of any entry that was archived.
     highest_sequence_number: Option<u64>,
+    /// Length of the log when the timestamp index was introduced. Entries archived from then on are
+    /// added to the index when written.
+    timestamp_index_start: Option<LogIndex>,
+    /// Number of log entries archived before the timestamp index was introduced that have been
+    /// added to the index (see [backfill_indices]).
+    timestamp_index_progress: Option<LogIndex>,
+    /// Length of the log when the caller index was introduced. Entries archived from then on are
+    /// added to the index when written.
+    caller_index_start: Option<LogIndex>,
+    /// Number of log entries archived before the caller index was introduced that have been
+    /// added to the index (see [backfill_indices]).
+    caller_index_progress: Option<LogIndex>,
+}
+
+/// The fields of an [Entry] needed to index it. Decoding only these fields keeps the archive
+/// independent of the schema of the operations.
+#[derive(CandidType, Deserialize)]
+struct IndexedFields {
+    timestamp: Timestamp,
+    caller: Principal,
+}
+
+impl IndexedFields {
+    fn decode(entry: &[u8], log_index: LogIndex) -> Option<Self> {
+        candid::decode_one(entry)
+            .map_err(|err| {
+                print(format!(
+                    "Failed to decode the indexed fields of log entry {log_index}: {err}"
+                ))
+            })
+            .ok()
+    }
 }
 
 impl Storable for ConfigState {
@@ -238,6 +384,169 @@ impl BoundedStorable for AnchorIndexKey {
     const IS_FIXED_SIZE: bool = true;
 }
 
+/// Index key for the timestamp index.
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+struct TimestampIndexKey {
+    // Attention: order of fields MUST NOT be changed because Ord is derived!
+    timestamp: Timestamp,
+    log_index: LogIndex,
+}
+
+/// Storable implementation for the index key.
+/// Note: byte ordering is very important as the keys are sorted on a byte level (lower to higher index)
+/// --> use big endian to ensure that the most significant bytes are compared first
+impl Storable for TimestampIndexKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(std::mem::size_of::<TimestampIndexKey>());
+        buf.extend(self.timestamp.to_be_bytes());
+        buf.extend(self.log_index.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        TimestampIndexKey {
+            timestamp: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[0..8]).expect("failed to read timestamp"),
+            ),
+            log_index: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[8..]).expect("failed to read log_index"),
+            ),
+        }
+    }
+}
+
+impl BoundedStorable for TimestampIndexKey {
+    const MAX_SIZE: u32 = std::mem::size_of::<TimestampIndexKey>() as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Index key for the caller index.
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+struct CallerIndexKey {
+    // Attention: order of fields MUST NOT be changed because Ord is derived!
+    caller: Principal,
+    log_index: LogIndex,
+}
+
+/// Storable implementation for the index key.
+/// The principal is stored with its length and padded to the maximum length to get keys of fixed size.
+impl Storable for CallerIndexKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let caller = self.caller.as_slice();
+        let mut buf = Vec::with_capacity(Self::MAX_SIZE as usize);
+        buf.push(caller.len() as u8);
+        buf.extend(caller);
+        buf.resize(1 + MAX_PRINCIPAL_LENGTH, 0);
+        buf.extend(self.log_index.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let caller_len = bytes[0] as usize;
+        CallerIndexKey {
+            caller: Principal::from_slice(&bytes[1..1 + caller_len]),
+            log_index: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[1 + MAX_PRINCIPAL_LENGTH..])
+                    .expect("failed to read log_index"),
+            ),
+        }
+    }
+}
+
+impl BoundedStorable for CallerIndexKey {
+    const MAX_SIZE: u32 = (1 + MAX_PRINCIPAL_LENGTH + std::mem::size_of::<LogIndex>()) as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Hash of a log entry in the hash chain or of a node of the Merkle tree.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+struct EntryHash([u8; 32]);
+
+impl EntryHash {
+    /// The hash the first log entry is chained to.
+    const GENESIS: EntryHash = EntryHash([0; 32]);
+
+    /// Returns the hash of the given (encoded) entry chained to this hash, i.e.
+    /// SHA-256(self || entry).
+    fn chain(&self, entry: &[u8]) -> EntryHash {
+        let mut hasher = Sha256::new();
+        hasher.update(self.0);
+        hasher.update(entry);
+        EntryHash(hasher.finalize().into())
+    }
+
+    /// Returns the hash of the given (encoded) entry as a leaf of the Merkle tree, i.e.
+    /// SHA-256(0x00 || entry).
+    fn merkle_leaf(entry: &[u8]) -> EntryHash {
+        let mut hasher = Sha256::new();
+        hasher.update([0x00]);
+        hasher.update(entry);
+        EntryHash(hasher.finalize().into())
+    }
+
+    /// Returns the hash of an inner node of the Merkle tree, i.e. SHA-256(0x01 || left || right).
+    fn merkle_node(left: &EntryHash, right: &EntryHash) -> EntryHash {
+        let mut hasher = Sha256::new();
+        hasher.update([0x01]);
+        hasher.update(left.0);
+        hasher.update(right.0);
+        EntryHash(hasher.finalize().into())
+    }
+}
+
+impl Storable for EntryHash {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        EntryHash(TryFrom::try_from(bytes.as_ref()).expect("failed to read entry hash"))
+    }
+}
+
+impl BoundedStorable for EntryHash {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Key of a node of the Merkle tree. The node with the given level and index is the root of the
+/// perfect subtree over the log entries index * 2^level to (index + 1) * 2^level (exclusive).
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+struct MerkleNodeKey {
+    // Attention: order of fields MUST NOT be changed because Ord is derived!
+    level: u8,
+    index: u64,
+}
+
+/// Storable implementation for the node key.
+/// Note: byte ordering is very important as the keys are sorted on a byte level (lower to higher index)
+/// --> use big endian to ensure that the most significant bytes are compared first
+impl Storable for MerkleNodeKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::MAX_SIZE as usize);
+        buf.push(self.level);
+        buf.extend(self.index.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        MerkleNodeKey {
+            level: bytes[0],
+            index: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[1..]).expect("failed to read index"),
+            ),
+        }
+    }
+}
+
+impl BoundedStorable for MerkleNodeKey {
+    const MAX_SIZE: u32 = (1 + std::mem::size_of::<u64>()) as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
 /// This method is kept for legacy compatibility and easier testability of the archive.
 /// I.e. this allows rolling back Internet Identity from pull to push without rolling back the
 /// archive.
@@ -353,10 +662,8 @@ async fn fetch_entries() {
 }
 
 fn write_entry_internal(anchor: AnchorNumber, timestamp: Timestamp, entry: ByteBuf) {
-    let idx = with_log(|log| {
-        log.append(&entry.into_vec())
-            .expect("failed to append log entry")
-    });
+    let entry = entry.into_vec();
+    let idx = with_log(|log| log.append(&entry).expect("failed to append log entry"));
 
     with_anchor_index_mut(|index| {
         let key = AnchorIndexKey {
@@ -366,7 +673,162 @@ fn write_entry_internal(anchor: AnchorNumber, timestamp: Timestamp, entry: ByteB
         };
 
         index.insert(key, ());
-    })
+    });
+
+    with_timestamp_index_mut(|index| {
+        let key = TimestampIndexKey {
+            timestamp,
+            log_index: idx,
+        };
+
+        index.insert(key, ());
+    });
+
+    if let Some(fields) = IndexedFields::decode(&entry, idx) {
+        with_caller_index_mut(|index| {
+            let key = CallerIndexKey {
+                caller: fields.caller,
+                log_index: idx,
+            };
+
+            index.insert(key, ());
+        })
+    }
+
+    // the entries archived before the hash chain was introduced have to be added first
+    if with_hash_chain_mut(|chain| chain.len()) == idx {
+        with_log(|log| extend_hash_chain(log, idx + 1));
+    }
+}
+
+/// Returns the hash the log entry with the given index is chained to.
+fn hash_before(chain: &HashChain, log_index: LogIndex) -> EntryHash {
+    match log_index.checked_sub(1) {
+        None => EntryHash::GENESIS,
+        Some(previous) => chain.get(&previous).expect("bug: missing entry hash"),
+    }
+}
+
+/// Adds the log entries up to `end` (exclusive) that are not yet in the hash chain to it and to
+/// the Merkle tree and certifies the new state.
+fn extend_hash_chain(log: &StableLog, end: LogIndex) {
+    with_hash_chain_mut(|chain| {
+        with_merkle_tree_mut(|tree| {
+            let start = chain.len();
+            let mut hash = hash_before(chain, start);
+            for log_index in start..end {
+                let entry = log.get(log_index).expect("bug: missing log entry");
+                hash = hash.chain(&entry);
+                chain.insert(log_index, hash);
+                append_to_merkle_tree(tree, log_index, &entry);
+            }
+        })
+    });
+    certify_hash_chain_head();
+}
+
+/// Adds the log entry with the given index, which has to be the number of entries in the Merkle
+/// tree, to the Merkle tree together with the roots of the perfect subtrees it completes.
+fn append_to_merkle_tree(tree: &mut MerkleTree, log_index: LogIndex, entry: &[u8]) {
+    let mut key = MerkleNodeKey {
+        level: 0,
+        index: log_index,
+    };
+    let mut hash = EntryHash::merkle_leaf(entry);
+    tree.insert(key.clone(), hash);
+    // a right child completes the perfect subtree of its parent
+    while key.index % 2 == 1 {
+        let left = tree
+            .get(&MerkleNodeKey {
+                level: key.level,
+                index: key.index - 1,
+            })
+            .expect("bug: missing Merkle tree node");
+        hash = EntryHash::merkle_node(&left, &hash);
+        key = MerkleNodeKey {
+            level: key.level + 1,
+            index: key.index / 2,
+        };
+        tree.insert(key.clone(), hash);
+    }
+}
+
+/// Returns the largest power of two smaller than `size` (which has to be at least 2), i.e. the
+/// number of entries in the left subtree of a Merkle tree over `size` entries.
+fn merkle_split(size: u64) -> u64 {
+    1 << (63 - (size - 1).leading_zeros())
+}
+
+/// Returns the root of the Merkle (sub)tree over the `size` log entries starting at `start`, where
+/// `start` has to be a multiple of the largest power of two not larger than `size` (which holds
+/// for all subtrees of a Merkle tree starting at 0).
+fn merkle_subtree_hash(tree: &MerkleTree, start: LogIndex, size: u64) -> EntryHash {
+    if size.is_power_of_two() {
+        let level = size.trailing_zeros();
+        return tree
+            .get(&MerkleNodeKey {
+                level: level as u8,
+                index: start >> level,
+            })
+            .expect("bug: missing Merkle tree node");
+    }
+    let split = merkle_split(size);
+    EntryHash::merkle_node(
+        &merkle_subtree_hash(tree, start, split),
+        &merkle_subtree_hash(tree, start + split, size - split),
+    )
+}
+
+/// Returns the root of the Merkle tree over the first `length` log entries. The root of the empty
+/// tree is SHA-256 of the empty string.
+fn merkle_root(tree: &MerkleTree, length: u64) -> EntryHash {
+    if length == 0 {
+        return EntryHash(Sha256::digest(b"").into());
+    }
+    merkle_subtree_hash(tree, 0, length)
+}
+
+/// Returns the inclusion proof of the log entry with the given index in the Merkle (sub)tree over
+/// the `size` log entries starting at `start` (see RFC 9162, section 2.1.3.1), i.e. the hashes of
+/// the siblings on the path from the leaf to the root, starting with the sibling of the leaf.
+fn merkle_inclusion_path(
+    tree: &MerkleTree,
+    log_index: LogIndex,
+    start: LogIndex,
+    size: u64,
+) -> Vec<EntryHash> {
+    if size <= 1 {
+        return vec![];
+    }
+    let split = merkle_split(size);
+    if log_index < start + split {
+        let mut path = merkle_inclusion_path(tree, log_index, start, split);
+        path.push(merkle_subtree_hash(tree, start + split, size - split));
+        path
+    } else {
+        let mut path = merkle_inclusion_path(tree, log_index, start + split, size - split);
+        path.push(merkle_subtree_hash(tree, start, split));
+        path
+    }
+}
+
+/// Returns the hash certified by the archive: SHA-256(length || head || merkle_root), where length
+/// is encoded as 8 bytes big endian.
+fn certified_hash(length: u64, head: &EntryHash, merkle_root: &EntryHash) -> EntryHash {
+    let mut hasher = Sha256::new();
+    hasher.update(length.to_be_bytes());
+    hasher.update(head.0);
+    hasher.update(merkle_root.0);
+    EntryHash(hasher.finalize().into())
+}
+
+fn certify_hash_chain_head() {
+    let hash = with_hash_chain_mut(|chain| {
+        let length = chain.len();
+        let root = with_merkle_tree_mut(|tree| merkle_root(tree, length));
+        certified_hash(length, &hash_before(chain, length), &root)
+    });
+    set_certified_data(&hash.0);
 }
 
 fn store_call_error(call_error: CallErrorInfo) {
@@ -382,8 +844,11 @@ fn store_call_error(call_error: CallErrorInfo) {
 
 #[query]
 #[candid_method(query)]
-fn get_entries(index: Option<u64>, limit: Option<u16>) -> Entries {
+fn get_entries(index: Option<u64>, limit: Option<u16>, filter: Option<EntryFilter>) -> Entries {
     let limit = limit_or_default(limit);
+    if let Some(filter) = filter {
+        return get_filtered_entries(index.unwrap_or(0), limit, filter);
+    }
 
     with_log(|log| {
         let length = log.len();
@@ -402,7 +867,180 @@ fn get_entries(index: Option<u64>, limit: Option<u16>) -> Entries {
                 candid::decode_one(&entry).expect("failed to decode log entry"),
             ))
         }
-        Entries { entries }
+        let next_index = start_idx + entries.len() as u64;
+        Entries {
+            entries,
+            next_index: (next_index < length).then_some(next_index),
+        }
+    })
+}
+
+/// Returns the entries matching the filter in the order they were archived, starting from the given
+/// index. If a caller is given, only the entries of the caller are checked using the caller index.
+/// The entries that have not yet been added to the caller index (see [backfill_indices]) are
+/// checked by scanning the log instead.
+fn get_filtered_entries(start_idx: LogIndex, limit: usize, filter: EntryFilter) -> Entries {
+    let unindexed = with_config(|config| {
+        let progress = config.caller_index_progress.unwrap_or(0).max(start_idx);
+        progress..config.caller_index_start.unwrap_or(0).max(progress)
+    });
+    with_log(|log| {
+        let (entries, next_index) = match filter.caller {
+            Some(caller) => with_caller_index_mut(|index| {
+                let index_key = |log_index| CallerIndexKey { caller, log_index };
+                let indexed_before = index
+                    .range(index_key(start_idx)..index_key(unindexed.start))
+                    .map(|(key, _)| key.log_index);
+                let indexed_after = index
+                    .range(index_key(unindexed.end)..=index_key(LogIndex::MAX))
+                    .map(|(key, _)| key.log_index);
+                let candidates = indexed_before
+                    .chain(unindexed.clone())
+                    .chain(indexed_after)
+                    .map(|log_index| (log_index, log_index));
+                collect_entries(log, candidates, limit, Some(&filter))
+            }),
+            None => {
+                let candidates = (start_idx..log.len()).map(|idx| (idx, idx));
+                collect_entries(log, candidates, limit, Some(&filter))
+            }
+        };
+        Entries {
+            entries,
+            next_index,
+        }
+    })
+}
+
+/// Collects up to `limit` entries matching the filter (if any) from the candidates, given as pairs
+/// of a cursor key and a log index. Returns the entries and the key of the first candidate not
+/// included, if any.
+///
+/// With a filter, at most [MAX_FILTERED_ENTRIES_PER_CALL] candidates are checked, i.e. fewer than
+/// `limit` entries might be returned even though more matching entries exist.
+fn collect_entries<K>(
+    log: &StableLog,
+    candidates: impl Iterator<Item = (K, LogIndex)>,
+    limit: usize,
+    filter: Option<&EntryFilter>,
+) -> (Vec<Option<Entry>>, Option<K>) {
+    let max_checked = match filter {
+        None => limit,
+        Some(_) => MAX_FILTERED_ENTRIES_PER_CALL.max(limit),
+    };
+    let mut entries = vec![];
+    for (checked, (key, log_index)) in candidates.enumerate() {
+        if entries.len() >= limit || checked >= max_checked {
+            return (entries, Some(key));
+        }
+        let entry = log
+            .get(log_index)
+            .expect("bug: index to non-existing entry");
+        let entry: Option<Entry> = candid::decode_one(&entry).expect("failed to decode log entry");
+        let matches = match (filter, &entry) {
+            (None, _) => true,
+            (Some(filter), Some(entry)) => matches_filter(filter, entry),
+            // entries that cannot be decoded do not match any filter
+            (Some(_), None) => false,
+        };
+        if matches {
+            entries.push(entry);
+        }
+    }
+    (entries, None)
+}
+
+fn matches_filter(filter: &EntryFilter, entry: &Entry) -> bool {
+    if matches!(filter.caller, Some(caller) if caller != entry.caller) {
+        return false;
+    }
+    match filter.operation_types {
+        None => true,
+        Some(ref operation_types) => has_operation_type(&entry.operation, operation_types),
+    }
+}
+
+/// Returns true if the operation, or any operation of a batch, has one of the given types.
+fn has_operation_type(operation: &Operation, operation_types: &[OperationType]) -> bool {
+    operation_types.contains(&OperationType::from(operation))
+        || matches!(operation, Operation::Batch { operations }
+            if operations.iter().any(|operation| has_operation_type(operation, operation_types)))
+}
+
+/// Returns the head of the hash chain and the root of the Merkle tree together with their
+/// certificate.
+#[query]
+#[candid_method(query)]
+fn get_hash_chain_head() -> HashChainHead {
+    with_hash_chain_mut(|chain| {
+        let length = chain.len();
+        HashChainHead {
+            length,
+            head: ByteBuf::from(hash_before(chain, length).0),
+            merkle_root: ByteBuf::from(with_merkle_tree_mut(|tree| merkle_root(tree, length)).0),
+            certificate: data_certificate().map(ByteBuf::from),
+        }
+    })
+}
+
+/// Recomputes the hash chain over the log entries in the given range (starting from the stored hash
+/// of the predecessor) and returns the index of the first entry not matching the stored hash, if any.
+/// At most [MAX_VERIFIED_ENTRIES_PER_CALL] entries of the hash chain are verified.
+#[query]
+#[candid_method(query)]
+fn verify_range(from: LogIndex, to: LogIndex) -> RangeVerification {
+    with_log(|log| {
+        with_hash_chain_mut(|chain| {
+            let to = to
+                .min(chain.len())
+                .min(from.saturating_add(MAX_VERIFIED_ENTRIES_PER_CALL))
+                .max(from);
+            let mut first_mismatch = None;
+            if from < to {
+                let mut hash = hash_before(chain, from);
+                for log_index in from..to {
+                    // a missing entry does not match the hash chain either
+                    let Some(entry) = log.get(log_index) else {
+                        first_mismatch = Some(log_index);
+                        break;
+                    };
+                    hash = hash.chain(&entry);
+                    if chain.get(&log_index) != Some(hash) {
+                        first_mismatch = Some(log_index);
+                        break;
+                    }
+                }
+            }
+            RangeVerification {
+                from,
+                to,
+                first_mismatch,
+            }
+        })
+    })
+}
+
+/// Returns the log entry with the given index as archived together with its inclusion proof in the
+/// Merkle tree and the certificate of the Merkle root (see [certified_hash]).
+#[query]
+#[candid_method(query)]
+fn get_entry_proof(index: LogIndex) -> Option<EntryProof> {
+    with_log(|log| {
+        with_hash_chain_mut(|chain| {
+            let length = chain.len();
+            if index >= length {
+                return None;
+            }
+            let path = with_merkle_tree_mut(|tree| merkle_inclusion_path(tree, index, 0, length));
+            Some(EntryProof {
+                index,
+                entry: ByteBuf::from(log.get(index)?),
+                length,
+                path: path.iter().map(|hash| ByteBuf::from(hash.0)).collect(),
+                head: ByteBuf::from(hash_before(chain, length).0),
+                certificate: data_certificate().map(ByteBuf::from),
+            })
+        })
     })
 }
 
@@ -412,6 +1050,7 @@ fn get_anchor_entries(
     anchor: AnchorNumber,
     cursor: Option<Cursor>,
     limit: Option<u16>,
+    filter: Option<EntryFilter>,
 ) -> AnchorEntries {
     let limit = limit_or_default(limit);
 
@@ -458,16 +1097,83 @@ fn get_anchor_entries(
             log_index: 0,
         };
         with_log(|log| {
-            // Take one too many from the iterator to extract the cursor. This avoids having to
-            // iterate twice or use next explicitly.
-            let mut entries: Vec<(AnchorIndexKey, Vec<u8>)> = index
+            let candidates = index.range(start_key..end_key).map(|(anchor_key, _)| {
+                let log_index = anchor_key.log_index;
+                (anchor_key, log_index)
+            });
+            // The key of the first entry not included is used as the cursor.
+            let (entries, next_key) = collect_entries(log, candidates, limit, filter.as_ref());
+            let cursor = next_key.map(|key| Cursor::NextToken {
+                next_token: ByteBuf::from(key.to_bytes()),
+            });
+
+            AnchorEntries { entries, cursor }
+        })
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_entries_by_time(
+    from: Timestamp,
+    to: Timestamp,
+    cursor: Option<Cursor>,
+    limit: Option<u16>,
+) -> TimeRangeEntries {
+    let limit = limit_or_default(limit);
+
+    // Like for get_anchor_entries, the range scan starts at index key
+    // - (from, 0): given no cursor
+    // - (timestamp, 0): given a Timestamp cursor
+    // - (timestamp, idx): given a NextToken cursor
+    let start_key = match cursor {
+        None => TimestampIndexKey {
+            timestamp: from,
+            log_index: 0,
+        },
+        Some(Cursor::NextToken { next_token }) => {
+            let index_key = TimestampIndexKey::from_bytes(Cow::from(next_token.into_vec()));
+            assert!(
+                from <= index_key.timestamp && index_key.timestamp < to,
+                "time range does not match the next_token"
+            );
+            index_key
+        }
+        Some(Cursor::Timestamp { timestamp }) => TimestampIndexKey {
+            timestamp: timestamp.max(from),
+            log_index: 0,
+        },
+    };
+    // End of the range (exclusive) of applicable entries
+    let end_key = TimestampIndexKey {
+        timestamp: to,
+        log_index: 0,
+    };
+    // the entries that have not been added to the index yet are missing from the response
+    let unindexed_entries = with_config(|config| {
+        let from = config.timestamp_index_progress.unwrap_or(0);
+        let to = config.timestamp_index_start.unwrap_or(0);
+        (from < to).then_some(LogRange { from, to })
+    });
+    if start_key >= end_key {
+        return TimeRangeEntries {
+            entries: vec![],
+            cursor: None,
+            unindexed_entries,
+        };
+    }
+
+    with_timestamp_index_mut(|index| {
+        with_log(|log| {
+            // Take one too many from the iterator to extract the cursor.
+            let mut entries: Vec<(TimestampIndexKey, Vec<u8>)> = index
                 .range(start_key..end_key)
                 .take(limit + 1)
-                .map(|(anchor_key, _)| {
+                .map(|(timestamp_key, _)| {
                     let entry = log
-                        .get(anchor_key.log_index)
+                        .get(timestamp_key.log_index)
                         .expect("bug: index to non-existing entry");
-                    (anchor_key, entry)
+                    (timestamp_key, entry)
                 })
                 .collect();
 
@@ -484,7 +1190,11 @@ fn get_anchor_entries(
                 .map(|(_, entry)| candid::decode_one(entry).expect("failed to decode log entry"))
                 .collect();
 
-            AnchorEntries { entries, cursor }
+            TimeRangeEntries {
+                entries,
+                cursor,
+                unindexed_entries,
+            }
         })
     })
 }
@@ -511,17 +1221,132 @@ fn set_highest_archived_sequence_number(sequence_number: u64) {
     write_config(config);
 }
 
+/// Returns the config written by the previous version, if any.
+fn previous_config() -> Option<ArchiveConfig> {
+    CONFIG.with(|config| match config.borrow().get() {
+        ConfigState::Uninitialized => None,
+        ConfigState::Initialized(config) => Some(config.clone()),
+    })
+}
+
+/// Returns true if there are log entries archived before an index was introduced that have not yet
+/// been added to the index.
+fn backfill_pending(config: &ArchiveConfig) -> bool {
+    config.timestamp_index_progress.unwrap_or(0) < config.timestamp_index_start.unwrap_or(0)
+        || config.caller_index_progress.unwrap_or(0) < config.caller_index_start.unwrap_or(0)
+}
+
+/// Returns the log indices to add to an index in the next batch, given the length of the log when
+/// the index was introduced and the number of entries already added.
+fn backfill_batch(start: Option<LogIndex>, progress: Option<LogIndex>) -> Range<LogIndex> {
+    let progress = progress.unwrap_or(0);
+    progress..start.unwrap_or(0).min(progress + INDEX_BACKFILL_BATCH_SIZE)
+}
+
+/// Adds a batch of log entries archived before the timestamp index and the caller index were
+/// introduced to the indices. Re-arms itself until all these entries have been indexed.
+fn backfill_indices() {
+    let mut config = with_config(|config| config.clone());
+    let timestamp_batch = backfill_batch(
+        config.timestamp_index_start,
+        config.timestamp_index_progress,
+    );
+    let caller_batch = backfill_batch(config.caller_index_start, config.caller_index_progress);
+
+    with_log(|log| {
+        for log_index in timestamp_batch.clone() {
+            let entry = log.get(log_index).expect("bug: missing log entry");
+            if let Some(fields) = IndexedFields::decode(&entry, log_index) {
+                with_timestamp_index_mut(|index| {
+                    index.insert(
+                        TimestampIndexKey {
+                            timestamp: fields.timestamp,
+                            log_index,
+                        },
+                        (),
+                    )
+                });
+            }
+        }
+        for log_index in caller_batch.clone() {
+            let entry = log.get(log_index).expect("bug: missing log entry");
+            if let Some(fields) = IndexedFields::decode(&entry, log_index) {
+                with_caller_index_mut(|index| {
+                    index.insert(
+                        CallerIndexKey {
+                            caller: fields.caller,
+                            log_index,
+                        },
+                        (),
+                    )
+                });
+            }
+        }
+    });
+
+    config.timestamp_index_progress = Some(timestamp_batch.end.max(timestamp_batch.start));
+    config.caller_index_progress = Some(caller_batch.end.max(caller_batch.start));
+    let pending = backfill_pending(&config);
+    write_config(config);
+    if pending {
+        set_timer(Duration::ZERO, backfill_indices);
+    }
+}
+
+/// Adds a batch of log entries archived before the hash chain was introduced to the hash chain.
+/// Re-arms itself until the hash chain covers all log entries.
+fn backfill_hash_chain() {
+    let complete = with_log(|log| {
+        let end = log
+            .len()
+            .min(with_hash_chain_mut(|chain| chain.len()) + INDEX_BACKFILL_BATCH_SIZE);
+        extend_hash_chain(log, end);
+        end == log.len()
+    });
+    if !complete {
+        set_timer(Duration::ZERO, backfill_hash_chain);
+    }
+}
+
 #[init]
 #[post_upgrade]
 fn initialize(arg: ArchiveInit) {
-    write_config(ArchiveConfig {
+    let previous_config = previous_config();
+    // the entries archived before an index was introduced are added by a timer
+    let log_length = with_log(|log| log.len());
+    let index_start = |start: Option<LogIndex>| Some(start.unwrap_or(log_length));
+    let config = ArchiveConfig {
         ii_canister: arg.ii_canister,
         max_entries_per_call: arg.max_entries_per_call,
         last_upgrade_timestamp: time(),
         polling_interval_ns: Some(arg.polling_interval_ns),
         error_buffer_limit: Some(arg.error_buffer_limit),
         highest_sequence_number: highest_archived_sequence_number(),
-    });
+        timestamp_index_start: index_start(
+            previous_config
+                .as_ref()
+                .and_then(|config| config.timestamp_index_start),
+        ),
+        timestamp_index_progress: previous_config
+            .as_ref()
+            .and_then(|config| config.timestamp_index_progress),
+        caller_index_start: index_start(
+            previous_config
+                .as_ref()
+                .and_then(|config| config.caller_index_start),
+        ),
+        caller_index_progress: previous_config
+            .as_ref()
+            .and_then(|config| config.caller_index_progress),
+    };
+    if backfill_pending(&config) {
+        set_timer(Duration::ZERO, backfill_indices);
+    }
+    write_config(config);
+    if with_hash_chain_mut(|chain| chain.len()) < log_length {
+        set_timer(Duration::ZERO, backfill_hash_chain);
+    }
+    certify_hash_chain_head();
 
     set_timer_interval(Duration::from_nanos(arg.polling_interval_ns), || {
         ic_cdk::spawn(fetch_entries())
@@ -603,6 +1428,21 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             .value(&[("source", "log")], log.len() as f64)
             .unwrap()
             .value(&[("source", "anchor_index")], index.len() as f64)
+            .unwrap()
+            .value(
+                &[("source", "timestamp_index")],
+                with_timestamp_index_mut(|index| index.len()) as f64,
+            )
+            .unwrap()
+            .value(
+                &[("source", "caller_index")],
+                with_caller_index_mut(|index| index.len()) as f64,
+            )
+            .unwrap()
+            .value(
+                &[("source", "hash_chain")],
+                with_hash_chain_mut(|chain| chain.len()) as f64,
+            )
         })?;
         w.gauge_vec("ii_archive_log_bytes", "Size of log data in bytes.")
             .unwrap()
@@ -631,6 +1471,26 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             &[("kind", "anchor_index")],
             manager.get(ANCHOR_ACCESS_INDEX_MEMORY_ID).size() as f64,
         )
+        .unwrap()
+        .value(
+            &[("kind", "timestamp_index")],
+            manager.get(TIMESTAMP_INDEX_MEMORY_ID).size() as f64,
+        )
+        .unwrap()
+        .value(
+            &[("kind", "caller_index")],
+            manager.get(CALLER_INDEX_MEMORY_ID).size() as f64,
+        )
+        .unwrap()
+        .value(
+            &[("kind", "hash_chain")],
+            manager.get(HASH_CHAIN_MEMORY_ID).size() as f64,
+        )
+        .unwrap()
+        .value(
+            &[("kind", "merkle_tree")],
+            manager.get(MERKLE_TREE_MEMORY_ID).size() as f64,
+        )
     })?;
     w.encode_gauge(
         "ii_archive_stable_memory_pages",
@@ -0,0 +1,126 @@
+use crate::{
+    append_to_merkle_tree, merkle_inclusion_path, merkle_root, EntryHash, MerkleNodeKey,
+    MerkleTree, MAX_WASM_PAGES,
+};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+use ic_stable_structures::{DefaultMemoryImpl, RestrictedMemory, StableBTreeMap, Storable};
+use std::borrow::Cow;
+
+fn merkle_tree(entries: &[Vec<u8>]) -> MerkleTree {
+    let memory = RestrictedMemory::new(DefaultMemoryImpl::default(), 0..MAX_WASM_PAGES);
+    let mut tree = StableBTreeMap::init(MemoryManager::init(memory).get(MemoryId::new(0)));
+    for (log_index, entry) in entries.iter().enumerate() {
+        append_to_merkle_tree(&mut tree, log_index as u64, entry);
+    }
+    tree
+}
+
+fn entries(count: u8) -> Vec<Vec<u8>> {
+    (0..count).map(|n| vec![n; n as usize]).collect()
+}
+
+/// Splits the leaves as defined by RFC 9162, section 2.1.1.
+fn split(len: usize) -> usize {
+    let mut split = 1;
+    while split * 2 < len {
+        split *= 2;
+    }
+    split
+}
+
+/// Computes the root as defined by RFC 9162, section 2.1.1.
+fn expected_root(entries: &[Vec<u8>]) -> EntryHash {
+    match entries.len() {
+        0 => EntryHash(
+            TryFrom::try_from(
+                hex::decode("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+                    .unwrap(),
+            )
+            .unwrap(),
+        ),
+        1 => EntryHash::merkle_leaf(&entries[0]),
+        len => {
+            let (left, right) = entries.split_at(split(len));
+            EntryHash::merkle_node(&expected_root(left), &expected_root(right))
+        }
+    }
+}
+
+/// Computes the inclusion proof as defined by RFC 9162, section 2.1.3.1.
+fn expected_path(index: usize, entries: &[Vec<u8>]) -> Vec<EntryHash> {
+    if entries.len() <= 1 {
+        return vec![];
+    }
+    let (left, right) = entries.split_at(split(entries.len()));
+    if index < left.len() {
+        let mut path = expected_path(index, left);
+        path.push(expected_root(right));
+        path
+    } else {
+        let mut path = expected_path(index - left.len(), right);
+        path.push(expected_root(left));
+        path
+    }
+}
+
+#[test]
+fn should_have_correct_length() {
+    let node_key = MerkleNodeKey {
+        level: 3,
+        index: 5678,
+    };
+    let bytes = node_key.to_bytes();
+    assert_eq!(bytes.len(), 9);
+    assert_eq!(bytes, hex::decode("03000000000000162e").unwrap());
+}
+
+#[test]
+fn should_deserialize_correctly() {
+    let decoded = hex::decode("0100000002e1b7ad6e").unwrap();
+    let node_key = MerkleNodeKey::from_bytes(Cow::from(decoded));
+
+    assert_eq!(
+        node_key,
+        MerkleNodeKey {
+            level: 1,
+            index: 12376845678,
+        }
+    );
+}
+
+#[test]
+fn should_compute_merkle_root() {
+    let entries = entries(33);
+    let tree = merkle_tree(&entries);
+    for length in 0..=entries.len() {
+        assert_eq!(
+            merkle_root(&tree, length as u64),
+            expected_root(&entries[..length]),
+            "unexpected root for length {length}"
+        );
+    }
+}
+
+#[test]
+fn should_compute_inclusion_paths() {
+    let entries = entries(33);
+    let tree = merkle_tree(&entries);
+    for length in 1..=entries.len() {
+        for index in 0..length {
+            assert_eq!(
+                merkle_inclusion_path(&tree, index as u64, 0, length as u64),
+                expected_path(index, &entries[..length]),
+                "unexpected path for entry {index} and length {length}"
+            );
+        }
+    }
+}
+
+#[test]
+fn should_only_store_perfect_subtrees() {
+    let tree = merkle_tree(&entries(5));
+    // 5 leaves, 2 nodes on level 1 and 1 node on level 2
+    assert_eq!(tree.len(), 8);
+    assert!(tree.get(&MerkleNodeKey { level: 2, index: 0 }).is_some());
+    assert!(tree.get(&MerkleNodeKey { level: 1, index: 2 }).is_none());
+}
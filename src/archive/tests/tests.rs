@@ -491,6 +491,375 @@ mod read_tests {
         }
         Ok(())
     }
+
+    /// Verifies that the entries of all anchors can be retrieved by time range.
+    #[test]
+    fn should_return_entries_by_time_range() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        for n in 0..6 {
+            api::add_entry(
+                &env,
+                canister_id,
+                principal_1(),
+                n % 2,
+                n * 10,
+                candid::encode_one(log_entry(n, n * 10, n % 2)).expect("failed to encode entry"),
+            )?;
+        }
+
+        let logs = api::get_entries_by_time(&env, canister_id, 10, 40, None, None)?;
+        let timestamps: Vec<Timestamp> = logs
+            .entries
+            .iter()
+            .map(|entry| entry.as_ref().unwrap().timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![10, 20, 30]);
+        assert!(logs.cursor.is_none());
+
+        let logs = api::get_entries_by_time(&env, canister_id, 60, 100, None, None)?;
+        assert!(logs.entries.is_empty());
+        Ok(())
+    }
+
+    /// Verifies that additional entries in the time range can be retrieved by supplying the cursor.
+    #[test]
+    fn should_return_time_range_cursor() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        // entries with the same timestamp must not be skipped
+        for n in 0..24 {
+            api::add_entry(
+                &env,
+                canister_id,
+                principal_1(),
+                n % 3,
+                n / 2,
+                candid::encode_one(log_entry(n, n / 2, n % 3)).expect("failed to encode entry"),
+            )?;
+        }
+
+        let logs = api::get_entries_by_time(&env, canister_id, 1, 11, None, None)?;
+        assert_eq!(logs.entries.len(), 10);
+        assert!(matches!(
+            logs.clone().cursor,
+            Some(Cursor::NextToken { next_token: _ })
+        ));
+
+        let logs = api::get_entries_by_time(&env, canister_id, 1, 11, logs.cursor, None)?;
+        let sequence_numbers: Vec<u64> = logs
+            .entries
+            .iter()
+            .map(|entry| entry.as_ref().unwrap().sequence_number)
+            .collect();
+        assert_eq!(sequence_numbers, (12..22).collect::<Vec<u64>>());
+        assert!(logs.cursor.is_none());
+        Ok(())
+    }
+
+    /// Verifies that a cursor obtained for one time range cannot be used for another.
+    #[test]
+    fn should_reject_cursor_outside_time_range() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        for n in 0..12 {
+            api::add_entry(
+                &env,
+                canister_id,
+                principal_1(),
+                ANCHOR_NUMBER_1,
+                n,
+                candid::encode_one(log_entry(n, n, ANCHOR_NUMBER_1))
+                    .expect("failed to encode entry"),
+            )?;
+        }
+        let logs = api::get_entries_by_time(&env, canister_id, 0, 12, None, None)?;
+
+        let result = api::get_entries_by_time(&env, canister_id, 0, 5, logs.cursor, None);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("time range does not match the next_token").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that the entries can be filtered by caller.
+    #[test]
+    fn should_filter_entries_by_caller() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        for n in 0..30 {
+            api::add_entry(
+                &env,
+                canister_id,
+                principal_1(),
+                ANCHOR_NUMBER_1,
+                n,
+                candid::encode_one(log_entry(n, n, ANCHOR_NUMBER_1))
+                    .expect("failed to encode entry"),
+            )?;
+        }
+
+        let filter = EntryFilter {
+            operation_types: None,
+            caller: Some(test_principal(7)),
+        };
+        let logs = api::get_entries_with_filter(&env, canister_id, None, None, &filter)?;
+        assert_eq!(logs.entries.len(), 1);
+        assert_eq!(logs.entries[0].as_ref().unwrap().sequence_number, 7);
+        assert_eq!(logs.next_index, None);
+
+        let logs = api::get_entries_with_filter(&env, canister_id, Some(8), None, &filter)?;
+        assert!(logs.entries.is_empty());
+        Ok(())
+    }
+
+    /// Verifies that the entries can be filtered by operation type, including the operations of
+    /// batches, and that all the entries can be retrieved using `next_index`.
+    #[test]
+    fn should_filter_entries_by_operation_type() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        let batch = Entry {
+            operation: Operation::Batch {
+                operations: vec![log_entry_1().operation, log_entry_2().operation],
+            },
+            sequence_number: 2,
+            ..log_entry_1()
+        };
+        let mut entries = vec![log_entry_1(), log_entry_2(), batch];
+        entries.extend((3..30).map(|n| log_entry(n, n, ANCHOR_NUMBER_1)));
+        for entry in &entries {
+            api::add_entry(
+                &env,
+                canister_id,
+                principal_1(),
+                entry.anchor,
+                entry.timestamp,
+                candid::encode_one(entry).expect("failed to encode entry"),
+            )?;
+        }
+
+        let filter = EntryFilter {
+            operation_types: Some(vec![OperationType::AddDevice]),
+            caller: None,
+        };
+        let logs = api::get_entries_with_filter(&env, canister_id, None, None, &filter)?;
+        let sequence_numbers: Vec<u64> = logs
+            .entries
+            .iter()
+            .map(|entry| entry.as_ref().unwrap().sequence_number)
+            .collect();
+        assert_eq!(sequence_numbers, vec![1, 2]);
+        assert_eq!(logs.next_index, None);
+
+        let filter = EntryFilter {
+            operation_types: Some(vec![OperationType::UpdateDevice]),
+            caller: None,
+        };
+        let logs = api::get_entries_with_filter(&env, canister_id, None, None, &filter)?;
+        assert_eq!(logs.entries.len(), 10);
+        assert_eq!(logs.next_index, Some(13));
+        let logs = api::get_entries_with_filter(&env, canister_id, logs.next_index, None, &filter)?;
+        assert_eq!(logs.entries.len(), 10);
+        let logs = api::get_entries_with_filter(&env, canister_id, logs.next_index, None, &filter)?;
+        assert_eq!(logs.entries.len(), 7);
+        assert_eq!(logs.next_index, None);
+        Ok(())
+    }
+
+    /// Verifies that the entries of an anchor can be filtered.
+    #[test]
+    fn should_filter_anchor_entries() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        for n in 0..24 {
+            api::add_entry(
+                &env,
+                canister_id,
+                principal_1(),
+                n % 2,
+                n,
+                candid::encode_one(log_entry(n, n, n % 2)).expect("failed to encode entry"),
+            )?;
+        }
+
+        let filter = EntryFilter {
+            operation_types: None,
+            caller: Some(test_principal(21)),
+        };
+        let logs = api::get_anchor_entries_with_filter(&env, canister_id, 1, None, None, &filter)?;
+        assert_eq!(logs.entries.len(), 1);
+        assert_eq!(logs.entries[0].as_ref().unwrap().sequence_number, 21);
+        assert!(logs.cursor.is_none());
+
+        let logs = api::get_anchor_entries_with_filter(&env, canister_id, 0, None, None, &filter)?;
+        assert!(logs.entries.is_empty());
+        assert!(logs.cursor.is_none());
+        Ok(())
+    }
+}
+
+/// Tests the hash chain over the archived entries.
+#[cfg(test)]
+mod hash_chain_tests {
+    use super::*;
+    use ic_cdk::api::management_canister::main::CanisterId;
+    use ic_test_state_machine_client::StateMachine;
+    use sha2::{Digest, Sha256};
+
+    fn add_entries(env: &StateMachine, canister_id: CanisterId, count: u64) -> Vec<Vec<u8>> {
+        let entries: Vec<Vec<u8>> = (0..count)
+            .map(|n| candid::encode_one(log_entry(n, n, n % 2)).expect("failed to encode entry"))
+            .collect();
+        for (n, entry) in entries.iter().enumerate() {
+            api::add_entry(
+                env,
+                canister_id,
+                principal_1(),
+                n as u64 % 2,
+                n as u64,
+                entry.clone(),
+            )
+            .expect("failed to add entry");
+        }
+        entries
+    }
+
+    fn chain(prev_hash: &[u8], entry: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(prev_hash);
+        hasher.update(entry);
+        hasher.finalize().to_vec()
+    }
+
+    fn sha256(parts: &[&[u8]]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().to_vec()
+    }
+
+    /// Computes the Merkle root from an inclusion proof as defined by RFC 9162, section 2.1.3.2.
+    fn merkle_root_from_proof(proof: &EntryProof) -> Option<Vec<u8>> {
+        if proof.index >= proof.length {
+            return None;
+        }
+        let mut fn_ = proof.index;
+        let mut sn = proof.length - 1;
+        let mut root = sha256(&[&[0x00], &proof.entry]);
+        for sibling in &proof.path {
+            if sn == 0 {
+                return None;
+            }
+            if fn_ % 2 == 1 || fn_ == sn {
+                root = sha256(&[&[0x01], sibling, &root]);
+                while fn_ % 2 == 0 && fn_ != 0 {
+                    fn_ >>= 1;
+                    sn >>= 1;
+                }
+            } else {
+                root = sha256(&[&[0x01], &root, sibling]);
+            }
+            fn_ >>= 1;
+            sn >>= 1;
+        }
+        (sn == 0).then_some(root)
+    }
+
+    /// Verifies that the certified head is the hash chained over all entries.
+    #[test]
+    fn should_certify_hash_chain_head() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        let head = api::get_hash_chain_head(&env, canister_id)?;
+        assert_eq!(head.length, 0);
+        assert_eq!(head.head.into_vec(), vec![0; 32]);
+        assert_eq!(head.merkle_root.into_vec(), sha256(&[]));
+
+        let entries = add_entries(&env, canister_id, 5);
+        let expected_head = entries
+            .iter()
+            .fold(vec![0; 32], |hash, entry| chain(&hash, entry));
+
+        let leaves: Vec<Vec<u8>> = entries
+            .iter()
+            .map(|entry| sha256(&[&[0x00], entry]))
+            .collect();
+        let node = |left: &[u8], right: &[u8]| sha256(&[&[0x01], left, right]);
+        let expected_root = node(
+            &node(&node(&leaves[0], &leaves[1]), &node(&leaves[2], &leaves[3])),
+            &leaves[4],
+        );
+
+        let head = api::get_hash_chain_head(&env, canister_id)?;
+        assert_eq!(head.length, 5);
+        assert_eq!(head.head.to_vec(), expected_head);
+        assert_eq!(head.merkle_root.to_vec(), expected_root);
+        assert!(head.certificate.is_some());
+
+        upgrade_archive_canister(&env, canister_id, ARCHIVE_WASM.clone());
+        let head = api::get_hash_chain_head(&env, canister_id)?;
+        assert_eq!(head.head.into_vec(), expected_head);
+        Ok(())
+    }
+
+    /// Verifies that the proofs of logarithmic size link the entries to the certified Merkle root.
+    #[test]
+    fn should_return_entry_proofs() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        let entries = add_entries(&env, canister_id, 11);
+        let head = api::get_hash_chain_head(&env, canister_id)?;
+
+        for (n, entry) in entries.iter().enumerate() {
+            let proof =
+                api::get_entry_proof(&env, canister_id, n as u64)?.expect("missing entry proof");
+            assert_eq!(proof.index, n as u64);
+            assert_eq!(&proof.entry.to_vec(), entry);
+            assert_eq!(proof.length, 11);
+            assert_eq!(proof.head, head.head);
+            assert!(proof.path.len() <= 4);
+            assert_eq!(proof.certificate, head.certificate);
+            assert_eq!(
+                merkle_root_from_proof(&proof),
+                Some(head.merkle_root.to_vec())
+            );
+        }
+        assert!(api::get_entry_proof(&env, canister_id, 11)?.is_none());
+        Ok(())
+    }
+
+    /// Verifies that ranges of the hash chain are verified in pages.
+    #[test]
+    fn should_verify_range() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        add_entries(&env, canister_id, 1_010);
+
+        let verification = api::verify_range(&env, canister_id, 0, 2_000)?;
+        assert_eq!(verification.from, 0);
+        assert_eq!(verification.to, 1_000);
+        assert_eq!(verification.first_mismatch, None);
+
+        let verification = api::verify_range(&env, canister_id, verification.to, 2_000)?;
+        assert_eq!(verification.to, 1_010);
+        assert_eq!(verification.first_mismatch, None);
+
+        let verification = api::verify_range(&env, canister_id, 2_000, 3_000)?;
+        assert_eq!(verification.to, 2_000);
+        assert_eq!(verification.first_mismatch, None);
+        Ok(())
+    }
 }
 
 /// Tests the metrics exposed via for the HTTP.
@@ -505,11 +874,18 @@ mod metrics_tests {
             "ii_archive_last_upgrade_timestamp_seconds",
             "ii_archive_entries_count{source=\"log\"}",
             "ii_archive_entries_count{source=\"anchor_index\"}",
+            "ii_archive_entries_count{source=\"timestamp_index\"}",
+            "ii_archive_entries_count{source=\"caller_index\"}",
+            "ii_archive_entries_count{source=\"hash_chain\"}",
             "ii_archive_log_bytes{type=\"entries\"}",
             "ii_archive_log_bytes{type=\"index\"}",
             "ii_archive_virtual_memory_pages{kind=\"log_index\"}",
             "ii_archive_virtual_memory_pages{kind=\"log_data\"}",
             "ii_archive_virtual_memory_pages{kind=\"anchor_index\"}",
+            "ii_archive_virtual_memory_pages{kind=\"timestamp_index\"}",
+            "ii_archive_virtual_memory_pages{kind=\"caller_index\"}",
+            "ii_archive_virtual_memory_pages{kind=\"hash_chain\"}",
+            "ii_archive_virtual_memory_pages{kind=\"merkle_tree\"}",
             "ii_archive_stable_memory_pages",
             // The metrics
             //   * ii_archive_last_successful_fetch_timestamp_seconds
@@ -571,6 +947,9 @@ mod metrics_tests {
         let metrics = vec![
             "ii_archive_entries_count{source=\"log\"}",
             "ii_archive_entries_count{source=\"anchor_index\"}",
+            "ii_archive_entries_count{source=\"timestamp_index\"}",
+            "ii_archive_entries_count{source=\"caller_index\"}",
+            "ii_archive_entries_count{source=\"hash_chain\"}",
         ];
 
         let env = env();
@@ -662,10 +1041,30 @@ mod metrics_tests {
             "ii_archive_virtual_memory_pages{kind=\"anchor_index\"}",
             1f64,
         );
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_virtual_memory_pages{kind=\"timestamp_index\"}",
+            1f64,
+        );
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_virtual_memory_pages{kind=\"caller_index\"}",
+            1f64,
+        );
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_virtual_memory_pages{kind=\"hash_chain\"}",
+            1f64,
+        );
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_virtual_memory_pages{kind=\"merkle_tree\"}",
+            1f64,
+        );
         assert_metric(
             &get_metrics(&env, canister_id),
             "ii_archive_stable_memory_pages",
-            3074f64, // the memory_manager pre-allocates a lot of memory (1024 page buckets per virtual memory and some overhead)
+            7170f64, // the memory_manager pre-allocates a lot of memory (1024 page buckets per virtual memory and some overhead)
         );
 
         api::add_entry(
@@ -692,10 +1091,30 @@ mod metrics_tests {
             "ii_archive_virtual_memory_pages{kind=\"anchor_index\"}",
             1f64, // does not change because the index additions are small
         );
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_virtual_memory_pages{kind=\"timestamp_index\"}",
+            1f64, // does not change because the index additions are small
+        );
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_virtual_memory_pages{kind=\"caller_index\"}",
+            1f64, // does not change because the index additions are small
+        );
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_virtual_memory_pages{kind=\"hash_chain\"}",
+            1f64, // does not change because the hash chain additions are small
+        );
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_virtual_memory_pages{kind=\"merkle_tree\"}",
+            1f64, // does not change because the Merkle tree additions are small
+        );
         assert_metric(
             &get_metrics(&env, canister_id),
             "ii_archive_stable_memory_pages",
-            3074f64, // does not change due to pre-allocation
+            7170f64, // does not change due to pre-allocation
         );
 
         Ok(())
@@ -801,5 +1220,70 @@ mod stable_memory_tests {
             entries.entries.get(3).unwrap().as_ref().unwrap(),
             &delete_entry
         );
+
+        // the entries not yet added to the caller index are found by scanning the log
+        let entries = api::get_entries_with_filter(
+            &env,
+            canister_id,
+            None,
+            None,
+            &EntryFilter {
+                operation_types: None,
+                caller: Some(principal_1()),
+            },
+        )
+        .unwrap();
+        assert_eq!(entries.entries.len(), 4);
+
+        // the entries archived before the timestamp index was introduced are indexed by a timer,
+        // until then the response indicates the missing entries
+        let entries =
+            api::get_entries_by_time(&env, canister_id, TIMESTAMP, TIMESTAMP + 1, None, None)
+                .unwrap();
+        assert!(entries.entries.is_empty());
+        assert_eq!(entries.unindexed_entries, Some(LogRange { from: 0, to: 4 }));
+        env.tick();
+        let entries =
+            api::get_entries_by_time(&env, canister_id, TIMESTAMP, TIMESTAMP + 1, None, None)
+                .unwrap();
+        assert_eq!(entries.entries.len(), 4);
+        assert_eq!(entries.unindexed_entries, None);
+        assert_eq!(
+            entries.entries.get(3).unwrap().as_ref().unwrap(),
+            &delete_entry
+        );
+
+        // the caller index is backfilled by the same timer
+        let entries = api::get_entries_with_filter(
+            &env,
+            canister_id,
+            None,
+            None,
+            &EntryFilter {
+                operation_types: None,
+                caller: Some(principal_1()),
+            },
+        )
+        .unwrap();
+        assert_eq!(entries.entries.len(), 4);
+        let entries = api::get_entries_with_filter(
+            &env,
+            canister_id,
+            None,
+            None,
+            &EntryFilter {
+                operation_types: None,
+                caller: Some(principal_2()),
+            },
+        )
+        .unwrap();
+        assert!(entries.entries.is_empty());
+
+        // the hash chain is backfilled by a timer as well
+        let head = api::get_hash_chain_head(&env, canister_id).unwrap();
+        assert_eq!(head.length, 4);
+        let verification = api::verify_range(&env, canister_id, 0, 4).unwrap();
+        assert_eq!(verification.to, 4);
+        assert_eq!(verification.first_mismatch, None);
     }
 }
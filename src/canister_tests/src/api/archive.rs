@@ -32,6 +32,16 @@ pub fn get_entries(
     query_candid(env, canister_id, "get_entries", (idx, limit)).map(|(x,)| x)
 }
 
+pub fn get_entries_with_filter(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    idx: Option<u64>,
+    limit: Option<u16>,
+    filter: &EntryFilter,
+) -> Result<Entries, CallError> {
+    query_candid(env, canister_id, "get_entries", (idx, limit, Some(filter))).map(|(x,)| x)
+}
+
 pub fn get_anchor_entries(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -48,6 +58,64 @@ pub fn get_anchor_entries(
     .map(|(x,)| x)
 }
 
+pub fn get_anchor_entries_with_filter(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    anchor: AnchorNumber,
+    cursor: Option<Cursor>,
+    limit: Option<u16>,
+    filter: &EntryFilter,
+) -> Result<AnchorEntries, CallError> {
+    query_candid(
+        env,
+        canister_id,
+        "get_anchor_entries",
+        (anchor, cursor, limit, Some(filter)),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn get_entries_by_time(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    from: Timestamp,
+    to: Timestamp,
+    cursor: Option<Cursor>,
+    limit: Option<u16>,
+) -> Result<TimeRangeEntries, CallError> {
+    query_candid(
+        env,
+        canister_id,
+        "get_entries_by_time",
+        (from, to, cursor, limit),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn get_hash_chain_head(
+    env: &StateMachine,
+    canister_id: CanisterId,
+) -> Result<HashChainHead, CallError> {
+    query_candid(env, canister_id, "get_hash_chain_head", ()).map(|(x,)| x)
+}
+
+pub fn verify_range(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    from: u64,
+    to: u64,
+) -> Result<RangeVerification, CallError> {
+    query_candid(env, canister_id, "verify_range", (from, to)).map(|(x,)| x)
+}
+
+pub fn get_entry_proof(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    index: u64,
+) -> Result<Option<EntryProof>, CallError> {
+    query_candid(env, canister_id, "get_entry_proof", (index,)).map(|(x,)| x)
+}
+
 pub fn status(env: &StateMachine, canister_id: CanisterId) -> Result<ArchiveStatus, CallError> {
     call_candid(env, canister_id, "status", ()).map(|(x,)| x)
 }
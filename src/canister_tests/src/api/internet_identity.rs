@@ -64,10 +64,82 @@ pub fn prepare_delegation(
             frontend_hostname,
             session_key,
             max_time_to_live,
+            None::<Vec<Principal>>,
+            Some(frontend_hostname),
         ),
     )
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_delegation_with_targets(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    frontend_hostname: &str,
+    session_key: &types::SessionKey,
+    max_time_to_live: Option<u64>,
+    targets: &Option<Vec<Principal>>,
+) -> Result<(types::UserKey, types::Timestamp), CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "prepare_delegation",
+        (
+            anchor_number,
+            frontend_hostname,
+            session_key,
+            max_time_to_live,
+            targets,
+            Some(frontend_hostname),
+        ),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_delegation_with_origin(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    frontend_hostname: &str,
+    session_key: &types::SessionKey,
+    max_time_to_live: Option<u64>,
+    origin: &str,
+) -> Result<(types::UserKey, types::Timestamp), CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "prepare_delegation",
+        (
+            anchor_number,
+            frontend_hostname,
+            session_key,
+            max_time_to_live,
+            None::<Vec<Principal>>,
+            Some(origin),
+        ),
+    )
+}
+
+pub fn revoke_delegations(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    frontend_hostname: Option<&str>,
+) -> Result<(), CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "revoke_delegations",
+        (anchor_number, frontend_hostname),
+    )
+}
+
 pub fn init_salt(env: &StateMachine, canister_id: CanisterId) -> Result<(), CallError> {
     call_candid(env, canister_id, "init_salt", ())
 }
@@ -91,6 +163,33 @@ pub fn get_delegation(
     .map(|(x,)| x)
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn get_delegation_with_targets(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    frontend_hostname: &str,
+    session_key: &types::SessionKey,
+    timestamp: u64,
+    targets: &Option<Vec<Principal>>,
+) -> Result<types::GetDelegationResponse, CallError> {
+    query_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_delegation",
+        (
+            anchor_number,
+            frontend_hostname,
+            session_key,
+            timestamp,
+            targets,
+        ),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn get_principal(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -284,6 +383,74 @@ pub fn stats(
     query_candid(env, canister_id, "stats", ()).map(|(x,)| x)
 }
 
+pub fn config(
+    env: &StateMachine,
+    canister_id: CanisterId,
+) -> Result<types::InternetIdentityInit, CallError> {
+    query_candid(env, canister_id, "config", ()).map(|(x,)| x)
+}
+
+pub fn config_update(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    config: &types::InternetIdentityInit,
+) -> Result<(), CallError> {
+    call_candid_as(env, canister_id, sender, "config_update", (config,))
+}
+
+pub fn integrity_scan_start(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    arg: &types::IntegrityScanArg,
+) -> Result<(), CallError> {
+    call_candid_as(env, canister_id, sender, "integrity_scan_start", (arg,))
+}
+
+pub fn integrity_scan_report(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+) -> Result<Option<types::IntegrityScanReport>, CallError> {
+    query_candid_as(env, canister_id, sender, "integrity_scan_report", ()).map(|(x,)| x)
+}
+
+pub fn export_anchors(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    arg: &types::ExportAnchorsArg,
+) -> Result<types::ExportAnchorsResponse, CallError> {
+    call_candid_as(env, canister_id, sender, "export_anchors", (arg,)).map(|(x,)| x)
+}
+
+pub fn import_anchors(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    arg: &types::ImportAnchorsArg,
+) -> Result<(), CallError> {
+    call_candid_as(env, canister_id, sender, "import_anchors", (arg,))
+}
+
+pub fn mark_anchors_moved(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    arg: &types::MarkAnchorsMovedArg,
+) -> Result<(), CallError> {
+    call_candid_as(env, canister_id, sender, "mark_anchors_moved", (arg,))
+}
+
+pub fn anchor_moved_to(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    anchor_number: types::AnchorNumber,
+) -> Result<Option<Principal>, CallError> {
+    query_candid(env, canister_id, "anchor_moved_to", (anchor_number,)).map(|(x,)| x)
+}
+
 pub fn fetch_entries(
     env: &StateMachine,
     canister_id: CanisterId,
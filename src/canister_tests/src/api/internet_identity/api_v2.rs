@@ -2,8 +2,17 @@ use candid::Principal;
 use ic_cdk::api::management_canister::main::CanisterId;
 use ic_test_state_machine_client::{call_candid_as, CallError, StateMachine};
 use internet_identity_interface::internet_identity::types::{
-    AuthnMethodAddResponse, AuthnMethodData, IdentityInfoResponse, IdentityNumber,
+    AuthnMethodAddResponse, AuthnMethodBatchResponse, AuthnMethodData,
+    AuthnMethodMetadataReplaceResponse, AuthnMethodOperation, AuthnMethodRemoveResponse,
+    AuthnMethodReplaceResponse, AuthnMethodSecuritySettings,
+    AuthnMethodSecuritySettingsReplaceResponse, GuardianConfig, GuardianRecoveryApproveResponse,
+    GuardianRecoveryRequestResponse, IdentityDeleteResponse, IdentityGuardiansInfoResponse,
+    IdentityGuardiansSetResponse, IdentityInfoResponse, IdentityNumber,
+    IdentityRecoveryDelaySetResponse, IdentityRecoveryInfoResponse, IdentitySessionsResponse,
+    MetadataEntry, PublicKey, RecoveryOperationCancelResponse,
 };
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
 
 pub fn identity_info(
     env: &StateMachine,
@@ -21,6 +30,157 @@ pub fn identity_info(
     .map(|(x,)| x)
 }
 
+pub fn identity_sessions(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+) -> Result<Option<IdentitySessionsResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "identity_sessions",
+        (identity_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn identity_delete(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+    confirmation_token: Option<&ByteBuf>,
+) -> Result<Option<IdentityDeleteResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "identity_delete",
+        (identity_number, confirmation_token),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn identity_recovery_info(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+) -> Result<Option<IdentityRecoveryInfoResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "identity_recovery_info",
+        (identity_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn identity_recovery_delay_set(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+    recovery_delay_ns: Option<u64>,
+) -> Result<Option<IdentityRecoveryDelaySetResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "identity_recovery_delay_set",
+        (identity_number, recovery_delay_ns),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn identity_recovery_operation_cancel(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+    id: u64,
+) -> Result<Option<RecoveryOperationCancelResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "identity_recovery_operation_cancel",
+        (identity_number, id),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn identity_guardians_info(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+) -> Result<Option<IdentityGuardiansInfoResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "identity_guardians_info",
+        (identity_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn identity_guardians_set(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+    config: Option<GuardianConfig>,
+) -> Result<Option<IdentityGuardiansSetResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "identity_guardians_set",
+        (identity_number, config),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn guardian_recovery_request(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+    authn_method: &AuthnMethodData,
+) -> Result<Option<GuardianRecoveryRequestResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "guardian_recovery_request",
+        (identity_number, authn_method),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn guardian_recovery_approve(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    guardian: IdentityNumber,
+    identity_number: IdentityNumber,
+    public_key: &PublicKey,
+) -> Result<Option<GuardianRecoveryApproveResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "guardian_recovery_approve",
+        (guardian, identity_number, public_key),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn authn_method_add(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -37,3 +197,91 @@ pub fn authn_method_add(
     )
     .map(|(x,)| x)
 }
+
+pub fn authn_method_remove(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+    public_key: &PublicKey,
+) -> Result<Option<AuthnMethodRemoveResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "authn_method_remove",
+        (identity_number, public_key),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn authn_method_replace(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+    public_key: &PublicKey,
+    authn_method: &AuthnMethodData,
+) -> Result<Option<AuthnMethodReplaceResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "authn_method_replace",
+        (identity_number, public_key, authn_method),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn authn_method_metadata_replace(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+    public_key: &PublicKey,
+    metadata: &HashMap<String, MetadataEntry>,
+) -> Result<Option<AuthnMethodMetadataReplaceResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "authn_method_metadata_replace",
+        (identity_number, public_key, metadata),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn authn_method_security_settings_replace(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+    public_key: &PublicKey,
+    security_settings: &AuthnMethodSecuritySettings,
+) -> Result<Option<AuthnMethodSecuritySettingsReplaceResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "authn_method_security_settings_replace",
+        (identity_number, public_key, security_settings),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn authn_method_batch(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    identity_number: IdentityNumber,
+    operations: &[AuthnMethodOperation],
+) -> Result<Option<AuthnMethodBatchResponse>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "authn_method_batch",
+        (identity_number, operations),
+    )
+    .map(|(x,)| x)
+}
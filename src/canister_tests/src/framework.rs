@@ -175,6 +175,11 @@ pub fn arg_with_wasm_hash(wasm: Vec<u8>) -> Option<InternetIdentityInit> {
         register_rate_limit: None,
         max_num_latest_delegation_origins: None,
         migrate_storage_to_memory_manager: None,
+        delegation_ttl_policy: None,
+        challenge_provider: None,
+        captcha_config: None,
+        method_rate_limits: None,
+        migrate_storage_to_stable_btree_map: None,
     })
 }
 
@@ -186,6 +191,11 @@ pub fn arg_with_rate_limit(rate_limit: RateLimitConfig) -> Option<InternetIdenti
         register_rate_limit: Some(rate_limit),
         max_num_latest_delegation_origins: None,
         migrate_storage_to_memory_manager: None,
+        delegation_ttl_policy: None,
+        challenge_provider: None,
+        captcha_config: None,
+        method_rate_limits: None,
+        migrate_storage_to_stable_btree_map: None,
     })
 }
 
@@ -199,6 +209,11 @@ pub fn arg_with_anchor_range(
         register_rate_limit: None,
         max_num_latest_delegation_origins: None,
         migrate_storage_to_memory_manager: None,
+        delegation_ttl_policy: None,
+        challenge_provider: None,
+        captcha_config: None,
+        method_rate_limits: None,
+        migrate_storage_to_stable_btree_map: None,
     })
 }
 
@@ -521,7 +536,7 @@ pub fn verify_delegation(
     // followed by the representation independent hash of a map with entries
     // pubkey, expiration and targets (if any), using the respective values from the delegation.
     // See https://internetcomputer.org/docs/current/references/ic-interface-spec#authentication for details
-    let key_value_pairs = vec![
+    let mut key_value_pairs = vec![
         (
             "pubkey".to_string(),
             Value::Bytes(signed_delegation.delegation.pubkey.clone().into_vec()),
@@ -531,6 +546,17 @@ pub fn verify_delegation(
             Value::Number(signed_delegation.delegation.expiration),
         ),
     ];
+    if let Some(ref targets) = signed_delegation.delegation.targets {
+        key_value_pairs.push((
+            "targets".to_string(),
+            Value::Array(
+                targets
+                    .iter()
+                    .map(|target| Value::Bytes(target.as_slice().to_vec()))
+                    .collect(),
+            ),
+        ));
+    }
     let mut msg: Vec<u8> = Vec::from([(DOMAIN_SEPARATOR.len() as u8)]);
     msg.extend_from_slice(DOMAIN_SEPARATOR);
     msg.extend_from_slice(
@@ -279,7 +279,13 @@ export const idlFactory = ({ IDL }) => {
       ),
     'get_anchor_info' : IDL.Func([UserNumber], [IdentityAnchorInfo], []),
     'get_delegation' : IDL.Func(
-        [UserNumber, FrontendHostname, SessionKey, Timestamp],
+        [
+          UserNumber,
+          FrontendHostname,
+          SessionKey,
+          Timestamp,
+          IDL.Opt(IDL.Vec(IDL.Principal)),
+        ],
         [GetDelegationResponse],
         ['query'],
       ),
@@ -298,7 +304,14 @@ export const idlFactory = ({ IDL }) => {
     'init_salt' : IDL.Func([], [], []),
     'lookup' : IDL.Func([UserNumber], [IDL.Vec(DeviceData)], ['query']),
     'prepare_delegation' : IDL.Func(
-        [UserNumber, FrontendHostname, SessionKey, IDL.Opt(IDL.Nat64)],
+        [
+          UserNumber,
+          FrontendHostname,
+          SessionKey,
+          IDL.Opt(IDL.Nat64),
+          IDL.Opt(IDL.Vec(IDL.Principal)),
+          IDL.Opt(FrontendHostname),
+        ],
         [UserKey, Timestamp],
         [],
       ),
@@ -244,7 +244,13 @@ export interface _SERVICE {
   'get_anchor_credentials' : ActorMethod<[UserNumber], AnchorCredentials>,
   'get_anchor_info' : ActorMethod<[UserNumber], IdentityAnchorInfo>,
   'get_delegation' : ActorMethod<
-    [UserNumber, FrontendHostname, SessionKey, Timestamp],
+    [
+      UserNumber,
+      FrontendHostname,
+      SessionKey,
+      Timestamp,
+      [] | [Array<Principal>],
+    ],
     GetDelegationResponse
   >,
   'get_principal' : ActorMethod<[UserNumber, FrontendHostname], Principal>,
@@ -254,7 +260,14 @@ export interface _SERVICE {
   'init_salt' : ActorMethod<[], undefined>,
   'lookup' : ActorMethod<[UserNumber], Array<DeviceData>>,
   'prepare_delegation' : ActorMethod<
-    [UserNumber, FrontendHostname, SessionKey, [] | [bigint]],
+    [
+      UserNumber,
+      FrontendHostname,
+      SessionKey,
+      [] | [bigint],
+      [] | [Array<Principal>],
+      [] | [FrontendHostname],
+    ],
     [UserKey, Timestamp]
   >,
   'register' : ActorMethod<
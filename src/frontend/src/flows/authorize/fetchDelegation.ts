@@ -38,10 +38,12 @@ export const fetchDelegation = async (
     derivationOrigin = `https://${subdomain}.ic0.app`;
   }
 
+  // The backend verifies again that the requesting origin may use the derivation origin
   const result = await connection.prepareDelegation(
     derivationOrigin,
     sessionKey,
-    authContext.authRequest.maxTimeToLive
+    authContext.authRequest.maxTimeToLive,
+    authContext.requestOrigin
   );
 
   if ("error" in result) {
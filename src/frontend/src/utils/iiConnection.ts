@@ -548,7 +548,8 @@ export class AuthenticatedConnection extends Connection {
   prepareDelegation = async (
     hostname: FrontendHostname,
     sessionKey: SessionKey,
-    maxTimeToLive?: bigint
+    maxTimeToLive: bigint | undefined,
+    origin: FrontendHostname
   ): Promise<[PublicKey, bigint] | { error: unknown }> => {
     try {
       console.log(
@@ -559,7 +560,9 @@ export class AuthenticatedConnection extends Connection {
         this.userNumber,
         hostname,
         sessionKey,
-        nonNullish(maxTimeToLive) ? [maxTimeToLive] : []
+        nonNullish(maxTimeToLive) ? [maxTimeToLive] : [],
+        [],
+        [origin]
       );
     } catch (e: unknown) {
       console.error(e);
@@ -581,7 +584,8 @@ export class AuthenticatedConnection extends Connection {
         this.userNumber,
         hostname,
         sessionKey,
-        timestamp
+        timestamp,
+        []
       );
     } catch (e: unknown) {
       console.error(e);
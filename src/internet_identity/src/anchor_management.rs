@@ -2,15 +2,22 @@ use crate::active_anchor_stats::IIDomain;
 use crate::archive::{archive_operation, device_diff};
 use crate::state::RegistrationState::DeviceTentativelyAdded;
 use crate::state::TentativeDeviceRegistration;
-use crate::storage::anchor::{Anchor, Device};
+use crate::storage::anchor::{Anchor, AnchorBatch, AnchorError, Device};
 use crate::{active_anchor_stats, state};
 use ic_cdk::api::time;
-use ic_cdk::{caller, trap};
+use ic_cdk::caller;
 use internet_identity_interface::archive::types::{DeviceDataWithoutAlias, Operation};
+use internet_identity_interface::internet_identity::conversions::AuthnMethodConversionError;
 use internet_identity_interface::internet_identity::types::*;
+use std::collections::HashMap;
 
+pub mod deletion;
+pub mod guardian_recovery;
+pub mod rate_limit;
+pub mod recovery_delay;
 pub mod registration;
 pub mod tentative_device_registration;
+pub mod transfer;
 
 pub fn get_anchor_info(anchor_number: AnchorNumber) -> IdentityAnchorInfo {
     let devices = state::anchor(anchor_number)
@@ -28,18 +35,18 @@ pub fn get_anchor_info(anchor_number: AnchorNumber) -> IdentityAnchorInfo {
                     DeviceTentativelyAdded {
                         tentative_device, ..
                     },
-            }) if *expiration > now => IdentityAnchorInfo {
+            }) if expiration > now => IdentityAnchorInfo {
                 devices,
                 device_registration: Some(DeviceRegistrationInfo {
-                    expiration: *expiration,
-                    tentative_device: Some(tentative_device.clone()),
+                    expiration,
+                    tentative_device: Some(tentative_device),
                 }),
             },
-            Some(TentativeDeviceRegistration { expiration, .. }) if *expiration > now => {
+            Some(TentativeDeviceRegistration { expiration, .. }) if expiration > now => {
                 IdentityAnchorInfo {
                     devices,
                     device_registration: Some(DeviceRegistrationInfo {
-                        expiration: *expiration,
+                        expiration,
                         tentative_device: None,
                     }),
                 }
@@ -81,43 +88,108 @@ pub fn post_operation_bookkeeping(anchor_number: AnchorNumber, operation: Operat
 }
 
 /// Adds a device to the given anchor and returns the operation to be archived.
-/// Panics if this operation violates anchor constraints (see [Anchor]).
-pub fn add(anchor: &mut Anchor, device_data: DeviceData) -> Operation {
+/// Returns an error if this operation violates anchor constraints (see [Anchor]).
+pub fn add(anchor: &mut Anchor, device_data: DeviceData) -> Result<Operation, AnchorError> {
     let new_device = Device::from(device_data);
-    anchor
-        .add_device(new_device.clone())
-        .unwrap_or_else(|err| trap(&format!("failed to add device: {err}")));
+    anchor.add_device(new_device.clone())?;
 
-    Operation::AddDevice {
+    Ok(Operation::AddDevice {
         device: DeviceDataWithoutAlias::from(new_device),
-    }
+    })
 }
 
 /// Updates a device of the given anchor and returns the operation to be archived.
-/// Panics if
+/// Returns an error if
 /// * the device to be updated does not exist
 /// * the operation violates anchor constraints (see [Anchor])
-pub fn update(anchor: &mut Anchor, device_key: DeviceKey, device_data: DeviceData) -> Operation {
-    let Some(existing_device) = anchor.device(&device_key) else {
-        trap("Could not find device to update, check device key")
-    };
+pub fn update(
+    anchor: &mut Anchor,
+    device_key: DeviceKey,
+    device_data: DeviceData,
+) -> Result<Operation, AnchorError> {
+    let existing_device = anchor.device(&device_key).ok_or(AnchorError::NotFound {
+        device_key: device_key.clone(),
+    })?;
 
     let mut new_device = existing_device.clone();
     new_device.apply_device_data(device_data);
     let diff = device_diff(existing_device, &new_device);
 
-    anchor
-        .modify_device(&device_key, new_device)
-        .unwrap_or_else(|err| trap(&format!("failed to modify device: {err}")));
+    anchor.modify_device(&device_key, new_device)?;
 
-    Operation::UpdateDevice {
+    Ok(Operation::UpdateDevice {
         device: device_key,
         new_values: diff,
+    })
+}
+
+/// Error returned when replacing the metadata of a device fails.
+#[derive(Debug)]
+pub enum ReplaceMetadataError {
+    InvalidMetadata(AuthnMethodConversionError),
+    AnchorError(AnchorError),
+}
+
+impl From<AuthnMethodConversionError> for ReplaceMetadataError {
+    fn from(err: AuthnMethodConversionError) -> Self {
+        ReplaceMetadataError::InvalidMetadata(err)
     }
 }
 
+impl From<AnchorError> for ReplaceMetadataError {
+    fn from(err: AnchorError) -> Self {
+        ReplaceMetadataError::AnchorError(err)
+    }
+}
+
+/// Returns the device data of a device of the given anchor with its metadata replaced, to be applied
+/// using [update]. The device fields represented as metadata in the v2 API (alias, origin and key
+/// type) are updated accordingly, except for the key type which is kept if not present in the new
+/// metadata.
+/// Returns an error if
+/// * the metadata cannot be converted to device data
+/// * the device to be updated does not exist
+pub fn device_data_with_metadata(
+    anchor: &Anchor,
+    device_key: &DeviceKey,
+    metadata: HashMap<String, MetadataEntry>,
+) -> Result<DeviceData, ReplaceMetadataError> {
+    let existing_device = anchor.device(device_key).ok_or(AnchorError::NotFound {
+        device_key: device_key.clone(),
+    })?;
+    let existing_key_type = existing_device.key_type.clone();
+    let mut authn_method = AuthnMethodData::from(DeviceWithUsage::from(existing_device.clone()));
+
+    let keep_key_type = !metadata.contains_key("key_type");
+    authn_method.metadata = metadata;
+    let mut device_data = DeviceData::from(DeviceWithUsage::try_from(authn_method)?);
+    if keep_key_type {
+        device_data.key_type = existing_key_type;
+    }
+    Ok(device_data)
+}
+
+/// Returns the device data of a device of the given anchor with its security settings (protection
+/// and purpose) replaced, to be applied using [update].
+/// Returns an error if the device to be updated does not exist.
+pub fn device_data_with_security_settings(
+    anchor: &Anchor,
+    device_key: &DeviceKey,
+    security_settings: AuthnMethodSecuritySettings,
+) -> Result<DeviceData, AnchorError> {
+    let existing_device = anchor.device(device_key).ok_or(AnchorError::NotFound {
+        device_key: device_key.clone(),
+    })?;
+    let mut device_data = DeviceData::from(existing_device.clone());
+    device_data.protection = DeviceProtection::from(security_settings.protection);
+    device_data.purpose = security_settings.purpose;
+    Ok(device_data)
+}
+
 /// Replaces a device of the given anchor with another and returns the operation to be archived.
-/// Panics if
+/// The operations queued by the replaced device are dropped (see [recovery_delay]).
+/// The anchor is left unchanged if an error is returned.
+/// Returns an error if
 /// * the device to be replaced does not exist
 /// * the operation violates anchor constraints (see [Anchor])
 pub fn replace(
@@ -125,33 +197,173 @@ pub fn replace(
     anchor: &mut Anchor,
     old_device: DeviceKey,
     new_device: DeviceData,
-) -> Operation {
-    anchor
-        .remove_device(&old_device)
-        .unwrap_or_else(|err| trap(&format!("failed to replace device: {err}")));
+) -> Result<Operation, AnchorError> {
+    // operate on a copy so that a failed add does not leave the anchor with the old device removed
+    let mut modified_anchor = anchor.clone();
+    modified_anchor.remove_device(&old_device)?;
     let new_device = Device::from(new_device);
-    anchor
-        .add_device(new_device.clone())
-        .unwrap_or_else(|err| trap(&format!("failed to replace device: {err}")));
+    modified_anchor.add_device(new_device.clone())?;
+    *anchor = modified_anchor;
 
     state::with_temp_keys_mut(|temp_keys| temp_keys.remove_temp_key(anchor_number, &old_device));
-    Operation::ReplaceDevice {
+    recovery_delay::drop_operations_requested_by(anchor_number, &old_device);
+    Ok(Operation::ReplaceDevice {
         old_device,
         new_device: DeviceDataWithoutAlias::from(new_device),
-    }
+    })
 }
 
 /// Removes a device of the given anchor and returns the operation to be archived.
-/// Panics if the device to be removed does not exist
+/// The operations queued by the removed device are dropped (see [recovery_delay]).
+/// Returns an error if the device to be removed does not exist or cannot be removed by the caller.
 pub fn remove(
     anchor_number: AnchorNumber,
     anchor: &mut Anchor,
     device_key: DeviceKey,
-) -> Operation {
-    anchor
-        .remove_device(&device_key)
-        .unwrap_or_else(|err| trap(&format!("failed to remove device: {err}")));
+) -> Result<Operation, AnchorError> {
+    anchor.remove_device(&device_key)?;
 
     state::with_temp_keys_mut(|temp_keys| temp_keys.remove_temp_key(anchor_number, &device_key));
-    Operation::RemoveDevice { device: device_key }
+    recovery_delay::drop_operations_requested_by(anchor_number, &device_key);
+    Ok(Operation::RemoveDevice { device: device_key })
+}
+
+/// Device operation to be applied as part of a [batch].
+pub enum BatchOperation {
+    Add(DeviceData),
+    Update {
+        device_key: DeviceKey,
+        device_data: DeviceData,
+    },
+    Replace {
+        old_device: DeviceKey,
+        new_device: DeviceData,
+    },
+    Remove(DeviceKey),
+}
+
+impl TryFrom<AuthnMethodOperation> for BatchOperation {
+    type Error = AuthnMethodConversionError;
+
+    fn try_from(operation: AuthnMethodOperation) -> Result<Self, Self::Error> {
+        fn device_data(
+            authn_method: AuthnMethodData,
+        ) -> Result<DeviceData, AuthnMethodConversionError> {
+            Ok(DeviceData::from(DeviceWithUsage::try_from(authn_method)?))
+        }
+
+        Ok(match operation {
+            AuthnMethodOperation::Add(authn_method) => {
+                BatchOperation::Add(device_data(authn_method)?)
+            }
+            AuthnMethodOperation::Update {
+                public_key,
+                authn_method,
+            } => BatchOperation::Update {
+                device_key: public_key,
+                device_data: device_data(authn_method)?,
+            },
+            AuthnMethodOperation::Replace {
+                public_key,
+                authn_method,
+            } => BatchOperation::Replace {
+                old_device: public_key,
+                new_device: device_data(authn_method)?,
+            },
+            AuthnMethodOperation::Remove(public_key) => BatchOperation::Remove(public_key),
+        })
+    }
+}
+
+/// Error returned when applying a [batch] of device operations fails.
+#[derive(Debug)]
+pub enum BatchError {
+    /// The operation at the given index was rejected.
+    Operation { index: usize, error: AnchorError },
+    /// The set of devices resulting from the batch was rejected.
+    Anchor(AnchorError),
+}
+
+/// Applies the given device operations to the anchor as a whole and returns the operation to be
+/// archived. The individual operations are checked like their single device counterparts
+/// ([add], [update], [replace] and [remove]) except for the anchor invariants (see [Anchor]),
+/// which are checked only once on the resulting set of devices.
+/// The anchor is left unchanged if an error is returned.
+pub fn batch(
+    anchor_number: AnchorNumber,
+    anchor: &mut Anchor,
+    operations: Vec<BatchOperation>,
+) -> Result<Operation, BatchError> {
+    let mut batch = anchor.batch();
+    let mut removed_devices = vec![];
+    let mut archive_operations = Vec::with_capacity(operations.len());
+    for (index, operation) in operations.into_iter().enumerate() {
+        let archive_operation = apply_batch_operation(&mut batch, operation, &mut removed_devices)
+            .map_err(|error| BatchError::Operation { index, error })?;
+        archive_operations.push(archive_operation);
+    }
+    batch.commit().map_err(BatchError::Anchor)?;
+
+    state::with_temp_keys_mut(|temp_keys| {
+        for device_key in &removed_devices {
+            temp_keys.remove_temp_key(anchor_number, device_key)
+        }
+    });
+    for device_key in &removed_devices {
+        recovery_delay::drop_operations_requested_by(anchor_number, device_key);
+    }
+    Ok(Operation::Batch {
+        operations: archive_operations,
+    })
+}
+
+fn apply_batch_operation(
+    batch: &mut AnchorBatch<'_>,
+    operation: BatchOperation,
+    removed_devices: &mut Vec<DeviceKey>,
+) -> Result<Operation, AnchorError> {
+    match operation {
+        BatchOperation::Add(device_data) => {
+            let new_device = Device::from(device_data);
+            batch.add_device(new_device.clone())?;
+            Ok(Operation::AddDevice {
+                device: DeviceDataWithoutAlias::from(new_device),
+            })
+        }
+        BatchOperation::Update {
+            device_key,
+            device_data,
+        } => {
+            let existing_device = batch.device(&device_key).ok_or(AnchorError::NotFound {
+                device_key: device_key.clone(),
+            })?;
+            let mut new_device = existing_device.clone();
+            new_device.apply_device_data(device_data);
+            let diff = device_diff(existing_device, &new_device);
+
+            batch.modify_device(&device_key, new_device)?;
+            Ok(Operation::UpdateDevice {
+                device: device_key,
+                new_values: diff,
+            })
+        }
+        BatchOperation::Replace {
+            old_device,
+            new_device,
+        } => {
+            batch.remove_device(&old_device)?;
+            let new_device = Device::from(new_device);
+            batch.add_device(new_device.clone())?;
+            removed_devices.push(old_device.clone());
+            Ok(Operation::ReplaceDevice {
+                old_device,
+                new_device: DeviceDataWithoutAlias::from(new_device),
+            })
+        }
+        BatchOperation::Remove(device_key) => {
+            batch.remove_device(&device_key)?;
+            removed_devices.push(device_key.clone());
+            Ok(Operation::RemoveDevice { device: device_key })
+        }
+    }
 }
@@ -0,0 +1,233 @@
+use crate::anchor_management::{guardian_recovery, post_operation_bookkeeping, recovery_delay};
+use crate::storage::anchor::{Anchor, Device};
+use crate::{delegation, state, HOUR_NS, MINUTE_NS};
+use candid::Principal;
+use ic_cdk::api::{caller, time};
+use ic_cdk::{call, trap};
+use internet_identity_interface::archive::types::Operation;
+use internet_identity_interface::internet_identity::types::*;
+use serde_bytes::ByteBuf;
+
+/// Time window in which a deletion request must be confirmed using another authentication method.
+const DELETION_CONFIRMATION_PERIOD_NS: u64 = 5 * MINUTE_NS;
+/// Time the authentication method that requested the deletion of an anchor with no other
+/// authentication method has to wait before it can confirm the deletion itself. The request is
+/// archived, so that it is visible during the waiting period.
+const DELETION_WAITING_PERIOD_NS: u64 = HOUR_NS;
+/// Time window in which the authentication method that requested a deletion can confirm it,
+/// starting after the waiting period.
+const DELAYED_DELETION_CONFIRMATION_PERIOD_NS: u64 = HOUR_NS;
+
+/// A deletion request awaiting confirmation.
+pub struct PendingDeletion {
+    confirmation_token: ByteBuf,
+    // the authentication method that requested the deletion, which can only confirm it after the
+    // waiting period if it was the only authentication method of the anchor
+    requested_by: DeviceKey,
+    requested_at: Timestamp,
+    // whether the requesting authentication method was the only one of the anchor
+    single_authn_method: bool,
+}
+
+impl PendingDeletion {
+    /// End of the window in which another authentication method can confirm the deletion.
+    fn expiration(&self) -> Timestamp {
+        self.requested_at + DELETION_CONFIRMATION_PERIOD_NS
+    }
+
+    /// Start of the window in which the requesting authentication method can confirm the deletion.
+    fn delayed_confirmation_start(&self) -> Timestamp {
+        self.requested_at + DELETION_WAITING_PERIOD_NS
+    }
+
+    /// End of the window in which the requesting authentication method can confirm the deletion.
+    fn delayed_confirmation_expiration(&self) -> Timestamp {
+        self.delayed_confirmation_start() + DELAYED_DELETION_CONFIRMATION_PERIOD_NS
+    }
+}
+
+/// Deletes the given anchor if the deletion is sufficiently authorized, i.e. if either
+/// * the caller is authenticated using a protected recovery method, or
+/// * the anchor has no protected recovery method and this call confirms a previous deletion
+///   request, i.e. it provides the confirmation token returned for the request, is authenticated
+///   directly (i.e. not using a temporary key) and is either
+///   * authenticated using another authentication method than the request, within 5 minutes of
+///     the request, or
+///   * authenticated using the same authentication method as the request if it was the only
+///     authentication method of the anchor, within the hour following a waiting period of one
+///     hour and provided no other authentication method has been used since the request. This
+///     allows anchors with a single authentication method to be deleted.
+///
+/// Pending deletion requests are not persisted, i.e. an upgrade cancels them.
+///
+/// Without confirmation token, a new deletion request is made (replacing the pending one, if any)
+/// and its confirmation token is returned.
+///
+/// Anchors with a protected recovery method can only be deleted using that recovery method, as
+/// deleting the anchor also removes the protected recovery method.
+pub async fn delete_identity(
+    anchor_number: AnchorNumber,
+    anchor: Anchor,
+    device_key: &DeviceKey,
+    confirmation_token: Option<ByteBuf>,
+) -> IdentityDeleteResponse {
+    let Some(device) = anchor.device(device_key) else {
+        trap(&format!("device of anchor {anchor_number} not found"))
+    };
+
+    if !is_protected_recovery_method(device) {
+        if anchor.devices().iter().any(is_protected_recovery_method) {
+            return IdentityDeleteResponse::ProtectedRecoveryMethodRequired;
+        }
+        let Some(confirmation_token) = confirmation_token else {
+            return request_deletion(anchor_number, &anchor, device_key.clone()).await;
+        };
+        if let Err(err) = confirm_deletion(anchor_number, &anchor, device_key, &confirmation_token)
+        {
+            return err;
+        }
+    }
+
+    delete(anchor_number, anchor);
+    IdentityDeleteResponse::Ok
+}
+
+fn is_protected_recovery_method(device: &Device) -> bool {
+    device.purpose == Purpose::Recovery && device.protection == DeviceProtection::Protected
+}
+
+/// Records (and archives) a deletion request for the given anchor and returns the token required
+/// to confirm it.
+async fn request_deletion(
+    anchor_number: AnchorNumber,
+    anchor: &Anchor,
+    requested_by: DeviceKey,
+) -> IdentityDeleteResponse {
+    let confirmation_token = random_token().await;
+    let pending = PendingDeletion {
+        confirmation_token: confirmation_token.clone(),
+        requested_by: requested_by.clone(),
+        requested_at: time(),
+        single_authn_method: anchor.devices().len() == 1,
+    };
+    let (delayed_confirmation_start, delayed_confirmation_expiration) =
+        if pending.single_authn_method {
+            (
+                Some(pending.delayed_confirmation_start()),
+                Some(pending.delayed_confirmation_expiration()),
+            )
+        } else {
+            (None, None)
+        };
+    let response = IdentityDeleteResponse::ConfirmationRequired {
+        confirmation_token,
+        expiration: pending.expiration(),
+        delayed_confirmation_start,
+        delayed_confirmation_expiration,
+    };
+    state::pending_deletions_mut(|pending_deletions| {
+        let now = time();
+        pending_deletions.retain(|_, pending| pending.delayed_confirmation_expiration() > now);
+        pending_deletions.insert(anchor_number, pending);
+    });
+    post_operation_bookkeeping(
+        anchor_number,
+        Operation::RequestIdentityDeletion {
+            device: requested_by,
+        },
+    );
+    response
+}
+
+/// Checks that the confirmation token matches the pending deletion request of the anchor and that
+/// the request can be confirmed by the given authentication method at this time.
+fn confirm_deletion(
+    anchor_number: AnchorNumber,
+    anchor: &Anchor,
+    device_key: &DeviceKey,
+    confirmation_token: &ByteBuf,
+) -> Result<(), IdentityDeleteResponse> {
+    let now = time();
+    state::pending_deletions_mut(|pending_deletions| {
+        let Some(pending) = pending_deletions.get(&anchor_number) else {
+            return Err(IdentityDeleteResponse::InvalidConfirmation);
+        };
+        if pending.confirmation_token != *confirmation_token {
+            return Err(IdentityDeleteResponse::InvalidConfirmation);
+        }
+        // temporary keys are held by the front-end that may also have requested the deletion
+        if caller() != Principal::self_authenticating(device_key) {
+            return Err(IdentityDeleteResponse::AuthnMethodNotAllowedToConfirm);
+        }
+        if pending.requested_by != *device_key {
+            return if now < pending.expiration() {
+                Ok(())
+            } else {
+                Err(IdentityDeleteResponse::InvalidConfirmation)
+            };
+        }
+        if !pending.single_authn_method {
+            return Err(IdentityDeleteResponse::AuthnMethodNotAllowedToConfirm);
+        }
+        let used_since_request = anchor.devices().iter().any(|device| {
+            device.pubkey != *device_key
+                && device
+                    .last_usage_timestamp
+                    .map_or(false, |timestamp| timestamp >= pending.requested_at)
+        });
+        if used_since_request {
+            // another authentication method has been added and used, it can request the
+            // deletion itself
+            return Err(IdentityDeleteResponse::InvalidConfirmation);
+        }
+        if now < pending.delayed_confirmation_start() {
+            return Err(IdentityDeleteResponse::ConfirmationNotYetAllowed {
+                delayed_confirmation_start: pending.delayed_confirmation_start(),
+            });
+        }
+        if now < pending.delayed_confirmation_expiration() {
+            Ok(())
+        } else {
+            Err(IdentityDeleteResponse::InvalidConfirmation)
+        }
+    })
+}
+
+/// Returns a confirmation token based on `raw_rand`.
+async fn random_token() -> ByteBuf {
+    match call(Principal::management_canister(), "raw_rand", ()).await {
+        Ok((res,)) => res,
+        Err((_, err)) => trap(&format!("failed to get confirmation token: {err}")),
+    }
+}
+
+/// Wipes the anchor from stable memory together with all the state associated to it (see
+/// [remove_associated_state]).
+fn delete(anchor_number: AnchorNumber, anchor: Anchor) {
+    state::storage_borrow_mut(|storage| storage.delete(anchor_number)).unwrap_or_else(|err| {
+        panic!("unable to delete anchor {anchor_number} from stable memory: {err}")
+    });
+    remove_associated_state(anchor_number, &anchor);
+
+    post_operation_bookkeeping(anchor_number, Operation::DeleteAnchor);
+}
+
+/// Removes the state kept for the anchor outside of its record, e.g. once it has been deleted or
+/// moved to another canister: temporary keys, tentative device registrations, pending deletions,
+/// guardian recovery requests, queued recovery operations, delegations, its entries in the guardian
+/// index and its role as guardian of other anchors.
+pub fn remove_associated_state(anchor_number: AnchorNumber, anchor: &Anchor) {
+    state::with_temp_keys_mut(|temp_keys| {
+        for device in anchor.devices() {
+            temp_keys.remove_temp_key(anchor_number, &device.pubkey);
+        }
+    });
+    state::tentative_device_registrations_mut(|registrations| registrations.remove(&anchor_number));
+    state::pending_deletions_mut(|pending_deletions| pending_deletions.remove(&anchor_number));
+    guardian_recovery::remove_requests(anchor_number);
+    guardian_recovery::index_wards(anchor_number, anchor.guardians(), None);
+    guardian_recovery::remove_guardian(anchor_number);
+    recovery_delay::remove_operations(anchor_number);
+    // also removes the delegation sessions of the anchor
+    delegation::revoke_delegations(anchor_number, None);
+}
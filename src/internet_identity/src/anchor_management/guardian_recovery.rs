@@ -0,0 +1,513 @@
+//! Guardian (social) recovery.
+//!
+//! Users can name other anchors as guardians of their anchor, together with a threshold. Someone
+//! who lost access to the anchor can request the addition of a new device. Once `threshold`
+//! guardians approved the request (each authenticated using their own anchor), the device is added
+//! to the anchor.
+//!
+//! Anyone can request the recovery of an anchor that has guardians. Guardians approve the request
+//! for a specific public key, which they should confirm with the user out of band.
+//! If the anchor has a recovery delay, the device is only added once the delay has passed (see
+//! [crate::anchor_management::recovery_delay]).
+//!
+//! Since requesting is unauthenticated, requests are cheap to make and must not block legitimate
+//! recoveries:
+//! * an anchor has at most [MAX_PENDING_REQUESTS] pending requests (one per public key), kept in
+//!   stable memory (see [crate::storage])
+//! * once the maximum is reached, a new request replaces the oldest request that has not been
+//!   approved by a guardian yet, approved requests are never replaced before they expire
+//! * requesting again for the same public key renews an unapproved request, i.e. it becomes the
+//!   newest request of the anchor
+//! * requests are not rate limited, so that callers cannot use up the rate limit a legitimate
+//!   requester depends on
+//! * unapproved requests are not archived, the request is archived together with its first approval
+//!
+//! The anchors an anchor is a guardian of (its wards) are indexed in stable memory, so that a
+//! deleted anchor can be removed from the guardians of its wards (see [remove_guardian]).
+use crate::anchor_management::recovery_delay::{self, DelayedOperation};
+use crate::anchor_management::{add, post_operation_bookkeeping};
+use crate::storage::anchor::{Anchor, Device, Guardians, PK_LEN_LIMIT};
+use crate::storage::MapMemory;
+use crate::{state, DAY_NS};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk::{caller, trap};
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use internet_identity_interface::archive::types::{DeviceDataWithoutAlias, Operation};
+use internet_identity_interface::internet_identity::types::*;
+use std::borrow::Cow;
+use std::cmp::min;
+use std::collections::HashSet;
+
+/// Maximum number of guardians of an anchor.
+pub const MAX_GUARDIANS: usize = 10;
+/// Maximum number of anchors an anchor can be the guardian of. This bounds the work of removing a
+/// deleted anchor from the guardians of its wards.
+const MAX_WARDS: usize = 1000;
+/// Time the guardians have to approve a recovery request.
+const REQUEST_EXPIRATION_NS: u64 = 7 * DAY_NS;
+/// Maximum number of pending recovery requests of an anchor.
+const MAX_PENDING_REQUESTS: usize = 5;
+
+/// The pending recovery requests of the anchors, by anchor and public key.
+pub type GuardianRecoveryRequests<M> =
+    StableBTreeMap<RecoveryRequestKey, PendingRecovery, MapMemory<M>>;
+
+/// Index of the anchors each anchor is a guardian of.
+pub type GuardianWards<M> = StableBTreeMap<GuardianWardKey, (), MapMemory<M>>;
+
+/// Key of the guardian index, sorting the wards by guardian.
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+pub struct GuardianWardKey {
+    // Attention: order of fields MUST NOT be changed because Ord is derived!
+    guardian: AnchorNumber,
+    ward: AnchorNumber,
+}
+
+impl GuardianWardKey {
+    fn new(guardian: AnchorNumber, ward: AnchorNumber) -> Self {
+        Self { guardian, ward }
+    }
+}
+
+/// Note: byte ordering is very important as the keys are sorted on a byte level
+/// --> use big endian to ensure that the most significant bytes are compared first
+impl Storable for GuardianWardKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::MAX_SIZE as usize);
+        buf.extend(self.guardian.to_be_bytes());
+        buf.extend(self.ward.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            guardian: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[0..8]).expect("failed to read guardian"),
+            ),
+            ward: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[8..16]).expect("failed to read ward"),
+            ),
+        }
+    }
+}
+
+impl BoundedStorable for GuardianWardKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Key of the pending recovery requests, sorting the requests by anchor.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+pub struct RecoveryRequestKey {
+    // Attention: order of fields MUST NOT be changed because Ord is derived!
+    anchor_number: AnchorNumber,
+    public_key: Vec<u8>,
+}
+
+impl RecoveryRequestKey {
+    fn new(anchor_number: AnchorNumber, public_key: &[u8]) -> Self {
+        Self {
+            anchor_number,
+            public_key: public_key.to_vec(),
+        }
+    }
+}
+
+/// Note: byte ordering is very important as the keys are sorted on a byte level
+/// --> use big endian to ensure that the most significant bytes are compared first
+impl Storable for RecoveryRequestKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(8 + self.public_key.len());
+        buf.extend(self.anchor_number.to_be_bytes());
+        buf.extend(&self.public_key);
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            anchor_number: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[0..8]).expect("failed to read anchor number"),
+            ),
+            public_key: bytes[8..].to_vec(),
+        }
+    }
+}
+
+impl BoundedStorable for RecoveryRequestKey {
+    // the device has been checked against the anchor invariants, see request_recovery
+    const MAX_SIZE: u32 = 8 + PK_LEN_LIMIT as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A request to add a device to an anchor that is awaiting the approval of its guardians.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct PendingRecovery {
+    device: DeviceData,
+    approvals: Vec<AnchorNumber>,
+    expiration: Timestamp,
+}
+
+impl Storable for PendingRecovery {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode pending recovery"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode pending recovery")
+    }
+}
+
+impl BoundedStorable for PendingRecovery {
+    // Large enough for any device satisfying the anchor invariants, see insert_request.
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl From<PendingRecovery> for GuardianRecoveryRequest {
+    fn from(pending: PendingRecovery) -> Self {
+        Self {
+            authn_method: AuthnMethodData::from(DeviceWithUsage::from(Device::from(
+                pending.device,
+            ))),
+            approvals: pending.approvals,
+            expiration: pending.expiration,
+        }
+    }
+}
+
+/// Sets (or removes) the guardians of the anchor and returns the operation to be archived.
+/// Pending recovery requests are dropped as the approvals collected so far may no longer be valid.
+pub fn set_guardians(
+    anchor_number: AnchorNumber,
+    anchor: &mut Anchor,
+    config: Option<GuardianConfig>,
+) -> Result<Operation, String> {
+    let operation = match &config {
+        Some(config) => {
+            check_guardian_config(anchor_number, anchor.guardians(), config)?;
+            Operation::SetGuardians {
+                guardians: config.guardians.clone(),
+                threshold: config.threshold,
+            }
+        }
+        None => Operation::SetGuardians {
+            guardians: vec![],
+            threshold: 0,
+        },
+    };
+    index_wards(anchor_number, anchor.guardians(), config.as_ref());
+    anchor.set_guardians(config.map(Guardians::from));
+    remove_requests(anchor_number);
+    Ok(operation)
+}
+
+fn check_guardian_config(
+    anchor_number: AnchorNumber,
+    current: Option<&Guardians>,
+    config: &GuardianConfig,
+) -> Result<(), String> {
+    let num_guardians = config.guardians.len();
+    if num_guardians == 0 || num_guardians > MAX_GUARDIANS {
+        return Err(format!(
+            "the number of guardians must be between 1 and {MAX_GUARDIANS}, got {num_guardians}"
+        ));
+    }
+    if config.threshold == 0 || config.threshold as usize > num_guardians {
+        return Err(format!(
+            "the threshold must be between 1 and the number of guardians ({num_guardians}), got {}",
+            config.threshold
+        ));
+    }
+
+    let mut seen = HashSet::new();
+    for guardian in &config.guardians {
+        if *guardian == anchor_number {
+            return Err("an identity cannot be its own guardian".to_string());
+        }
+        if !seen.insert(guardian) {
+            return Err(format!("duplicate guardian {guardian}"));
+        }
+        if state::storage_borrow(|storage| storage.read(*guardian)).is_err() {
+            return Err(format!("guardian {guardian} does not exist"));
+        }
+        let is_new = current.map_or(true, |current| !current.guardians.contains(guardian));
+        if is_new && wards(*guardian).len() >= MAX_WARDS {
+            return Err(format!(
+                "guardian {guardian} is already the guardian of {MAX_WARDS} identities"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the anchors the given anchor is a guardian of.
+fn wards(guardian: AnchorNumber) -> Vec<AnchorNumber> {
+    state::guardian_wards(|wards| {
+        wards
+            .range(GuardianWardKey::new(guardian, 0)..=GuardianWardKey::new(guardian, u64::MAX))
+            .map(|(key, _)| key.ward)
+            .collect()
+    })
+}
+
+/// Replaces the current guardians of the ward by the configured ones in the guardian index.
+pub fn index_wards(
+    ward: AnchorNumber,
+    current: Option<&Guardians>,
+    config: Option<&GuardianConfig>,
+) {
+    state::guardian_wards_mut(|wards| {
+        for guardian in current.iter().flat_map(|current| current.guardians.iter()) {
+            wards.remove(&GuardianWardKey::new(*guardian, ward));
+        }
+        for guardian in config.iter().flat_map(|config| config.guardians.iter()) {
+            wards.insert(GuardianWardKey::new(*guardian, ward), ());
+        }
+    });
+}
+
+/// Removes the (deleted) guardian from the guardians of its wards and from the approvals of their
+/// pending recovery requests. Wards left without guardians no longer have guardians configured, the
+/// threshold of the others is lowered if necessary.
+pub fn remove_guardian(guardian: AnchorNumber) {
+    for ward in wards(guardian) {
+        state::guardian_wards_mut(|wards| wards.remove(&GuardianWardKey::new(guardian, ward)));
+        let Ok(mut anchor) = state::storage_borrow(|storage| storage.read(ward)) else {
+            // deleted or moved anchors keep their guardians
+            continue;
+        };
+        let Some(mut guardians) = anchor.guardians().cloned() else {
+            continue;
+        };
+        guardians.guardians.retain(|other| *other != guardian);
+        guardians.threshold = min(guardians.threshold, guardians.guardians.len() as u8);
+        let operation = Operation::SetGuardians {
+            guardians: guardians.guardians.clone(),
+            threshold: guardians.threshold,
+        };
+        anchor.set_guardians(Some(guardians).filter(|guardians| !guardians.guardians.is_empty()));
+        state::storage_borrow_mut(|storage| storage.write(ward, anchor))
+            .unwrap_or_else(|err| panic!("unable to update anchor {ward} in stable memory: {err}"));
+
+        for mut request in unexpired_requests(ward) {
+            request.approvals.retain(|approval| *approval != guardian);
+            insert_request(ward, request);
+        }
+        post_operation_bookkeeping(ward, operation);
+    }
+}
+
+/// Returns the pending (i.e. not yet expired) recovery requests of the anchor, oldest first.
+pub fn pending_requests(anchor_number: AnchorNumber) -> Vec<GuardianRecoveryRequest> {
+    unexpired_requests(anchor_number)
+        .into_iter()
+        .map(GuardianRecoveryRequest::from)
+        .collect()
+}
+
+/// Returns the recovery requests of the anchor that have not expired yet, oldest first.
+fn unexpired_requests(anchor_number: AnchorNumber) -> Vec<PendingRecovery> {
+    let now = time();
+    let mut requests: Vec<PendingRecovery> = requests(anchor_number)
+        .into_iter()
+        .map(|(_, request)| request)
+        .filter(|request| request.expiration > now)
+        .collect();
+    requests.sort_by_key(|request| request.expiration);
+    requests
+}
+
+fn unexpired_request(anchor_number: AnchorNumber, public_key: &[u8]) -> Option<PendingRecovery> {
+    let now = time();
+    state::guardian_recovery_requests(|requests| {
+        requests.get(&RecoveryRequestKey::new(anchor_number, public_key))
+    })
+    .filter(|request| request.expiration > now)
+}
+
+/// Returns all the recovery requests of the anchor, including the expired ones.
+fn requests(anchor_number: AnchorNumber) -> Vec<(RecoveryRequestKey, PendingRecovery)> {
+    state::guardian_recovery_requests(|requests| {
+        requests
+            .range(
+                RecoveryRequestKey::new(anchor_number, &[])
+                    ..RecoveryRequestKey::new(anchor_number.saturating_add(1), &[]),
+            )
+            .collect()
+    })
+}
+
+/// Replaces the pending recovery request of the anchor for the public key of the requested
+/// device, if any.
+fn insert_request(anchor_number: AnchorNumber, request: PendingRecovery) {
+    // the device has been checked against the anchor invariants
+    if request.to_bytes().len() > PendingRecovery::MAX_SIZE as usize {
+        trap("guardian recovery request is too large");
+    }
+    let key = RecoveryRequestKey::new(anchor_number, &request.device.pubkey);
+    state::guardian_recovery_requests_mut(|requests| requests.insert(key, request));
+}
+
+/// Requests the addition of the given device to the anchor. The caller must prove possession of
+/// the device key, i.e. the caller must be the principal derived from it.
+/// If the anchor already has [MAX_PENDING_REQUESTS] other pending requests, the oldest request not
+/// yet approved by a guardian is replaced (see module docs).
+pub fn request_recovery(
+    anchor_number: AnchorNumber,
+    device: DeviceData,
+) -> GuardianRecoveryRequestResponse {
+    if caller() != Principal::self_authenticating(&device.pubkey) {
+        return GuardianRecoveryRequestResponse::PublicKeyMismatch;
+    }
+    let anchor = state::anchor(anchor_number);
+    if anchor.guardians().is_none() {
+        return GuardianRecoveryRequestResponse::NoGuardians;
+    }
+
+    // check that the device can be added now to not collect approvals for a device bound to fail
+    if let Err(err) = anchor.clone().add_device(Device::from(device.clone())) {
+        return GuardianRecoveryRequestResponse::AuthnMethodError(AuthnMethodError::from(err));
+    }
+
+    if let Some(approved) = unexpired_request(anchor_number, &device.pubkey)
+        .filter(|pending| !pending.approvals.is_empty())
+    {
+        return GuardianRecoveryRequestResponse::RequestPending {
+            expiration: approved.expiration,
+        };
+    }
+
+    remove_expired_requests(anchor_number);
+    let others: Vec<PendingRecovery> = unexpired_requests(anchor_number)
+        .into_iter()
+        .filter(|pending| pending.device.pubkey != device.pubkey)
+        .collect();
+    if others.len() >= MAX_PENDING_REQUESTS {
+        // the requests are sorted by expiration, i.e. the first unapproved one is the oldest
+        let Some(oldest_unapproved) = others.iter().find(|pending| pending.approvals.is_empty())
+        else {
+            return GuardianRecoveryRequestResponse::RequestPending {
+                expiration: others[0].expiration,
+            };
+        };
+        remove_request(anchor_number, &oldest_unapproved.device.pubkey);
+    }
+
+    let expiration = time() + REQUEST_EXPIRATION_NS;
+    insert_request(
+        anchor_number,
+        PendingRecovery {
+            device,
+            approvals: vec![],
+            expiration,
+        },
+    );
+    GuardianRecoveryRequestResponse::Ok { expiration }
+}
+
+/// Records the approval of the pending recovery request of the anchor by the given guardian.
+/// Once the threshold is reached, the requested device is added to the anchor (or queued, if the
+/// anchor has a recovery delay).
+///
+/// Note: the caller must be authenticated for the `guardian` anchor.
+pub fn approve(
+    guardian: AnchorNumber,
+    anchor_number: AnchorNumber,
+    public_key: PublicKey,
+) -> GuardianRecoveryApproveResponse {
+    let mut anchor = state::anchor(anchor_number);
+    let Some(threshold) = anchor
+        .guardians()
+        .filter(|guardians| guardians.guardians.contains(&guardian))
+        .map(|guardians| guardians.threshold) else {
+        return GuardianRecoveryApproveResponse::NotAGuardian;
+    };
+
+    let Some(mut request) = unexpired_request(anchor_number, &public_key) else {
+        return GuardianRecoveryApproveResponse::RequestNotFound;
+    };
+
+    // the request itself is only archived once it has been approved by a guardian
+    let mut operations = vec![];
+    if request.approvals.is_empty() {
+        operations.push(Operation::RequestGuardianRecovery {
+            device: DeviceDataWithoutAlias::from(Device::from(request.device.clone())),
+        });
+    }
+    if !request.approvals.contains(&guardian) {
+        request.approvals.push(guardian);
+    }
+    operations.push(Operation::ApproveGuardianRecovery {
+        guardian,
+        device: public_key,
+    });
+
+    if request.approvals.len() < threshold as usize {
+        let approvals = request.approvals.len() as u8;
+        insert_request(anchor_number, request);
+        post_operation_bookkeeping(anchor_number, batch(operations));
+        return GuardianRecoveryApproveResponse::Ok {
+            approvals,
+            threshold,
+        };
+    }
+
+    let delayed = DelayedOperation::GuardianRecovery {
+        device: request.device.clone(),
+    };
+    match recovery_delay::queue_if_delayed(anchor_number, &anchor, &public_key, delayed) {
+        Ok(None) => {}
+        Ok(Some((id, execution_time))) => {
+            remove_request(anchor_number, &public_key);
+            post_operation_bookkeeping(anchor_number, batch(operations));
+            return GuardianRecoveryApproveResponse::RecoveryDelayed { id, execution_time };
+        }
+        Err(err) => {
+            return GuardianRecoveryApproveResponse::AuthnMethodError(AuthnMethodError::from(err))
+        }
+    }
+    let add_device = match add(&mut anchor, request.device) {
+        Ok(operation) => operation,
+        Err(err) => {
+            return GuardianRecoveryApproveResponse::AuthnMethodError(AuthnMethodError::from(err))
+        }
+    };
+    state::storage_borrow_mut(|storage| storage.write(anchor_number, anchor)).unwrap_or_else(
+        |err| panic!("unable to update anchor {anchor_number} in stable memory: {err}"),
+    );
+    remove_request(anchor_number, &public_key);
+    operations.push(add_device);
+    post_operation_bookkeeping(anchor_number, batch(operations));
+    GuardianRecoveryApproveResponse::Recovered
+}
+
+fn batch(mut operations: Vec<Operation>) -> Operation {
+    if operations.len() == 1 {
+        return operations.remove(0);
+    }
+    Operation::Batch { operations }
+}
+
+fn remove_request(anchor_number: AnchorNumber, public_key: &[u8]) {
+    state::guardian_recovery_requests_mut(|requests| {
+        requests.remove(&RecoveryRequestKey::new(anchor_number, public_key))
+    });
+}
+
+fn remove_expired_requests(anchor_number: AnchorNumber) {
+    let now = time();
+    for (key, request) in requests(anchor_number) {
+        if request.expiration <= now {
+            state::guardian_recovery_requests_mut(|requests| requests.remove(&key));
+        }
+    }
+}
+
+/// Drops the pending recovery requests of the anchor, if any.
+pub fn remove_requests(anchor_number: AnchorNumber) {
+    for (key, _) in requests(anchor_number) {
+        state::guardian_recovery_requests_mut(|requests| requests.remove(&key));
+    }
+}
@@ -0,0 +1,238 @@
+use crate::state::{RateLimitKey, RateLimitState};
+use crate::{secs_to_nanos, state, HOUR_NS};
+use ic_cdk::api::time;
+use ic_cdk::trap;
+use internet_identity_interface::internet_identity::types::{
+    AnchorNumber, MethodRateLimit, RateLimitConfig, RateLimitedMethod,
+};
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+
+/// Maximum number of per anchor rate limit states tracked at the same time. Only the states of
+/// anchors that have not yet replenished all their tokens need to be kept.
+const MAX_PER_ANCHOR_STATES: usize = 10_000;
+
+/// Processes the registration rate limit, see [process_rate_limit].
+pub fn process_register_rate_limit() {
+    let config = state::persistent_state(|ps| ps.registration_rate_limit.clone());
+    consume_token(RateLimitKey::Register, config);
+}
+
+/// Processes the rate limits of the given method: first the global rate limit, then the rate limit
+/// of the given anchor (if any).
+///
+/// For each of the rate limits:
+///   1. Check if the rate limit is enabled
+///   2. Initialize / update the token count
+///   3. Verify that the current call is not rate limited
+///
+/// The rate limit is based on `tokens`. Each call uses one token. Tokens replenish over time,
+/// every `time_per_token_ns` a new token is added. If tokens is 0 no further calls are allowed until
+/// tokens have replenished.
+/// There is a maximum of `max_tokens` tokens, when reached the tokens not increase any further.
+/// This is the maximum number of calls that can be handled in a burst.
+pub fn process_rate_limit(method: RateLimitedMethod, anchor_number: Option<AnchorNumber>) {
+    let limit = method_rate_limit(method);
+    consume_token(
+        RateLimitKey::Global(method),
+        limit.as_ref().and_then(|limit| limit.global.clone()),
+    );
+    if let Some(anchor_number) = anchor_number {
+        process_per_anchor_rate_limit(method, anchor_number);
+    }
+}
+
+/// Processes only the rate limit of the given method for the given anchor, see [process_rate_limit].
+pub fn process_per_anchor_rate_limit(method: RateLimitedMethod, anchor_number: AnchorNumber) {
+    consume_token(
+        RateLimitKey::PerAnchor(method, anchor_number),
+        method_rate_limit(method).and_then(|limit| limit.per_anchor),
+    );
+}
+
+/// Replenishes all the tokens of the rate limit of the given method for the given anchor.
+pub fn reset_per_anchor_rate_limit(method: RateLimitedMethod, anchor_number: AnchorNumber) {
+    state::rate_limits_mut(|rate_limits| {
+        // not tracking the state is equivalent to having all tokens
+        rate_limits.remove(&RateLimitKey::PerAnchor(method, anchor_number))
+    });
+}
+
+fn method_rate_limit(method: RateLimitedMethod) -> Option<MethodRateLimit> {
+    state::persistent_state(|ps| {
+        ps.method_rate_limits
+            .iter()
+            .flatten()
+            .find(|limit| limit.method == method)
+            .cloned()
+    })
+    .or_else(|| default_method_rate_limit(method))
+}
+
+/// Returns the rate limit applied to the given method if none is configured.
+///
+/// `add_tentative_device` is unauthenticated: the global rate limit bounds how often callers
+/// can poll for anchors in registration mode. The per anchor rate limit only applies to calls
+/// actually adding a tentative device and tokens used by devices discarded by the owner are given
+/// back (see [crate::anchor_management::tentative_device_registration]), so unauthenticated callers
+/// cannot use it up to keep the owner's device from being added.
+///
+/// `create_challenge` is unauthenticated and every challenge is kept until it expires (up to the
+/// maximum number of inflight challenges), hence it is rate limited globally.
+///
+/// `prepare_delegation` is only rate limited per anchor and only for calls fetching the alternative
+/// origins of a derivation origin (see [crate::delegation::alternative_origins]).
+fn default_method_rate_limit(method: RateLimitedMethod) -> Option<MethodRateLimit> {
+    match method {
+        RateLimitedMethod::AddTentativeDevice => Some(MethodRateLimit {
+            method,
+            global: Some(RateLimitConfig {
+                time_per_token_ns: secs_to_nanos(1),
+                max_tokens: 100,
+            }),
+            per_anchor: Some(RateLimitConfig {
+                time_per_token_ns: HOUR_NS,
+                max_tokens: 10,
+            }),
+        }),
+        RateLimitedMethod::VerifyTentativeDevice => Some(MethodRateLimit {
+            method,
+            global: None,
+            per_anchor: Some(RateLimitConfig {
+                time_per_token_ns: secs_to_nanos(60),
+                max_tokens: 10,
+            }),
+        }),
+        RateLimitedMethod::PrepareDelegation => Some(MethodRateLimit {
+            method,
+            global: None,
+            per_anchor: Some(RateLimitConfig {
+                time_per_token_ns: secs_to_nanos(60),
+                max_tokens: 10,
+            }),
+        }),
+        RateLimitedMethod::CreateChallenge => Some(MethodRateLimit {
+            method,
+            global: Some(RateLimitConfig {
+                time_per_token_ns: secs_to_nanos(1),
+                max_tokens: 100,
+            }),
+            per_anchor: None,
+        }),
+    }
+}
+
+fn consume_token(key: RateLimitKey, config: Option<RateLimitConfig>) {
+    let Some(config) = config else {
+        // rate limit disabled -> nothing to do
+        return;
+    };
+
+    state::rate_limits_mut(|rate_limits| {
+        if !rate_limits.contains_key(&key) && matches!(key, RateLimitKey::PerAnchor(..)) {
+            make_room_for_per_anchor_state(rate_limits);
+        }
+        let state = rate_limits.entry(key).or_insert_with(|| RateLimitState {
+            tokens: config.max_tokens,
+            token_timestamp: time(),
+        });
+        add_tokens(state, &config);
+
+        // deduct a token for the current call
+        if state.tokens > 0 {
+            state.tokens -= 1;
+        } else {
+            trap("rate limit reached, try again later");
+        }
+    })
+}
+
+/// Drops the per anchor states that have replenished all their tokens (which is equivalent to not
+/// tracking them at all) if the maximum number of per anchor states is reached.
+fn make_room_for_per_anchor_state(rate_limits: &mut HashMap<RateLimitKey, RateLimitState>) {
+    let per_anchor_states = rate_limits
+        .keys()
+        .filter(|key| matches!(key, RateLimitKey::PerAnchor(..)))
+        .count();
+    if per_anchor_states < MAX_PER_ANCHOR_STATES {
+        return;
+    }
+
+    let mut per_anchor_states = 0;
+    rate_limits.retain(|key, state| {
+        let RateLimitKey::PerAnchor(method, _) = key else {
+            return true;
+        };
+        let Some(config) = method_rate_limit(*method).and_then(|limit| limit.per_anchor) else {
+            return false;
+        };
+        add_tokens(state, &config);
+        let keep = state.tokens < config.max_tokens;
+        if keep {
+            per_anchor_states += 1;
+        }
+        keep
+    });
+    if per_anchor_states >= MAX_PER_ANCHOR_STATES {
+        trap("rate limit reached, try again later");
+    }
+}
+
+/// Adds new tokens to the rate limit state according to the time past since the last update.
+/// To avoid floating point computation, the state is only updated if at least enough time has passed
+/// to add one full token and the `token_timestamp` refers to the last timestamp that has ben accounted
+/// for in the current token count.
+///
+/// I.e. if the time passed would allow to add 1.7 tokens to the state, only 1 token is added and
+/// the `token_timestamp` is increased by 1*`time_per_token_ns`.
+fn add_tokens(state: &mut RateLimitState, config: &RateLimitConfig) {
+    let new_tokens = (time() - state.token_timestamp) / config.time_per_token_ns;
+    if new_tokens > 0 {
+        // The number of tokens is capped otherwise tokens might accumulate
+        state.tokens = min(config.max_tokens, state.tokens + new_tokens);
+        state.token_timestamp += config.time_per_token_ns * new_tokens;
+    }
+}
+
+/// Returns the candid name of the method, e.g. to be used in error messages and metrics.
+pub fn method_name(method: RateLimitedMethod) -> &'static str {
+    match method {
+        RateLimitedMethod::CreateChallenge => "create_challenge",
+        RateLimitedMethod::AddTentativeDevice => "add_tentative_device",
+        RateLimitedMethod::VerifyTentativeDevice => "verify_tentative_device",
+        RateLimitedMethod::PrepareDelegation => "prepare_delegation",
+    }
+}
+
+/// Checks that the per method rate limits are valid.
+pub fn check_method_rate_limits(limits: &[MethodRateLimit]) -> Result<(), String> {
+    let mut methods = HashSet::new();
+    for limit in limits {
+        if !methods.insert(limit.method) {
+            return Err(format!(
+                "duplicate rate limit for {}",
+                method_name(limit.method)
+            ));
+        }
+        if limit.method == RateLimitedMethod::CreateChallenge && limit.per_anchor.is_some() {
+            return Err("create_challenge cannot be rate limited per anchor".to_string());
+        }
+        if limit.method == RateLimitedMethod::PrepareDelegation && limit.global.is_some() {
+            return Err("prepare_delegation can only be rate limited per anchor".to_string());
+        }
+        for config in limit.global.iter().chain(limit.per_anchor.iter()) {
+            check_rate_limit_config(config)
+                .map_err(|err| format!("{}: {err}", method_name(limit.method)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the rate limit is valid, i.e. that tokens replenish (a `time_per_token_ns` of 0
+/// would divide by zero when adding tokens) and that calls are allowed at all.
+pub fn check_rate_limit_config(config: &RateLimitConfig) -> Result<(), String> {
+    if config.time_per_token_ns == 0 || config.max_tokens == 0 {
+        return Err("time_per_token_ns and max_tokens must be greater than 0".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,696 @@
+//! Time-locked recovery.
+//!
+//! Anchors can opt into a recovery delay. If set, modifications of the devices that are
+//! authenticated by a device with [Purpose::Recovery] are not applied immediately but queued for
+//! the configured amount of time (see [must_delay]). This includes adding devices, modifying or
+//! removing other devices and changing the security settings of the recovery device itself.
+//! During that time, the operation can be cancelled using any authentication device (i.e. a device
+//! with [Purpose::Authentication]). Queued operations that are due are applied by a canister timer.
+//! The queue is kept in stable memory (see [crate::storage]).
+//!
+//! This protects users whose recovery phrase leaked: the attacker can neither lock them out of
+//! their anchor nor gain an authentication device without them having the chance to notice and
+//! cancel the operation.
+//!
+//! For the same reason, devices added by a guardian recovery (see
+//! [crate::anchor_management::guardian_recovery]) are queued as well.
+//!
+//! Removing the recovery device that requested an operation drops the operation, as does removing
+//! the recovery delay of the anchor. This way, the owner can stop all the operations requested
+//! using a leaked recovery phrase by removing it.
+use crate::anchor_management::{add, remove, replace, update};
+use crate::archive::archive_operation;
+use crate::storage::anchor::{with_acting_principal, Anchor, AnchorError, Device};
+use crate::storage::MapMemory;
+use crate::{state, DAY_NS, MINUTE_NS};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_cdk::{caller, trap};
+use ic_cdk_timers::set_timer_interval;
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap, Storable};
+use internet_identity_interface::internet_identity::types::*;
+use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
+
+/// Upper bound for the recovery delay that can be configured on an anchor.
+pub const MAX_RECOVERY_DELAY_NS: u64 = 30 * DAY_NS;
+/// Interval in which the canister timer checks for due operations.
+const PROCESSING_INTERVAL_NS: u64 = 10 * MINUTE_NS;
+const MAX_QUEUED_OPERATIONS_PER_ANCHOR: usize = 10;
+/// Maximum number of dropped operations kept per anchor to be reported.
+const MAX_DROPPED_OPERATIONS_PER_ANCHOR: usize = 10;
+/// Maximum number of due operations applied per timer execution, the remaining ones are applied
+/// in the next interval.
+const MAX_APPLIED_OPERATIONS_PER_INTERVAL: usize = 100;
+/// Reason reported for operations dropped because the recovery device that requested them has been
+/// removed.
+const REQUESTING_DEVICE_REMOVED: &str =
+    "the recovery device that requested the operation has been removed";
+/// Reason reported for operations dropped because the recovery delay of the anchor has been removed.
+const RECOVERY_DELAY_REMOVED: &str = "the recovery delay of the identity has been removed";
+
+pub type QueuedOperations<M> = StableBTreeMap<QueuedOperationKey, QueuedOperation, MapMemory<M>>;
+pub type QueueSchedule<M> = StableBTreeMap<ScheduleKey, AnchorNumber, MapMemory<M>>;
+
+/// The operations awaiting their execution time.
+pub struct RecoveryQueue<M: Memory> {
+    // The queued and the dropped operations keyed by anchor number and id
+    operations: QueuedOperations<M>,
+    // Index of the queued (but not the dropped) operations sorted by execution time
+    schedule: QueueSchedule<M>,
+}
+
+impl<M: Memory> RecoveryQueue<M> {
+    pub fn new(operations: QueuedOperations<M>, schedule: QueueSchedule<M>) -> Self {
+        Self {
+            operations,
+            schedule,
+        }
+    }
+
+    fn insert(&mut self, queued: QueuedOperation) {
+        if queued.to_bytes().len() > QueuedOperation::MAX_SIZE as usize {
+            trap("queued recovery operation is too large");
+        }
+        self.schedule.insert(
+            ScheduleKey::new(queued.execution_time, queued.id),
+            queued.anchor_number,
+        );
+        self.operations.insert(
+            QueuedOperationKey::new(queued.anchor_number, queued.id),
+            queued,
+        );
+    }
+
+    /// Keeps the operation that has been removed from the queue because it could not be applied to
+    /// be reported. Only the [MAX_DROPPED_OPERATIONS_PER_ANCHOR] most recently queued ones are kept.
+    fn insert_dropped(&mut self, mut queued: QueuedOperation, error: String) {
+        let anchor_number = queued.anchor_number;
+        queued.error = Some(error);
+        self.operations
+            .insert(QueuedOperationKey::new(anchor_number, queued.id), queued);
+
+        // the operations are sorted by id, i.e. the oldest ones come first
+        let dropped: Vec<u64> = self
+            .operations(anchor_number)
+            .into_iter()
+            .filter(|queued| queued.error.is_some())
+            .map(|queued| queued.id)
+            .collect();
+        let excess = dropped
+            .len()
+            .saturating_sub(MAX_DROPPED_OPERATIONS_PER_ANCHOR);
+        for id in &dropped[..excess] {
+            self.operations
+                .remove(&QueuedOperationKey::new(anchor_number, *id));
+        }
+    }
+
+    /// Returns the queued and the dropped operations of the given anchor.
+    fn operations(&self, anchor_number: AnchorNumber) -> Vec<QueuedOperation> {
+        self.operations
+            .range(
+                QueuedOperationKey::first(anchor_number)..=QueuedOperationKey::last(anchor_number),
+            )
+            .map(|(_, queued)| queued)
+            .collect()
+    }
+
+    /// Removes the queued or dropped operation with the given id.
+    fn remove(&mut self, anchor_number: AnchorNumber, id: u64) -> Option<QueuedOperation> {
+        let queued = self
+            .operations
+            .remove(&QueuedOperationKey::new(anchor_number, id))?;
+        if queued.error.is_none() {
+            self.schedule
+                .remove(&ScheduleKey::new(queued.execution_time, queued.id));
+        }
+        Some(queued)
+    }
+
+    /// Drops the queued operations of the given anchor matching the filter, to be reported with
+    /// the given reason.
+    fn drop_queued(
+        &mut self,
+        anchor_number: AnchorNumber,
+        filter: impl Fn(&QueuedOperation) -> bool,
+        reason: &str,
+    ) {
+        for queued in self.operations(anchor_number) {
+            if queued.error.is_none() && filter(&queued) {
+                self.remove(anchor_number, queued.id);
+                self.insert_dropped(queued, reason.to_string());
+            }
+        }
+    }
+
+    /// Removes the queued and the dropped operations of the given anchor.
+    fn remove_all(&mut self, anchor_number: AnchorNumber) {
+        for queued in self.operations(anchor_number) {
+            self.remove(anchor_number, queued.id);
+        }
+    }
+
+    /// Removes and returns the operation with the earliest execution time, if it is due.
+    fn pop_due(&mut self, now: Timestamp) -> Option<QueuedOperation> {
+        let (key, anchor_number) = self.schedule.iter().next()?;
+        if key.execution_time > now {
+            return None;
+        }
+        self.remove(anchor_number, key.id)
+    }
+}
+
+/// Key of a queued operation.
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+pub struct QueuedOperationKey {
+    // Attention: order of fields MUST NOT be changed because Ord is derived!
+    anchor_number: AnchorNumber,
+    id: u64,
+}
+
+impl QueuedOperationKey {
+    fn new(anchor_number: AnchorNumber, id: u64) -> Self {
+        Self { anchor_number, id }
+    }
+
+    fn first(anchor_number: AnchorNumber) -> Self {
+        Self::new(anchor_number, 0)
+    }
+
+    fn last(anchor_number: AnchorNumber) -> Self {
+        Self::new(anchor_number, u64::MAX)
+    }
+}
+
+/// Note: byte ordering is very important as the keys are sorted on a byte level
+/// --> use big endian to ensure that the most significant bytes are compared first
+impl Storable for QueuedOperationKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::MAX_SIZE as usize);
+        buf.extend(self.anchor_number.to_be_bytes());
+        buf.extend(self.id.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            anchor_number: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[0..8]).expect("failed to read anchor number"),
+            ),
+            id: u64::from_be_bytes(TryFrom::try_from(&bytes[8..16]).expect("failed to read id")),
+        }
+    }
+}
+
+impl BoundedStorable for QueuedOperationKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Key of the index of the queued operations by execution time.
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+pub struct ScheduleKey {
+    // Attention: order of fields MUST NOT be changed because Ord is derived!
+    execution_time: Timestamp,
+    id: u64,
+}
+
+impl ScheduleKey {
+    fn new(execution_time: Timestamp, id: u64) -> Self {
+        Self { execution_time, id }
+    }
+}
+
+/// Note: byte ordering is very important as the keys are sorted on a byte level
+/// --> use big endian to ensure that the most significant bytes are compared first
+impl Storable for ScheduleKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::MAX_SIZE as usize);
+        buf.extend(self.execution_time.to_be_bytes());
+        buf.extend(self.id.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            execution_time: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[0..8]).expect("failed to read execution time"),
+            ),
+            id: u64::from_be_bytes(TryFrom::try_from(&bytes[8..16]).expect("failed to read id")),
+        }
+    }
+}
+
+impl BoundedStorable for ScheduleKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct QueuedOperation {
+    id: u64,
+    anchor_number: AnchorNumber,
+    operation: DelayedOperation,
+    // The recovery device used to request the operation or, for guardian recoveries, the device
+    // to be added
+    requested_by: DeviceKey,
+    // The caller requesting the operation, recorded in the archive once the operation is applied
+    caller: Principal,
+    execution_time: Timestamp,
+    // Why the operation has been dropped, if it could not be applied once due
+    error: Option<String>,
+}
+
+impl Storable for QueuedOperation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode queued operation"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode queued operation")
+    }
+}
+
+impl BoundedStorable for QueuedOperation {
+    // Large enough for any device satisfying the anchor invariants, see RecoveryQueue::insert.
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum DelayedOperation {
+    Remove {
+        device_key: DeviceKey,
+    },
+    Replace {
+        old_device: DeviceKey,
+        new_device: DeviceData,
+    },
+    Add {
+        device: DeviceData,
+    },
+    Update {
+        device_key: DeviceKey,
+        device: DeviceData,
+    },
+    // Addition of a device approved by the guardians of the anchor
+    GuardianRecovery {
+        device: DeviceData,
+    },
+}
+
+impl From<QueuedOperation> for DroppedRecoveryOperation {
+    fn from(mut dropped: QueuedOperation) -> Self {
+        let error = dropped.error.take().unwrap_or_default();
+        Self {
+            operation: QueuedRecoveryOperation::from(dropped),
+            error,
+        }
+    }
+}
+
+impl From<QueuedOperation> for QueuedRecoveryOperation {
+    fn from(queued: QueuedOperation) -> Self {
+        let operation = match queued.operation {
+            DelayedOperation::Remove { device_key } => RecoveryOperation::AuthnMethodRemove {
+                public_key: device_key,
+            },
+            DelayedOperation::Replace {
+                old_device,
+                new_device,
+            } => RecoveryOperation::AuthnMethodReplace {
+                public_key: old_device,
+                authn_method: authn_method_data(new_device),
+            },
+            DelayedOperation::Add { device } | DelayedOperation::GuardianRecovery { device } => {
+                RecoveryOperation::AuthnMethodAdd {
+                    authn_method: authn_method_data(device),
+                }
+            }
+            DelayedOperation::Update { device_key, device } => {
+                RecoveryOperation::AuthnMethodUpdate {
+                    public_key: device_key,
+                    authn_method: authn_method_data(device),
+                }
+            }
+        };
+        Self {
+            id: queued.id,
+            operation,
+            requested_by: queued.requested_by,
+            execution_time: queued.execution_time,
+        }
+    }
+}
+
+fn authn_method_data(device: DeviceData) -> AuthnMethodData {
+    AuthnMethodData::from(DeviceWithUsage::from(Device::from(device)))
+}
+
+/// Starts the timer applying due operations. Timers do not survive upgrades, so this needs to be
+/// called both on `init` and `post_upgrade`.
+pub fn start_timer() {
+    set_timer_interval(
+        Duration::from_nanos(PROCESSING_INTERVAL_NS),
+        apply_due_operations,
+    );
+}
+
+/// Returns true if the given operation on the anchor must be delayed, i.e. if the anchor has
+/// a recovery delay and the operation is a guardian recovery or it is authenticated by a recovery
+/// device and it
+/// * adds a device or
+/// * modifies, replaces or removes another device or
+/// * changes the purpose or protection of the recovery device itself.
+fn must_delay(anchor: &Anchor, device_key: &DeviceKey, operation: &DelayedOperation) -> bool {
+    if anchor.recovery_delay_ns().is_none() {
+        return false;
+    }
+    if let DelayedOperation::GuardianRecovery { .. } = operation {
+        return true;
+    }
+    if !is_recovery_device(anchor, device_key) {
+        return false;
+    }
+    let changes_own_device = |new_device: &DeviceData| {
+        anchor.device(device_key).map_or(true, |device| {
+            device.purpose != new_device.purpose || device.protection != new_device.protection
+        })
+    };
+    match operation {
+        DelayedOperation::Remove { device_key: target } => target != device_key,
+        DelayedOperation::Replace {
+            old_device,
+            new_device,
+        } => old_device != device_key || changes_own_device(new_device),
+        DelayedOperation::Add { .. } | DelayedOperation::GuardianRecovery { .. } => true,
+        DelayedOperation::Update {
+            device_key: target,
+            device,
+        } => target != device_key || changes_own_device(device),
+    }
+}
+
+/// Id and execution time of a queued operation.
+pub type QueuedOperationInfo = (u64, Timestamp);
+
+/// Error returned when an operation cannot be queued.
+#[derive(Debug)]
+pub enum QueueError {
+    /// The operation cannot be applied to the anchor in its current state.
+    Anchor(AnchorError),
+    /// The anchor already has [MAX_QUEUED_OPERATIONS_PER_ANCHOR] queued operations.
+    TooManyQueuedOperations { limit: usize },
+}
+
+impl From<AnchorError> for QueueError {
+    fn from(err: AnchorError) -> Self {
+        QueueError::Anchor(err)
+    }
+}
+
+impl From<QueueError> for AuthnMethodError {
+    fn from(err: QueueError) -> Self {
+        match err {
+            QueueError::Anchor(err) => AuthnMethodError::from(err),
+            QueueError::TooManyQueuedOperations { limit } => {
+                AuthnMethodError::TooManyQueuedOperations {
+                    limit: limit as u64,
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::Anchor(err) => write!(f, "{err}"),
+            QueueError::TooManyQueuedOperations { limit } => write!(
+                f,
+                "Too many queued recovery operations: the limit of {limit} has been reached."
+            ),
+        }
+    }
+}
+
+/// Queues the operation if it must be delayed (see [must_delay]) and returns the id and the
+/// execution time of the queued operation. Returns `None` if the operation can be applied
+/// immediately.
+/// `device_key` is the device authenticating the operation or, for guardian recoveries, the device
+/// to be added.
+pub fn queue_if_delayed(
+    anchor_number: AnchorNumber,
+    anchor: &Anchor,
+    device_key: &DeviceKey,
+    operation: DelayedOperation,
+) -> Result<Option<QueuedOperationInfo>, QueueError> {
+    if !must_delay(anchor, device_key, &operation) {
+        return Ok(None);
+    }
+    queue(anchor_number, anchor, device_key.clone(), operation).map(Some)
+}
+
+/// Traps if the anchor has a recovery delay and the caller is authenticated by a recovery device.
+/// Used for operations that cannot be delayed.
+pub fn check_not_delayed(anchor: &Anchor, device_key: &DeviceKey) {
+    if anchor.recovery_delay_ns().is_some() && is_recovery_device(anchor, device_key) {
+        trap("operation not allowed: the identity has a recovery delay and must be modified using an authentication method that is not a recovery method");
+    }
+}
+
+fn is_recovery_device(anchor: &Anchor, device_key: &DeviceKey) -> bool {
+    anchor
+        .device(device_key)
+        .map(|device| device.purpose == Purpose::Recovery)
+        .unwrap_or(false)
+}
+
+/// Queues the operation to be applied once the recovery delay of the anchor has passed and
+/// returns the id and the execution time of the queued operation.
+/// Returns an error if the operation could not be applied to the anchor in its current state or if
+/// the anchor already has the maximum number of queued operations.
+fn queue(
+    anchor_number: AnchorNumber,
+    anchor: &Anchor,
+    requested_by: DeviceKey,
+    operation: DelayedOperation,
+) -> Result<QueuedOperationInfo, QueueError> {
+    let delay = anchor
+        .recovery_delay_ns()
+        .expect("bug: only operations on anchors with recovery delay can be queued");
+
+    // check that the operation is valid now to not queue operations that are bound to fail
+    let mut dry_run_anchor = anchor.clone();
+    match operation.clone() {
+        DelayedOperation::Remove { device_key } => {
+            dry_run_anchor.remove_device(&device_key)?;
+        }
+        DelayedOperation::Replace {
+            old_device,
+            new_device,
+        } => {
+            dry_run_anchor.remove_device(&old_device)?;
+            dry_run_anchor.add_device(Device::from(new_device))?;
+        }
+        DelayedOperation::Add { device } | DelayedOperation::GuardianRecovery { device } => {
+            dry_run_anchor.add_device(Device::from(device))?;
+        }
+        DelayedOperation::Update { device_key, device } => {
+            update(&mut dry_run_anchor, device_key, device)?;
+        }
+    }
+
+    let num_queued = state::recovery_queue(|queue| {
+        queue
+            .operations(anchor_number)
+            .iter()
+            .filter(|queued| queued.error.is_none())
+            .count()
+    });
+    if num_queued >= MAX_QUEUED_OPERATIONS_PER_ANCHOR {
+        return Err(QueueError::TooManyQueuedOperations {
+            limit: MAX_QUEUED_OPERATIONS_PER_ANCHOR,
+        });
+    }
+    let id = state::persistent_state_mut(|persistent_state| {
+        let next_id = persistent_state.recovery_queue_next_id.get_or_insert(0);
+        let id = *next_id;
+        *next_id += 1;
+        id
+    });
+    let execution_time = time() + delay;
+    state::recovery_queue_mut(|queue| {
+        queue.insert(QueuedOperation {
+            id,
+            anchor_number,
+            operation,
+            requested_by,
+            caller: caller(),
+            execution_time,
+            error: None,
+        })
+    });
+    Ok((id, execution_time))
+}
+
+/// Returns the operations queued for the given anchor.
+pub fn queued_operations(anchor_number: AnchorNumber) -> Vec<QueuedRecoveryOperation> {
+    state::recovery_queue(|queue| queue.operations(anchor_number))
+        .into_iter()
+        .filter(|queued| queued.error.is_none())
+        .map(QueuedRecoveryOperation::from)
+        .collect()
+}
+
+/// Returns the operations of the given anchor that have been dropped because they could not be
+/// applied once due.
+pub fn dropped_operations(anchor_number: AnchorNumber) -> Vec<DroppedRecoveryOperation> {
+    state::recovery_queue(|queue| queue.operations(anchor_number))
+        .into_iter()
+        .filter(|queued| queued.error.is_some())
+        .map(DroppedRecoveryOperation::from)
+        .collect()
+}
+
+/// Cancels the queued operation (or removes the dropped operation) with the given id. Only
+/// authentication devices are allowed to cancel operations.
+pub fn cancel(
+    anchor_number: AnchorNumber,
+    anchor: &Anchor,
+    device_key: &DeviceKey,
+    id: u64,
+) -> RecoveryOperationCancelResponse {
+    if is_recovery_device(anchor, device_key) {
+        return RecoveryOperationCancelResponse::AuthenticationMethodRequired;
+    }
+    match state::recovery_queue_mut(|queue| queue.remove(anchor_number, id)) {
+        Some(_) => RecoveryOperationCancelResponse::Ok,
+        None => RecoveryOperationCancelResponse::NotFound,
+    }
+}
+
+/// Removes the queued and the dropped operations of the given (deleted) anchor.
+pub fn remove_operations(anchor_number: AnchorNumber) {
+    state::recovery_queue_mut(|queue| queue.remove_all(anchor_number));
+}
+
+/// Drops the operations queued for the anchor that have been requested by the given (removed)
+/// device. They are reported like the operations that could not be applied once due (see
+/// [dropped_operations]).
+/// Guardian recoveries are not affected, they are not requested by a device of the anchor (see
+/// [QueuedOperation::requested_by]).
+pub fn drop_operations_requested_by(anchor_number: AnchorNumber, device_key: &DeviceKey) {
+    state::recovery_queue_mut(|queue| {
+        queue.drop_queued(
+            anchor_number,
+            |queued| {
+                &queued.requested_by == device_key
+                    && !matches!(queued.operation, DelayedOperation::GuardianRecovery { .. })
+            },
+            REQUESTING_DEVICE_REMOVED,
+        )
+    });
+}
+
+/// Drops all the operations queued for the anchor, once its recovery delay has been removed.
+pub fn drop_queued_operations(anchor_number: AnchorNumber) {
+    state::recovery_queue_mut(|queue| {
+        queue.drop_queued(anchor_number, |_| true, RECOVERY_DELAY_REMOVED)
+    });
+}
+
+/// Sets the recovery delay of the anchor. Reducing or removing the delay is not allowed when
+/// authenticated by a recovery device as it would defeat the purpose of the delay.
+pub fn set_recovery_delay(
+    anchor: &mut Anchor,
+    device_key: &DeviceKey,
+    recovery_delay_ns: Option<u64>,
+) -> IdentityRecoveryDelaySetResponse {
+    if matches!(recovery_delay_ns, Some(delay) if delay > MAX_RECOVERY_DELAY_NS) {
+        return IdentityRecoveryDelaySetResponse::InvalidDelay {
+            max_delay_ns: MAX_RECOVERY_DELAY_NS,
+        };
+    }
+    let reduced = match (anchor.recovery_delay_ns(), recovery_delay_ns) {
+        (Some(current), Some(new)) => new < current,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    if reduced && is_recovery_device(anchor, device_key) {
+        return IdentityRecoveryDelaySetResponse::AuthenticationMethodRequired;
+    }
+    anchor.set_recovery_delay_ns(recovery_delay_ns);
+    IdentityRecoveryDelaySetResponse::Ok
+}
+
+/// Applies the queued operations that are due, in the order of their execution time. Operations
+/// that can no longer be applied (e.g. because the device has been removed in the meantime) are
+/// dropped and reported (see [dropped_operations]).
+fn apply_due_operations() {
+    let now = time();
+    for _ in 0..MAX_APPLIED_OPERATIONS_PER_INTERVAL {
+        let Some(queued) = state::recovery_queue_mut(|queue| queue.pop_due(now)) else {
+            return;
+        };
+        apply(queued);
+    }
+}
+
+/// Applies the operation on behalf of the principal that requested it: the timer executing the
+/// operation is called by the canister itself, which must not be allowed to modify protected
+/// devices.
+/// The operation is dropped if the anchor no longer has a recovery delay or if the recovery device
+/// that requested it is no longer on the anchor. Both already drop the queued operations (see
+/// [drop_queued_operations] and [drop_operations_requested_by]), this is checked again here in case
+/// the anchor was modified some other way (e.g. by an import).
+fn apply(queued: QueuedOperation) {
+    let anchor_number = queued.anchor_number;
+    let Ok(mut anchor) = state::storage_borrow(|storage| storage.read(anchor_number)) else {
+        // the anchor has been deleted in the meantime
+        return;
+    };
+    let drop_reason = if anchor.recovery_delay_ns().is_none() {
+        Some(RECOVERY_DELAY_REMOVED)
+    } else if !matches!(queued.operation, DelayedOperation::GuardianRecovery { .. })
+        && anchor.device(&queued.requested_by).is_none()
+    {
+        Some(REQUESTING_DEVICE_REMOVED)
+    } else {
+        None
+    };
+    if let Some(reason) = drop_reason {
+        state::recovery_queue_mut(|queue| queue.insert_dropped(queued, reason.to_string()));
+        return;
+    }
+    let result = with_acting_principal(queued.caller, || match queued.operation.clone() {
+        DelayedOperation::Remove { device_key } => remove(anchor_number, &mut anchor, device_key),
+        DelayedOperation::Replace {
+            old_device,
+            new_device,
+        } => replace(anchor_number, &mut anchor, old_device, new_device),
+        DelayedOperation::Add { device } | DelayedOperation::GuardianRecovery { device } => {
+            add(&mut anchor, device)
+        }
+        DelayedOperation::Update { device_key, device } => update(&mut anchor, device_key, device),
+    });
+    let operation = match result {
+        Ok(operation) => operation,
+        Err(err) => {
+            state::recovery_queue_mut(|queue| queue.insert_dropped(queued, err.to_string()));
+            return;
+        }
+    };
+
+    state::storage_borrow_mut(|storage| storage.write(anchor_number, anchor)).unwrap_or_else(
+        |err| panic!("unable to update anchor {anchor_number} in stable memory: {err}"),
+    );
+    archive_operation(anchor_number, queued.caller, operation);
+    state::usage_metrics_mut(|metrics| {
+        metrics.anchor_operation_counter += 1;
+    });
+}
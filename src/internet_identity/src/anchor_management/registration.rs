@@ -1,41 +1,103 @@
+use crate::anchor_management::rate_limit::{process_rate_limit, process_register_rate_limit};
+use crate::anchor_management::registration::captcha::{captcha_config, Captcha};
+use crate::anchor_management::registration::proof_of_work::ProofOfWork;
 use crate::anchor_management::{activity_bookkeeping, post_operation_bookkeeping};
-use crate::state::ChallengeInfo;
+use crate::state;
+use crate::state::{ChallengeInfo, CHALLENGE_KEY_LEN};
 use crate::storage::anchor::Device;
-use crate::storage::Salt;
-use crate::{secs_to_nanos, state};
+use crate::storage::{BoundedKey, InflightChallenges, Salt};
 use candid::Principal;
 use ic_cdk::api::time;
 use ic_cdk::{call, caller, trap};
+use ic_stable_structures::DefaultMemoryImpl;
 use internet_identity_interface::archive::types::{DeviceDataWithoutAlias, Operation};
 use internet_identity_interface::internet_identity::types::*;
+use rand_chacha::ChaCha20Rng;
 use rand_core::{RngCore, SeedableRng};
-use std::collections::{HashMap, HashSet};
 
-#[cfg(not(feature = "dummy_captcha"))]
-use captcha::filters::Wave;
-use captcha::fonts::Default as DefaultFont;
-use captcha::fonts::Font;
-use lazy_static::lazy_static;
+pub mod captcha;
+pub mod proof_of_work;
 
-mod rate_limit;
+/// Whether the challenge is older than the configured challenge lifetime (see [CaptchaConfig]).
+fn is_expired(challenge: &ChallengeInfo, now: Timestamp) -> bool {
+    challenge.created <= now.saturating_sub(captcha_config().challenge_lifetime_ns)
+}
+
+/// Prune old challenges. This drops all challenges that are older than the configured challenge
+/// lifetime. As this iterates over all the challenges, it is only done once the maximum number of
+/// inflight challenges has been reached.
+fn prune_expired_challenges(
+    inflight_challenges: &mut InflightChallenges<DefaultMemoryImpl>,
+    now: Timestamp,
+) {
+    let expired: Vec<_> = inflight_challenges
+        .iter()
+        .filter(|(_, challenge)| is_expired(challenge, now))
+        .map(|(key, _)| key)
+        .collect();
+    for key in expired {
+        inflight_challenges.remove(&key);
+    }
+}
 
-// 5 mins
-const CAPTCHA_CHALLENGE_LIFETIME: u64 = secs_to_nanos(300);
-// How many captcha challenges we keep in memory (at most)
-const MAX_INFLIGHT_CHALLENGES: usize = 500;
+/// A kind of challenge that has to be solved in order to register a new anchor.
+pub trait ChallengeProvider {
+    /// Creates a new challenge with the given key. Returns the [Challenge] sent to the client and
+    /// the [ChallengeInfo] kept to check the solution.
+    fn create_challenge(
+        &self,
+        rng: ChaCha20Rng,
+        challenge_key: ChallengeKey,
+        now: Timestamp,
+    ) -> (Challenge, ChallengeInfo);
+
+    /// Checks whether the attempt solves the challenge created by this provider.
+    fn check_solution(&self, challenge: &ChallengeInfo, attempt: &ChallengeAttempt) -> bool;
+}
+
+fn challenge_provider(config: &ChallengeProviderConfig) -> Box<dyn ChallengeProvider> {
+    match config {
+        ChallengeProviderConfig::Captcha => Box::new(Captcha {
+            config: captcha_config(),
+        }),
+        ChallengeProviderConfig::ProofOfWork(params) => Box::new(ProofOfWork {
+            difficulty: params.difficulty,
+        }),
+    }
+}
+
+/// Checks that the challenge provider configuration is valid.
+pub fn check_challenge_provider_config(config: &ChallengeProviderConfig) -> Result<(), String> {
+    match config {
+        ChallengeProviderConfig::Captcha => Ok(()),
+        ChallengeProviderConfig::ProofOfWork(params) => {
+            proof_of_work::check_difficulty(params.difficulty)
+        }
+    }
+}
 
 pub async fn create_challenge() -> Challenge {
+    process_rate_limit(RateLimitedMethod::CreateChallenge, None);
     let mut rng = make_rng().await;
+    let max_inflight_challenges = captcha_config().max_inflight_challenges;
+    let provider = state::persistent_state(|persistent_state| {
+        challenge_provider(
+            persistent_state
+                .challenge_provider
+                .as_ref()
+                .unwrap_or(&ChallengeProviderConfig::Captcha),
+        )
+    });
 
     state::inflight_challenges_mut(|inflight_challenges| {
         let now = time();
 
-        // Prune old challenges. This drops all challenges that are older than
-        // CAPTCHA_CHALLENGE_LIFETIME
-        inflight_challenges.retain(|_, v| v.created > now - CAPTCHA_CHALLENGE_LIFETIME);
+        if inflight_challenges.len() >= max_inflight_challenges {
+            prune_expired_challenges(inflight_challenges, now);
+        }
 
         // Error out if there are too many inflight challenges
-        if inflight_challenges.len() >= MAX_INFLIGHT_CHALLENGES {
+        if inflight_challenges.len() >= max_inflight_challenges {
             trap("too many inflight captchas");
         }
 
@@ -46,24 +108,16 @@ pub async fn create_challenge() -> Challenge {
         const MAX_TRIES: u8 = 10;
 
         for _ in 0..MAX_TRIES {
-            let challenge_key = random_string(&mut rng, 10);
-            if !inflight_challenges.contains_key(&challenge_key) {
-                // Then we create the CAPTCHA
-                let (Base64(png_base64), chars) = create_captcha(rng);
+            let challenge_key = random_string(&mut rng, CHALLENGE_KEY_LEN as usize);
+            let key = BoundedKey::new(challenge_key.as_bytes())
+                .expect("challenge key exceeds the maximum length");
+            if !inflight_challenges.contains_key(&key) {
+                // Then we create the challenge
+                let (challenge, info) = provider.create_challenge(rng, challenge_key, now);
 
                 // Finally insert
-                inflight_challenges.insert(
-                    challenge_key.clone(),
-                    ChallengeInfo {
-                        created: now,
-                        chars,
-                    },
-                );
-
-                return Challenge {
-                    png_base64,
-                    challenge_key,
-                };
+                inflight_challenges.insert(key, info);
+                return challenge;
             }
         }
 
@@ -72,7 +126,7 @@ pub async fn create_challenge() -> Challenge {
 }
 
 // Get a random number generator based on 'raw_rand'
-async fn make_rng() -> rand_chacha::ChaCha20Rng {
+async fn make_rng() -> ChaCha20Rng {
     let raw_rand: Vec<u8> = match call(Principal::management_canister(), "raw_rand", ()).await {
         Ok((res,)) => res,
         Err((_, err)) => trap(&format!("failed to get seed: {err}")),
@@ -84,7 +138,7 @@ async fn make_rng() -> rand_chacha::ChaCha20Rng {
         ));
     });
 
-    rand_chacha::ChaCha20Rng::from_seed(seed)
+    ChaCha20Rng::from_seed(seed)
 }
 
 // Generate an n-char long string of random characters. The characters are sampled from the rang
@@ -110,117 +164,31 @@ fn random_string<T: RngCore>(rng: &mut T, n: usize) -> String {
     return String::from_utf8_lossy(&chars).to_string();
 }
 
-#[cfg(feature = "dummy_captcha")]
-fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
-    let mut captcha = captcha::new_captcha_with(rng, CAPTCHA_FONT.clone());
-    let captcha = captcha.set_charset(&vec!['a']).add_chars(1).view(96, 48);
-
-    let resp = match captcha.as_base64() {
-        Some(png_base64) => Base64(png_base64),
-        None => trap("Could not get base64 of captcha"),
-    };
-
-    return (resp, captcha.chars_as_string());
-}
-
-lazy_static! {
-    /// Problematic characters that are easily mixed up by humans to "normalized" replacement.
-    /// I.e. the captcha will only contain a "replaced" character (values below in map) if the
-    /// character also appears as a "replacement" (keys below in map). All occurrences of
-    /// "replaced" characters in the user's challenge result will be replaced with the
-    /// "replacements".
-    /// Note: the captcha library already excludes the characters o, O and 0.
-    static ref CHAR_REPLACEMENTS: HashMap<char, Vec<char>> = vec![
-        ('c', vec!['c', 'C']),
-        ('i', vec!['1', 'i', 'l', 'I', 'j']),
-        ('s', vec!['s', 'S']),
-        ('x', vec!['x', 'X']),
-        ('y', vec!['y', 'Y']),
-        ('z', vec!['z', 'Z']),
-        ('p', vec!['p', 'P']),
-        ('w', vec!['w', 'W']),
-    ].into_iter().collect();
-
-    /// The font (glyphs) used when creating captchas
-    static ref CAPTCHA_FONT: DefaultFont = DefaultFont::new();
-
-    /// The character set used in CAPTCHA challenges (font charset with replacements)
-    static ref CHALLENGE_CHARSET: Vec<char> = {
-        // To get the final charset:
-        // * Start with all chars supported by the font by default
-        // * Remove all the chars that will be "replaced"
-        // * Add (potentially re-add) replacement chars
-        let mut chars = CAPTCHA_FONT.chars();
-        {
-          let dropped: HashSet<char> = CHAR_REPLACEMENTS.values().flat_map(|x| x.clone()).collect();
-          chars.retain(|c| !dropped.contains(c));
-        }
-
-        {
-          chars.append(&mut CHAR_REPLACEMENTS.keys().copied().collect());
-        }
-
-        chars
+// Check whether the challenge was solved. The solution is checked by the provider that created the
+// challenge, even if the configured provider changed in the meantime.
+fn check_challenge(res: ChallengeAttempt) -> Result<(), ()> {
+    let Some(key) = BoundedKey::new(res.key.as_bytes()) else {
+        return Err(());
     };
-}
-
-const CAPTCHA_LENGTH: usize = 5;
-#[cfg(not(feature = "dummy_captcha"))]
-fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
-    let mut captcha = captcha::new_captcha_with(rng, CAPTCHA_FONT.clone());
-
-    let captcha = captcha
-        // Replace the default charset with our more readable charset
-        .set_charset(&CHALLENGE_CHARSET)
-        // add some characters
-        .add_chars(CAPTCHA_LENGTH as u32)
-        .apply_filter(Wave::new(2.0, 20.0).horizontal())
-        .apply_filter(Wave::new(2.0, 20.0).vertical())
-        .view(220, 120);
-    // if you ever change the size of the captcha, make sure to also change the
-    // CSS in the frontend to match the new size (.c-captcha-placeholder)
-
-    let resp = match captcha.as_base64() {
-        Some(png_base64) => Base64(png_base64),
-        None => trap("Could not get base64 of captcha"),
+    let Some(challenge) =
+        state::inflight_challenges_mut(|inflight_challenges| inflight_challenges.remove(&key))
+    else {
+        return Err(());
     };
-
-    (resp, captcha.chars_as_string())
-}
-
-// Check whether the CAPTCHA challenge was solved
-fn check_challenge(res: ChallengeAttempt) -> Result<(), ()> {
-    // avoid processing too many characters
-    if res.chars.len() > CAPTCHA_LENGTH {
+    // expired challenges are only pruned once there are too many
+    if is_expired(&challenge, time()) {
         return Err(());
     }
-    // Normalize challenge attempts by replacing characters that are not in the captcha character set
-    // with the respective replacement from CHAR_REPLACEMENTS.
-    let normalized_challenge_res: String = res
-        .chars
-        .chars()
-        .map(|c| {
-            // Apply all replacements
-            *CHAR_REPLACEMENTS
-                .iter()
-                // For each key, see if the char matches any of the values (replaced chars) and if
-                // so replace with the key itself (replacement char)
-                .find_map(|(k, v)| if v.contains(&c) { Some(k) } else { None })
-                .unwrap_or(&c)
-        })
-        .collect();
-
-    state::inflight_challenges_mut(|inflight_challenges| {
-        match inflight_challenges.remove(&res.key) {
-            Some(challenge) => {
-                if normalized_challenge_res != challenge.chars {
-                    return Err(());
-                }
-                Ok(())
-            }
-            None => Err(()),
-        }
-    })
+    let provider = challenge_provider(
+        challenge
+            .provider
+            .as_ref()
+            .unwrap_or(&ChallengeProviderConfig::Captcha),
+    );
+    if !provider.check_solution(&challenge, &res) {
+        return Err(());
+    }
+    Ok(())
 }
 
 pub fn register(
@@ -230,7 +198,7 @@ pub fn register(
     // The key is optional for backwards compatibility
     temp_key: Option<Principal>,
 ) -> RegisterResponse {
-    rate_limit::process_rate_limit();
+    process_register_rate_limit();
     if let Err(()) = check_challenge(challenge_result) {
         return RegisterResponse::BadChallenge;
     }
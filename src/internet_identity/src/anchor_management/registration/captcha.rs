@@ -0,0 +1,209 @@
+//! Image CAPTCHA challenges: the user has to type the characters shown on a distorted image.
+use crate::anchor_management::registration::ChallengeProvider;
+use crate::state::ChallengeInfo;
+use crate::{secs_to_nanos, state, MINUTE_NS};
+use ic_cdk::trap;
+use internet_identity_interface::internet_identity::types::*;
+use lazy_static::lazy_static;
+use rand_chacha::ChaCha20Rng;
+use rand_core::RngCore;
+use std::collections::{HashMap, HashSet};
+
+#[cfg(not(feature = "dummy_captcha"))]
+use captcha::filters::Wave;
+use captcha::fonts::Default as DefaultFont;
+use captcha::fonts::Font;
+
+const DEFAULT_CAPTCHA_LENGTH: u8 = 5;
+// if you ever change the size of the captcha, make sure to also change the
+// CSS in the frontend to match the new size (.c-captcha-placeholder)
+const DEFAULT_CAPTCHA_WIDTH: u32 = 220;
+const DEFAULT_CAPTCHA_HEIGHT: u32 = 120;
+const DEFAULT_WAVE_FREQUENCY: u32 = 2;
+const DEFAULT_WAVE_AMPLITUDE: u32 = 20;
+// 5 mins
+const DEFAULT_CHALLENGE_LIFETIME: u64 = secs_to_nanos(300);
+// How many captcha challenges we keep in memory (at most)
+const DEFAULT_MAX_INFLIGHT_CHALLENGES: u64 = 500;
+
+const MAX_CAPTCHA_LENGTH: u8 = 10;
+const CAPTCHA_SIZE_RANGE: (u32, u32) = (10, 1000);
+const CHALLENGE_LIFETIME_RANGE: (u64, u64) = (MINUTE_NS, 60 * MINUTE_NS);
+const MAX_INFLIGHT_CHALLENGES: u64 = 10_000;
+
+/// Returns the configured CAPTCHA parameters, or the defaults if none are configured.
+pub fn captcha_config() -> CaptchaConfig {
+    state::persistent_state(|persistent_state| persistent_state.captcha_config.clone()).unwrap_or(
+        CaptchaConfig {
+            length: DEFAULT_CAPTCHA_LENGTH,
+            width: DEFAULT_CAPTCHA_WIDTH,
+            height: DEFAULT_CAPTCHA_HEIGHT,
+            wave_frequency: DEFAULT_WAVE_FREQUENCY,
+            wave_amplitude: DEFAULT_WAVE_AMPLITUDE,
+            challenge_lifetime_ns: DEFAULT_CHALLENGE_LIFETIME,
+            max_inflight_challenges: DEFAULT_MAX_INFLIGHT_CHALLENGES,
+        },
+    )
+}
+
+pub fn check_captcha_config(config: &CaptchaConfig) -> Result<(), String> {
+    if config.length == 0 || config.length > MAX_CAPTCHA_LENGTH {
+        return Err(format!(
+            "length must be between 1 and {MAX_CAPTCHA_LENGTH}, got {}",
+            config.length
+        ));
+    }
+    let (min_size, max_size) = CAPTCHA_SIZE_RANGE;
+    for (dimension, value) in [("width", config.width), ("height", config.height)] {
+        if value < min_size || value > max_size {
+            return Err(format!(
+                "{dimension} must be between {min_size} and {max_size}, got {value}"
+            ));
+        }
+    }
+    let (min_lifetime, max_lifetime) = CHALLENGE_LIFETIME_RANGE;
+    if config.challenge_lifetime_ns < min_lifetime || config.challenge_lifetime_ns > max_lifetime {
+        return Err(format!(
+            "challenge_lifetime_ns must be between {min_lifetime} and {max_lifetime}, got {}",
+            config.challenge_lifetime_ns
+        ));
+    }
+    if config.max_inflight_challenges == 0
+        || config.max_inflight_challenges > MAX_INFLIGHT_CHALLENGES
+    {
+        return Err(format!(
+            "max_inflight_challenges must be between 1 and {MAX_INFLIGHT_CHALLENGES}, got {}",
+            config.max_inflight_challenges
+        ));
+    }
+    Ok(())
+}
+
+pub struct Captcha {
+    pub config: CaptchaConfig,
+}
+
+impl ChallengeProvider for Captcha {
+    fn create_challenge(
+        &self,
+        rng: ChaCha20Rng,
+        challenge_key: ChallengeKey,
+        now: Timestamp,
+    ) -> (Challenge, ChallengeInfo) {
+        let (Base64(png_base64), chars) = create_captcha(rng, &self.config);
+        let challenge = Challenge {
+            png_base64,
+            challenge_key,
+            proof_of_work: None,
+        };
+        let info = ChallengeInfo {
+            created: now,
+            chars,
+            provider: Some(ChallengeProviderConfig::Captcha),
+        };
+        (challenge, info)
+    }
+
+    fn check_solution(&self, challenge: &ChallengeInfo, attempt: &ChallengeAttempt) -> bool {
+        // avoid processing too many characters
+        if attempt.chars.len() > challenge.chars.len() {
+            return false;
+        }
+        // Normalize challenge attempts by replacing characters that are not in the captcha character set
+        // with the respective replacement from CHAR_REPLACEMENTS.
+        let normalized_attempt: String = attempt
+            .chars
+            .chars()
+            .map(|c| {
+                // Apply all replacements
+                *CHAR_REPLACEMENTS
+                    .iter()
+                    // For each key, see if the char matches any of the values (replaced chars) and if
+                    // so replace with the key itself (replacement char)
+                    .find_map(|(k, v)| if v.contains(&c) { Some(k) } else { None })
+                    .unwrap_or(&c)
+            })
+            .collect();
+        normalized_attempt == challenge.chars
+    }
+}
+
+#[cfg(feature = "dummy_captcha")]
+fn create_captcha<T: RngCore>(rng: T, _config: &CaptchaConfig) -> (Base64, String) {
+    let mut captcha = captcha::new_captcha_with(rng, CAPTCHA_FONT.clone());
+    let captcha = captcha.set_charset(&vec!['a']).add_chars(1).view(96, 48);
+
+    let resp = match captcha.as_base64() {
+        Some(png_base64) => Base64(png_base64),
+        None => trap("Could not get base64 of captcha"),
+    };
+
+    return (resp, captcha.chars_as_string());
+}
+
+lazy_static! {
+    /// Problematic characters that are easily mixed up by humans to "normalized" replacement.
+    /// I.e. the captcha will only contain a "replaced" character (values below in map) if the
+    /// character also appears as a "replacement" (keys below in map). All occurrences of
+    /// "replaced" characters in the user's challenge result will be replaced with the
+    /// "replacements".
+    /// Note: the captcha library already excludes the characters o, O and 0.
+    static ref CHAR_REPLACEMENTS: HashMap<char, Vec<char>> = vec![
+        ('c', vec!['c', 'C']),
+        ('i', vec!['1', 'i', 'l', 'I', 'j']),
+        ('s', vec!['s', 'S']),
+        ('x', vec!['x', 'X']),
+        ('y', vec!['y', 'Y']),
+        ('z', vec!['z', 'Z']),
+        ('p', vec!['p', 'P']),
+        ('w', vec!['w', 'W']),
+    ].into_iter().collect();
+
+    /// The font (glyphs) used when creating captchas
+    static ref CAPTCHA_FONT: DefaultFont = DefaultFont::new();
+
+    /// The character set used in CAPTCHA challenges (font charset with replacements)
+    static ref CHALLENGE_CHARSET: Vec<char> = {
+        // To get the final charset:
+        // * Start with all chars supported by the font by default
+        // * Remove all the chars that will be "replaced"
+        // * Add (potentially re-add) replacement chars
+        let mut chars = CAPTCHA_FONT.chars();
+        {
+          let dropped: HashSet<char> = CHAR_REPLACEMENTS.values().flat_map(|x| x.clone()).collect();
+          chars.retain(|c| !dropped.contains(c));
+        }
+
+        {
+          chars.append(&mut CHAR_REPLACEMENTS.keys().copied().collect());
+        }
+
+        chars
+    };
+}
+
+#[cfg(not(feature = "dummy_captcha"))]
+fn create_captcha<T: RngCore>(rng: T, config: &CaptchaConfig) -> (Base64, String) {
+    let mut captcha = captcha::new_captcha_with(rng, CAPTCHA_FONT.clone());
+
+    let captcha = captcha
+        // Replace the default charset with our more readable charset
+        .set_charset(&CHALLENGE_CHARSET)
+        // add some characters
+        .add_chars(config.length as u32);
+    if config.wave_amplitude > 0 {
+        let frequency = config.wave_frequency as f64;
+        let amplitude = config.wave_amplitude as f64;
+        captcha
+            .apply_filter(Wave::new(frequency, amplitude).horizontal())
+            .apply_filter(Wave::new(frequency, amplitude).vertical());
+    }
+    let captcha = captcha.view(config.width, config.height);
+
+    let resp = match captcha.as_base64() {
+        Some(png_base64) => Base64(png_base64),
+        None => trap("Could not get base64 of captcha"),
+    };
+
+    (resp, captcha.chars_as_string())
+}
@@ -0,0 +1,138 @@
+//! Hashcash-style proof-of-work challenges: the client has to find a solution such that the
+//! SHA-256 hash of `<challenge key>:<solution>` starts with at least `difficulty` zero bits.
+//!
+//! Unlike the image CAPTCHA, these challenges are solved by the client without any user
+//! interaction. On average, 2^difficulty hashes need to be computed to find a solution.
+use crate::anchor_management::registration::ChallengeProvider;
+use crate::state::ChallengeInfo;
+use internet_identity_interface::internet_identity::types::*;
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+
+/// Upper bound for the difficulty to keep the challenges solvable in a reasonable amount of time.
+pub const MAX_DIFFICULTY: u8 = 32;
+// Any solution can be expressed with far less characters, this only bounds the hashing effort
+const MAX_SOLUTION_LENGTH: usize = 64;
+
+pub struct ProofOfWork {
+    pub difficulty: u8,
+}
+
+impl ChallengeProvider for ProofOfWork {
+    fn create_challenge(
+        &self,
+        _rng: ChaCha20Rng,
+        challenge_key: ChallengeKey,
+        now: Timestamp,
+    ) -> (Challenge, ChallengeInfo) {
+        let params = ProofOfWorkParams {
+            difficulty: self.difficulty,
+        };
+        let challenge = Challenge {
+            png_base64: String::new(),
+            challenge_key,
+            proof_of_work: Some(params.clone()),
+        };
+        let info = ChallengeInfo {
+            created: now,
+            chars: String::new(),
+            provider: Some(ChallengeProviderConfig::ProofOfWork(params)),
+        };
+        (challenge, info)
+    }
+
+    fn check_solution(&self, _challenge: &ChallengeInfo, attempt: &ChallengeAttempt) -> bool {
+        if attempt.chars.len() > MAX_SOLUTION_LENGTH {
+            return false;
+        }
+        let hash = solution_hash(&attempt.key, &attempt.chars);
+        leading_zero_bits(&hash) >= self.difficulty as u32
+    }
+}
+
+pub fn check_difficulty(difficulty: u8) -> Result<(), String> {
+    if difficulty == 0 || difficulty > MAX_DIFFICULTY {
+        return Err(format!(
+            "the proof of work difficulty must be between 1 and {MAX_DIFFICULTY}, got {difficulty}"
+        ));
+    }
+    Ok(())
+}
+
+fn solution_hash(challenge_key: &str, solution: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(challenge_key.as_bytes());
+    hasher.update(b":");
+    hasher.update(solution.as_bytes());
+    hasher.finalize().into()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::SeedableRng;
+
+    fn attempt(chars: &str) -> ChallengeAttempt {
+        ChallengeAttempt {
+            chars: chars.to_string(),
+            key: "challenge".to_string(),
+        }
+    }
+
+    fn solve(difficulty: u8) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|solution| {
+                leading_zero_bits(&solution_hash("challenge", solution)) >= difficulty as u32
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn should_count_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff, 0x00]), 0);
+        assert_eq!(leading_zero_bits(&[0x01, 0x00]), 7);
+        assert_eq!(leading_zero_bits(&[0x00, 0x20, 0x00]), 10);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn should_accept_valid_solution() {
+        let provider = ProofOfWork { difficulty: 12 };
+        let (_, info) =
+            provider.create_challenge(ChaCha20Rng::from_seed([0; 32]), "challenge".to_string(), 0);
+        assert!(provider.check_solution(&info, &attempt(&solve(12))));
+    }
+
+    #[test]
+    fn should_reject_invalid_solution() {
+        let provider = ProofOfWork { difficulty: 12 };
+        let (_, info) =
+            provider.create_challenge(ChaCha20Rng::from_seed([0; 32]), "challenge".to_string(), 0);
+        let solution = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|solution| leading_zero_bits(&solution_hash("challenge", solution)) < 12)
+            .unwrap();
+        assert!(!provider.check_solution(&info, &attempt(&solution)));
+        assert!(!provider.check_solution(&info, &attempt(&"0".repeat(65))));
+    }
+
+    #[test]
+    fn should_check_difficulty() {
+        assert!(check_difficulty(0).is_err());
+        assert!(check_difficulty(1).is_ok());
+        assert!(check_difficulty(MAX_DIFFICULTY).is_ok());
+        assert!(check_difficulty(MAX_DIFFICULTY + 1).is_err());
+    }
+}
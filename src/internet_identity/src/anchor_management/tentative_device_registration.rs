@@ -1,21 +1,27 @@
 use crate::anchor_management::add;
+use crate::anchor_management::rate_limit::{
+    process_per_anchor_rate_limit, process_rate_limit, reset_per_anchor_rate_limit,
+};
+use crate::anchor_management::recovery_delay;
+use crate::anchor_management::recovery_delay::{DelayedOperation, QueuedOperationInfo};
 use crate::state::RegistrationState::{DeviceRegistrationModeActive, DeviceTentativelyAdded};
 use crate::state::TentativeDeviceRegistration;
 use crate::storage::anchor::Anchor;
+use crate::storage::TentativeDeviceRegistrations;
 use crate::{secs_to_nanos, state};
 use candid::Principal;
 use ic_cdk::api::time;
 use ic_cdk::{call, trap};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, Storable};
 use internet_identity_interface::archive::types::Operation;
 use internet_identity_interface::internet_identity::types::*;
-use std::collections::HashMap;
 use AddTentativeDeviceResponse::{AddedTentatively, AnotherDeviceTentativelyAdded};
 use VerifyTentativeDeviceResponse::{NoDeviceToVerify, WrongCode};
 
 // 15 mins
 const REGISTRATION_MODE_DURATION: u64 = secs_to_nanos(900);
 // How many anchors can be in registration mode simultaneously
-const MAX_ANCHORS_IN_REGISTRATION_MODE: usize = 10_000;
+const MAX_ANCHORS_IN_REGISTRATION_MODE: u64 = 10_000;
 // How many verification attempts are given for a tentative device
 const MAX_DEVICE_REGISTRATION_ATTEMPTS: u8 = 3;
 
@@ -23,14 +29,15 @@ const MAX_DEVICE_REGISTRATION_ATTEMPTS: u8 = 3;
 /// If the device registration mode is already active it will just return the expiration timestamp again.
 pub fn enter_device_registration_mode(anchor_number: AnchorNumber) -> Timestamp {
     state::tentative_device_registrations_mut(|registrations| {
-        prune_expired_tentative_device_registrations(registrations);
-        if registrations.len() >= MAX_ANCHORS_IN_REGISTRATION_MODE {
-            trap("too many anchors in device registration mode");
-        }
-
-        match registrations.get(&anchor_number) {
-            Some(TentativeDeviceRegistration { expiration, .. }) => *expiration, // already enabled, just return the existing expiration
+        match unexpired_registration(registrations, anchor_number) {
+            Some(TentativeDeviceRegistration { expiration, .. }) => expiration, // already enabled, just return the existing expiration
             None => {
+                if registrations.len() >= MAX_ANCHORS_IN_REGISTRATION_MODE {
+                    prune_expired_tentative_device_registrations(registrations);
+                }
+                if registrations.len() >= MAX_ANCHORS_IN_REGISTRATION_MODE {
+                    trap("too many anchors in device registration mode");
+                }
                 let expiration = time() + REGISTRATION_MODE_DURATION;
                 registrations.insert(
                     anchor_number,
@@ -45,62 +52,110 @@ pub fn enter_device_registration_mode(anchor_number: AnchorNumber) -> Timestamp
     })
 }
 
+/// Disables device registration mode for the given anchor, discarding the tentative device (if any).
+/// As [add_tentative_device] can be called by anyone, this allows the anchor owner to get rid of a
+/// tentative device added by someone else: the token of the per anchor rate limit used to add it
+/// is given back, such that the owner's device can be added once registration mode is entered again.
 pub fn exit_device_registration_mode(anchor_number: AnchorNumber) {
-    state::tentative_device_registrations_mut(|registrations| {
-        prune_expired_tentative_device_registrations(registrations);
-        registrations.remove(&anchor_number)
+    let registration = state::tentative_device_registrations_mut(|registrations| {
+        let registration = unexpired_registration(registrations, anchor_number);
+        registrations.remove(&anchor_number);
+        registration
     });
+    if let Some(TentativeDeviceRegistration {
+        state: DeviceTentativelyAdded { .. },
+        ..
+    }) = registration
+    {
+        reset_per_anchor_rate_limit(RateLimitedMethod::AddTentativeDevice, anchor_number);
+    }
 }
 
+/// Tentatively adds the device to the anchor, if device registration mode is active and no other
+/// device has been added tentatively.
+///
+/// This method is called without authentication. To keep unauthenticated callers from exhausting
+/// the rate limit of the anchor, only calls actually adding a tentative device consume a token of
+/// the per anchor rate limit, i.e. at most one per activation of the registration mode by the owner.
+/// Every call consumes a token of the global rate limit, which bounds how often callers can poll
+/// for anchors entering registration mode.
 pub async fn add_tentative_device(
     anchor_number: AnchorNumber,
     device_data: DeviceData,
 ) -> AddTentativeDeviceResponse {
+    process_rate_limit(RateLimitedMethod::AddTentativeDevice, None);
     let verification_code = new_verification_code().await;
-    let now = time();
 
+    let mut registration = match state::tentative_device_registrations_mut(|registrations| {
+        unexpired_registration(registrations, anchor_number)
+    }) {
+        None => return AddTentativeDeviceResponse::DeviceRegistrationModeOff,
+        Some(TentativeDeviceRegistration {
+            state: DeviceTentativelyAdded { .. },
+            ..
+        }) => return AnotherDeviceTentativelyAdded,
+        Some(registration) => registration,
+    };
+    registration.state = DeviceTentativelyAdded {
+        tentative_device: device_data,
+        failed_attempts: 0,
+        verification_code: verification_code.clone(),
+    };
+    // the device is only checked against the anchor invariants once verified
+    if registration.to_bytes().len() > TentativeDeviceRegistration::MAX_SIZE as usize {
+        trap("tentative device is too large");
+    }
+    process_per_anchor_rate_limit(RateLimitedMethod::AddTentativeDevice, anchor_number);
+    let device_registration_timeout = registration.expiration;
     state::tentative_device_registrations_mut(|registrations| {
-        prune_expired_tentative_device_registrations(registrations);
-
-        match registrations.get_mut(&anchor_number) {
-            None => AddTentativeDeviceResponse::DeviceRegistrationModeOff,
-            Some(TentativeDeviceRegistration { expiration, .. }) if *expiration <= now => {
-                AddTentativeDeviceResponse::DeviceRegistrationModeOff
-            }
-            Some(TentativeDeviceRegistration {
-                state: DeviceTentativelyAdded { .. },
-                ..
-            }) => AnotherDeviceTentativelyAdded,
-            Some(mut registration) => {
-                registration.state = DeviceTentativelyAdded {
-                    tentative_device: device_data,
-                    failed_attempts: 0,
-                    verification_code: verification_code.clone(),
-                };
-                AddedTentatively {
-                    device_registration_timeout: registration.expiration,
-                    verification_code,
-                }
-            }
-        }
-    })
+        registrations.insert(anchor_number, registration)
+    });
+    AddedTentatively {
+        device_registration_timeout,
+        verification_code,
+    }
 }
 
 /// Verifies the tentative device using the submitted `user_verification_code` and returns
 /// a result of [VerifyTentativeDeviceResponse]. [VerifyTentativeDeviceResponse] is used both as
 /// a success and an error type because it corresponds to the candid variant unifying success and
-/// error cases. See `authenticated_anchor_operation` for more details on how the [Result] is handled.
+/// error cases. See `authenticated_anchor_operation_with_device` for more details on how the [Result] is handled.
+///
+/// If the caller is authenticated with a recovery device of an anchor with a recovery delay, adding
+/// the verified device is queued instead and the id and execution time of the queued operation are
+/// returned.
 pub fn verify_tentative_device(
     anchor: &mut Anchor,
     anchor_number: AnchorNumber,
+    device_key: &DeviceKey,
     user_verification_code: DeviceVerificationCode,
-) -> Result<(VerifyTentativeDeviceResponse, Operation), VerifyTentativeDeviceResponse> {
+) -> Result<
+    (
+        Result<VerifyTentativeDeviceResponse, QueuedOperationInfo>,
+        Operation,
+    ),
+    Result<VerifyTentativeDeviceResponse, QueuedOperationInfo>,
+> {
+    process_rate_limit(
+        RateLimitedMethod::VerifyTentativeDevice,
+        Some(anchor_number),
+    );
     match get_verified_device(anchor_number, user_verification_code) {
         Ok(device) => {
-            let operation = add(anchor, device);
-            Ok((VerifyTentativeDeviceResponse::Verified, operation))
+            let delayed = DelayedOperation::Add {
+                device: device.clone(),
+            };
+            if let Some(queued) =
+                recovery_delay::queue_if_delayed(anchor_number, anchor, device_key, delayed)
+                    .unwrap_or_else(|err| trap(&format!("failed to add device: {err}")))
+            {
+                return Err(Err(queued));
+            }
+            let operation = add(anchor, device)
+                .unwrap_or_else(|err| trap(&format!("failed to add device: {err}")));
+            Ok((Ok(VerifyTentativeDeviceResponse::Verified), operation))
         }
-        Err(err) => Err(err),
+        Err(err) => Err(Ok(err)),
     }
 }
 
@@ -112,11 +167,9 @@ fn get_verified_device(
     user_verification_code: DeviceVerificationCode,
 ) -> Result<DeviceData, VerifyTentativeDeviceResponse> {
     state::tentative_device_registrations_mut(|registrations| {
-        prune_expired_tentative_device_registrations(registrations);
-
-        let mut tentative_registration = registrations
-            .remove(&anchor_number)
+        let mut tentative_registration = unexpired_registration(registrations, anchor_number)
             .ok_or(VerifyTentativeDeviceResponse::DeviceRegistrationModeOff)?;
+        registrations.remove(&anchor_number);
 
         match tentative_registration.state {
             DeviceRegistrationModeActive => Err(NoDeviceToVerify),
@@ -164,11 +217,32 @@ async fn new_verification_code() -> DeviceVerificationCode {
     format!("{:06}", (rand % 1_000_000))
 }
 
-/// Removes __all__ expired device registrations -> there is no need to check expiration immediately after pruning.
+/// Returns the registration of the given anchor, unless it has expired. An expired registration is
+/// removed.
+fn unexpired_registration(
+    registrations: &mut TentativeDeviceRegistrations<DefaultMemoryImpl>,
+    anchor_number: AnchorNumber,
+) -> Option<TentativeDeviceRegistration> {
+    let registration = registrations.get(&anchor_number)?;
+    if registration.expiration <= time() {
+        registrations.remove(&anchor_number);
+        return None;
+    }
+    Some(registration)
+}
+
+/// Removes __all__ expired device registrations. As this iterates over the whole map, it is only
+/// done once the maximum number of anchors in registration mode has been reached.
 fn prune_expired_tentative_device_registrations(
-    registrations: &mut HashMap<AnchorNumber, TentativeDeviceRegistration>,
+    registrations: &mut TentativeDeviceRegistrations<DefaultMemoryImpl>,
 ) {
     let now = time();
-
-    registrations.retain(|_, TentativeDeviceRegistration { expiration, .. }| *expiration > now)
+    let expired: Vec<AnchorNumber> = registrations
+        .iter()
+        .filter(|(_, registration)| registration.expiration <= now)
+        .map(|(anchor_number, _)| anchor_number)
+        .collect();
+    for anchor_number in expired {
+        registrations.remove(&anchor_number);
+    }
 }
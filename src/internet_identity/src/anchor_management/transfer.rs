@@ -0,0 +1,225 @@
+//! Transfer of anchors between II canisters (e.g. to migrate anchors or to split a canister).
+//!
+//! Every II canister serves the anchors of its assigned anchor number range. To move anchors to
+//! another canister, a controller exports them from this canister (see [export_anchors]) and
+//! imports them on the canister whose range contains them (see [import_anchors]). The anchors are
+//! transferred as they are stored, i.e. including the metadata and last usage timestamps of the
+//! devices. Finally, the exported anchors are replaced by tombstones (see [mark_anchors_moved])
+//! telling clients which canister now serves them.
+//!
+//! **Moving an anchor changes all of its principals.** The principal a dapp sees for an anchor is
+//! derived from the canister signature public key, which consists of the canister id of the II
+//! canister and a seed computed from the salt of the canister, the anchor number and the frontend
+//! hostname. Even with the same salt, the canister id differs on the importing canister, so every
+//! derived principal changes (and delegations issued by the exporting canister are not valid for
+//! the new canister signature key). Carrying the salt across would therefore not help, so it is not
+//! transferred. Since users lose access to their accounts on dapps, the import must be explicitly
+//! acknowledged (see [ImportAnchorsArg::acknowledge_principal_change]).
+//!
+//! Guardians are only supported among the anchors of the same canister: guardians outside of the
+//! assigned range of the importing canister are removed from the imported anchors (see
+//! [import_anchors]) and anchors marked as moved are removed as guardians of the anchors that stay
+//! (see [mark_anchors_moved]).
+//!
+//! Imports and tombstones are not archived: the archive of the exporting canister keeps the
+//! history of the moved anchors.
+use crate::anchor_management::{deletion, guardian_recovery};
+use crate::state;
+use crate::storage::anchor::Anchor;
+use crate::storage::StorageError;
+use candid::Principal;
+use ic_cdk::{id, trap};
+use internet_identity_interface::internet_identity::types::*;
+use serde_bytes::ByteBuf;
+use std::cmp::{max, min};
+
+/// Maximum number of anchors exported, imported or marked as moved per call, chosen to stay well
+/// within the instruction limit.
+const MAX_ANCHORS_PER_CALL: usize = 1_000;
+/// Maximum total size of the exported anchors per call to stay within the response size limit.
+const MAX_EXPORT_BYTES: usize = 1_500_000;
+
+/// Returns the anchors in the given range, skipping deleted and moved anchors. Anchors that
+/// cannot be read (e.g. corrupted records) are skipped as well and reported in `unreadable`, so
+/// that the rest of the range can still be exported. If the range does not fit into a single
+/// response, `next` is set to the anchor number to continue from.
+pub fn export_anchors(arg: ExportAnchorsArg) -> ExportAnchorsResponse {
+    let limit = arg
+        .limit
+        .map_or(MAX_ANCHORS_PER_CALL, |limit| limit as usize)
+        .clamp(1, MAX_ANCHORS_PER_CALL);
+    state::storage_borrow(|storage| {
+        let (lo, _) = storage.assigned_anchor_number_range();
+        let end = lo + storage.anchor_count() as AnchorNumber;
+        let end = arg.to.map_or(end, |to| min(to, end));
+
+        let mut anchors = vec![];
+        let mut unreadable = vec![];
+        let mut size = 0;
+        for anchor_number in max(arg.from, lo)..end {
+            if anchors.len() + unreadable.len() >= limit {
+                return ExportAnchorsResponse {
+                    anchors,
+                    unreadable,
+                    next: Some(anchor_number),
+                };
+            }
+            let anchor = match storage.read(anchor_number) {
+                Ok(anchor) => anchor,
+                Err(StorageError::AnchorDeleted(_) | StorageError::AnchorMoved { .. }) => continue,
+                Err(_) => {
+                    unreadable.push(anchor_number);
+                    continue;
+                }
+            };
+            let Ok(data) = candid::encode_one(anchor) else {
+                unreadable.push(anchor_number);
+                continue;
+            };
+            size += data.len();
+            if size > MAX_EXPORT_BYTES && !anchors.is_empty() {
+                return ExportAnchorsResponse {
+                    anchors,
+                    unreadable,
+                    next: Some(anchor_number),
+                };
+            }
+            anchors.push(ExportedAnchor {
+                anchor_number,
+                data: ByteBuf::from(data),
+            });
+        }
+        ExportAnchorsResponse {
+            anchors,
+            unreadable,
+            next: None,
+        }
+    })
+}
+
+/// Writes the given anchors exported by another canister. The anchor numbers must be within the
+/// assigned range of this canister and must not belong to an existing anchor (tombstones are
+/// replaced). Anchor numbers skipped by the import read as deleted.
+///
+/// Anchor numbers of deleted anchors are never reused, so deleted anchors are only replaced if
+/// their numbers are explicitly listed in [ImportAnchorsArg::replace_deleted]. Hence, exported
+/// pages should be imported in order.
+///
+/// Guardians outside of the assigned range of this canister cannot approve recoveries on this
+/// canister, so they are removed from the imported anchors (the threshold is lowered if necessary,
+/// anchors left without guardians no longer have guardians configured). The remaining guardians
+/// are added to the guardian index.
+///
+/// Traps if the change of principals is not acknowledged (see module docs) or if any of the anchors
+/// cannot be imported, which reverts the whole import.
+pub fn import_anchors(arg: ImportAnchorsArg) {
+    if !arg.acknowledge_principal_change {
+        trap("importing anchors changes their principals, this must be acknowledged by setting acknowledge_principal_change");
+    }
+    let anchors = arg.anchors;
+    if anchors.len() > MAX_ANCHORS_PER_CALL {
+        trap(&format!(
+            "cannot import more than {MAX_ANCHORS_PER_CALL} anchors per call"
+        ));
+    }
+    let replace_deleted = arg.replace_deleted.unwrap_or_default();
+    let mut guardians = vec![];
+    state::storage_borrow_mut(|storage| {
+        let (lo, hi) = storage.assigned_anchor_number_range();
+        for ExportedAnchor {
+            anchor_number,
+            data,
+        } in anchors
+        {
+            let mut anchor: Anchor = candid::decode_one(&data).unwrap_or_else(|err| {
+                trap(&format!("failed to decode anchor {anchor_number}: {err}"))
+            });
+            if anchor.moved_to().is_some() {
+                trap(&format!("anchor {anchor_number} is a tombstone"));
+            }
+            match storage.read(anchor_number) {
+                Err(StorageError::BadAnchorNumber(_) | StorageError::AnchorMoved { .. }) => {}
+                Err(StorageError::AnchorDeleted(_))
+                    if replace_deleted.contains(&anchor_number) => {}
+                Err(StorageError::AnchorDeleted(_)) => trap(&format!(
+                    "anchor {anchor_number} has been deleted, list it in replace_deleted to replace it"
+                )),
+                Ok(_) => trap(&format!("anchor {anchor_number} already exists")),
+                Err(err) => trap(&format!("cannot import anchor {anchor_number}: {err}")),
+            }
+            if let Some(mut anchor_guardians) = anchor.guardians().cloned() {
+                anchor_guardians
+                    .guardians
+                    .retain(|guardian| *guardian >= lo && *guardian < hi);
+                anchor_guardians.threshold = min(
+                    anchor_guardians.threshold,
+                    anchor_guardians.guardians.len() as u8,
+                );
+                anchor.set_guardians(
+                    Some(anchor_guardians).filter(|guardians| !guardians.guardians.is_empty()),
+                );
+            }
+            if let Some(anchor_guardians) = anchor.guardians() {
+                guardians.push((
+                    anchor_number,
+                    GuardianConfig::from(anchor_guardians.clone()),
+                ));
+            }
+            storage
+                .allocate_anchors_up_to(anchor_number)
+                .and_then(|_| storage.write(anchor_number, anchor))
+                .unwrap_or_else(|err| {
+                    trap(&format!("failed to import anchor {anchor_number}: {err}"))
+                });
+        }
+    });
+    for (anchor_number, config) in guardians {
+        guardian_recovery::index_wards(anchor_number, None, Some(&config));
+    }
+}
+
+/// Replaces the given anchors by tombstones pointing to the canister now serving them.
+///
+/// The state kept for the moved anchors outside of their records is removed the same way as for
+/// deleted anchors (see [deletion::remove_associated_state]), in particular the moved anchors are
+/// removed as guardians of the anchors that stay on this canister.
+pub fn mark_anchors_moved(arg: MarkAnchorsMovedArg) {
+    if arg.anchor_numbers.len() > MAX_ANCHORS_PER_CALL {
+        trap(&format!(
+            "cannot mark more than {MAX_ANCHORS_PER_CALL} anchors as moved per call"
+        ));
+    }
+    if arg.canister_id == id() {
+        trap("anchors cannot be moved to this canister");
+    }
+    let mut moved = vec![];
+    state::storage_borrow_mut(|storage| {
+        for anchor_number in arg.anchor_numbers {
+            match storage.read(anchor_number) {
+                Ok(anchor) => moved.push((anchor_number, anchor)),
+                Err(StorageError::AnchorMoved { .. }) => {}
+                Err(err) => trap(&format!(
+                    "cannot mark anchor {anchor_number} as moved: {err}"
+                )),
+            }
+            storage
+                .write(anchor_number, Anchor::moved(arg.canister_id))
+                .unwrap_or_else(|err| {
+                    trap(&format!(
+                        "failed to mark anchor {anchor_number} as moved: {err}"
+                    ))
+                });
+        }
+    });
+    for (anchor_number, anchor) in moved {
+        deletion::remove_associated_state(anchor_number, &anchor);
+    }
+}
+
+/// Returns the canister now serving the given anchor, if it has been moved.
+pub fn anchor_moved_to(anchor_number: AnchorNumber) -> Option<Principal> {
+    match state::storage_borrow(|storage| storage.read(anchor_number)) {
+        Err(StorageError::AnchorMoved { canister_id, .. }) => Some(canister_id),
+        _ => None,
+    }
+}
@@ -340,9 +340,6 @@ pub fn archive_operation(anchor_number: AnchorNumber, caller: Principal, operati
             entry: ByteBuf::from(encoded_entry),
             sequence_number: data.sequence_number,
         });
-    });
-
-    state::archive_data_mut(|data| {
         data.sequence_number += 1;
     })
 }
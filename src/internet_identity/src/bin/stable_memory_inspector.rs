@@ -0,0 +1,234 @@
+//! Offline tool to inspect backups of the II stable memory (e.g. created using
+//! `canister_tests::framework::save_compressed_stable_memory`).
+//!
+//! It is built as a separate binary on top of the `internet_identity` library (the canister itself
+//! does not include it), only with the feature of the same name:
+//! ```text
+//! cargo run -p internet_identity --features stable_memory_inspector \
+//!     --bin stable_memory_inspector -- <command> <stable memory file> [args]
+//! ```
+//! The backup is decoded using the same [Storage] implementation as the canister. It is opened
+//! read-only, gzip compressed backups (`.gz`) are decompressed in memory first.
+use flate2::read::GzDecoder;
+use ic_stable_structures::Memory;
+use internet_identity::storage::anchor::Anchor;
+use internet_identity::storage::{Storage, StorageError};
+use internet_identity_interface::internet_identity::types::AnchorNumber;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::process::exit;
+use std::rc::Rc;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+const USAGE: &str = "\
+Usage: stable_memory_inspector <command> <stable memory file> [args]
+
+Commands:
+  header                              print the storage header
+  anchor <anchor number>              print the given anchor
+  persistent-state                    print the persistent state
+  export [json|cbor] [<output file>]  export all anchors (default: json to stdout)";
+
+/// An anchor as exported by the `export` command.
+#[derive(Serialize)]
+struct ExportedAnchor {
+    anchor_number: AnchorNumber,
+    anchor: Anchor,
+}
+
+/// Read-only [Memory] backed by a stable memory backup file.
+#[derive(Clone)]
+struct FileMemory(Rc<RefCell<File>>);
+
+impl Memory for FileMemory {
+    fn size(&self) -> u64 {
+        let len = self
+            .0
+            .borrow()
+            .metadata()
+            .expect("failed to read file metadata")
+            .len();
+        (len + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE
+    }
+
+    fn grow(&self, _pages: u64) -> i64 {
+        // the backup must not be modified
+        -1
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let mut file = self.0.borrow_mut();
+        file.seek(SeekFrom::Start(offset))
+            .expect("failed to seek stable memory file");
+        // the last page might not be fully backed by the file, the remainder reads as zeros
+        let mut read = 0;
+        while read < dst.len() {
+            match file
+                .read(&mut dst[read..])
+                .expect("failed to read stable memory file")
+            {
+                0 => break,
+                n => read += n,
+            }
+        }
+        dst[read..].fill(0);
+    }
+
+    fn write(&self, _offset: u64, _src: &[u8]) {
+        panic!("the stable memory backup is opened read-only");
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        fail(USAGE);
+    }
+    let (command, path, args) = (args[0].as_str(), args[1].as_str(), &args[2..]);
+
+    if path.ends_with(".gz") {
+        let file = File::open(path).unwrap_or_else(|err| fail(&format!("{path}: {err}")));
+        let mut buffer = vec![];
+        GzDecoder::new(file)
+            .read_to_end(&mut buffer)
+            .unwrap_or_else(|err| fail(&format!("{path}: {err}")));
+        run(command, args, Rc::new(RefCell::new(buffer)));
+    } else {
+        let file = File::open(path).unwrap_or_else(|err| fail(&format!("{path}: {err}")));
+        run(command, args, FileMemory(Rc::new(RefCell::new(file))));
+    }
+}
+
+fn run<M: Memory + Clone>(command: &str, args: &[String], memory: M) {
+    let mut magic = [0u8; 3];
+    if memory.size() > 0 {
+        memory.read(0, &mut magic);
+    }
+    let storage =
+        Storage::from_memory(memory).unwrap_or_else(|| fail("the stable memory is empty"));
+
+    match (command, args) {
+        ("header", []) => print_header(&storage, magic),
+        ("anchor", [anchor_number]) => {
+            let anchor_number = anchor_number
+                .parse()
+                .unwrap_or_else(|_| fail(&format!("invalid anchor number: {anchor_number}")));
+            let anchor = storage
+                .read(anchor_number)
+                .unwrap_or_else(|err| fail(&err.to_string()));
+            println!("{}", serde_json::to_string_pretty(&anchor).unwrap());
+        }
+        ("persistent-state", []) => match storage.read_persistent_state() {
+            Ok(state) => println!("{state:#?}"),
+            Err(err) => fail(&format!("failed to read the persistent state: {err:?}")),
+        },
+        ("export", args) if args.len() <= 2 => {
+            let format = args.first().map(String::as_str).unwrap_or("json");
+            let mut output: Box<dyn Write> = match args.get(1) {
+                Some(path) => Box::new(
+                    File::create(path).unwrap_or_else(|err| fail(&format!("{path}: {err}"))),
+                ),
+                None => Box::new(std::io::stdout()),
+            };
+            let anchors = export_anchors(&storage);
+            let result = match format {
+                "json" => serde_json::to_writer_pretty(&mut output, &anchors)
+                    .map_err(|err| err.to_string()),
+                "cbor" => {
+                    serde_cbor::to_writer(&mut output, &anchors).map_err(|err| err.to_string())
+                }
+                _ => fail(&format!("unsupported export format: {format}")),
+            };
+            result
+                .and_then(|_| output.flush().map_err(|err| err.to_string()))
+                .unwrap_or_else(|err| fail(&format!("failed to export the anchors: {err}")));
+            eprintln!("exported {} anchors", anchors.len());
+        }
+        _ => fail(USAGE),
+    }
+}
+
+fn print_header<M: Memory + Clone>(storage: &Storage<M>, magic: [u8; 3]) {
+    let (lo, hi) = storage.assigned_anchor_number_range();
+    println!("magic: {}", String::from_utf8_lossy(&magic));
+    println!("layout version: {}", storage.version());
+    if storage.is_migrating_to_v8() {
+        println!("migration to layout version 8: in progress");
+    }
+    println!("anchor range: [{lo}, {hi})");
+    println!("anchors: {}", storage.anchor_count());
+    println!(
+        "salt: {}",
+        if storage.salt().is_some() {
+            "set"
+        } else {
+            "not set"
+        }
+    );
+}
+
+/// Reads all the anchors, skipping the deleted and moved ones. Anchors that cannot be decoded are reported
+/// but do not abort the export.
+fn export_anchors<M: Memory + Clone>(storage: &Storage<M>) -> Vec<ExportedAnchor> {
+    let (lo, _) = storage.assigned_anchor_number_range();
+    (lo..lo + storage.anchor_count() as AnchorNumber)
+        .filter_map(|anchor_number| match storage.read(anchor_number) {
+            Ok(anchor) => Some(ExportedAnchor {
+                anchor_number,
+                anchor,
+            }),
+            Err(StorageError::AnchorDeleted(_) | StorageError::AnchorMoved { .. }) => None,
+            Err(err) => {
+                eprintln!("skipping anchor {anchor_number}: {err}");
+                None
+            }
+        })
+        .collect()
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    exit(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+    use internet_identity::storage::StableMemory;
+
+    #[test]
+    fn should_export_anchors_without_deleted_ones() {
+        let memory = VectorMemory::default();
+        let mut storage = Storage::new((10_000, 20_000), StableMemory::Managed(memory.clone()));
+        for _ in 0..3 {
+            let (anchor_number, anchor) = storage.allocate_anchor().unwrap();
+            storage.write(anchor_number, anchor).unwrap();
+        }
+        storage.delete(10_001).unwrap();
+        storage.flush();
+
+        let storage = Storage::from_memory(memory).unwrap();
+        let anchor_numbers: Vec<AnchorNumber> = export_anchors(&storage)
+            .iter()
+            .map(|exported| exported.anchor_number)
+            .collect();
+        assert_eq!(anchor_numbers, vec![10_000, 10_002]);
+    }
+
+    #[test]
+    fn should_read_zeros_beyond_end_of_file() {
+        let path = std::env::temp_dir().join("ii_stable_memory_inspector_test.bin");
+        File::create(&path).unwrap().write_all(&[1, 2, 3]).unwrap();
+        let memory = FileMemory(Rc::new(RefCell::new(File::open(&path).unwrap())));
+
+        assert_eq!(memory.size(), 1);
+        let mut buf = [0xff; 5];
+        memory.read(1, &mut buf);
+        assert_eq!(buf, [2, 3, 0, 0, 0]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
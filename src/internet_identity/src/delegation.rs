@@ -1,18 +1,22 @@
 use crate::active_anchor_stats::IIDomain;
 use crate::assets::CertifiedAssets;
-use crate::state::persistent_state_mut;
+use crate::delegation::alternative_origins::{validate_derivation_origin, CachingFetcher};
+use crate::signature_map::SignatureMap;
+use crate::state::revocation_epochs::RevocationScope;
+use crate::state::{persistent_state_mut, PreparedSignature};
 use crate::{hash, state, update_root_hash, DAY_NS, LABEL_SIG, MINUTE_NS};
 use candid::Principal;
 use ic_cdk::api::{data_certificate, time};
 use ic_cdk::{id, trap};
 use ic_certified_map::{Hash, HashTree};
-use internet_identity::signature_map::SignatureMap;
 use internet_identity_interface::internet_identity::types::*;
 use serde::Serialize;
 use serde_bytes::ByteBuf;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::IpAddr;
 
+pub mod alternative_origins;
+
 // The expiration used for delegations if none is specified
 // (calculated as now() + this)
 const DEFAULT_EXPIRATION_PERIOD_NS: u64 = 30 * MINUTE_NS;
@@ -25,26 +29,66 @@ const MAX_EXPIRATION_PERIOD_NS: u64 = 30 * DAY_NS;
 #[allow(clippy::identity_op)]
 const SIGNATURE_EXPIRATION_PERIOD_NS: u64 = 1 * MINUTE_NS;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn prepare_delegation(
     anchor_number: AnchorNumber,
     frontend: FrontendHostname,
     session_key: SessionKey,
     max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    origin: Option<FrontendHostname>,
+    device_key: DeviceKey,
     ii_domain: &Option<IIDomain>,
 ) -> (UserKey, Timestamp) {
+    let prepared_at = time();
     state::ensure_salt_set().await;
     prune_expired_signatures();
     check_frontend_length(&frontend);
+    check_targets_length(&targets);
+    // Without the requesting origin, it cannot be checked whether it may use the principals of the
+    // derivation origin.
+    let Some(origin) = origin else {
+        trap("the requesting origin must be provided");
+    };
+    check_frontend_length(&origin);
+    // Refuse to sign for a derivation origin that does not list the requesting origin as an
+    // alternative origin.
+    let fetcher = CachingFetcher { anchor_number };
+    if let Err(err) = validate_derivation_origin(&fetcher, &origin, &frontend).await {
+        trap(&format!("invalid derivation origin: {err}"));
+    }
+    let seed = calculate_seed(anchor_number, &frontend);
+    check_not_revoked_since(anchor_number, hash::hash_bytes(seed), prepared_at);
 
+    let (default_time_to_live, max_allowed_time_to_live) = time_to_live_bounds(&frontend);
     let delta = u64::min(
-        max_time_to_live.unwrap_or(DEFAULT_EXPIRATION_PERIOD_NS),
-        MAX_EXPIRATION_PERIOD_NS,
+        max_time_to_live.unwrap_or(default_time_to_live),
+        max_allowed_time_to_live,
     );
     let expiration = time().saturating_add(delta);
-    let seed = calculate_seed(anchor_number, &frontend);
 
-    state::signature_map_mut(|sigs| {
-        add_signature(sigs, session_key, seed, expiration);
+    let session = DelegationSession {
+        frontend: frontend.clone(),
+        session_key_fingerprint: ByteBuf::from(hash::hash_bytes(&session_key).to_vec()),
+        expiration,
+        authn_method_pubkey: device_key,
+    };
+    // The session is recorded when the delegation is prepared rather than when it is retrieved,
+    // because `get_delegation` is a query and cannot modify the state.
+    state::with_delegation_sessions_mut(|sessions| {
+        sessions.add_session(anchor_number, session, time())
+    });
+
+    let (msg_hash, expires_at) = state::signature_map_mut(|sigs| {
+        add_signature(sigs, session_key, seed, expiration, targets)
+    });
+    state::prepared_signatures_mut(|prepared_signatures| {
+        prepared_signatures.push_back(PreparedSignature {
+            anchor_number,
+            seed_hash: hash::hash_bytes(seed),
+            msg_hash,
+            expires_at,
+        })
     });
     update_root_hash();
 
@@ -56,6 +100,77 @@ pub async fn prepare_delegation(
     )
 }
 
+/// Returns the default and maximum time to live of delegations for the given front-end.
+fn time_to_live_bounds(frontend: &FrontendHostname) -> (u64, u64) {
+    state::persistent_state(|persistent_state| {
+        persistent_state
+            .delegation_ttl_policy
+            .as_ref()
+            .and_then(|rules| matching_ttl_rule(rules, frontend))
+            .map(|rule| (rule.default_time_to_live_ns, rule.max_time_to_live_ns))
+    })
+    .unwrap_or((DEFAULT_EXPIRATION_PERIOD_NS, MAX_EXPIRATION_PERIOD_NS))
+}
+
+/// Returns the rule applying to the given front-end. Exact matches take precedence over patterns
+/// and longer (i.e. more specific) patterns take precedence over shorter ones.
+fn matching_ttl_rule<'a>(
+    rules: &'a [DelegationTtlRule],
+    frontend: &str,
+) -> Option<&'a DelegationTtlRule> {
+    rules
+        .iter()
+        .filter(|rule| frontend_matches(&rule.frontend, frontend))
+        .max_by_key(|rule| (!rule.frontend.contains('*'), rule.frontend.len()))
+}
+
+/// Checks whether the front-end matches the given hostname or pattern. A pattern such as
+/// `https://*.example.com` matches all subdomains of `example.com` but not `example.com` itself.
+fn frontend_matches(pattern: &str, frontend: &str) -> bool {
+    let Some((scheme, domain)) = pattern.split_once("*.") else {
+        return pattern == frontend;
+    };
+    let Some(subdomain) = frontend
+        .strip_prefix(scheme)
+        .and_then(|host| host.strip_suffix(domain))
+        .and_then(|host| host.strip_suffix('.')) else {
+        return false;
+    };
+    !subdomain.is_empty()
+        && subdomain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+/// Checks that the delegation lifetime policy is well-formed and within the global limits.
+pub fn validate_ttl_policy(rules: &[DelegationTtlRule]) -> Result<(), String> {
+    for rule in rules {
+        let frontend = &rule.frontend;
+        if let Some((scheme, domain)) = frontend.split_once("*.") {
+            if !scheme.ends_with("://") || scheme.contains('*') || domain.contains('*') {
+                return Err(format!(
+                    "{frontend}: patterns must be of the form <scheme>://*.<domain>"
+                ));
+            }
+        } else if frontend.contains('*') {
+            return Err(format!(
+                "{frontend}: patterns must be of the form <scheme>://*.<domain>"
+            ));
+        }
+        if rule.max_time_to_live_ns > MAX_EXPIRATION_PERIOD_NS {
+            return Err(format!(
+                "{frontend}: max time to live exceeds the limit of {MAX_EXPIRATION_PERIOD_NS} ns"
+            ));
+        }
+        if rule.default_time_to_live_ns > rule.max_time_to_live_ns {
+            return Err(format!(
+                "{frontend}: default time to live exceeds the max time to live"
+            ));
+        }
+    }
+    Ok(())
+}
+
 /// Update metrics and the list of latest front-end origins.
 fn delegation_bookkeeping(frontend: FrontendHostname, ii_domain: &Option<IIDomain>) {
     state::usage_metrics_mut(|metrics| {
@@ -119,28 +234,102 @@ fn update_latest_delegation_origins(frontend: FrontendHostname) {
     });
 }
 
+/// Deletes the signatures of the given anchor that have not yet been retrieved using `get_delegation`
+/// (restricted to the given frontend, if any) and records the revocation epoch of the revoked
+/// delegations. Delegations of the same scope being prepared concurrently are rejected as well.
+/// The sessions of the revoked delegations are no longer reported (see [delegation_sessions]).
+///
+/// The revocation epochs are kept in stable memory (see [state::revocation_epochs]).
+///
+/// Limitation: delegations that have already been retrieved cannot be invalidated by II, they stay
+/// valid until they expire (i.e. for up to [MAX_EXPIRATION_PERIOD_NS]).
+pub fn revoke_delegations(anchor_number: AnchorNumber, frontend: Option<FrontendHostname>) {
+    let now = time();
+    let salt_set = state::storage_borrow(|storage| storage.salt().is_some());
+    // Without salt the seed of the delegations being prepared concurrently is not yet known, hence
+    // all the delegations of the anchor are revoked.
+    if let Some(ref frontend) = frontend {
+        check_frontend_length(frontend);
+    }
+    state::with_delegation_sessions_mut(|sessions| {
+        sessions.remove_sessions(anchor_number, frontend.as_ref())
+    });
+    let seed_hash = frontend
+        .filter(|_| salt_set)
+        .map(|frontend| hash::hash_bytes(calculate_seed(anchor_number, &frontend)));
+    let scope = match seed_hash {
+        Some(seed_hash) => RevocationScope::frontend(anchor_number, seed_hash),
+        None => RevocationScope::anchor(anchor_number),
+    };
+    // delegations prepared before the maximum delegation time to live have expired anyway
+    let prune_before = now.saturating_sub(MAX_EXPIRATION_PERIOD_NS);
+    state::with_revocation_epochs_mut(|epochs| epochs.revoke(scope, now, prune_before));
+
+    if !salt_set {
+        // without salt no delegation can have been prepared
+        return;
+    }
+
+    let revoked = state::prepared_signatures_mut(|prepared_signatures| {
+        let (revoked, kept): (VecDeque<_>, VecDeque<_>) =
+            prepared_signatures.drain(..).partition(|sig| {
+                sig.anchor_number == anchor_number
+                    && seed_hash.map_or(true, |seed_hash| seed_hash == sig.seed_hash)
+            });
+        *prepared_signatures = kept;
+        revoked
+    });
+    if revoked.is_empty() {
+        return;
+    }
+    state::signature_map_mut(|sigs| {
+        for sig in revoked {
+            sigs.delete(sig.seed_hash, sig.msg_hash);
+        }
+    });
+    update_root_hash();
+}
+
+/// Traps if the delegations of the anchor for the frontend with the given seed hash were revoked at
+/// or after the given time.
+fn check_not_revoked_since(anchor_number: AnchorNumber, seed_hash: Hash, timestamp: Timestamp) {
+    if state::with_revocation_epochs(|epochs| {
+        epochs.revoked_since(anchor_number, seed_hash, timestamp)
+    }) {
+        trap(&format!(
+            "delegations of anchor {anchor_number} were revoked while preparing the delegation"
+        ));
+    }
+}
+
+/// Returns the not yet expired delegations prepared for the given anchor.
+pub fn delegation_sessions(anchor_number: AnchorNumber) -> Vec<DelegationSession> {
+    state::with_delegation_sessions(|sessions| sessions.sessions(anchor_number, time()))
+}
+
 pub fn get_delegation(
     anchor_number: AnchorNumber,
     frontend: FrontendHostname,
     session_key: SessionKey,
     expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
 ) -> GetDelegationResponse {
     check_frontend_length(&frontend);
 
+    let delegation = Delegation {
+        pubkey: session_key,
+        expiration,
+        targets,
+    };
     state::assets_and_signatures(|asset_hashes, sigs| {
         match get_signature(
             asset_hashes,
             sigs,
-            session_key.clone(),
             calculate_seed(anchor_number, &frontend),
-            expiration,
+            &delegation,
         ) {
             Some(signature) => GetDelegationResponse::SignedDelegation(SignedDelegation {
-                delegation: Delegation {
-                    pubkey: session_key,
-                    expiration,
-                    targets: None,
-                },
+                delegation,
                 signature: ByteBuf::from(signature),
             }),
             None => GetDelegationResponse::NoSuchDelegation,
@@ -219,18 +408,13 @@ fn delegation_signature_msg_hash(d: &Delegation) -> Hash {
 fn get_signature(
     assets: &CertifiedAssets,
     sigs: &SignatureMap,
-    pk: PublicKey,
     seed: Hash,
-    expiration: Timestamp,
+    delegation: &Delegation,
 ) -> Option<Vec<u8>> {
     let certificate = data_certificate().unwrap_or_else(|| {
         trap("data certificate is only available in query calls");
     });
-    let msg_hash = delegation_signature_msg_hash(&Delegation {
-        pubkey: pk,
-        expiration,
-        targets: None,
-    });
+    let msg_hash = delegation_signature_msg_hash(delegation);
     let witness = sigs.witness(hash::hash_bytes(seed), msg_hash)?;
 
     let witness_hash = witness.reconstruct();
@@ -265,14 +449,21 @@ fn get_signature(
     Some(cbor.into_inner())
 }
 
-fn add_signature(sigs: &mut SignatureMap, pk: PublicKey, seed: Hash, expiration: Timestamp) {
+fn add_signature(
+    sigs: &mut SignatureMap,
+    pk: PublicKey,
+    seed: Hash,
+    expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
+) -> (Hash, Timestamp) {
     let msg_hash = delegation_signature_msg_hash(&Delegation {
         pubkey: pk,
         expiration,
-        targets: None,
+        targets,
     });
     let expires_at = time().saturating_add(SIGNATURE_EXPIRATION_PERIOD_NS);
     sigs.put(hash::hash_bytes(seed), msg_hash, expires_at);
+    (msg_hash, expires_at)
 }
 
 /// Removes a batch of expired signatures from the signature map.
@@ -282,7 +473,13 @@ fn add_signature(sigs: &mut SignatureMap, pk: PublicKey, seed: Hash, expiration:
 /// will prune at most MAX_SIGS_TO_PRUNE other signatures.
 pub fn prune_expired_signatures() {
     const MAX_SIGS_TO_PRUNE: usize = 50;
-    let num_pruned = state::signature_map_mut(|sigs| sigs.prune_expired(time(), MAX_SIGS_TO_PRUNE));
+    let now = time();
+    let num_pruned = state::signature_map_mut(|sigs| sigs.prune_expired(now, MAX_SIGS_TO_PRUNE));
+    state::prepared_signatures_mut(|prepared_signatures| {
+        while matches!(prepared_signatures.front(), Some(sig) if sig.expires_at <= now) {
+            prepared_signatures.pop_front();
+        }
+    });
     if num_pruned > 0 {
         update_root_hash();
     }
@@ -298,3 +495,100 @@ fn check_frontend_length(frontend: &FrontendHostname) {
         ));
     }
 }
+
+fn check_targets_length(targets: &Option<Vec<Principal>>) {
+    // The IC rejects delegations with more targets than this.
+    const TARGETS_LIMIT: usize = 1000;
+
+    let Some(targets) = targets else {
+        return;
+    };
+    let n = targets.len();
+    if n > TARGETS_LIMIT {
+        trap(&format!(
+            "number of delegation targets {n} exceeds the limit of {TARGETS_LIMIT}",
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(frontend: &str, max_time_to_live_ns: u64) -> DelegationTtlRule {
+        DelegationTtlRule {
+            frontend: frontend.to_string(),
+            max_time_to_live_ns,
+            default_time_to_live_ns: 0,
+        }
+    }
+
+    #[test]
+    fn should_match_frontends() {
+        assert!(frontend_matches(
+            "https://example.com",
+            "https://example.com"
+        ));
+        assert!(frontend_matches(
+            "https://*.example.com",
+            "https://a.example.com"
+        ));
+        assert!(frontend_matches(
+            "https://*.example.com",
+            "https://a.b.example.com"
+        ));
+
+        assert!(!frontend_matches(
+            "https://example.com",
+            "https://a.example.com"
+        ));
+        assert!(!frontend_matches(
+            "https://*.example.com",
+            "https://example.com"
+        ));
+        assert!(!frontend_matches(
+            "https://*.example.com",
+            "https://evilexample.com"
+        ));
+        assert!(!frontend_matches(
+            "https://*.example.com",
+            "http://a.example.com"
+        ));
+        assert!(!frontend_matches(
+            "https://*.example.com",
+            "https://evil.com/.example.com"
+        ));
+    }
+
+    #[test]
+    fn should_prefer_most_specific_rule() {
+        let rules = vec![
+            rule("https://*.example.com", 1),
+            rule("https://*.wallet.example.com", 2),
+            rule("https://my.wallet.example.com", 3),
+        ];
+        let max_ttl =
+            |frontend| matching_ttl_rule(&rules, frontend).map(|rule| rule.max_time_to_live_ns);
+
+        assert_eq!(max_ttl("https://forum.example.com"), Some(1));
+        assert_eq!(max_ttl("https://your.wallet.example.com"), Some(2));
+        assert_eq!(max_ttl("https://my.wallet.example.com"), Some(3));
+        assert_eq!(max_ttl("https://example.org"), None);
+    }
+
+    #[test]
+    fn should_validate_ttl_policy() {
+        assert!(validate_ttl_policy(&[rule("https://*.example.com", MINUTE_NS)]).is_ok());
+        assert!(validate_ttl_policy(&[rule("https://a.*.example.com", MINUTE_NS)]).is_err());
+        assert!(validate_ttl_policy(&[rule("https://*example.com", MINUTE_NS)]).is_err());
+        assert!(
+            validate_ttl_policy(&[rule("https://example.com", MAX_EXPIRATION_PERIOD_NS + 1)])
+                .is_err()
+        );
+        assert!(validate_ttl_policy(&[DelegationTtlRule {
+            default_time_to_live_ns: 2 * MINUTE_NS,
+            ..rule("https://example.com", MINUTE_NS)
+        }])
+        .is_err());
+    }
+}
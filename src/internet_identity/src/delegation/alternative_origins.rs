@@ -0,0 +1,472 @@
+//! Canister-side verification of alternative frontend origins.
+//!
+//! A dapp may request principals for a derivation origin that is different from its own origin,
+//! provided the derivation origin lists the requesting origin in its
+//! `/.well-known/ii-alternative-origins` resource. See the spec for details:
+//! https://github.com/dfinity/internet-identity/blob/main/docs/ii-spec.md#alternative-frontend-origins
+//!
+//! The resource is fetched using HTTPS outcalls. Fetching is abstracted behind the
+//! [AlternativeOriginsFetcher] trait so that the validation logic can be tested without outcalls.
+//!
+//! As outcalls are expensive, the fetched resources (and fetch errors) are cached for a few minutes
+//! (see [AlternativeOriginsCache]) and the outcalls made on behalf of an anchor are subject to the
+//! per anchor rate limit of `prepare_delegation` (see [CachingFetcher]).
+use crate::anchor_management::rate_limit::process_per_anchor_rate_limit;
+use crate::{state, MINUTE_NS};
+use candid::{Func, Nat, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext, TransformFunc,
+};
+use ic_cdk::api::time;
+use ic_cdk::id;
+use internet_identity_interface::internet_identity::types::{
+    AnchorNumber, RateLimitedMethod, Timestamp,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Maximum number of alternative origins a derivation origin may list.
+const MAX_ALTERNATIVE_ORIGINS: usize = 10;
+
+/// Upper bound for the size of the alternative origins resource. This bounds the cost of the
+/// HTTPS outcall.
+const MAX_RESPONSE_BYTES: u64 = 10_000;
+
+/// Time for which a fetched alternative origins resource is reused.
+const CACHED_RESOURCE_TTL_NS: u64 = 10 * MINUTE_NS;
+
+/// Time for which a failure to fetch an alternative origins resource is reused. This is shorter
+/// than [CACHED_RESOURCE_TTL_NS] as the failure may be transient.
+const CACHED_ERROR_TTL_NS: u64 = MINUTE_NS;
+
+/// Maximum number of cached resources. Together with [MAX_RESPONSE_BYTES], this bounds the memory
+/// used by the cache.
+const MAX_CACHED_RESOURCES: usize = 500;
+
+/// Name of the (query) method used to make the outcall responses deterministic across replicas.
+pub const TRANSFORM_METHOD: &str = "transform_alternative_origins";
+
+/// The NNS dapp has always been served from a custom domain (nns.ic0.app), which is why it is
+/// allowed as an alternative origin despite the subdomain not being a canister id.
+const NNS_DAPP_CANISTER_ID: &str = "qoctq-giaaa-aaaaa-aaaea-cai";
+
+/// Future returned by [AlternativeOriginsFetcher::fetch].
+pub type FetchResult = Pin<Box<dyn Future<Output = Result<Vec<u8>, String>>>>;
+
+/// Retrieves the body of the alternative origins resource at the given URL.
+pub trait AlternativeOriginsFetcher {
+    fn fetch(&self, url: String) -> FetchResult;
+}
+
+/// Fetches the alternative origins resource using HTTPS outcalls.
+pub struct HttpOutcallFetcher;
+
+impl AlternativeOriginsFetcher for HttpOutcallFetcher {
+    fn fetch(&self, url: String) -> FetchResult {
+        Box::pin(async move {
+            let request = CanisterHttpRequestArgument {
+                url: url.clone(),
+                max_response_bytes: Some(MAX_RESPONSE_BYTES),
+                method: HttpMethod::GET,
+                headers: vec![HttpHeader {
+                    name: "Accept".to_string(),
+                    value: "application/json".to_string(),
+                }],
+                body: None,
+                transform: Some(TransformContext {
+                    function: TransformFunc(Func {
+                        principal: id(),
+                        method: TRANSFORM_METHOD.to_string(),
+                    }),
+                    context: vec![],
+                }),
+            };
+            let (response,) = http_request(request)
+                .await
+                .map_err(|(code, msg)| format!("failed to fetch {url}: {code:?} {msg}"))?;
+            if response.status != Nat::from(200u64) {
+                return Err(format!(
+                    "resource {url} returned invalid status: {}",
+                    response.status
+                ));
+            }
+            Ok(response.body)
+        })
+    }
+}
+
+/// Fetches the alternative origins resource on behalf of an anchor, reusing the cached result (see
+/// [AlternativeOriginsCache]) if any. Outcalls consume a token of the per anchor rate limit of
+/// `prepare_delegation`, so that an anchor cannot make the canister spend cycles on outcalls
+/// without bound and does not consume a rate limit other anchors depend on.
+pub struct CachingFetcher {
+    pub anchor_number: AnchorNumber,
+}
+
+impl AlternativeOriginsFetcher for CachingFetcher {
+    fn fetch(&self, url: String) -> FetchResult {
+        let anchor_number = self.anchor_number;
+        Box::pin(async move {
+            if let Some(result) = state::alternative_origins_cache(|cache| cache.get(&url, time()))
+            {
+                return result;
+            }
+            process_per_anchor_rate_limit(RateLimitedMethod::PrepareDelegation, anchor_number);
+            let result = HttpOutcallFetcher.fetch(url.clone()).await;
+            state::alternative_origins_cache_mut(|cache| cache.insert(url, result.clone(), time()));
+            result
+        })
+    }
+}
+
+/// Fetched alternative origins resources (or fetch errors) by URL, see [CachingFetcher].
+/// Not persisted across upgrades.
+#[derive(Default)]
+pub struct AlternativeOriginsCache {
+    entries: HashMap<String, CachedResource>,
+}
+
+struct CachedResource {
+    result: Result<Vec<u8>, String>,
+    expiration: Timestamp,
+}
+
+impl AlternativeOriginsCache {
+    /// Returns the cached result of fetching the given URL, unless it has expired.
+    pub fn get(&self, url: &str, now: Timestamp) -> Option<Result<Vec<u8>, String>> {
+        self.entries
+            .get(url)
+            .filter(|entry| entry.expiration > now)
+            .map(|entry| entry.result.clone())
+    }
+
+    /// Caches the result of fetching the given URL. If the cache is full, the expired entries are
+    /// dropped or, if there are none, the entry expiring first.
+    pub fn insert(&mut self, url: String, result: Result<Vec<u8>, String>, now: Timestamp) {
+        if self.entries.len() >= MAX_CACHED_RESOURCES && !self.entries.contains_key(&url) {
+            self.entries.retain(|_, entry| entry.expiration > now);
+        }
+        if self.entries.len() >= MAX_CACHED_RESOURCES && !self.entries.contains_key(&url) {
+            let first_expiring = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expiration)
+                .map(|(url, _)| url.clone());
+            if let Some(first_expiring) = first_expiring {
+                self.entries.remove(&first_expiring);
+            }
+        }
+        let ttl = if result.is_ok() {
+            CACHED_RESOURCE_TTL_NS
+        } else {
+            CACHED_ERROR_TTL_NS
+        };
+        self.entries.insert(
+            url,
+            CachedResource {
+                result,
+                expiration: now + ttl,
+            },
+        );
+    }
+}
+
+/// Strips everything from the outcall response that may differ between replicas (i.e. the headers)
+/// so that consensus can be reached on the response.
+pub fn transform(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: args.response.body,
+    }
+}
+
+#[derive(Deserialize)]
+struct AlternativeOrigins {
+    #[serde(rename = "alternativeOrigins")]
+    alternative_origins: Vec<String>,
+}
+
+/// Checks that `derivation_origin` allows `requesting_origin` to use its principals.
+pub async fn validate_derivation_origin(
+    fetcher: &dyn AlternativeOriginsFetcher,
+    requesting_origin: &str,
+    derivation_origin: &str,
+) -> Result<(), String> {
+    if requesting_origin == derivation_origin {
+        // this is the default behaviour -> no further validation necessary
+        return Ok(());
+    }
+    if is_legacy_domain_of(derivation_origin, requesting_origin) {
+        // the II frontend maps canister origins on the official domain to the legacy domain to
+        // give dapps the same principals on both domains
+        return Ok(());
+    }
+
+    let url = alternative_origins_url(derivation_origin)?;
+    let body = fetcher.fetch(url.clone()).await?;
+    check_alternative_origins(&url, &body, requesting_origin)
+}
+
+/// Computes the URL of the alternative origins resource of the given derivation origin.
+///
+/// Only origins of the form `https://<canister-id>(.raw).(ic0.app|icp0.io)` are allowed. Regardless
+/// of the domain of the derivation origin, the resource is always fetched from the official domain
+/// (icp0.io) and never from the raw domain.
+fn alternative_origins_url(derivation_origin: &str) -> Result<String, String> {
+    let invalid =
+        || format!("derivation origin {derivation_origin} is not a valid canister origin");
+
+    let subdomain = derivation_origin
+        .strip_prefix("https://")
+        .and_then(|s| {
+            s.strip_suffix(".ic0.app")
+                .or_else(|| s.strip_suffix(".icp0.io"))
+        })
+        .ok_or_else(invalid)?;
+    let subdomain = subdomain.strip_suffix(".raw").unwrap_or(subdomain);
+
+    let canister_id = if subdomain == "nns" {
+        NNS_DAPP_CANISTER_ID
+    } else {
+        subdomain
+    };
+    let canister_id = Principal::from_text(canister_id).map_err(|_| invalid())?;
+    Ok(format!(
+        "https://{}.icp0.io/.well-known/ii-alternative-origins",
+        canister_id.to_text()
+    ))
+}
+
+/// Checks whether `derivation_origin` is the legacy domain (ic0.app) origin of the canister served
+/// at `requesting_origin` on the official domain (icp0.io).
+fn is_legacy_domain_of(derivation_origin: &str, requesting_origin: &str) -> bool {
+    let legacy_subdomain = derivation_origin
+        .strip_prefix("https://")
+        .and_then(|s| s.strip_suffix(".ic0.app"));
+    let official_subdomain = requesting_origin
+        .strip_prefix("https://")
+        .and_then(|s| s.strip_suffix(".icp0.io"));
+    matches!((legacy_subdomain, official_subdomain), (Some(legacy), Some(official)) if legacy == official)
+}
+
+fn check_alternative_origins(
+    url: &str,
+    body: &[u8],
+    requesting_origin: &str,
+) -> Result<(), String> {
+    let AlternativeOrigins {
+        alternative_origins,
+    } = serde_json::from_slice(body)
+        .map_err(|err| format!("resource {url} has invalid format: {err}"))?;
+
+    if alternative_origins.len() > MAX_ALTERNATIVE_ORIGINS {
+        return Err(format!(
+            "resource {url} has too many entries: at most {MAX_ALTERNATIVE_ORIGINS} alternative origins are allowed"
+        ));
+    }
+
+    if !alternative_origins
+        .iter()
+        .any(|origin| origin == requesting_origin)
+    {
+        return Err(format!(
+            "{requesting_origin} is not listed in the alternative origins of {url}"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    const DERIVATION_ORIGIN: &str = "https://rdmx6-jaaaa-aaaaa-aaadq-cai.ic0.app";
+    const REQUESTING_ORIGIN: &str = "https://example.com";
+
+    /// Serves a fixed response instead of performing HTTPS outcalls.
+    struct StubFetcher(Result<&'static str, &'static str>);
+
+    impl AlternativeOriginsFetcher for StubFetcher {
+        fn fetch(&self, url: String) -> FetchResult {
+            assert_eq!(
+                url,
+                "https://rdmx6-jaaaa-aaaaa-aaadq-cai.icp0.io/.well-known/ii-alternative-origins"
+            );
+            let result = self
+                .0
+                .map(|body| body.as_bytes().to_vec())
+                .map_err(|err| err.to_string());
+            Box::pin(async move { result })
+        }
+    }
+
+    /// Polls the future exactly once, which is sufficient for the stub fetcher.
+    fn run<F: Future>(future: F) -> F::Output {
+        fn noop_raw_waker() -> RawWaker {
+            fn no_op(_: *const ()) {}
+            fn clone(_: *const ()) -> RawWaker {
+                noop_raw_waker()
+            }
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, no_op, no_op, no_op);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut future = Box::pin(future);
+        match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("stub fetcher should not block"),
+        }
+    }
+
+    fn validate(fetcher: StubFetcher, derivation_origin: &str) -> Result<(), String> {
+        run(validate_derivation_origin(
+            &fetcher,
+            REQUESTING_ORIGIN,
+            derivation_origin,
+        ))
+    }
+
+    #[test]
+    fn should_accept_listed_origin() {
+        let fetcher = StubFetcher(Ok(
+            r#"{"alternativeOrigins":["https://other.com","https://example.com"]}"#,
+        ));
+        assert_eq!(validate(fetcher, DERIVATION_ORIGIN), Ok(()));
+    }
+
+    #[test]
+    fn should_accept_same_origin_without_fetching() {
+        let fetcher = StubFetcher(Err("should not fetch"));
+        assert_eq!(validate(fetcher, REQUESTING_ORIGIN), Ok(()));
+    }
+
+    #[test]
+    fn should_accept_official_domain_of_legacy_origin_without_fetching() {
+        let fetcher = StubFetcher(Err("should not fetch"));
+        assert_eq!(
+            run(validate_derivation_origin(
+                &fetcher,
+                "https://rdmx6-jaaaa-aaaaa-aaadq-cai.icp0.io",
+                DERIVATION_ORIGIN,
+            )),
+            Ok(())
+        );
+        assert!(!is_legacy_domain_of(
+            DERIVATION_ORIGIN,
+            "https://rdmx6-jaaaa-aaaaa-aaadq-cai.raw.icp0.io"
+        ));
+        assert!(!is_legacy_domain_of(
+            "https://rdmx6-jaaaa-aaaaa-aaadq-cai.icp0.io",
+            "https://rdmx6-jaaaa-aaaaa-aaadq-cai.ic0.app"
+        ));
+    }
+
+    #[test]
+    fn should_fetch_from_official_domain() {
+        let body = r#"{"alternativeOrigins":["https://example.com"]}"#;
+        for origin in [
+            "https://rdmx6-jaaaa-aaaaa-aaadq-cai.icp0.io",
+            "https://rdmx6-jaaaa-aaaaa-aaadq-cai.raw.ic0.app",
+            "https://rdmx6-jaaaa-aaaaa-aaadq-cai.raw.icp0.io",
+        ] {
+            assert_eq!(validate(StubFetcher(Ok(body)), origin), Ok(()));
+        }
+    }
+
+    #[test]
+    fn should_map_nns_dapp_to_canister_id() {
+        assert_eq!(
+            alternative_origins_url("https://nns.ic0.app"),
+            Ok(
+                "https://qoctq-giaaa-aaaaa-aaaea-cai.icp0.io/.well-known/ii-alternative-origins"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn should_reject_non_canister_origins() {
+        for origin in [
+            "https://example.org",
+            "http://rdmx6-jaaaa-aaaaa-aaadq-cai.ic0.app",
+            "https://not-a-canister-id.ic0.app",
+            "https://rdmx6-jaaaa-aaaaa-aaadq-cai.ic0.app.evil.com",
+        ] {
+            let fetcher = StubFetcher(Err("should not fetch"));
+            assert!(validate(fetcher, origin)
+                .unwrap_err()
+                .contains("is not a valid canister origin"));
+        }
+    }
+
+    #[test]
+    fn should_reject_unlisted_origin() {
+        let fetcher = StubFetcher(Ok(r#"{"alternativeOrigins":["https://other.com"]}"#));
+        assert!(validate(fetcher, DERIVATION_ORIGIN)
+            .unwrap_err()
+            .contains("is not listed in the alternative origins"));
+    }
+
+    #[test]
+    fn should_reject_invalid_format() {
+        let fetcher = StubFetcher(Ok(r#"{"origins":["https://example.com"]}"#));
+        assert!(validate(fetcher, DERIVATION_ORIGIN)
+            .unwrap_err()
+            .contains("has invalid format"));
+    }
+
+    #[test]
+    fn should_reject_too_many_origins() {
+        let fetcher = StubFetcher(Ok(
+            r#"{"alternativeOrigins":["https://example.com","https://a.com","https://b.com","https://c.com","https://d.com","https://e.com","https://f.com","https://g.com","https://h.com","https://i.com","https://j.com"]}"#,
+        ));
+        assert!(validate(fetcher, DERIVATION_ORIGIN)
+            .unwrap_err()
+            .contains("has too many entries"));
+    }
+
+    #[test]
+    fn should_reuse_cached_resources_until_expiration() {
+        let mut cache = AlternativeOriginsCache::default();
+        cache.insert("resource".to_string(), Ok(b"body".to_vec()), 0);
+        cache.insert("failing".to_string(), Err("error".to_string()), 0);
+
+        assert_eq!(cache.get("resource", 0), Some(Ok(b"body".to_vec())));
+        assert_eq!(cache.get("failing", 0), Some(Err("error".to_string())));
+        assert_eq!(cache.get("other", 0), None);
+
+        // errors expire first
+        assert_eq!(cache.get("failing", CACHED_ERROR_TTL_NS), None);
+        assert!(cache.get("resource", CACHED_ERROR_TTL_NS).is_some());
+        assert_eq!(cache.get("resource", CACHED_RESOURCE_TTL_NS), None);
+    }
+
+    #[test]
+    fn should_bound_cache_size() {
+        let mut cache = AlternativeOriginsCache::default();
+        for i in 0..MAX_CACHED_RESOURCES as u64 {
+            cache.insert(format!("resource-{i}"), Ok(vec![]), i);
+        }
+        cache.insert("new".to_string(), Ok(vec![]), MAX_CACHED_RESOURCES as u64);
+
+        assert_eq!(cache.entries.len(), MAX_CACHED_RESOURCES);
+        // the entry expiring first has been dropped
+        assert_eq!(cache.get("resource-0", 0), None);
+        assert!(cache.get("resource-1", 0).is_some());
+        assert!(cache.get("new", 0).is_some());
+    }
+
+    #[test]
+    fn should_propagate_fetch_errors() {
+        let fetcher = StubFetcher(Err("resource returned invalid status: 404"));
+        assert_eq!(
+            validate(fetcher, DERIVATION_ORIGIN),
+            Err("resource returned invalid status: 404".to_string())
+        );
+    }
+}
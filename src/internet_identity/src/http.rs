@@ -1,5 +1,7 @@
+use crate::anchor_management::rate_limit;
 use crate::archive::ArchiveState;
 use crate::assets::{ContentType, EXACT_MATCH_TERMINATOR, IC_CERTIFICATE_EXPRESSION};
+use crate::state::RateLimitKey;
 use crate::{assets, state, IC0_APP_DOMAIN, INTERNETCOMPUTER_ORG_DOMAIN, LABEL_SIG};
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine;
@@ -11,6 +13,7 @@ use ic_metrics_encoder::MetricsEncoder;
 use internet_identity_interface::http_gateway::{HeaderField, HttpRequest, HttpResponse};
 use serde::Serialize;
 use serde_bytes::ByteBuf;
+use std::collections::HashMap;
 use std::time::Duration;
 
 pub const IC_CERTIFICATE_HEADER: &str = "IC-Certificate";
@@ -133,7 +136,20 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             "internet_identity_max_user_number",
             (hi - 1) as f64,
             "The highest Identity Anchor that can be served by this canister.",
-        )
+        )?;
+        if let Some(progress) = storage.v8_migration_progress() {
+            w.encode_gauge(
+                "internet_identity_storage_migration_migrated_anchors",
+                progress.migrated_anchors as f64,
+                "The number of anchors processed by the migration to storage layout version 8.",
+            )?;
+            w.encode_gauge(
+                "internet_identity_storage_migration_skipped_anchors",
+                progress.num_skipped_anchors as f64,
+                "The number of anchors skipped by the migration to storage layout version 8.",
+            )?;
+        }
+        Ok(())
     })?;
     state::signature_map(|sigs| {
         w.encode_gauge(
@@ -165,9 +181,14 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
         )
     })?;
     state::tentative_device_registrations(|tentative_device_registrations| {
+        // expired registrations are only removed lazily
+        let now = time();
         w.encode_gauge(
             "internet_identity_users_in_registration_mode",
-            tentative_device_registrations.len() as f64,
+            tentative_device_registrations
+                .iter()
+                .filter(|(_, registration)| registration.expiration > now)
+                .count() as f64,
             "The number of users in registration mode",
         )
     })?;
@@ -181,6 +202,16 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             "internet_identity_anchor_operations_counter",
             usage_metrics.anchor_operation_counter as f64,
             "The number of anchor operations since last upgrade",
+        )?;
+        w.encode_gauge(
+            "internet_identity_persistent_state_writes",
+            usage_metrics.persistent_state_writes as f64,
+            "The number of writes of the persistent state to stable memory since last upgrade",
+        )?;
+        w.encode_gauge(
+            "internet_identity_persistent_state_write_instructions",
+            usage_metrics.persistent_state_write_instructions as f64,
+            "The number of instructions used by the last write of the persistent state to stable memory",
         )
     })?;
     if let ArchiveState::Created { ref data, config } = state::archive_state() {
@@ -280,14 +311,43 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
 
         Ok::<(), std::io::Error>(())
     })?;
-    state::registration_rate_limit(|rate_limit_opt| {
-        if let Some(ref rate_limit_state) = rate_limit_opt {
+    state::rate_limits(|rate_limits| {
+        if let Some(rate_limit_state) = rate_limits.get(&RateLimitKey::Register) {
             w.encode_gauge(
                 "internet_identity_register_rate_limit_current_tokens",
                 rate_limit_state.tokens as f64,
                 "The number of `register` calls that are still allowed in the current time window.",
             )?;
         }
+        let mut current_tokens = w.gauge_vec(
+            "internet_identity_rate_limit_current_tokens",
+            "The number of calls to the method that are still allowed in the current time window (across all anchors).",
+        )?;
+        for (key, rate_limit_state) in rate_limits {
+            if let RateLimitKey::Global(method) = key {
+                current_tokens = current_tokens.value(
+                    &[("method", rate_limit::method_name(*method))],
+                    rate_limit_state.tokens as f64,
+                )?;
+            }
+        }
+        let mut limited_anchors = HashMap::new();
+        for (key, rate_limit_state) in rate_limits {
+            if let RateLimitKey::PerAnchor(method, _) = key {
+                let count = limited_anchors.entry(*method).or_insert(0);
+                if rate_limit_state.tokens == 0 {
+                    *count += 1;
+                }
+            }
+        }
+        let mut anchors_gauge = w.gauge_vec(
+            "internet_identity_rate_limit_exhausted_anchors",
+            "The number of anchors that have used up all tokens of the per anchor rate limit of the method.",
+        )?;
+        for (method, count) in limited_anchors {
+            anchors_gauge = anchors_gauge
+                .value(&[("method", rate_limit::method_name(method))], count as f64)?;
+        }
         Ok::<(), std::io::Error>(())
     })?;
     Ok(())
@@ -0,0 +1,247 @@
+//! Integrity scan over all allocated anchors, started by a controller.
+//!
+//! Anchors written by previous versions might violate invariants that have been introduced since
+//! (e.g. multiple recovery phrases, see [Anchor::remove_device]). The scan checks the invariants of
+//! every anchor (see [Anchor::check_invariants]) and reports the records that cannot be decoded.
+//! A batch of anchors is checked per timer invocation. The report is kept on the heap only, i.e.
+//! an upgrade aborts a running scan and discards the report.
+//!
+//! If a batch fails (i.e. traps), its changes are rolled back including the timer for the next
+//! batch, so the scan stops making progress while still being reported as running. A controller
+//! can then restart the scan (see [IntegrityScanArg::restart]), e.g. without repairs if the
+//! failure was caused by a repair.
+//!
+//! Optionally, the violations that can be fixed safely (i.e. over-long aliases) are repaired. The
+//! repairs are archived like any other modification of an anchor.
+use crate::archive::{archive_operation, ArchiveState};
+use crate::state;
+use crate::storage::anchor::Anchor;
+use crate::storage::StorageError;
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_cdk::trap;
+use ic_cdk_timers::set_timer;
+use internet_identity_interface::archive::types::{DeviceDataUpdate, Operation, Private};
+use internet_identity_interface::internet_identity::types::*;
+use std::cmp::min;
+use std::time::Duration;
+
+/// Number of anchors checked per timer invocation, chosen to stay well within the instruction limit.
+const SCAN_BATCH_SIZE: u64 = 1_000;
+/// Maximum number of violations listed in the report to bound its size.
+const MAX_REPORTED_VIOLATIONS: usize = 1_000;
+
+pub struct IntegrityScan {
+    // Identifies the scan, so that the timer of an aborted scan does not continue the new one.
+    id: u64,
+    report: IntegrityScanReport,
+    // The controller that started the scan, recorded as the caller of the archived repairs.
+    caller: Principal,
+}
+
+/// Starts a new scan, replacing the report of the previous one. Traps if a scan is running, unless
+/// `restart` is set, in which case the running scan is aborted.
+pub fn start(caller: Principal, repair: bool, restart: bool) {
+    let id = state::integrity_scan_mut(|scan| {
+        if !restart && matches!(scan, Some(scan) if scan.report.completed_at.is_none()) {
+            trap("an integrity scan is already running, set restart to abort it");
+        }
+        let id = scan.as_ref().map_or(0, |scan| scan.id + 1);
+        *scan = Some(IntegrityScan {
+            id,
+            report: IntegrityScanReport {
+                started_at: time(),
+                completed_at: None,
+                repair,
+                scanned_anchors: 0,
+                anchors_with_violations: 0,
+                repaired_anchors: 0,
+                violation_counts: IntegrityViolationCounts::default(),
+                violations: vec![],
+            },
+            caller,
+        });
+        id
+    });
+    set_timer(Duration::ZERO, move || scan_batch(id));
+}
+
+/// Returns the report of the running or last completed scan, if any.
+pub fn report() -> Option<IntegrityScanReport> {
+    state::integrity_scan(|scan| scan.as_ref().map(|scan| scan.report.clone()))
+}
+
+fn scan_batch(id: u64) {
+    let Some((first, repair, caller)) = state::integrity_scan(|scan| {
+        scan.as_ref()
+            .filter(|scan| scan.id == id)
+            .map(|scan| (scan.report.scanned_anchors, scan.report.repair, scan.caller))
+    }) else {
+        // the scan has been aborted
+        return;
+    };
+    let ((lo, _), num_anchors) = state::storage_borrow(|storage| {
+        (
+            storage.assigned_anchor_number_range(),
+            storage.anchor_count() as u64,
+        )
+    });
+
+    let end = min(first.saturating_add(SCAN_BATCH_SIZE), num_anchors);
+    let violations: Vec<Vec<IntegrityViolation>> = (first..end)
+        .map(|index| check_anchor(lo + index, repair, caller))
+        .collect();
+
+    let complete = state::integrity_scan_mut(|scan| {
+        let report = &mut scan
+            .as_mut()
+            .expect("bug: integrity scan state removed")
+            .report;
+        for anchor_violations in violations {
+            record_violations(report, anchor_violations);
+        }
+        report.scanned_anchors = end;
+        // anchors registered during the scan are checked as well
+        if end >= num_anchors {
+            report.completed_at = Some(time());
+        }
+        report.completed_at.is_some()
+    });
+    if !complete {
+        set_timer(Duration::ZERO, move || scan_batch(id));
+    }
+}
+
+/// Checks the given anchor and returns its violations. If `repair` is set, the violations that
+/// can be fixed safely are repaired (and reported as such).
+fn check_anchor(
+    anchor_number: AnchorNumber,
+    repair: bool,
+    caller: Principal,
+) -> Vec<IntegrityViolation> {
+    let violation = |kind, message, repaired| IntegrityViolation {
+        anchor_number,
+        kind,
+        message,
+        repaired,
+    };
+    let mut anchor = match state::storage_borrow(|storage| storage.read(anchor_number)) {
+        Ok(anchor) => anchor,
+        Err(StorageError::AnchorDeleted(_) | StorageError::AnchorMoved { .. }) => return vec![],
+        Err(err @ StorageError::PersistedEntrySizeExceeded { .. }) => {
+            return vec![violation(
+                IntegrityViolationKind::SizeOverflow,
+                err.to_string(),
+                false,
+            )]
+        }
+        Err(err) => {
+            return vec![violation(
+                IntegrityViolationKind::Undecodable,
+                err.to_string(),
+                false,
+            )]
+        }
+    };
+
+    let mut violations = vec![];
+    // repairs must be archived, which is not possible while the archive buffer is full
+    if repair && !archive_buffer_full() {
+        let truncated = anchor.truncate_aliases();
+        if !truncated.is_empty() {
+            violations.extend(truncated.iter().map(|device_key| {
+                violation(
+                    IntegrityViolationKind::DeviceInvariant,
+                    format!(
+                        "device {}: alias exceeded the length limit and has been truncated",
+                        hex::encode(device_key)
+                    ),
+                    true,
+                )
+            }));
+            write_repaired_anchor(anchor_number, anchor.clone(), truncated, caller);
+        }
+    }
+
+    let remaining = anchor.check_invariants();
+    violations.extend(remaining.devices.into_iter().map(|(device_key, err)| {
+        violation(
+            IntegrityViolationKind::DeviceInvariant,
+            format!("device {}: {err}", hex::encode(device_key)),
+            false,
+        )
+    }));
+    violations.extend(remaining.anchor.map(|err| {
+        violation(
+            IntegrityViolationKind::AnchorInvariant,
+            err.to_string(),
+            false,
+        )
+    }));
+    violations
+}
+
+fn write_repaired_anchor(
+    anchor_number: AnchorNumber,
+    anchor: Anchor,
+    truncated_aliases: Vec<DeviceKey>,
+    caller: Principal,
+) {
+    state::storage_borrow_mut(|storage| storage.write(anchor_number, anchor)).unwrap_or_else(
+        |err| panic!("unable to update anchor {anchor_number} in stable memory: {err}"),
+    );
+
+    let mut operations: Vec<Operation> = truncated_aliases
+        .into_iter()
+        .map(|device_key| Operation::UpdateDevice {
+            device: device_key,
+            new_values: DeviceDataUpdate {
+                alias: Some(Private::Redacted),
+                credential_id: None,
+                purpose: None,
+                key_type: None,
+                protection: None,
+                origin: None,
+                metadata_keys: None,
+            },
+        })
+        .collect();
+    let operation = if operations.len() == 1 {
+        operations.remove(0)
+    } else {
+        Operation::Batch { operations }
+    };
+    archive_operation(anchor_number, caller, operation);
+    state::usage_metrics_mut(|metrics| {
+        metrics.anchor_operation_counter += 1;
+    });
+}
+
+fn archive_buffer_full() -> bool {
+    matches!(
+        state::archive_state(),
+        ArchiveState::Created { data, config } if data.entries_buffer.len() as u64 >= config.entries_buffer_limit
+    )
+}
+
+fn record_violations(report: &mut IntegrityScanReport, violations: Vec<IntegrityViolation>) {
+    if violations.is_empty() {
+        return;
+    }
+    report.anchors_with_violations += 1;
+    if violations.iter().any(|violation| violation.repaired) {
+        report.repaired_anchors += 1;
+    }
+    for violation in violations {
+        let counts = &mut report.violation_counts;
+        match violation.kind {
+            IntegrityViolationKind::Undecodable => counts.undecodable += 1,
+            IntegrityViolationKind::SizeOverflow => counts.size_overflow += 1,
+            IntegrityViolationKind::AnchorInvariant => counts.anchor_invariant += 1,
+            IntegrityViolationKind::DeviceInvariant => counts.device_invariant += 1,
+        }
+        if report.violations.len() < MAX_REPORTED_VIOLATIONS {
+            report.violations.push(violation);
+        }
+    }
+}
@@ -1,5 +1,31 @@
 //! Various APIs for managing internet identities.
+//!
+//! The canister itself is built from `main.rs`, which declares all of its modules privately. This
+//! library only exposes the [storage] (including the [PersistentState] kept in it) to offline tools
+//! (see `src/bin`). The modules the storage depends on are compiled as private modules, unused code
+//! in them is reported when building the canister rather than here.
+#![allow(dead_code)]
+
+use ic_cdk::api::set_certified_data;
+use storage::{Salt, Storage};
+
+pub use state::PersistentState;
+
 pub mod signature_map;
+pub mod storage;
+
+mod active_anchor_stats;
+mod anchor_management;
+mod archive;
+mod assets;
+mod delegation;
+mod hash;
+mod http;
+mod integrity_check;
+/// Infrastructure to help building nested certification trees.
+mod nested_tree;
+mod state;
+mod storage_migration;
 
 /// A small module that makes get_random work on wasm32-unknown-unknown.
 /// The dependency on get_random comes from the captcha library.
@@ -9,3 +35,30 @@ pub mod signature_map;
     target_os = "unknown"
 ))]
 mod wasm_get_random;
+
+// The crate level helpers used by the modules, see main.rs.
+const fn secs_to_nanos(secs: u64) -> u64 {
+    secs * 1_000_000_000
+}
+const MINUTE_NS: u64 = secs_to_nanos(60);
+const HOUR_NS: u64 = 60 * MINUTE_NS;
+const DAY_NS: u64 = 24 * HOUR_NS;
+
+const LABEL_SIG: &[u8] = b"sig";
+
+const IC0_APP_DOMAIN: &str = "identity.ic0.app";
+const IC0_APP_ORIGIN: &str = "https://identity.ic0.app";
+const INTERNETCOMPUTER_ORG_DOMAIN: &str = "identity.internetcomputer.org";
+const INTERNETCOMPUTER_ORG_ORIGIN: &str = "https://identity.internetcomputer.org";
+
+fn update_root_hash() {
+    use ic_certified_map::{fork_hash, labeled_hash};
+    state::assets_and_signatures(|assets, sigs| {
+        let prefixed_root_hash = fork_hash(
+            &assets.root_hash(),
+            // NB: sigs have to be added last due to lexicographic order of labels
+            &labeled_hash(LABEL_SIG, &sigs.root_hash()),
+        );
+        set_certified_data(&prefixed_root_hash[..]);
+    })
+}
@@ -1,17 +1,24 @@
 use crate::active_anchor_stats::IIDomain;
-use crate::anchor_management::{post_operation_bookkeeping, tentative_device_registration};
+use crate::anchor_management::recovery_delay::{self, DelayedOperation, QueuedOperationInfo};
+use crate::anchor_management::{
+    post_operation_bookkeeping, tentative_device_registration, transfer,
+};
 use crate::archive::ArchiveState;
 use crate::assets::init_assets;
 use crate::storage::anchor::Anchor;
 use candid::{candid_method, Principal};
-use ic_cdk::api::{caller, set_certified_data, trap};
+use ic_cdk::api::call::{reject, reply, ManualReply};
+use ic_cdk::api::{caller, is_controller, set_certified_data, trap};
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use internet_identity::signature_map;
 use internet_identity_interface::archive::types::{BufferedEntry, Operation};
 use internet_identity_interface::http_gateway::{HttpRequest, HttpResponse};
 use internet_identity_interface::internet_identity::types::*;
 use serde_bytes::ByteBuf;
 use storage::{Salt, Storage};
 
+// The canister is built from this binary, which declares its modules privately (so that unused code
+// is reported). The library only exposes the storage to offline tools (see lib.rs).
 mod active_anchor_stats;
 mod anchor_management;
 mod archive;
@@ -19,10 +26,12 @@ mod assets;
 mod delegation;
 mod hash;
 mod http;
+mod integrity_check;
 /// Infrastructure to help building nested certification trees.
 mod nested_tree;
 mod state;
 mod storage;
+mod storage_migration;
 
 // Some time helpers
 const fn secs_to_nanos(secs: u64) -> u64 {
@@ -69,19 +78,24 @@ async fn add_tentative_device(
     tentative_device_registration::add_tentative_device(anchor_number, device_data).await
 }
 
-#[update]
+#[update(manual_reply = true)]
 #[candid_method]
 fn verify_tentative_device(
     anchor_number: AnchorNumber,
     user_verification_code: DeviceVerificationCode,
-) -> VerifyTentativeDeviceResponse {
-    authenticated_anchor_operation(anchor_number, |anchor| {
+) -> ManualReply<VerifyTentativeDeviceResponse> {
+    let result = authenticated_anchor_operation_with_device(anchor_number, |anchor, device_key| {
         tentative_device_registration::verify_tentative_device(
             anchor,
             anchor_number,
+            device_key,
             user_verification_code,
         )
-    })
+    });
+    match result {
+        Ok(response) => ManualReply::one(response),
+        Err(queued) => ManualReply::reject(queued_message(queued)),
+    }
 }
 
 #[update]
@@ -100,45 +114,126 @@ fn register(
     anchor_management::registration::register(device_data, challenge_result, temp_key)
 }
 
-#[update]
+#[update(manual_reply = true)]
 #[candid_method]
 fn add(anchor_number: AnchorNumber, device_data: DeviceData) {
-    authenticated_anchor_operation(anchor_number, |anchor| {
-        Ok(((), anchor_management::add(anchor, device_data)))
-    })
+    let result =
+        authenticated_anchor_operation_with_device(anchor_number, |anchor, authn_device_key| {
+            let delayed = DelayedOperation::Add {
+                device: device_data.clone(),
+            };
+            queue_v1_if_delayed(
+                anchor_number,
+                anchor,
+                authn_device_key,
+                delayed,
+                "failed to add device",
+            )?;
+            let operation = anchor_management::add(anchor, device_data)
+                .unwrap_or_else(|err| trap(&format!("failed to add device: {err}")));
+            Ok((Ok(()), operation))
+        });
+    reply_unless_queued(result)
 }
 
-#[update]
+#[update(manual_reply = true)]
 #[candid_method]
 fn update(anchor_number: AnchorNumber, device_key: DeviceKey, device_data: DeviceData) {
-    authenticated_anchor_operation(anchor_number, |anchor| {
-        Ok((
-            (),
-            anchor_management::update(anchor, device_key, device_data),
-        ))
-    })
+    let result =
+        authenticated_anchor_operation_with_device(anchor_number, |anchor, authn_device_key| {
+            let delayed = DelayedOperation::Update {
+                device_key: device_key.clone(),
+                device: device_data.clone(),
+            };
+            queue_v1_if_delayed(
+                anchor_number,
+                anchor,
+                authn_device_key,
+                delayed,
+                "failed to modify device",
+            )?;
+            let operation = anchor_management::update(anchor, device_key, device_data)
+                .unwrap_or_else(|err| trap(&format!("failed to modify device: {err}")));
+            Ok((Ok(()), operation))
+        });
+    reply_unless_queued(result)
 }
 
-#[update]
+#[update(manual_reply = true)]
 #[candid_method]
 fn replace(anchor_number: AnchorNumber, device_key: DeviceKey, device_data: DeviceData) {
-    authenticated_anchor_operation(anchor_number, |anchor| {
-        Ok((
-            (),
-            anchor_management::replace(anchor_number, anchor, device_key, device_data),
-        ))
-    })
+    let result =
+        authenticated_anchor_operation_with_device(anchor_number, |anchor, authn_device_key| {
+            let delayed = DelayedOperation::Replace {
+                old_device: device_key.clone(),
+                new_device: device_data.clone(),
+            };
+            queue_v1_if_delayed(
+                anchor_number,
+                anchor,
+                authn_device_key,
+                delayed,
+                "failed to replace device",
+            )?;
+            let operation =
+                anchor_management::replace(anchor_number, anchor, device_key, device_data)
+                    .unwrap_or_else(|err| trap(&format!("failed to replace device: {err}")));
+            Ok((Ok(()), operation))
+        });
+    reply_unless_queued(result)
 }
 
-#[update]
+#[update(manual_reply = true)]
 #[candid_method]
 fn remove(anchor_number: AnchorNumber, device_key: DeviceKey) {
-    authenticated_anchor_operation(anchor_number, |anchor| {
-        Ok((
-            (),
-            anchor_management::remove(anchor_number, anchor, device_key),
-        ))
-    })
+    let result =
+        authenticated_anchor_operation_with_device(anchor_number, |anchor, authn_device_key| {
+            let delayed = DelayedOperation::Remove {
+                device_key: device_key.clone(),
+            };
+            queue_v1_if_delayed(
+                anchor_number,
+                anchor,
+                authn_device_key,
+                delayed,
+                "failed to remove device",
+            )?;
+            let operation = anchor_management::remove(anchor_number, anchor, device_key)
+                .unwrap_or_else(|err| trap(&format!("failed to remove device: {err}")));
+            Ok((Ok(()), operation))
+        });
+    reply_unless_queued(result)
+}
+
+/// Queues the operation of a v1 call if it must be delayed (see [recovery_delay::queue_if_delayed]).
+/// The v1 API has no response for queued operations, hence a queued operation is returned as
+/// error to be passed on to [reply_unless_queued].
+fn queue_v1_if_delayed<T>(
+    anchor_number: AnchorNumber,
+    anchor: &Anchor,
+    device_key: &DeviceKey,
+    operation: DelayedOperation,
+    error_context: &str,
+) -> Result<(), Result<T, QueuedOperationInfo>> {
+    match recovery_delay::queue_if_delayed(anchor_number, anchor, device_key, operation) {
+        Ok(None) => Ok(()),
+        Ok(Some(queued)) => Err(Err(queued)),
+        Err(err) => trap(&format!("{error_context}: {err}")),
+    }
+}
+
+/// Replies to a v1 call without return value. If the operation has been queued, the call is
+/// rejected with the id of the queued operation instead. Unlike a trap, the rejection keeps the
+/// queued operation.
+fn reply_unless_queued(result: Result<(), QueuedOperationInfo>) {
+    match result {
+        Ok(()) => reply(()),
+        Err(queued) => reject(&queued_message(queued)),
+    }
+}
+
+fn queued_message((id, execution_time): QueuedOperationInfo) -> String {
+    format!("the operation has been queued as recovery operation {id} because of the recovery delay of the identity and will be applied at {execution_time} unless cancelled")
 }
 
 /// Returns all devices of the anchor (authentication and recovery) but no information about device registrations.
@@ -215,13 +310,18 @@ async fn prepare_delegation(
     frontend: FrontendHostname,
     session_key: SessionKey,
     max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    origin: Option<FrontendHostname>,
 ) -> (UserKey, Timestamp) {
-    let ii_domain = authenticate_and_record_activity(anchor_number);
+    let (device_key, ii_domain) = authenticate_and_record_activity(anchor_number);
     delegation::prepare_delegation(
         anchor_number,
         frontend,
         session_key,
         max_time_to_live,
+        targets,
+        origin,
+        device_key,
         &ii_domain,
     )
     .await
@@ -234,11 +334,28 @@ fn get_delegation(
     frontend: FrontendHostname,
     session_key: SessionKey,
     expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
 ) -> GetDelegationResponse {
     let Ok(_) = check_authentication(anchor_number) else {
         trap(&format!("{} could not be authenticated.", caller()));
     };
-    delegation::get_delegation(anchor_number, frontend, session_key, expiration)
+    delegation::get_delegation(anchor_number, frontend, session_key, expiration, targets)
+}
+
+#[update]
+#[candid_method]
+fn revoke_delegations(anchor_number: AnchorNumber, frontend: Option<FrontendHostname>) {
+    authenticate_and_record_activity(anchor_number);
+    delegation::revoke_delegations(anchor_number, frontend)
+}
+
+/// Used to make the HTTPS outcalls fetching alternative origins deterministic.
+#[query]
+#[candid_method(query)]
+fn transform_alternative_origins(
+    args: ic_cdk::api::management_canister::http_request::TransformArgs,
+) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    delegation::alternative_origins::transform(args)
 }
 
 #[query]
@@ -296,19 +413,172 @@ fn stats() -> InternetIdentityStats {
             )
         });
 
+    let delegation_ttl_policy = state::persistent_state(|persistent_state| {
+        persistent_state
+            .delegation_ttl_policy
+            .clone()
+            .unwrap_or_default()
+    });
+
+    let captcha_config = anchor_management::registration::captcha::captcha_config();
+
     state::storage_borrow(|storage| InternetIdentityStats {
         assigned_user_number_range: storage.assigned_anchor_number_range(),
         users_registered: storage.anchor_count() as u64,
         archive_info,
         canister_creation_cycles_cost,
         storage_layout_version: storage.version(),
+        storage_migration: storage.v8_migration_progress(),
         active_anchor_stats,
         domain_active_anchor_stats,
         max_num_latest_delegation_origins,
         latest_delegation_origins,
+        delegation_ttl_policy,
+        captcha_config,
     })
 }
 
+/// Returns the current configuration, i.e. the values that would be applied by the install
+/// arguments.
+#[query]
+#[candid_method(query)]
+fn config() -> InternetIdentityInit {
+    let archive_config = match state::archive_state() {
+        ArchiveState::NotConfigured => None,
+        ArchiveState::Configured { config }
+        | ArchiveState::CreationInProgress { config, .. }
+        | ArchiveState::Created { config, .. } => Some(config),
+    };
+    let assigned_user_number_range =
+        state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    let captcha_config = anchor_management::registration::captcha::captcha_config();
+
+    state::persistent_state(|persistent_state| InternetIdentityInit {
+        assigned_user_number_range: Some(assigned_user_number_range),
+        archive_config,
+        canister_creation_cycles_cost: Some(persistent_state.canister_creation_cycles_cost),
+        register_rate_limit: persistent_state.registration_rate_limit.clone(),
+        max_num_latest_delegation_origins: persistent_state.max_num_latest_delegation_origins,
+        migrate_storage_to_memory_manager: None,
+        migrate_storage_to_stable_btree_map: None,
+        delegation_ttl_policy: Some(
+            persistent_state
+                .delegation_ttl_policy
+                .clone()
+                .unwrap_or_default(),
+        ),
+        challenge_provider: Some(
+            persistent_state
+                .challenge_provider
+                .clone()
+                .unwrap_or(ChallengeProviderConfig::Captcha),
+        ),
+        captcha_config: Some(captcha_config),
+        method_rate_limits: Some(
+            persistent_state
+                .method_rate_limits
+                .clone()
+                .unwrap_or_default(),
+        ),
+    })
+}
+
+/// Applies the given configuration changes without an upgrade. Values set to `None` keep their
+/// current value. The changes are validated the same way as the install arguments.
+/// Only callable by the controllers of the canister.
+#[update]
+#[candid_method]
+fn config_update(config: InternetIdentityInit) {
+    if !is_controller(&caller()) {
+        trap(&format!(
+            "{} is not allowed to update the configuration",
+            caller()
+        ));
+    }
+    if config.migrate_storage_to_memory_manager.is_some()
+        || config.migrate_storage_to_stable_btree_map.is_some()
+    {
+        trap("the storage can only be migrated on upgrade");
+    }
+    apply_install_arg(Some(config));
+}
+
+/// Starts a scan checking the integrity of all anchors, see [integrity_check].
+/// Only callable by the controllers of the canister.
+#[update]
+#[candid_method]
+fn integrity_scan_start(arg: IntegrityScanArg) {
+    if !is_controller(&caller()) {
+        trap(&format!(
+            "{} is not allowed to start an integrity scan",
+            caller()
+        ));
+    }
+    integrity_check::start(
+        caller(),
+        arg.repair.unwrap_or(false),
+        arg.restart.unwrap_or(false),
+    );
+}
+
+/// Returns the report of the running or last completed integrity scan, if any.
+/// Only callable by the controllers of the canister.
+#[query]
+#[candid_method(query)]
+fn integrity_scan_report() -> Option<IntegrityScanReport> {
+    if !is_controller(&caller()) {
+        trap(&format!(
+            "{} is not allowed to read the integrity scan report",
+            caller()
+        ));
+    }
+    integrity_check::report()
+}
+
+/// Returns a page of the anchors in the given range, see [transfer].
+/// Only callable by the controllers of the canister.
+/// This is an update call so that the exported anchors are certified.
+#[update]
+#[candid_method]
+fn export_anchors(arg: ExportAnchorsArg) -> ExportAnchorsResponse {
+    if !is_controller(&caller()) {
+        trap(&format!("{} is not allowed to export anchors", caller()));
+    }
+    transfer::export_anchors(arg)
+}
+
+/// Imports anchors exported by another canister, see [transfer].
+/// Only callable by the controllers of the canister.
+#[update]
+#[candid_method]
+fn import_anchors(arg: ImportAnchorsArg) {
+    if !is_controller(&caller()) {
+        trap(&format!("{} is not allowed to import anchors", caller()));
+    }
+    transfer::import_anchors(arg)
+}
+
+/// Replaces the given anchors by tombstones pointing to the canister now serving them.
+/// Only callable by the controllers of the canister.
+#[update]
+#[candid_method]
+fn mark_anchors_moved(arg: MarkAnchorsMovedArg) {
+    if !is_controller(&caller()) {
+        trap(&format!(
+            "{} is not allowed to mark anchors as moved",
+            caller()
+        ));
+    }
+    transfer::mark_anchors_moved(arg)
+}
+
+/// Returns the canister now serving the anchor, if it has been moved.
+#[query]
+#[candid_method(query)]
+fn anchor_moved_to(anchor_number: AnchorNumber) -> Option<Principal> {
+    transfer::anchor_moved_to(anchor_number)
+}
+
 #[update]
 #[candid_method]
 async fn deploy_archive(wasm: ByteBuf) -> DeployArchiveResult {
@@ -332,30 +602,30 @@ fn acknowledge_entries(sequence_number: u64) {
     archive::acknowledge_entries(sequence_number)
 }
 
-fn migrate_to_memory_manager(maybe_arg: &Option<InternetIdentityInit>) -> bool {
-    if maybe_arg.is_none() {
-        return false;
-    }
-    let arg = maybe_arg.as_ref().unwrap();
-    arg.migrate_storage_to_memory_manager.unwrap_or(false)
+fn migrate_to_stable_btree_map(maybe_arg: &Option<InternetIdentityInit>) -> bool {
+    maybe_arg
+        .as_ref()
+        .and_then(|arg| arg.migrate_storage_to_stable_btree_map)
+        .unwrap_or(false)
 }
 
 #[init]
 fn init(maybe_arg: Option<InternetIdentityInit>) {
     init_assets();
-    state::init_new(migrate_to_memory_manager(&maybe_arg));
+    state::init_new(migrate_to_stable_btree_map(&maybe_arg));
 
     apply_install_arg(maybe_arg);
 
     // make sure the fully initialized storage configuration is written to stable memory
     state::storage_borrow_mut(|storage| storage.flush());
     update_root_hash();
+    recovery_delay::start_timer();
 }
 
 #[post_upgrade]
 fn post_upgrade(maybe_arg: Option<InternetIdentityInit>) {
     init_assets();
-    state::init_from_stable_memory(migrate_to_memory_manager(&maybe_arg));
+    state::init_from_stable_memory(migrate_to_stable_btree_map(&maybe_arg));
 
     // We drop all the signatures on upgrade, users will
     // re-request them if needed.
@@ -364,6 +634,8 @@ fn post_upgrade(maybe_arg: Option<InternetIdentityInit>) {
     state::load_persistent_state();
 
     apply_install_arg(maybe_arg);
+    recovery_delay::start_timer();
+    storage_migration::start_timer();
 }
 
 fn apply_install_arg(maybe_arg: Option<InternetIdentityInit>) {
@@ -382,15 +654,60 @@ fn apply_install_arg(maybe_arg: Option<InternetIdentityInit>) {
             })
         }
         if let Some(rate_limit) = arg.register_rate_limit {
+            anchor_management::rate_limit::check_rate_limit_config(&rate_limit).unwrap_or_else(
+                |err| {
+                    trap(&format!("invalid register rate limit: {err}"));
+                },
+            );
             state::persistent_state_mut(|persistent_state| {
                 persistent_state.registration_rate_limit = Some(rate_limit);
             })
         }
         if let Some(limit) = arg.max_num_latest_delegation_origins {
+            if limit == 0 {
+                trap("max_num_latest_delegation_origins must be greater than 0");
+            }
             state::persistent_state_mut(|persistent_state| {
                 persistent_state.max_num_latest_delegation_origins = Some(limit);
             })
         }
+        if let Some(policy) = arg.delegation_ttl_policy {
+            delegation::validate_ttl_policy(&policy).unwrap_or_else(|err| {
+                trap(&format!("invalid delegation TTL policy: {err}"));
+            });
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.delegation_ttl_policy = Some(policy);
+            })
+        }
+        if let Some(provider) = arg.challenge_provider {
+            anchor_management::registration::check_challenge_provider_config(&provider)
+                .unwrap_or_else(|err| {
+                    trap(&format!("invalid challenge provider: {err}"));
+                });
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.challenge_provider = Some(provider);
+            })
+        }
+        if let Some(limits) = arg.method_rate_limits {
+            anchor_management::rate_limit::check_method_rate_limits(&limits).unwrap_or_else(
+                |err| {
+                    trap(&format!("invalid method rate limits: {err}"));
+                },
+            );
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.method_rate_limits = Some(limits);
+            })
+        }
+        if let Some(config) = arg.captcha_config {
+            anchor_management::registration::captcha::check_captcha_config(&config).unwrap_or_else(
+                |err| {
+                    trap(&format!("invalid captcha config: {err}"));
+                },
+            );
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.captcha_config = Some(config);
+            })
+        }
     }
 }
 
@@ -407,9 +724,11 @@ fn update_archive_config(new_config: ArchiveConfig) {
     })
 }
 
+/// The persistent state is written to stable memory on every change, it is only copied to where
+/// the previous release reads it from here (see [storage]).
 #[pre_upgrade]
-fn save_persistent_state() {
-    state::save_persistent_state();
+fn copy_persistent_state_for_rollback() {
+    state::copy_persistent_state_for_rollback();
 }
 
 fn update_root_hash() {
@@ -427,9 +746,11 @@ fn update_root_hash() {
 /// Authenticates the caller (traps if not authenticated) and updates the device used to authenticate
 /// reflecting the current activity. Also updates the aggregated stats on daily and monthly active users.
 ///
+/// Returns the key of the device used to authenticate and the II domain of that device.
+///
 /// Note: this function reads / writes the anchor from / to stable memory. It is intended to be used by functions that
 /// do not further modify the anchor.
-fn authenticate_and_record_activity(anchor_number: AnchorNumber) -> Option<IIDomain> {
+fn authenticate_and_record_activity(anchor_number: AnchorNumber) -> (DeviceKey, Option<IIDomain>) {
     let Ok((mut anchor, device_key)) = check_authentication(anchor_number) else {
         trap(&format!("{} could not be authenticated.", caller()));
     };
@@ -438,7 +759,7 @@ fn authenticate_and_record_activity(anchor_number: AnchorNumber) -> Option<IIDom
     state::storage_borrow_mut(|storage| storage.write(anchor_number, anchor)).unwrap_or_else(
         |err| panic!("last_usage_timestamp update: unable to update anchor {anchor_number}: {err}"),
     );
-    domain
+    (device_key, domain)
 }
 
 /// Authenticates the caller (traps if not authenticated) calls the provided function and handles all
@@ -451,16 +772,26 @@ fn authenticate_and_record_activity(anchor_number: AnchorNumber) -> Option<IIDom
 ///       The type `R` is usually bound to an interface type specified in the candid file. This type
 ///       is either unit or a variant unifying success and error cases (which is why the [Result] has
 ///       `R` in both success and error positions).
-fn authenticated_anchor_operation<R>(
+///
+/// The key of the device used to authenticate the caller is provided to `op` as well.
+///
+/// The changes made to the anchor by `op` are only written (and archived) if `op` succeeds, i.e. `op`
+/// may modify the anchor before failing. On failure, only the activity of the caller is recorded.
+fn authenticated_anchor_operation_with_device<R>(
     anchor_number: AnchorNumber,
-    op: impl FnOnce(&mut Anchor) -> Result<(R, Operation), R>,
+    op: impl FnOnce(&mut Anchor, &DeviceKey) -> Result<(R, Operation), R>,
 ) -> R {
     let Ok((mut anchor, device_key)) = check_authentication(anchor_number) else {
         trap(&format!("{} could not be authenticated.", caller()));
     };
     anchor_management::activity_bookkeeping(&mut anchor, &device_key);
 
-    let result = op(&mut anchor);
+    let mut modified_anchor = anchor.clone();
+    let result = op(&mut modified_anchor, &device_key);
+
+    if result.is_ok() {
+        anchor = modified_anchor;
+    }
 
     // write back anchor
     state::storage_borrow_mut(|storage| storage.write(anchor_number, anchor)).unwrap_or_else(
@@ -503,6 +834,27 @@ fn check_authentication(anchor_number: AnchorNumber) -> Result<(Anchor, DeviceKe
 ///   in the future without breaking changes.
 mod v2_api {
     use super::*;
+    use crate::anchor_management::guardian_recovery;
+    use crate::anchor_management::{BatchError, BatchOperation, ReplaceMetadataError};
+    use std::collections::HashMap;
+
+    /// Queues the operation if it must be delayed (see [recovery_delay::queue_if_delayed]). If it
+    /// has been queued (or cannot be queued), the response to be returned by the endpoint is
+    /// returned as error, built using `delayed` (or `error`).
+    fn queue_if_delayed<E>(
+        identity_number: IdentityNumber,
+        anchor: &Anchor,
+        device_key: &DeviceKey,
+        operation: DelayedOperation,
+        delayed: impl FnOnce(u64, Timestamp) -> E,
+        error: impl FnOnce(AuthnMethodError) -> E,
+    ) -> Result<(), E> {
+        match recovery_delay::queue_if_delayed(identity_number, anchor, device_key, operation) {
+            Ok(None) => Ok(()),
+            Ok(Some((id, execution_time))) => Err(delayed(id, execution_time)),
+            Err(err) => Err(error(AuthnMethodError::from(err))),
+        }
+    }
 
     #[update]
     #[candid_method]
@@ -522,23 +874,397 @@ mod v2_api {
         Some(IdentityInfoResponse::Ok(identity_info))
     }
 
+    #[update]
+    #[candid_method]
+    fn identity_sessions(identity_number: IdentityNumber) -> Option<IdentitySessionsResponse> {
+        authenticate_and_record_activity(identity_number);
+        Some(IdentitySessionsResponse::Ok(
+            delegation::delegation_sessions(identity_number),
+        ))
+    }
+
+    #[update]
+    #[candid_method]
+    async fn identity_delete(
+        identity_number: IdentityNumber,
+        confirmation_token: Option<ByteBuf>,
+    ) -> Option<IdentityDeleteResponse> {
+        let Ok((anchor, device_key)) = check_authentication(identity_number) else {
+            trap(&format!("{} could not be authenticated.", caller()));
+        };
+        recovery_delay::check_not_delayed(&anchor, &device_key);
+        Some(
+            anchor_management::deletion::delete_identity(
+                identity_number,
+                anchor,
+                &device_key,
+                confirmation_token,
+            )
+            .await,
+        )
+    }
+
     #[update]
     #[candid_method]
     fn authn_method_add(
         identity_number: IdentityNumber,
         authn_method: AuthnMethodData,
     ) -> Option<AuthnMethodAddResponse> {
-        let result = match DeviceWithUsage::try_from(authn_method)
-            .map_err(|err| AuthnMethodAddResponse::InvalidMetadata(err.to_string()))
-        {
-            Ok(device) => {
-                add(identity_number, DeviceData::from(device));
-                AuthnMethodAddResponse::Ok
+        let device = match DeviceWithUsage::try_from(authn_method) {
+            Ok(device) => device,
+            Err(err) => return Some(AuthnMethodAddResponse::InvalidMetadata(err.to_string())),
+        };
+        let device = DeviceData::from(device);
+        let result =
+            authenticated_anchor_operation_with_device(identity_number, |anchor, device_key| {
+                let delayed = DelayedOperation::Add {
+                    device: device.clone(),
+                };
+                queue_if_delayed(
+                    identity_number,
+                    anchor,
+                    device_key,
+                    delayed,
+                    |id, execution_time| AuthnMethodAddResponse::RecoveryDelayed {
+                        id,
+                        execution_time,
+                    },
+                    AuthnMethodAddResponse::AuthnMethodError,
+                )?;
+                match anchor_management::add(anchor, device) {
+                    Ok(operation) => Ok((AuthnMethodAddResponse::Ok, operation)),
+                    Err(err) => Err(AuthnMethodAddResponse::AuthnMethodError(
+                        AuthnMethodError::from(err),
+                    )),
+                }
+            });
+        Some(result)
+    }
+
+    #[update]
+    #[candid_method]
+    fn authn_method_remove(
+        identity_number: IdentityNumber,
+        public_key: PublicKey,
+    ) -> Option<AuthnMethodRemoveResponse> {
+        let result =
+            authenticated_anchor_operation_with_device(identity_number, |anchor, device_key| {
+                let delayed = DelayedOperation::Remove {
+                    device_key: public_key.clone(),
+                };
+                queue_if_delayed(
+                    identity_number,
+                    anchor,
+                    device_key,
+                    delayed,
+                    |id, execution_time| AuthnMethodRemoveResponse::RecoveryDelayed {
+                        id,
+                        execution_time,
+                    },
+                    AuthnMethodRemoveResponse::AuthnMethodError,
+                )?;
+                match anchor_management::remove(identity_number, anchor, public_key) {
+                    Ok(operation) => Ok((AuthnMethodRemoveResponse::Ok, operation)),
+                    Err(err) => Err(AuthnMethodRemoveResponse::AuthnMethodError(
+                        AuthnMethodError::from(err),
+                    )),
+                }
+            });
+        Some(result)
+    }
+
+    #[update]
+    #[candid_method]
+    fn authn_method_replace(
+        identity_number: IdentityNumber,
+        public_key: PublicKey,
+        authn_method: AuthnMethodData,
+    ) -> Option<AuthnMethodReplaceResponse> {
+        let device = match DeviceWithUsage::try_from(authn_method) {
+            Ok(device) => DeviceData::from(device),
+            Err(err) => return Some(AuthnMethodReplaceResponse::InvalidMetadata(err.to_string())),
+        };
+        let result =
+            authenticated_anchor_operation_with_device(identity_number, |anchor, device_key| {
+                let delayed = DelayedOperation::Replace {
+                    old_device: public_key.clone(),
+                    new_device: device.clone(),
+                };
+                queue_if_delayed(
+                    identity_number,
+                    anchor,
+                    device_key,
+                    delayed,
+                    |id, execution_time| AuthnMethodReplaceResponse::RecoveryDelayed {
+                        id,
+                        execution_time,
+                    },
+                    AuthnMethodReplaceResponse::AuthnMethodError,
+                )?;
+                match anchor_management::replace(identity_number, anchor, public_key, device) {
+                    Ok(operation) => Ok((AuthnMethodReplaceResponse::Ok, operation)),
+                    Err(err) => Err(AuthnMethodReplaceResponse::AuthnMethodError(
+                        AuthnMethodError::from(err),
+                    )),
+                }
+            });
+        Some(result)
+    }
+
+    #[update]
+    #[candid_method]
+    fn authn_method_metadata_replace(
+        identity_number: IdentityNumber,
+        public_key: PublicKey,
+        metadata: HashMap<String, MetadataEntry>,
+    ) -> Option<AuthnMethodMetadataReplaceResponse> {
+        let result =
+            authenticated_anchor_operation_with_device(identity_number, |anchor, device_key| {
+                let device = match anchor_management::device_data_with_metadata(
+                    anchor,
+                    &public_key,
+                    metadata,
+                ) {
+                    Ok(device) => device,
+                    Err(ReplaceMetadataError::InvalidMetadata(err)) => {
+                        return Err(AuthnMethodMetadataReplaceResponse::InvalidMetadata(
+                            err.to_string(),
+                        ))
+                    }
+                    Err(ReplaceMetadataError::AnchorError(err)) => {
+                        return Err(AuthnMethodMetadataReplaceResponse::AuthnMethodError(
+                            AuthnMethodError::from(err),
+                        ))
+                    }
+                };
+                let delayed = DelayedOperation::Update {
+                    device_key: public_key.clone(),
+                    device: device.clone(),
+                };
+                queue_if_delayed(
+                    identity_number,
+                    anchor,
+                    device_key,
+                    delayed,
+                    |id, execution_time| AuthnMethodMetadataReplaceResponse::RecoveryDelayed {
+                        id,
+                        execution_time,
+                    },
+                    AuthnMethodMetadataReplaceResponse::AuthnMethodError,
+                )?;
+                match anchor_management::update(anchor, public_key, device) {
+                    Ok(operation) => Ok((AuthnMethodMetadataReplaceResponse::Ok, operation)),
+                    Err(err) => Err(AuthnMethodMetadataReplaceResponse::AuthnMethodError(
+                        AuthnMethodError::from(err),
+                    )),
+                }
+            });
+        Some(result)
+    }
+
+    #[update]
+    #[candid_method]
+    fn authn_method_security_settings_replace(
+        identity_number: IdentityNumber,
+        public_key: PublicKey,
+        security_settings: AuthnMethodSecuritySettings,
+    ) -> Option<AuthnMethodSecuritySettingsReplaceResponse> {
+        let result =
+            authenticated_anchor_operation_with_device(identity_number, |anchor, device_key| {
+                let error = |err| {
+                    AuthnMethodSecuritySettingsReplaceResponse::AuthnMethodError(
+                        AuthnMethodError::from(err),
+                    )
+                };
+                let device = anchor_management::device_data_with_security_settings(
+                    anchor,
+                    &public_key,
+                    security_settings,
+                )
+                .map_err(error)?;
+                let delayed = DelayedOperation::Update {
+                    device_key: public_key.clone(),
+                    device: device.clone(),
+                };
+                queue_if_delayed(
+                    identity_number,
+                    anchor,
+                    device_key,
+                    delayed,
+                    |id, execution_time| {
+                        AuthnMethodSecuritySettingsReplaceResponse::RecoveryDelayed {
+                            id,
+                            execution_time,
+                        }
+                    },
+                    AuthnMethodSecuritySettingsReplaceResponse::AuthnMethodError,
+                )?;
+                match anchor_management::update(anchor, public_key, device) {
+                    Ok(operation) => {
+                        Ok((AuthnMethodSecuritySettingsReplaceResponse::Ok, operation))
+                    }
+                    Err(err) => Err(error(err)),
+                }
+            });
+        Some(result)
+    }
+
+    #[update]
+    #[candid_method]
+    fn authn_method_batch(
+        identity_number: IdentityNumber,
+        operations: Vec<AuthnMethodOperation>,
+    ) -> Option<AuthnMethodBatchResponse> {
+        let mut batch_operations = Vec::with_capacity(operations.len());
+        for (index, operation) in operations.into_iter().enumerate() {
+            match BatchOperation::try_from(operation) {
+                Ok(operation) => batch_operations.push(operation),
+                Err(err) => {
+                    return Some(AuthnMethodBatchResponse::InvalidMetadata {
+                        index: index as u64,
+                        message: err.to_string(),
+                    })
+                }
             }
-            Err(err) => err,
+        }
+
+        let result =
+            authenticated_anchor_operation_with_device(identity_number, |anchor, device_key| {
+                // batches cannot be queued as a whole, hence they are not available to recovery
+                // methods of identities with a recovery delay
+                recovery_delay::check_not_delayed(anchor, device_key);
+                match anchor_management::batch(identity_number, anchor, batch_operations) {
+                    Ok(operation) => Ok((AuthnMethodBatchResponse::Ok, operation)),
+                    Err(BatchError::Operation { index, error }) => {
+                        Err(AuthnMethodBatchResponse::OperationError {
+                            index: index as u64,
+                            error: AuthnMethodError::from(error),
+                        })
+                    }
+                    Err(BatchError::Anchor(err)) => Err(
+                        AuthnMethodBatchResponse::AuthnMethodError(AuthnMethodError::from(err)),
+                    ),
+                }
+            });
+        Some(result)
+    }
+
+    #[update]
+    #[candid_method]
+    fn identity_recovery_info(
+        identity_number: IdentityNumber,
+    ) -> Option<IdentityRecoveryInfoResponse> {
+        authenticate_and_record_activity(identity_number);
+        let anchor = state::anchor(identity_number);
+        Some(IdentityRecoveryInfoResponse::Ok(IdentityRecoveryInfo {
+            recovery_delay_ns: anchor.recovery_delay_ns(),
+            queued_operations: recovery_delay::queued_operations(identity_number),
+            dropped_operations: recovery_delay::dropped_operations(identity_number),
+        }))
+    }
+
+    #[update]
+    #[candid_method]
+    fn identity_recovery_delay_set(
+        identity_number: IdentityNumber,
+        recovery_delay_ns: Option<u64>,
+    ) -> Option<IdentityRecoveryDelaySetResponse> {
+        let Ok((mut anchor, device_key)) = check_authentication(identity_number) else {
+            trap(&format!("{} could not be authenticated.", caller()));
         };
+        let response =
+            recovery_delay::set_recovery_delay(&mut anchor, &device_key, recovery_delay_ns);
+        if response == IdentityRecoveryDelaySetResponse::Ok {
+            anchor_management::activity_bookkeeping(&mut anchor, &device_key);
+            state::storage_borrow_mut(|storage| storage.write(identity_number, anchor))
+                .unwrap_or_else(|err| {
+                    panic!("unable to update anchor {identity_number} in stable memory: {err}")
+                });
+            if recovery_delay_ns.is_none() {
+                recovery_delay::drop_queued_operations(identity_number);
+            }
+        }
+        Some(response)
+    }
+
+    #[update]
+    #[candid_method]
+    fn identity_recovery_operation_cancel(
+        identity_number: IdentityNumber,
+        id: u64,
+    ) -> Option<RecoveryOperationCancelResponse> {
+        let (device_key, _) = authenticate_and_record_activity(identity_number);
+        let anchor = state::anchor(identity_number);
+        Some(recovery_delay::cancel(
+            identity_number,
+            &anchor,
+            &device_key,
+            id,
+        ))
+    }
+
+    #[update]
+    #[candid_method]
+    fn identity_guardians_info(
+        identity_number: IdentityNumber,
+    ) -> Option<IdentityGuardiansInfoResponse> {
+        authenticate_and_record_activity(identity_number);
+        let anchor = state::anchor(identity_number);
+        Some(IdentityGuardiansInfoResponse::Ok(IdentityGuardiansInfo {
+            config: anchor.guardians().cloned().map(GuardianConfig::from),
+            requests: guardian_recovery::pending_requests(identity_number),
+        }))
+    }
+
+    #[update]
+    #[candid_method]
+    fn identity_guardians_set(
+        identity_number: IdentityNumber,
+        config: Option<GuardianConfig>,
+    ) -> Option<IdentityGuardiansSetResponse> {
+        let result =
+            authenticated_anchor_operation_with_device(identity_number, |anchor, device_key| {
+                // otherwise the recovery delay could be bypassed by adding guardians
+                recovery_delay::check_not_delayed(anchor, device_key);
+                match guardian_recovery::set_guardians(identity_number, anchor, config) {
+                    Ok(operation) => Ok((IdentityGuardiansSetResponse::Ok, operation)),
+                    Err(message) => Err(IdentityGuardiansSetResponse::InvalidConfig(message)),
+                }
+            });
         Some(result)
     }
+
+    #[update]
+    #[candid_method]
+    fn guardian_recovery_request(
+        identity_number: IdentityNumber,
+        authn_method: AuthnMethodData,
+    ) -> Option<GuardianRecoveryRequestResponse> {
+        let device = match DeviceWithUsage::try_from(authn_method) {
+            Ok(device) => DeviceData::from(device),
+            Err(err) => {
+                return Some(GuardianRecoveryRequestResponse::InvalidMetadata(
+                    err.to_string(),
+                ))
+            }
+        };
+        Some(guardian_recovery::request_recovery(identity_number, device))
+    }
+
+    #[update]
+    #[candid_method]
+    fn guardian_recovery_approve(
+        guardian: IdentityNumber,
+        identity_number: IdentityNumber,
+        public_key: PublicKey,
+    ) -> Option<GuardianRecoveryApproveResponse> {
+        authenticate_and_record_activity(guardian);
+        Some(guardian_recovery::approve(
+            guardian,
+            identity_number,
+            public_key,
+        ))
+    }
 }
 
 fn main() {}
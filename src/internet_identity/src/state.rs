@@ -1,21 +1,34 @@
+use crate::anchor_management::deletion::PendingDeletion;
+use crate::anchor_management::guardian_recovery::{GuardianRecoveryRequests, GuardianWards};
+use crate::anchor_management::recovery_delay::RecoveryQueue;
 use crate::archive::{ArchiveData, ArchiveState, ArchiveStatusCache};
 use crate::assets::CertifiedAssets;
+use crate::delegation::alternative_origins::AlternativeOriginsCache;
+use crate::integrity_check::IntegrityScan;
+use crate::signature_map::SignatureMap;
+use crate::state::delegation_sessions::DelegationSessions;
+use crate::state::revocation_epochs::RevocationEpochs;
 use crate::state::temp_keys::TempKeys;
-use crate::storage::anchor::Anchor;
-use crate::storage::{StableMemory, DEFAULT_RANGE_SIZE};
+use crate::storage::anchor::{self, Anchor};
+use crate::storage::{
+    InflightChallenges, StableMemory, TentativeDeviceRegistrations, DEFAULT_RANGE_SIZE,
+};
 use crate::{Salt, Storage};
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::time;
+use ic_cdk::api::{performance_counter, time};
 use ic_cdk::{call, trap};
-use ic_stable_structures::DefaultMemoryImpl;
-use internet_identity::signature_map::SignatureMap;
+use ic_certified_map::Hash;
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, Storable};
 use internet_identity_interface::internet_identity::types::*;
+use std::borrow::Cow;
 use std::cell::{Cell, RefCell};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ops::{Deref, DerefMut};
 use std::time::Duration;
 
-mod temp_keys;
+pub mod delegation_sessions;
+pub mod revocation_epochs;
+pub mod temp_keys;
 
 // Default value for max number of delegation origins to store in the list of latest used delegation origins
 const MAX_NUM_DELEGATION_ORIGINS: u64 = 1000;
@@ -25,12 +38,32 @@ thread_local! {
     static ASSETS: RefCell<CertifiedAssets> = RefCell::new(CertifiedAssets::default());
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct TentativeDeviceRegistration {
     pub expiration: Timestamp,
     pub state: RegistrationState,
 }
 
+impl Storable for TentativeDeviceRegistration {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(
+            candid::encode_one(self).expect("failed to encode tentative device registration"),
+        )
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode tentative device registration")
+    }
+}
+
+impl BoundedStorable for TentativeDeviceRegistration {
+    // Large enough for any device satisfying the anchor invariants, see add_tentative_device.
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 /// Registration state of new devices added using the two step device add flow
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub enum RegistrationState {
     DeviceRegistrationModeActive,
     DeviceTentativelyAdded {
@@ -40,22 +73,57 @@ pub enum RegistrationState {
     },
 }
 
+/// Signature in the signature map that was prepared for a delegation of the given anchor.
+/// Used to find the signatures to delete when the delegations of an anchor are revoked.
+pub struct PreparedSignature {
+    pub anchor_number: AnchorNumber,
+    pub seed_hash: Hash,
+    pub msg_hash: Hash,
+    pub expires_at: Timestamp,
+}
+
 #[derive(Default)]
 pub struct UsageMetrics {
     // number of prepare_delegation calls since last upgrade
     pub delegation_counter: u64,
     // number of anchor operations (register, add, remove, update) since last upgrade
     pub anchor_operation_counter: u64,
+    // number of writes of the persistent state to its dedicated memory since last upgrade
+    pub persistent_state_writes: u64,
+    // instructions used by the last write of the persistent state to its dedicated memory
+    pub persistent_state_write_instructions: u64,
 }
 
 // The challenges we store and check against
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct ChallengeInfo {
     pub created: Timestamp,
     pub chars: String,
+    // The provider that created the challenge, None for CAPTCHAs created before the provider was
+    // recorded
+    pub provider: Option<ChallengeProviderConfig>,
+}
+
+impl Storable for ChallengeInfo {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode challenge"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode challenge")
+    }
+}
+
+impl BoundedStorable for ChallengeInfo {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
 }
 
 pub type ChallengeKey = String;
 
+/// Length of the challenge keys (in bytes).
+pub const CHALLENGE_KEY_LEN: u32 = 10;
+
 // The user's attempt
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct ChallengeAttempt {
@@ -86,6 +154,16 @@ pub struct PersistentState {
     pub latest_delegation_origins: Option<HashMap<FrontendHostname, Timestamp>>,
     // Maximum number of latest delegation origins to store
     pub max_num_latest_delegation_origins: Option<u64>,
+    // Per frontend lifetime policy for delegations, if any
+    pub delegation_ttl_policy: Option<Vec<DelegationTtlRule>>,
+    // Id of the next operation queued for the recovery delay of an anchor (see [RecoveryQueue])
+    pub recovery_queue_next_id: Option<u64>,
+    // Kind of challenge to be solved on registration, CAPTCHA if not set
+    pub challenge_provider: Option<ChallengeProviderConfig>,
+    // Parameters of the CAPTCHA challenges, the defaults are used if not set
+    pub captcha_config: Option<CaptchaConfig>,
+    // Rate limits of individual methods
+    pub method_rate_limits: Option<Vec<MethodRateLimit>>,
 }
 
 impl Default for PersistentState {
@@ -98,6 +176,11 @@ impl Default for PersistentState {
             domain_active_anchor_stats: None,
             latest_delegation_origins: None,
             max_num_latest_delegation_origins: Some(MAX_NUM_DELEGATION_ORIGINS),
+            delegation_ttl_policy: None,
+            recovery_queue_next_id: None,
+            challenge_provider: None,
+            captcha_config: None,
+            method_rate_limits: None,
         }
     }
 }
@@ -112,6 +195,15 @@ pub struct RateLimitState {
     pub token_timestamp: Timestamp,
 }
 
+/// Identifies the [RateLimitState] of a rate limit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum RateLimitKey {
+    // The rate limit on `register`
+    Register,
+    Global(RateLimitedMethod),
+    PerAnchor(RateLimitedMethod, AnchorNumber),
+}
+
 enum StorageState {
     Uninitialised,
     Initialised(Storage<DefaultMemoryImpl>),
@@ -120,26 +212,27 @@ enum StorageState {
 struct State {
     storage_state: RefCell<StorageState>,
     sigs: RefCell<SignatureMap>,
-    // Temporary keys that can be used in lieu of a particular device
-    temp_keys: RefCell<TempKeys>,
+    // The signatures in the signature map together with their anchor, ordered by expiration
+    prepared_signatures: RefCell<VecDeque<PreparedSignature>>,
+    // Identity deletion requests awaiting confirmation per anchor, not persisted across upgrades,
+    // i.e. an upgrade cancels them (see [crate::anchor_management::deletion])
+    pending_deletions: RefCell<HashMap<AnchorNumber, PendingDeletion>>,
     last_upgrade_timestamp: Cell<Timestamp>,
-    // note: we COULD persist this through upgrades, although this is currently NOT persisted
-    // through upgrades
-    inflight_challenges: RefCell<HashMap<ChallengeKey, ChallengeInfo>>,
-    // tentative device registrations, not persisted across updates
-    // if an anchor number is present in this map then registration mode is active until expiration
-    tentative_device_registrations: RefCell<HashMap<AnchorNumber, TentativeDeviceRegistration>>,
     // additional usage metrics, NOT persisted across updates (but probably should be in the future)
     usage_metrics: RefCell<UsageMetrics>,
-    // State that is temporarily persisted in stable memory during upgrades using
-    // pre- and post-upgrade hooks.
-    // This must remain small as it is serialized and deserialized on pre- and post-upgrade.
+    // State that is persisted in stable memory: written to its dedicated memory on every change
+    // (see [persistent_state_mut]).
+    // This must remain small as it is serialized on every change and deserialized on post-upgrade.
     // Be careful when making changes here, as II needs to be able to update and roll back.
     persistent_state: RefCell<PersistentState>,
     // Cache of the archive status (to make unwanted calls to deploy_archive cheap to dismiss).
     archive_status_cache: RefCell<Option<ArchiveStatusCache>>,
-    // Tracking data for the registration rate limit, if any. Not persisted across upgrades.
-    registration_rate_limit: RefCell<Option<RateLimitState>>,
+    // Tracking data for the rate limits, if any. Not persisted across upgrades.
+    rate_limits: RefCell<HashMap<RateLimitKey, RateLimitState>>,
+    // The running or last completed integrity scan, if any. Not persisted across upgrades.
+    integrity_scan: RefCell<Option<IntegrityScan>>,
+    // Cache of the fetched alternative origins resources. Not persisted across upgrades.
+    alternative_origins_cache: RefCell<AlternativeOriginsCache>,
 }
 
 impl Default for State {
@@ -147,14 +240,15 @@ impl Default for State {
         Self {
             storage_state: RefCell::new(StorageState::Uninitialised),
             sigs: RefCell::new(SignatureMap::default()),
-            temp_keys: RefCell::new(TempKeys::default()),
+            prepared_signatures: RefCell::new(VecDeque::new()),
+            pending_deletions: RefCell::new(HashMap::new()),
             last_upgrade_timestamp: Cell::new(0),
-            inflight_challenges: RefCell::new(HashMap::new()),
-            tentative_device_registrations: RefCell::new(HashMap::new()),
             usage_metrics: RefCell::new(UsageMetrics::default()),
             persistent_state: RefCell::new(PersistentState::default()),
             archive_status_cache: RefCell::new(None),
-            registration_rate_limit: RefCell::new(None),
+            rate_limits: RefCell::new(HashMap::new()),
+            integrity_scan: RefCell::new(None),
+            alternative_origins_cache: RefCell::new(AlternativeOriginsCache::default()),
         }
     }
 }
@@ -203,11 +297,13 @@ pub fn salt() -> [u8; 32] {
     })
 }
 
-pub fn init_new(migrate_to_memory_manager: bool) {
-    let memory = if migrate_to_memory_manager {
-        StableMemory::Managed(DefaultMemoryImpl::default())
+/// Initializes a new storage with layout version 7 or, if requested, 8. Layout version 6 keeps the
+/// stable maps in heap memory (see [crate::storage]) and is hence never used.
+pub fn init_new(migrate_to_stable_btree_map: bool) {
+    let memory = if migrate_to_stable_btree_map {
+        StableMemory::StableBTreeMap(DefaultMemoryImpl::default())
     } else {
-        StableMemory::Single(DefaultMemoryImpl::default())
+        StableMemory::Managed(DefaultMemoryImpl::default())
     };
     const FIRST_ANCHOR_NUMBER: AnchorNumber = 10_000;
     let storage = Storage::new(
@@ -218,20 +314,22 @@ pub fn init_new(migrate_to_memory_manager: bool) {
         memory,
     );
     storage_replace(storage);
+    write_persistent_state();
 }
 
-pub fn init_from_stable_memory(migrate_to_memory_manager: bool) {
+/// Initializes the storage from stable memory. Storages with layout version 6 are always migrated
+/// to layout version 7, so that the stable maps are persisted (see [crate::storage]).
+pub fn init_from_stable_memory(migrate_to_stable_btree_map: bool) {
     STATE.with(|s| {
         s.last_upgrade_timestamp.set(time());
     });
-    let maybe_new_storage = if migrate_to_memory_manager {
-        Storage::from_memory_v6_to_v7(DefaultMemoryImpl::default())
-    } else {
-        Storage::from_memory(DefaultMemoryImpl::default())
-    };
-    match maybe_new_storage {
+    match Storage::from_memory_v6_to_v7(DefaultMemoryImpl::default()) {
         Some(new_storage) => {
             storage_replace(new_storage);
+            if migrate_to_stable_btree_map {
+                // the anchors are migrated incrementally, see [crate::storage_migration]
+                storage_borrow_mut(|storage| storage.start_migration_to_v8());
+            }
         }
         None => {
             storage_borrow_mut(|storage| storage.flush());
@@ -239,10 +337,11 @@ pub fn init_from_stable_memory(migrate_to_memory_manager: bool) {
     }
 }
 
-pub fn save_persistent_state() {
-    STATE.with(|s| {
-        storage_borrow_mut(|storage| storage.write_persistent_state(&s.persistent_state.borrow()))
-    })
+/// Copies the persistent state after the anchors in `pre_upgrade` to keep a rollback to the
+/// previous release possible, see [crate::storage::Storage::copy_persistent_state_for_rollback].
+/// To be removed once the previous release can no longer be rolled back to.
+pub fn copy_persistent_state_for_rollback() {
+    storage_borrow_mut(|storage| storage.copy_persistent_state_for_rollback());
 }
 
 pub fn load_persistent_state() {
@@ -252,10 +351,13 @@ pub fn load_persistent_state() {
             Err(err) => trap(&format!("failed to recover persistent state! Err: {err:?}")),
         })
     });
+    storage_borrow_mut(|storage| storage.discard_persistent_state_after_anchors());
 
     // Initialize a sensible default for max_latest_delegation_origins
     // if it is not set in the persistent state.
     // This will allow us to later drop the opt and make the field u64.
+    // This also writes the persistent state to its dedicated memory, it may have been read from
+    // after the anchors.
     persistent_state_mut(|persistent_state| {
         persistent_state
             .max_num_latest_delegation_origins
@@ -280,7 +382,7 @@ pub fn archive_state() -> ArchiveState {
 }
 
 pub fn archive_data_mut<R>(f: impl FnOnce(&mut ArchiveData) -> R) -> R {
-    STATE.with(|s| {
+    let result = STATE.with(|s| {
         if let ArchiveState::Created { ref mut data, .. } =
             s.persistent_state.borrow_mut().archive_state
         {
@@ -288,19 +390,22 @@ pub fn archive_data_mut<R>(f: impl FnOnce(&mut ArchiveData) -> R) -> R {
         } else {
             trap("no archive deployed")
         }
-    })
+    });
+    write_persistent_state();
+    result
 }
 
+/// If an anchor number is present in the map then registration mode is active until expiration.
 pub fn tentative_device_registrations<R>(
-    f: impl FnOnce(&HashMap<AnchorNumber, TentativeDeviceRegistration>) -> R,
+    f: impl FnOnce(&TentativeDeviceRegistrations<DefaultMemoryImpl>) -> R,
 ) -> R {
-    STATE.with(|s| f(&s.tentative_device_registrations.borrow()))
+    storage_borrow(|storage| f(storage.tentative_device_registrations()))
 }
 
 pub fn tentative_device_registrations_mut<R>(
-    f: impl FnOnce(&mut HashMap<AnchorNumber, TentativeDeviceRegistration>) -> R,
+    f: impl FnOnce(&mut TentativeDeviceRegistrations<DefaultMemoryImpl>) -> R,
 ) -> R {
-    STATE.with(|s| f(&mut s.tentative_device_registrations.borrow_mut()))
+    storage_borrow_mut(|storage| f(storage.tentative_device_registrations_mut()))
 }
 
 pub fn assets<R>(f: impl FnOnce(&CertifiedAssets) -> R) -> R {
@@ -323,6 +428,20 @@ pub fn signature_map_mut<R>(f: impl FnOnce(&mut SignatureMap) -> R) -> R {
     STATE.with(|s| f(&mut s.sigs.borrow_mut()))
 }
 
+pub fn prepared_signatures_mut<R>(f: impl FnOnce(&mut VecDeque<PreparedSignature>) -> R) -> R {
+    STATE.with(|s| f(&mut s.prepared_signatures.borrow_mut()))
+}
+
+pub fn with_revocation_epochs<R>(f: impl FnOnce(&RevocationEpochs<DefaultMemoryImpl>) -> R) -> R {
+    storage_borrow(|storage| f(storage.revocation_epochs()))
+}
+
+pub fn with_revocation_epochs_mut<R>(
+    f: impl FnOnce(&mut RevocationEpochs<DefaultMemoryImpl>) -> R,
+) -> R {
+    storage_borrow_mut(|storage| f(storage.revocation_epochs_mut()))
+}
+
 pub fn storage_borrow<R>(f: impl FnOnce(&Storage<DefaultMemoryImpl>) -> R) -> R {
     STATE.with(|s| match s.storage_state.borrow().deref() {
         StorageState::Uninitialised => trap("Storage not initialized."),
@@ -338,15 +457,67 @@ pub fn storage_borrow_mut<R>(f: impl FnOnce(&mut Storage<DefaultMemoryImpl>) ->
 }
 
 pub fn storage_replace(storage: Storage<DefaultMemoryImpl>) {
+    anchor::set_anchor_limits(storage.anchor_limits());
     STATE.with(|s| s.storage_state.replace(StorageState::Initialised(storage)));
 }
 
-pub fn with_temp_keys_mut<R>(f: impl FnOnce(&mut TempKeys) -> R) -> R {
-    STATE.with(|s| f(&mut s.temp_keys.borrow_mut()))
+/// Temporary keys that can be used in lieu of a particular device.
+pub fn with_temp_keys_mut<R>(f: impl FnOnce(&mut TempKeys<DefaultMemoryImpl>) -> R) -> R {
+    storage_borrow_mut(|storage| f(storage.temp_keys_mut()))
 }
 
-pub fn with_temp_keys<R>(f: impl FnOnce(&TempKeys) -> R) -> R {
-    STATE.with(|s| f(&mut s.temp_keys.borrow()))
+pub fn with_temp_keys<R>(f: impl FnOnce(&TempKeys<DefaultMemoryImpl>) -> R) -> R {
+    storage_borrow(|storage| f(storage.temp_keys()))
+}
+
+/// Delegations prepared for an anchor that have not yet expired (nor been revoked).
+pub fn with_delegation_sessions<R>(
+    f: impl FnOnce(&DelegationSessions<DefaultMemoryImpl>) -> R,
+) -> R {
+    storage_borrow(|storage| f(storage.delegation_sessions()))
+}
+
+pub fn with_delegation_sessions_mut<R>(
+    f: impl FnOnce(&mut DelegationSessions<DefaultMemoryImpl>) -> R,
+) -> R {
+    storage_borrow_mut(|storage| f(storage.delegation_sessions_mut()))
+}
+
+/// Operations requested by recovery devices awaiting the recovery delay of the anchor.
+pub fn recovery_queue<R>(f: impl FnOnce(&RecoveryQueue<DefaultMemoryImpl>) -> R) -> R {
+    storage_borrow(|storage| f(storage.recovery_queue()))
+}
+
+pub fn recovery_queue_mut<R>(f: impl FnOnce(&mut RecoveryQueue<DefaultMemoryImpl>) -> R) -> R {
+    storage_borrow_mut(|storage| f(storage.recovery_queue_mut()))
+}
+
+/// Requests to add a device to an anchor awaiting the approval of the anchor's guardians.
+pub fn guardian_recovery_requests<R>(
+    f: impl FnOnce(&GuardianRecoveryRequests<DefaultMemoryImpl>) -> R,
+) -> R {
+    storage_borrow(|storage| f(storage.guardian_recovery_requests()))
+}
+
+pub fn guardian_recovery_requests_mut<R>(
+    f: impl FnOnce(&mut GuardianRecoveryRequests<DefaultMemoryImpl>) -> R,
+) -> R {
+    storage_borrow_mut(|storage| f(storage.guardian_recovery_requests_mut()))
+}
+
+/// Index of the anchors each anchor is a guardian of.
+pub fn guardian_wards<R>(f: impl FnOnce(&GuardianWards<DefaultMemoryImpl>) -> R) -> R {
+    storage_borrow(|storage| f(storage.guardian_wards()))
+}
+
+pub fn guardian_wards_mut<R>(f: impl FnOnce(&mut GuardianWards<DefaultMemoryImpl>) -> R) -> R {
+    storage_borrow_mut(|storage| f(storage.guardian_wards_mut()))
+}
+
+pub fn pending_deletions_mut<R>(
+    f: impl FnOnce(&mut HashMap<AnchorNumber, PendingDeletion>) -> R,
+) -> R {
+    STATE.with(|s| f(&mut s.pending_deletions.borrow_mut()))
 }
 
 pub fn usage_metrics<R>(f: impl FnOnce(&UsageMetrics) -> R) -> R {
@@ -357,14 +528,15 @@ pub fn usage_metrics_mut<R>(f: impl FnOnce(&mut UsageMetrics) -> R) -> R {
     STATE.with(|s| f(&mut s.usage_metrics.borrow_mut()))
 }
 
-pub fn inflight_challenges<R>(f: impl FnOnce(&HashMap<ChallengeKey, ChallengeInfo>) -> R) -> R {
-    STATE.with(|s| f(&s.inflight_challenges.borrow()))
+/// CAPTCHA challenges awaiting a solution.
+pub fn inflight_challenges<R>(f: impl FnOnce(&InflightChallenges<DefaultMemoryImpl>) -> R) -> R {
+    storage_borrow(|storage| f(storage.inflight_challenges()))
 }
 
 pub fn inflight_challenges_mut<R>(
-    f: impl FnOnce(&mut HashMap<ChallengeKey, ChallengeInfo>) -> R,
+    f: impl FnOnce(&mut InflightChallenges<DefaultMemoryImpl>) -> R,
 ) -> R {
-    STATE.with(|s| f(&mut s.inflight_challenges.borrow_mut()))
+    storage_borrow_mut(|storage| f(storage.inflight_challenges_mut()))
 }
 
 pub fn last_upgrade_timestamp() -> Timestamp {
@@ -375,16 +547,54 @@ pub fn persistent_state<R>(f: impl FnOnce(&PersistentState) -> R) -> R {
     STATE.with(|s| f(&s.persistent_state.borrow()))
 }
 
+/// Modifies the persistent state and writes it to stable memory.
+///
+/// Must not be called while the storage is borrowed.
 pub fn persistent_state_mut<R>(f: impl FnOnce(&mut PersistentState) -> R) -> R {
-    STATE.with(|s| f(&mut s.persistent_state.borrow_mut()))
+    let result = STATE.with(|s| f(&mut s.persistent_state.borrow_mut()));
+    write_persistent_state();
+    result
+}
+
+/// Writes the persistent state to its dedicated memory (see [crate::storage]), so that the
+/// persistent state in stable memory is always up to date and nothing needs to be serialized in
+/// `pre_upgrade`.
+fn write_persistent_state() {
+    STATE.with(|s| {
+        storage_borrow_mut(|storage| {
+            let start = performance_counter(0);
+            storage.write_persistent_state(&s.persistent_state.borrow());
+            let instructions = performance_counter(0) - start;
+            usage_metrics_mut(|metrics| {
+                metrics.persistent_state_writes += 1;
+                metrics.persistent_state_write_instructions = instructions;
+            });
+        })
+    })
+}
+
+pub fn rate_limits<R>(f: impl FnOnce(&HashMap<RateLimitKey, RateLimitState>) -> R) -> R {
+    STATE.with(|s| f(&s.rate_limits.borrow()))
+}
+
+pub fn rate_limits_mut<R>(f: impl FnOnce(&mut HashMap<RateLimitKey, RateLimitState>) -> R) -> R {
+    STATE.with(|s| f(&mut s.rate_limits.borrow_mut()))
+}
+
+pub fn alternative_origins_cache<R>(f: impl FnOnce(&AlternativeOriginsCache) -> R) -> R {
+    STATE.with(|s| f(&s.alternative_origins_cache.borrow()))
+}
+
+pub fn alternative_origins_cache_mut<R>(f: impl FnOnce(&mut AlternativeOriginsCache) -> R) -> R {
+    STATE.with(|s| f(&mut s.alternative_origins_cache.borrow_mut()))
 }
 
-pub fn registration_rate_limit<R>(f: impl FnOnce(&Option<RateLimitState>) -> R) -> R {
-    STATE.with(|s| f(&s.registration_rate_limit.borrow()))
+pub fn integrity_scan<R>(f: impl FnOnce(&Option<IntegrityScan>) -> R) -> R {
+    STATE.with(|s| f(&s.integrity_scan.borrow()))
 }
 
-pub fn registration_rate_limit_mut<R>(f: impl FnOnce(&mut Option<RateLimitState>) -> R) -> R {
-    STATE.with(|s| f(&mut s.registration_rate_limit.borrow_mut()))
+pub fn integrity_scan_mut<R>(f: impl FnOnce(&mut Option<IntegrityScan>) -> R) -> R {
+    STATE.with(|s| f(&mut s.integrity_scan.borrow_mut()))
 }
 
 pub fn cached_archive_status() -> Option<ArchiveStatusCache> {
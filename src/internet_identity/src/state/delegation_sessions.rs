@@ -0,0 +1,211 @@
+use crate::storage::MapMemory;
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap, Storable};
+use internet_identity_interface::internet_identity::types::{
+    AnchorNumber, DelegationSession, FrontendHostname, Timestamp,
+};
+use std::borrow::Cow;
+use std::ops::Bound;
+
+/// Maximum number of sessions tracked per anchor. If exceeded, the session expiring first is dropped.
+const MAX_SESSIONS_PER_ANCHOR: usize = 100;
+/// Maximum number of sessions checked for expiration when a session is added.
+const MAX_TO_PRUNE: usize = 100;
+
+pub type DelegationSessionMap<M> =
+    StableBTreeMap<DelegationSessionKey, DelegationSession, MapMemory<M>>;
+
+pub struct DelegationSessions<M: Memory> {
+    /// The sessions of each anchor, i.e. the delegations prepared for that anchor that have not
+    /// yet expired, sorted by expiration.
+    ///
+    /// The map is kept in stable memory (see [crate::storage]).
+    sessions: DelegationSessionMap<M>,
+
+    /// Key of the last session checked for expiration. Sessions of anchors that are not used
+    /// anymore are pruned by iterating over all sessions a few at a time, starting over from the
+    /// first session after an upgrade.
+    prune_cursor: Option<DelegationSessionKey>,
+}
+
+impl<M: Memory> DelegationSessions<M> {
+    pub fn new(sessions: DelegationSessionMap<M>) -> Self {
+        Self {
+            sessions,
+            prune_cursor: None,
+        }
+    }
+
+    pub fn add_session(
+        &mut self,
+        anchor: AnchorNumber,
+        session: DelegationSession,
+        now: Timestamp,
+    ) {
+        self.prune_expired_sessions(now);
+
+        let expired: Vec<DelegationSessionKey> = self
+            .sessions
+            .range(DelegationSessionKey::first(anchor)..=DelegationSessionKey::last(anchor, now))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            self.sessions.remove(&key);
+        }
+
+        self.sessions
+            .insert(DelegationSessionKey::new(anchor, &session), session);
+        let keys = self.session_keys(anchor);
+        if keys.len() > MAX_SESSIONS_PER_ANCHOR {
+            // the keys are sorted by expiration
+            self.sessions.remove(&keys[0]);
+        }
+    }
+
+    /// Returns the sessions of the given anchor that have not yet expired.
+    pub fn sessions(&self, anchor: AnchorNumber, now: Timestamp) -> Vec<DelegationSession> {
+        self.sessions
+            .range((
+                Bound::Excluded(DelegationSessionKey::last(anchor, now)),
+                Bound::Included(DelegationSessionKey::last(anchor, Timestamp::MAX)),
+            ))
+            .map(|(_, session)| session)
+            .collect()
+    }
+
+    /// Removes the sessions of the given anchor (only those for the given frontend, if any).
+    pub fn remove_sessions(&mut self, anchor: AnchorNumber, frontend: Option<&FrontendHostname>) {
+        let removed: Vec<DelegationSessionKey> = self
+            .sessions
+            .range(
+                DelegationSessionKey::first(anchor)
+                    ..=DelegationSessionKey::last(anchor, Timestamp::MAX),
+            )
+            .filter(|(_, session)| frontend.map_or(true, |frontend| session.frontend == *frontend))
+            .map(|(key, _)| key)
+            .collect();
+        for key in removed {
+            self.sessions.remove(&key);
+        }
+    }
+
+    fn session_keys(&self, anchor: AnchorNumber) -> Vec<DelegationSessionKey> {
+        self.sessions
+            .range(
+                DelegationSessionKey::first(anchor)
+                    ..=DelegationSessionKey::last(anchor, Timestamp::MAX),
+            )
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    fn prune_expired_sessions(&mut self, now: Timestamp) {
+        let start = match self.prune_cursor.take() {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+        let visited: Vec<DelegationSessionKey> = self
+            .sessions
+            .range((start, Bound::Unbounded))
+            .take(MAX_TO_PRUNE)
+            .map(|(key, _)| key)
+            .collect();
+        if visited.len() == MAX_TO_PRUNE {
+            // continue with the next session, otherwise start over
+            self.prune_cursor = visited.last().cloned();
+        }
+        for key in visited {
+            if key.expiration <= now {
+                self.sessions.remove(&key);
+            }
+        }
+    }
+}
+
+/// Key of a delegation session, sorting the sessions of an anchor by expiration.
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+pub struct DelegationSessionKey {
+    // Attention: order of fields MUST NOT be changed because Ord is derived!
+    anchor_number: AnchorNumber,
+    expiration: Timestamp,
+    session_key_fingerprint: [u8; 32],
+}
+
+impl DelegationSessionKey {
+    fn new(anchor_number: AnchorNumber, session: &DelegationSession) -> Self {
+        let mut session_key_fingerprint = [0; 32];
+        // the fingerprint is a SHA-256 hash
+        session_key_fingerprint.copy_from_slice(&session.session_key_fingerprint);
+        Self {
+            anchor_number,
+            expiration: session.expiration,
+            session_key_fingerprint,
+        }
+    }
+
+    /// Returns the lowest possible session key of the anchor.
+    fn first(anchor_number: AnchorNumber) -> Self {
+        Self {
+            anchor_number,
+            expiration: 0,
+            session_key_fingerprint: [0; 32],
+        }
+    }
+
+    /// Returns the highest possible key of the sessions of the anchor expiring at or before the
+    /// given time.
+    fn last(anchor_number: AnchorNumber, expiration: Timestamp) -> Self {
+        Self {
+            anchor_number,
+            expiration,
+            session_key_fingerprint: [u8::MAX; 32],
+        }
+    }
+}
+
+/// Note: byte ordering is very important as the keys are sorted on a byte level
+/// --> use big endian to ensure that the most significant bytes are compared first
+impl Storable for DelegationSessionKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::MAX_SIZE as usize);
+        buf.extend(self.anchor_number.to_be_bytes());
+        buf.extend(self.expiration.to_be_bytes());
+        buf.extend(self.session_key_fingerprint);
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            anchor_number: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[0..8]).expect("failed to read anchor number"),
+            ),
+            expiration: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[8..16]).expect("failed to read expiration"),
+            ),
+            session_key_fingerprint: TryFrom::try_from(&bytes[16..48])
+                .expect("failed to read session key fingerprint"),
+        }
+    }
+}
+
+impl BoundedStorable for DelegationSessionKey {
+    const MAX_SIZE: u32 = 48;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for DelegationSession {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode delegation session"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode delegation session")
+    }
+}
+
+impl BoundedStorable for DelegationSession {
+    // The frontend hostname and the public key of the authentication method are limited to 255
+    // and 300 bytes respectively.
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
@@ -0,0 +1,187 @@
+use crate::storage::MapMemory;
+use ic_certified_map::Hash;
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap, Storable};
+use internet_identity_interface::internet_identity::types::{AnchorNumber, Timestamp};
+use std::borrow::Cow;
+use std::ops::Bound;
+
+/// Maximum number of revocation epochs checked for expiration when a revocation is recorded.
+const MAX_TO_PRUNE: usize = 100;
+
+pub type RevocationEpochMap<M> = StableBTreeMap<RevocationScope, Timestamp, MapMemory<M>>;
+
+pub struct RevocationEpochs<M: Memory> {
+    /// The time of the last revocation of each scope.
+    ///
+    /// The map is kept in stable memory (see [crate::storage]).
+    epochs: RevocationEpochMap<M>,
+
+    /// Scope of the last epoch checked for expiration. The epochs are pruned by iterating over all
+    /// epochs a few at a time, starting over from the first epoch after an upgrade.
+    prune_cursor: Option<RevocationScope>,
+}
+
+impl<M: Memory> RevocationEpochs<M> {
+    pub fn new(epochs: RevocationEpochMap<M>) -> Self {
+        Self {
+            epochs,
+            prune_cursor: None,
+        }
+    }
+
+    /// Records the revocation of the delegations of the given scope and drops epochs at or before
+    /// `prune_before` (a few at a time).
+    pub fn revoke(&mut self, scope: RevocationScope, now: Timestamp, prune_before: Timestamp) {
+        self.prune_epochs(prune_before);
+        self.epochs.insert(scope, now);
+    }
+
+    /// Returns true if all the delegations of the anchor or those for the frontend with the given
+    /// seed hash were revoked at or after the given time.
+    pub fn revoked_since(
+        &self,
+        anchor_number: AnchorNumber,
+        seed_hash: Hash,
+        timestamp: Timestamp,
+    ) -> bool {
+        [
+            RevocationScope::anchor(anchor_number),
+            RevocationScope::frontend(anchor_number, seed_hash),
+        ]
+        .iter()
+        .any(|scope| matches!(self.epochs.get(scope), Some(revoked_at) if revoked_at >= timestamp))
+    }
+
+    fn prune_epochs(&mut self, prune_before: Timestamp) {
+        let start = match self.prune_cursor.take() {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+        let visited: Vec<(RevocationScope, Timestamp)> = self
+            .epochs
+            .range((start, Bound::Unbounded))
+            .take(MAX_TO_PRUNE)
+            .collect();
+        if visited.len() == MAX_TO_PRUNE {
+            // continue with the next epoch, otherwise start over
+            self.prune_cursor = visited.last().map(|(scope, _)| scope.clone());
+        }
+        for (scope, revoked_at) in visited {
+            if revoked_at <= prune_before {
+                self.epochs.remove(&scope);
+            }
+        }
+    }
+}
+
+/// Delegations revoked together: all the delegations of an anchor or only those for the frontend
+/// with the given seed hash.
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+pub struct RevocationScope {
+    // Attention: order of fields MUST NOT be changed because Ord is derived!
+    anchor_number: AnchorNumber,
+    seed_hash: Option<Hash>,
+}
+
+impl RevocationScope {
+    pub fn anchor(anchor_number: AnchorNumber) -> Self {
+        Self {
+            anchor_number,
+            seed_hash: None,
+        }
+    }
+
+    pub fn frontend(anchor_number: AnchorNumber, seed_hash: Hash) -> Self {
+        Self {
+            anchor_number,
+            seed_hash: Some(seed_hash),
+        }
+    }
+}
+
+/// Note: byte ordering is very important as the keys are sorted on a byte level
+/// --> use big endian to ensure that the most significant bytes are compared first
+impl Storable for RevocationScope {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::MAX_SIZE as usize);
+        buf.extend(self.anchor_number.to_be_bytes());
+        match self.seed_hash {
+            None => buf.extend([0; 33]),
+            Some(seed_hash) => {
+                buf.push(1);
+                buf.extend(seed_hash);
+            }
+        }
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            anchor_number: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[0..8]).expect("failed to read anchor number"),
+            ),
+            seed_hash: match bytes[8] {
+                0 => None,
+                _ => Some(TryFrom::try_from(&bytes[9..41]).expect("failed to read seed hash")),
+            },
+        }
+    }
+}
+
+impl BoundedStorable for RevocationScope {
+    const MAX_SIZE: u32 = 41;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+
+    fn revocation_epochs() -> RevocationEpochs<VectorMemory> {
+        RevocationEpochs::new(StableBTreeMap::init(MapMemory::Heap(
+            VectorMemory::default(),
+        )))
+    }
+
+    #[test]
+    fn should_only_reject_delegations_of_revoked_scope() {
+        let mut epochs = revocation_epochs();
+        epochs.revoke(RevocationScope::anchor(1), 100, 0);
+        epochs.revoke(RevocationScope::frontend(2, [1; 32]), 100, 0);
+
+        assert!(epochs.revoked_since(1, [1; 32], 100));
+        assert!(epochs.revoked_since(1, [2; 32], 50));
+        assert!(!epochs.revoked_since(1, [1; 32], 101));
+        assert!(epochs.revoked_since(2, [1; 32], 100));
+        assert!(!epochs.revoked_since(2, [2; 32], 100));
+        assert!(!epochs.revoked_since(3, [1; 32], 0));
+    }
+
+    #[test]
+    fn should_prune_revocation_epochs() {
+        let mut epochs = revocation_epochs();
+        epochs.revoke(RevocationScope::anchor(1), 100, 0);
+        epochs.revoke(RevocationScope::frontend(2, [1; 32]), 200, 0);
+
+        epochs.revoke(RevocationScope::anchor(3), 300, 150);
+        assert!(!epochs.revoked_since(1, [1; 32], 0));
+        assert!(epochs.revoked_since(2, [1; 32], 0));
+        assert!(epochs.revoked_since(3, [1; 32], 0));
+    }
+
+    #[test]
+    fn should_round_trip_revocation_scopes() {
+        for scope in [
+            RevocationScope::anchor(10_000),
+            RevocationScope::frontend(10_000, [7; 32]),
+        ] {
+            assert_eq!(RevocationScope::from_bytes(scope.to_bytes()), scope);
+        }
+        assert!(
+            RevocationScope::anchor(1).to_bytes()
+                < RevocationScope::frontend(1, [0; 32]).to_bytes()
+        );
+    }
+}
@@ -1,14 +1,17 @@
+use crate::storage::{BoundedKey, StorableDeviceKey, TempKeyMap};
 use crate::MINUTE_NS;
-use candid::Principal;
+use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::time;
+use ic_cdk::trap;
+use ic_stable_structures::{BoundedStorable, Memory, Storable};
 use internet_identity_interface::internet_identity::types::{AnchorNumber, DeviceKey, Timestamp};
-use std::collections::{HashMap, VecDeque};
+use std::borrow::Cow;
+use std::collections::VecDeque;
 
 // Expiration for temp keys, the same as the front-end delegation expiry
 const TEMP_KEY_EXPIRATION_NS: u64 = 10 * MINUTE_NS;
 
-#[derive(Default, Debug)]
-pub struct TempKeys {
+pub struct TempKeys<M: Memory> {
     /// A map of "temporary keys" attached to devices (and a specific anchor). A temporary key can be used in lieu
     /// of the device but has a short expiration time. These keys are used as a workaround for WebAuthn
     /// needing two users interactions: one for "create" and one for "sign". So instead we only "create"
@@ -25,40 +28,67 @@ pub struct TempKeys {
     ///
     /// Since temp keys can only be added during registration, the the max number of temp keys is
     /// bounded by the registration rate limit.
-    temp_keys: HashMap<DeviceKey, TempKey>,
+    ///
+    /// The map is kept in stable memory (see [crate::storage]).
+    temp_keys: TempKeyMap<M>,
 
-    /// Deque to efficiently prune expired temp keys
+    /// Deque to efficiently prune expired temp keys, rebuilt from the map when the storage is
+    /// initialized.
     expirations: VecDeque<TempKeyExpiration>,
 }
 
-impl TempKeys {
+impl<M: Memory> TempKeys<M> {
+    pub fn new(temp_keys: TempKeyMap<M>) -> Self {
+        let mut expirations: Vec<TempKeyExpiration> = temp_keys
+            .iter()
+            .map(|(device_key, temp_key)| TempKeyExpiration {
+                device_key,
+                expiration: temp_key.expiration,
+            })
+            .collect();
+        // restore the ordering of the expirations required by prune_expired_keys
+        expirations.sort_by_key(|expiration| expiration.expiration);
+        Self {
+            temp_keys,
+            expirations: expirations.into(),
+        }
+    }
+
     pub fn add_temp_key(
         &mut self,
         device_key: &DeviceKey,
         anchor: AnchorNumber,
         temp_key: Principal,
     ) {
-        let tmp_key = TempKey {
-            principal: temp_key,
-            anchor,
-            expiration: time() + TEMP_KEY_EXPIRATION_NS,
-        };
+        let tmp_key = TempKey::new(temp_key, anchor, time() + TEMP_KEY_EXPIRATION_NS);
+        self.insert_temp_key(device_key, tmp_key);
+    }
 
+    /// Links the temporary key to the given device. The expiration of the temporary key must not be
+    /// before the expiration of the temporary keys inserted previously (see [TempKeys::prune_expired_keys]).
+    pub fn insert_temp_key(&mut self, device_key: &DeviceKey, temp_key: TempKey) {
+        // the device has been checked against the device limits
+        let Some(device_key) = BoundedKey::new(device_key) else {
+            trap("device key is too long to link a temporary key");
+        };
         self.expirations.push_back(TempKeyExpiration {
             device_key: device_key.clone(),
-            expiration: tmp_key.expiration,
+            expiration: temp_key.expiration,
         });
-        self.temp_keys.insert(device_key.clone(), tmp_key);
+        self.temp_keys.insert(device_key, temp_key);
     }
 
     /// Removes the temporary key for the given device if it exists and is linked to the provided anchor.
     pub fn remove_temp_key(&mut self, anchor: AnchorNumber, device_key: &DeviceKey) {
+        let Some(device_key) = BoundedKey::new(device_key) else {
+            return;
+        };
         // we can skip the removal from expirations because there it will be removed
         // during amortized clean-up operations
-        if let Some(temp_key) = self.temp_keys.get(device_key) {
+        if let Some(temp_key) = self.temp_keys.get(&device_key) {
             if temp_key.anchor == anchor {
                 // Only remove temp key if the anchor matches
-                self.temp_keys.remove(device_key);
+                self.temp_keys.remove(&device_key);
             }
         }
     }
@@ -66,6 +96,7 @@ impl TempKeys {
     /// Checks that the temporary key is valid for the given device and anchor.
     ///
     /// Requires a mutable reference because it does amortized clean-up of expired temp keys.
+    #[allow(clippy::result_unit_err)]
     pub fn check_temp_key(
         &mut self,
         caller: &Principal,
@@ -74,7 +105,7 @@ impl TempKeys {
     ) -> Result<(), ()> {
         self.prune_expired_keys();
 
-        let Some(temp_key) = self.temp_keys.get(device_key) else {
+        let Some(temp_key) = self.temp_key(device_key) else {
             return Err(());
         };
         if temp_key.expiration < time() {
@@ -109,12 +140,17 @@ impl TempKeys {
         }
     }
 
+    /// Returns the temporary key for the given device, even if it has expired.
+    pub fn temp_key(&self, device_key: &DeviceKey) -> Option<TempKey> {
+        self.temp_keys.get(&BoundedKey::new(device_key)?)
+    }
+
     pub fn num_temp_keys(&self) -> usize {
-        self.temp_keys.len()
+        self.temp_keys.len() as usize
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct TempKey {
     /// The temp key principal
     principal: Principal,
@@ -124,10 +160,35 @@ pub struct TempKey {
     expiration: Timestamp,
 }
 
+impl TempKey {
+    pub fn new(principal: Principal, anchor: AnchorNumber, expiration: Timestamp) -> Self {
+        Self {
+            principal,
+            anchor,
+            expiration,
+        }
+    }
+}
+
+impl Storable for TempKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode temp key"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode temp key")
+    }
+}
+
+impl BoundedStorable for TempKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct TempKeyExpiration {
     /// The device key the temp key is linked to
-    pub device_key: DeviceKey,
+    pub device_key: StorableDeviceKey,
     /// The expiration timestamp of the temp key
     pub expiration: Timestamp,
 }
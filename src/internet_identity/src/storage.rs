@@ -63,7 +63,64 @@
 //! The [PersistentState] is serialized at the end of stable memory to allow for variable sized data
 //! without the risk of running out of space (which might easily happen if the RESERVED_HEADER_BYTES
 //! were used instead).
-
+//!
+//! With managed memory (layout version 7 and 8), the [PersistentState] is instead written to a
+//! dedicated virtual memory of the memory manager (using the same format) whenever it changes (see
+//! [crate::state::persistent_state_mut]). This way, nothing needs to be serialized in `pre_upgrade`
+//! (where a failure would prevent any further upgrades) and it can no longer be overwritten by
+//! anchors.
+//!
+//! To keep a rollback to the previous release (which reads the [PersistentState] only from after
+//! the anchor records) possible, `pre_upgrade` still copies the encoded [PersistentState] from its
+//! dedicated memory to after the anchor records with layout version 7, without serializing it again
+//! (see [Storage::copy_persistent_state_for_rollback]). For the same reason, a persistent state
+//! found there takes precedence when reading it in `post_upgrade` (it has been written by the
+//! `pre_upgrade` of either version), after which it is discarded.
+//! This is a one-release step: once the previous release can no longer be rolled back to, the copy
+//! in `pre_upgrade` (and the `pre_upgrade` hook itself) is to be removed, together with the
+//! precedence of the persistent state after the anchor records.
+
+//! ## Stable Maps
+//!
+//! With managed memory (layout version 7 and 8), the [TentativeDeviceRegistration]s, the inflight
+//! CAPTCHA challenges and the temporary keys are kept in [StableBTreeMap]s in dedicated virtual
+//! memories of the memory manager and updated in place, i.e. nothing needs to be saved in
+//! `pre_upgrade`. Layout version 6 has no memory manager, there the maps are kept in heap memory
+//! (see [MapMemory]). This is never used by the canister: fresh installations use layout version 7
+//! (or 8) and storages with layout version 6 are migrated to version 7 on upgrade (see
+//! [Storage::from_memory_v6_to_v7]), before any map is used.
+//!
+//! The operations queued for the recovery delay of anchors (see
+//! [crate::anchor_management::recovery_delay]) are kept the same way, in two maps (the operations
+//! by anchor and an index by execution time), as are the pending guardian recovery requests and
+//! the index of the anchors each anchor is a guardian of (see
+//! [crate::anchor_management::guardian_recovery]), the delegation sessions of the anchors (see
+//! [crate::state::delegation_sessions]) and the delegation revocation epochs (see
+//! [crate::state::revocation_epochs]).
+//!
+//! ## Layout Version 8
+//!
+//! With layout version 8, the anchors are no longer stored in fixed size records. Instead, the
+//! candid encoded anchors are split into chunks stored in a [ic_stable_structures::StableBTreeMap]
+//! in a dedicated virtual memory (see [anchor_chunks]). This removes the fixed record size as a
+//! constraint on the size of anchors and no longer wastes space on small anchors.
+//! As anchors no longer need to fit into a version 7 record, the anchor invariants are relaxed
+//! once the storage has switched to layout version 8 (see [AnchorLimits]), allowing for more
+//! devices and anchors larger than 4 KB.
+//!
+//! Storages with layout version 7 are migrated to version 8 incrementally: once the migration has
+//! been started (see [Storage::start_migration_to_v8]), the anchor records are copied in batches
+//! (see [Storage::migrate_anchors_to_v8]). Until all anchors have been copied, the anchor records
+//! remain the source of truth and anchors that have already been copied are written to both
+//! locations. The layout version only changes to 8 once all anchors have been copied, so the
+//! canister can be rolled back during the migration.
+//! Records that cannot be read (i.e. corrupted records) do not stop the migration: they are
+//! copied as a whole (including the invalid length) and reported (see
+//! [Storage::v8_migration_progress]). With layout version 8, such anchors still fail to decode
+//! (rather than reading as deleted), so they are reported by the integrity scan (see
+//! [crate::integrity_check]) and their data is kept for a manual recovery.
+
+use std::borrow::Cow;
 use std::cell::RefCell;
 use std::convert::TryInto;
 use std::io::{Error, Read, Write};
@@ -71,18 +128,33 @@ use std::ops::RangeInclusive;
 use std::rc::Rc;
 use std::{fmt, io};
 
+use candid::{CandidType, Principal};
 use ic_cdk::api::trap;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::reader::{BufferedReader, Reader};
 use ic_stable_structures::writer::{BufferedWriter, Writer};
-use ic_stable_structures::{Memory, RestrictedMemory};
+use ic_stable_structures::{
+    BoundedStorable, Memory, RestrictedMemory, StableBTreeMap, Storable, VectorMemory,
+};
 
 use internet_identity_interface::internet_identity::types::*;
-
-use crate::state::PersistentState;
-use crate::storage::anchor::Anchor;
+use serde::de::DeserializeOwned;
+
+use crate::anchor_management::guardian_recovery::{GuardianRecoveryRequests, GuardianWards};
+use crate::anchor_management::recovery_delay::RecoveryQueue;
+use crate::state::delegation_sessions::DelegationSessions;
+use crate::state::revocation_epochs::RevocationEpochs;
+use crate::state::temp_keys::{TempKey, TempKeys};
+use crate::state::{
+    ChallengeInfo, PersistentState, TentativeDeviceRegistration, CHALLENGE_KEY_LEN,
+};
+use crate::storage::anchor::{Anchor, AnchorLimits, PK_LEN_LIMIT};
+use crate::storage::anchor_chunks::{
+    delete_anchor_bytes, read_anchor_bytes, write_anchor_bytes, AnchorChunks, MAX_ANCHOR_SIZE,
+};
 
 pub mod anchor;
+pub mod anchor_chunks;
 
 #[cfg(test)]
 mod tests;
@@ -91,14 +163,17 @@ mod tests;
 // version 1-5: no longer supported
 // version   6: 4KB anchors, candid anchor record layout, persistent state with archive pull config
 // version   7: like version 6, but with memory manager (from 2nd page on)
-// version  8+: invalid
-const SUPPORTED_LAYOUT_VERSIONS: RangeInclusive<u8> = 6..=7;
+// version   8: like version 7, but with variable size anchors stored in a stable BTreeMap
+// version  9+: invalid
+const SUPPORTED_LAYOUT_VERSIONS: RangeInclusive<u8> = 6..=8;
 
 const WASM_PAGE_SIZE: u64 = 65_536;
 
 /// Reserved space for the header before the anchor records start.
 const ENTRY_OFFSET: u64 = 2 * WASM_PAGE_SIZE; // 1 page reserved for II config, 1 for memory manager
 const DEFAULT_ENTRY_SIZE: u16 = 4096;
+/// Maximum number of anchor numbers reported as skipped by the migration to layout version 8.
+const MAX_REPORTED_SKIPPED_ANCHORS: usize = 100;
 const EMPTY_SALT: [u8; 32] = [0; 32];
 const GB: u64 = 1 << 30;
 
@@ -115,7 +190,32 @@ const PERSISTENT_STATE_MAGIC: [u8; 4] = *b"IIPS"; // II Persistent State
 
 /// MemoryManager parameters.
 const ANCHOR_MEMORY_INDEX: u8 = 0u8;
+const TENTATIVE_DEVICE_REGISTRATIONS_MEMORY_INDEX: u8 = 1u8;
+const INFLIGHT_CHALLENGES_MEMORY_INDEX: u8 = 2u8;
+const TEMP_KEYS_MEMORY_INDEX: u8 = 3u8;
+const ANCHOR_CHUNKS_MEMORY_INDEX: u8 = 4u8;
+const PERSISTENT_STATE_MEMORY_INDEX: u8 = 5u8;
+const RECOVERY_QUEUE_MEMORY_INDEX: u8 = 6u8;
+const RECOVERY_QUEUE_SCHEDULE_MEMORY_INDEX: u8 = 7u8;
+const GUARDIAN_RECOVERY_REQUESTS_MEMORY_INDEX: u8 = 8u8;
+const DELEGATION_SESSIONS_MEMORY_INDEX: u8 = 9u8;
+const GUARDIAN_WARDS_MEMORY_INDEX: u8 = 10u8;
+const REVOCATION_EPOCHS_MEMORY_INDEX: u8 = 11u8;
 const ANCHOR_MEMORY_ID: MemoryId = MemoryId::new(ANCHOR_MEMORY_INDEX);
+const TENTATIVE_DEVICE_REGISTRATIONS_MEMORY_ID: MemoryId =
+    MemoryId::new(TENTATIVE_DEVICE_REGISTRATIONS_MEMORY_INDEX);
+const INFLIGHT_CHALLENGES_MEMORY_ID: MemoryId = MemoryId::new(INFLIGHT_CHALLENGES_MEMORY_INDEX);
+const TEMP_KEYS_MEMORY_ID: MemoryId = MemoryId::new(TEMP_KEYS_MEMORY_INDEX);
+const ANCHOR_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(ANCHOR_CHUNKS_MEMORY_INDEX);
+const PERSISTENT_STATE_MEMORY_ID: MemoryId = MemoryId::new(PERSISTENT_STATE_MEMORY_INDEX);
+const RECOVERY_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(RECOVERY_QUEUE_MEMORY_INDEX);
+const RECOVERY_QUEUE_SCHEDULE_MEMORY_ID: MemoryId =
+    MemoryId::new(RECOVERY_QUEUE_SCHEDULE_MEMORY_INDEX);
+const GUARDIAN_RECOVERY_REQUESTS_MEMORY_ID: MemoryId =
+    MemoryId::new(GUARDIAN_RECOVERY_REQUESTS_MEMORY_INDEX);
+const DELEGATION_SESSIONS_MEMORY_ID: MemoryId = MemoryId::new(DELEGATION_SESSIONS_MEMORY_INDEX);
+const GUARDIAN_WARDS_MEMORY_ID: MemoryId = MemoryId::new(GUARDIAN_WARDS_MEMORY_INDEX);
+const REVOCATION_EPOCHS_MEMORY_ID: MemoryId = MemoryId::new(REVOCATION_EPOCHS_MEMORY_INDEX);
 // The bucket size 128 is relatively low, to avoid wasting memory when using
 // multiple virtual memories for smaller amounts of data.
 // This value results in 256 GB of total managed memory, which should be enough
@@ -233,6 +333,81 @@ impl<M: Memory> AnchorMemory<M> {
 pub enum StableMemory<M: Memory> {
     Single(M),
     Managed(M),
+    StableBTreeMap(M),
+}
+
+/// Memory of the [StableBTreeMap]s of the storage (see module docs): a virtual memory of
+/// the memory manager or, with layout version 6 (i.e. only in tests), heap memory that is not
+/// persisted across upgrades.
+pub enum MapMemory<M: Memory> {
+    Managed(VirtualMemory<RestrictedMemory<M>>),
+    Heap(VectorMemory),
+}
+
+impl<M: Memory> Memory for MapMemory<M> {
+    fn size(&self) -> u64 {
+        match self {
+            MapMemory::Managed(memory) => memory.size(),
+            MapMemory::Heap(memory) => memory.size(),
+        }
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        match self {
+            MapMemory::Managed(memory) => memory.grow(pages),
+            MapMemory::Heap(memory) => memory.grow(pages),
+        }
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        match self {
+            MapMemory::Managed(memory) => memory.read(offset, dst),
+            MapMemory::Heap(memory) => memory.read(offset, dst),
+        }
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        match self {
+            MapMemory::Managed(memory) => memory.write(offset, src),
+            MapMemory::Heap(memory) => memory.write(offset, src),
+        }
+    }
+}
+
+pub type TentativeDeviceRegistrations<M> =
+    StableBTreeMap<AnchorNumber, TentativeDeviceRegistration, MapMemory<M>>;
+pub type InflightChallenges<M> =
+    StableBTreeMap<BoundedKey<CHALLENGE_KEY_LEN>, ChallengeInfo, MapMemory<M>>;
+pub type TempKeyMap<M> = StableBTreeMap<StorableDeviceKey, TempKey, MapMemory<M>>;
+pub type StorableDeviceKey = BoundedKey<{ PK_LEN_LIMIT as u32 }>;
+
+/// Key of variable size of at most `MAX_SIZE` bytes, e.g. a CAPTCHA challenge key or a device key.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+pub struct BoundedKey<const MAX_SIZE: u32>(Vec<u8>);
+
+impl<const MAX_SIZE: u32> BoundedKey<MAX_SIZE> {
+    /// Returns `None` if the key is too large, i.e. if it cannot be contained in the map.
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() > MAX_SIZE as usize {
+            return None;
+        }
+        Some(Self(bytes.to_vec()))
+    }
+}
+
+impl<const MAX_SIZE: u32> Storable for BoundedKey<MAX_SIZE> {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+impl<const MAX_SIZE: u32> BoundedStorable for BoundedKey<MAX_SIZE> {
+    const MAX_SIZE: u32 = MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
 }
 
 /// Data type responsible for managing anchor data in stable memory.
@@ -240,10 +415,43 @@ pub struct Storage<M: Memory> {
     header: Header,
     header_memory: RestrictedMemory<M>,
     anchor_memory: AnchorMemory<M>,
-    #[allow(dead_code)]
+    tentative_device_registrations: TentativeDeviceRegistrations<M>,
+    inflight_challenges: InflightChallenges<M>,
+    temp_keys: TempKeys<M>,
+    recovery_queue: RecoveryQueue<M>,
+    guardian_recovery_requests: GuardianRecoveryRequests<M>,
+    guardian_wards: GuardianWards<M>,
+    delegation_sessions: DelegationSessions<M>,
+    revocation_epochs: RevocationEpochs<M>,
+    // only available with managed memory (i.e. layout version 7 and 8)
+    maybe_persistent_state_memory: Option<VirtualMemory<RestrictedMemory<M>>>,
+    // only available with layout version 8 or while migrating from version 7 to version 8
+    maybe_anchor_chunks: Option<AnchorChunks<VirtualMemory<RestrictedMemory<M>>>>,
+    // number of anchor records copied to the anchor chunks while migrating to version 8
+    v8_migration_progress: Option<u32>,
+    // anchors whose records could not be read when copying them to the anchor chunks since the
+    // migration to version 8 (re)started, see [Storage::migrate_anchors_to_v8]
+    v8_migration_skipped: SkippedAnchors,
     maybe_memory_manager: Option<MemoryManager<RestrictedMemory<M>>>,
 }
 
+/// Anchors skipped by the migration to layout version 8. Only the first
+/// [MAX_REPORTED_SKIPPED_ANCHORS] anchor numbers are kept.
+#[derive(Default)]
+struct SkippedAnchors {
+    count: u32,
+    anchor_numbers: Vec<AnchorNumber>,
+}
+
+impl SkippedAnchors {
+    fn add(&mut self, anchor_number: AnchorNumber) {
+        self.count += 1;
+        if self.anchor_numbers.len() < MAX_REPORTED_SKIPPED_ANCHORS {
+            self.anchor_numbers.push(anchor_number);
+        }
+    }
+}
+
 #[repr(packed)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 struct Header {
@@ -252,7 +460,8 @@ struct Header {
     // version 1-5: no longer supported
     // version   6: 4KB anchors, candid anchor record layout, persistent state with archive pull config
     // version   7: like 6, but with managed memory
-    // version  8+: invalid
+    // version   8: like 7, but with variable size anchors stored in a stable BTreeMap
+    // version  9+: invalid
     version: u8,
     num_anchors: u32,
     id_range_lo: u64,
@@ -304,41 +513,136 @@ impl<M: Memory + Clone> Storage<M> {
                 "id range [{id_range_lo}, {id_range_hi}) is too large for a single canister (max {DEFAULT_RANGE_SIZE} entries)",
             ));
         }
-        let (header_memory, anchor_memory, maybe_memory_manager, version) = match memory {
-            StableMemory::Single(memory) => {
-                let header_memory = RestrictedMemory::new(memory.clone(), 0..2);
-                let anchor_memory =
-                    AnchorMemory::Single(RestrictedMemory::new(memory, 2..MAX_WASM_PAGES));
-                (header_memory, anchor_memory, None, 6)
+        let version = match memory {
+            StableMemory::Single(_) => 6,
+            StableMemory::Managed(_) => 7,
+            StableMemory::StableBTreeMap(_) => 8,
+        };
+        let header = Header {
+            magic: *b"IIC",
+            version,
+            num_anchors: 0,
+            id_range_lo,
+            id_range_hi,
+            entry_size: DEFAULT_ENTRY_SIZE,
+            salt: EMPTY_SALT,
+            first_entry_offset: ENTRY_OFFSET,
+        };
+
+        let mut storage = match memory {
+            StableMemory::Single(memory) => Self::new_single(header, memory),
+            StableMemory::Managed(memory) | StableMemory::StableBTreeMap(memory) => {
+                Self::new_managed(header, memory)
             }
-            StableMemory::Managed(memory) => {
-                let header_memory = RestrictedMemory::new(memory.clone(), 0..1);
-                let memory_manager = MemoryManager::init_with_bucket_size(
-                    RestrictedMemory::new(memory, 1..MAX_WASM_PAGES),
-                    BUCKET_SIZE_IN_PAGES,
-                );
-                let anchor_memory = AnchorMemory::Managed(memory_manager.get(ANCHOR_MEMORY_ID));
-                (header_memory, anchor_memory, Some(memory_manager), 7)
+        };
+        storage.flush();
+        storage
+    }
+
+    /// Creates the storage for layout version 6, i.e. without memory manager.
+    fn new_single(header: Header, memory: M) -> Self {
+        Self {
+            header,
+            header_memory: RestrictedMemory::new(memory.clone(), 0..2),
+            anchor_memory: AnchorMemory::Single(RestrictedMemory::new(memory, 2..MAX_WASM_PAGES)),
+            tentative_device_registrations: StableBTreeMap::init(MapMemory::Heap(
+                VectorMemory::default(),
+            )),
+            inflight_challenges: StableBTreeMap::init(MapMemory::Heap(VectorMemory::default())),
+            temp_keys: TempKeys::new(StableBTreeMap::init(MapMemory::Heap(
+                VectorMemory::default(),
+            ))),
+            recovery_queue: RecoveryQueue::new(
+                StableBTreeMap::init(MapMemory::Heap(VectorMemory::default())),
+                StableBTreeMap::init(MapMemory::Heap(VectorMemory::default())),
+            ),
+            guardian_recovery_requests: StableBTreeMap::init(MapMemory::Heap(
+                VectorMemory::default(),
+            )),
+            guardian_wards: StableBTreeMap::init(MapMemory::Heap(VectorMemory::default())),
+            delegation_sessions: DelegationSessions::new(StableBTreeMap::init(MapMemory::Heap(
+                VectorMemory::default(),
+            ))),
+            revocation_epochs: RevocationEpochs::new(StableBTreeMap::init(MapMemory::Heap(
+                VectorMemory::default(),
+            ))),
+            maybe_persistent_state_memory: None,
+            maybe_anchor_chunks: None,
+            v8_migration_progress: None,
+            v8_migration_skipped: SkippedAnchors::default(),
+            maybe_memory_manager: None,
+        }
+    }
+
+    /// Creates the storage for the layout versions using the memory manager (i.e. version 7 and 8).
+    fn new_managed(header: Header, memory: M) -> Self {
+        let header_memory = RestrictedMemory::new(memory.clone(), 0..1);
+        let memory_manager = MemoryManager::init_with_bucket_size(
+            RestrictedMemory::new(memory, 1..MAX_WASM_PAGES),
+            BUCKET_SIZE_IN_PAGES,
+        );
+        let anchor_memory = AnchorMemory::Managed(memory_manager.get(ANCHOR_MEMORY_ID));
+        let tentative_device_registrations = StableBTreeMap::init(MapMemory::Managed(
+            memory_manager.get(TENTATIVE_DEVICE_REGISTRATIONS_MEMORY_ID),
+        ));
+        let inflight_challenges = StableBTreeMap::init(MapMemory::Managed(
+            memory_manager.get(INFLIGHT_CHALLENGES_MEMORY_ID),
+        ));
+        let temp_keys = TempKeys::new(StableBTreeMap::init(MapMemory::Managed(
+            memory_manager.get(TEMP_KEYS_MEMORY_ID),
+        )));
+        let recovery_queue = RecoveryQueue::new(
+            StableBTreeMap::init(MapMemory::Managed(
+                memory_manager.get(RECOVERY_QUEUE_MEMORY_ID),
+            )),
+            StableBTreeMap::init(MapMemory::Managed(
+                memory_manager.get(RECOVERY_QUEUE_SCHEDULE_MEMORY_ID),
+            )),
+        );
+        let guardian_recovery_requests = StableBTreeMap::init(MapMemory::Managed(
+            memory_manager.get(GUARDIAN_RECOVERY_REQUESTS_MEMORY_ID),
+        ));
+        let guardian_wards = StableBTreeMap::init(MapMemory::Managed(
+            memory_manager.get(GUARDIAN_WARDS_MEMORY_ID),
+        ));
+        let delegation_sessions = DelegationSessions::new(StableBTreeMap::init(
+            MapMemory::Managed(memory_manager.get(DELEGATION_SESSIONS_MEMORY_ID)),
+        ));
+        let revocation_epochs = RevocationEpochs::new(StableBTreeMap::init(MapMemory::Managed(
+            memory_manager.get(REVOCATION_EPOCHS_MEMORY_ID),
+        )));
+
+        // With version 7, the anchor chunks memory is only allocated once the migration to
+        // version 8 has been started.
+        // The migration is restarted from the first anchor, because the anchors might have been
+        // modified by a version that does not know about the migration (i.e. after a rollback).
+        let anchor_chunks_memory = memory_manager.get(ANCHOR_CHUNKS_MEMORY_ID);
+        let (maybe_anchor_chunks, v8_migration_progress) = match header.version {
+            7 if anchor_chunks_memory.size() > 0 => {
+                (Some(StableBTreeMap::init(anchor_chunks_memory)), Some(0))
             }
+            8 => (Some(StableBTreeMap::init(anchor_chunks_memory)), None),
+            _ => (None, None),
         };
 
-        let mut storage = Self {
-            header: Header {
-                magic: *b"IIC",
-                version,
-                num_anchors: 0,
-                id_range_lo,
-                id_range_hi,
-                entry_size: DEFAULT_ENTRY_SIZE,
-                salt: EMPTY_SALT,
-                first_entry_offset: ENTRY_OFFSET,
-            },
+        Self {
+            header,
             header_memory,
             anchor_memory,
-            maybe_memory_manager,
-        };
-        storage.flush();
-        storage
+            tentative_device_registrations,
+            inflight_challenges,
+            temp_keys,
+            recovery_queue,
+            guardian_recovery_requests,
+            guardian_wards,
+            delegation_sessions,
+            revocation_epochs,
+            maybe_persistent_state_memory: Some(memory_manager.get(PERSISTENT_STATE_MEMORY_ID)),
+            maybe_anchor_chunks,
+            v8_migration_progress,
+            v8_migration_skipped: SkippedAnchors::default(),
+            maybe_memory_manager: Some(memory_manager),
+        }
     }
 
     pub fn salt(&self) -> Option<&Salt> {
@@ -397,37 +701,19 @@ impl<M: Memory + Clone> Storage<M> {
         }
 
         match header.version {
-            6 => Some(Self {
-                header,
-                header_memory: RestrictedMemory::new(memory.clone(), 0..2),
-                anchor_memory: AnchorMemory::Single(RestrictedMemory::new(
-                    memory,
-                    2..MAX_WASM_PAGES,
-                )),
-                maybe_memory_manager: None,
-            }),
-            7 => {
-                let header_memory = RestrictedMemory::new(memory.clone(), 0..1);
-                let managed_memory = RestrictedMemory::new(memory, 1..MAX_WASM_PAGES);
-                let memory_manager =
-                    MemoryManager::init_with_bucket_size(managed_memory, BUCKET_SIZE_IN_PAGES);
-                let anchor_memory = AnchorMemory::Managed(memory_manager.get(ANCHOR_MEMORY_ID));
-                Some(Self {
-                    header,
-                    header_memory,
-                    anchor_memory,
-                    maybe_memory_manager: Some(memory_manager),
-                })
-            }
+            6 => Some(Self::new_single(header, memory)),
+            7 | 8 => Some(Self::new_managed(header, memory)),
             _ => trap(&format!("unsupported header version: {}", header.version)),
         }
     }
 
+    /// Initializes the storage from the given memory like [Storage::from_memory], migrating a
+    /// storage with layout version 6 to layout version 7 (i.e. the memory manager).
     pub fn from_memory_v6_to_v7(memory: M) -> Option<Self> {
         let maybe_storage_v6 = Self::from_memory(memory.clone());
         let storage_v6 = maybe_storage_v6?;
-        if storage_v6.header.version == 7 {
-            // Already at v7, no migration needed.
+        if storage_v6.header.version >= 7 {
+            // Already using the memory manager, no migration needed.
             return Some(storage_v6);
         }
         if storage_v6.header.version != 6 {
@@ -515,6 +801,106 @@ impl<M: Memory + Clone> Storage<M> {
         Self::from_memory(memory)
     }
 
+    /// Starts the incremental migration of the anchors from layout version 7 to layout version 8.
+    /// The anchors are then copied using [Storage::migrate_anchors_to_v8].
+    /// Does nothing if the storage already has layout version 8 or the migration is in progress.
+    pub fn start_migration_to_v8(&mut self) {
+        match self.header.version {
+            7 => {}
+            8 => return,
+            version => trap(&format!(
+                "stable memory layout version {version} cannot be migrated to version 8, \
+                 migrate to the memory manager (version 7) first"
+            )),
+        }
+        if self.v8_migration_progress.is_some() {
+            return;
+        }
+        let memory_manager = self
+            .maybe_memory_manager
+            .as_ref()
+            .expect("bug: layout version 7 without memory manager");
+        self.maybe_anchor_chunks = Some(StableBTreeMap::init(
+            memory_manager.get(ANCHOR_CHUNKS_MEMORY_ID),
+        ));
+        self.v8_migration_progress = Some(0);
+    }
+
+    /// Returns true if the anchors are being migrated to layout version 8.
+    pub fn is_migrating_to_v8(&self) -> bool {
+        self.v8_migration_progress.is_some()
+    }
+
+    /// Returns the progress of the migration to layout version 8, if it is in progress or if
+    /// anchors had to be skipped.
+    pub fn v8_migration_progress(&self) -> Option<StorageMigrationProgress> {
+        if self.v8_migration_progress.is_none() && self.v8_migration_skipped.count == 0 {
+            return None;
+        }
+        Some(StorageMigrationProgress {
+            migrated_anchors: self
+                .v8_migration_progress
+                .unwrap_or(self.header.num_anchors),
+            total_anchors: self.header.num_anchors,
+            num_skipped_anchors: self.v8_migration_skipped.count,
+            skipped_anchors: self.v8_migration_skipped.anchor_numbers.clone(),
+        })
+    }
+
+    /// Copies the records of up to `max_anchors` anchors to the anchor chunks of layout version 8.
+    /// Once all the anchors have been copied, the storage switches to layout version 8.
+    /// Records that cannot be read are copied as a whole (see module docs).
+    ///
+    /// Returns true if the migration is complete (or not in progress).
+    pub fn migrate_anchors_to_v8(&mut self, max_anchors: u32) -> bool {
+        let Some(progress) = self.v8_migration_progress else {
+            return true;
+        };
+        let end = progress
+            .saturating_add(max_anchors)
+            .min(self.header.num_anchors);
+        for record_number in progress..end {
+            let anchor_number = self.header.id_range_lo + record_number as u64;
+            let buf = match self.read_entry_bytes(record_number) {
+                Ok(buf) => buf,
+                Err(_) => {
+                    // keep the whole record, which does not decode as an anchor
+                    self.v8_migration_skipped.add(anchor_number);
+                    self.read_record_bytes(record_number)
+                }
+            };
+            // empty records belong to deleted anchors, which have no chunks
+            write_anchor_bytes(self.anchor_chunks_mut(), anchor_number, &buf);
+        }
+
+        if end < self.header.num_anchors {
+            self.v8_migration_progress = Some(end);
+            return false;
+        }
+        self.header.version = 8;
+        self.v8_migration_progress = None;
+        self.flush();
+        true
+    }
+
+    /// Returns true if the record has already been copied to the anchor chunks while migrating
+    /// to layout version 8, i.e. if it needs to be kept up to date in both locations.
+    fn is_copied_to_v8(&self, record_number: u32) -> bool {
+        matches!(self.v8_migration_progress, Some(progress) if record_number < progress)
+    }
+
+    fn anchor_chunks(&self) -> &AnchorChunks<VirtualMemory<RestrictedMemory<M>>> {
+        self.maybe_anchor_chunks
+            .as_ref()
+            .expect("bug: anchor chunks not initialized")
+    }
+
+    fn anchor_chunks_mut(&mut self) -> &mut AnchorChunks<VirtualMemory<RestrictedMemory<M>>> {
+        self.maybe_anchor_chunks
+            .as_mut()
+            .expect("bug: anchor chunks not initialized")
+    }
+
     /// Allocates a fresh Identity Anchor.
     ///
     /// Returns None if the range of Identity Anchor assigned to this
@@ -529,14 +915,91 @@ impl<M: Memory + Clone> Storage<M> {
         Some((anchor_number, Anchor::new()))
     }
 
+    /// Allocates all the Identity Anchors up to and including the given one (e.g. to import an
+    /// anchor from another canister). Anchors allocated but never written read as deleted: their
+    /// records are zeroed like in [Storage::delete] as they may still contain stale data, e.g. the
+    /// persistent state written after the anchors by a previous version.
+    ///
+    /// Does nothing if the anchor has already been allocated.
+    pub fn allocate_anchors_up_to(
+        &mut self,
+        anchor_number: AnchorNumber,
+    ) -> Result<(), StorageError> {
+        if anchor_number < self.header.id_range_lo || anchor_number >= self.header.id_range_hi {
+            return Err(StorageError::AnchorNumberOutOfRange {
+                anchor_number,
+                range: self.assigned_anchor_number_range(),
+            });
+        }
+        let num_anchors = (anchor_number - self.header.id_range_lo + 1) as u32;
+        if num_anchors > self.header.num_anchors {
+            if self.header.version != 8 {
+                for record_number in self.header.num_anchors..num_anchors {
+                    self.zero_record(record_number);
+                }
+            }
+            self.header.num_anchors = num_anchors;
+            self.flush();
+        }
+        Ok(())
+    }
+
     /// Writes the data of the specified anchor to stable memory.
     pub fn write(&mut self, anchor_number: AnchorNumber, data: Anchor) -> Result<(), StorageError> {
         let record_number = self.anchor_number_to_record(anchor_number)?;
         let buf = candid::encode_one(data).map_err(StorageError::SerializationError)?;
-        self.write_entry_bytes(record_number, buf)
+        if self.header.version == 8 {
+            return self.write_anchor_chunks(anchor_number, &buf);
+        }
+
+        self.write_entry_bytes(record_number, &buf)?;
+        if self.is_copied_to_v8(record_number) {
+            self.write_anchor_chunks(anchor_number, &buf)?;
+        }
+        Ok(())
+    }
+
+    fn write_anchor_chunks(
+        &mut self,
+        anchor_number: AnchorNumber,
+        buf: &[u8],
+    ) -> Result<(), StorageError> {
+        if buf.len() > MAX_ANCHOR_SIZE {
+            return Err(StorageError::EntrySizeLimitExceeded(buf.len()));
+        }
+        write_anchor_bytes(self.anchor_chunks_mut(), anchor_number, buf);
+        Ok(())
+    }
+
+    /// Deletes the data of the specified anchor from stable memory.
+    /// The record of the anchor is overwritten with zeros (with layout version 8, the chunks of the
+    /// anchor are removed) and the anchor number is not reused.
+    pub fn delete(&mut self, anchor_number: AnchorNumber) -> Result<(), StorageError> {
+        let record_number = self.anchor_number_to_record(anchor_number)?;
+        if self.header.version == 8 || self.is_copied_to_v8(record_number) {
+            delete_anchor_bytes(self.anchor_chunks_mut(), anchor_number);
+        }
+        if self.header.version == 8 {
+            return Ok(());
+        }
+
+        self.zero_record(record_number);
+        Ok(())
+    }
+
+    fn zero_record(&mut self, record_number: u32) {
+        let address = self.record_address(record_number);
+        let writer_cell = self
+            .anchor_memory
+            .get_writer(address, self.header.entry_size as usize);
+        let mut writer = writer_cell.borrow_mut();
+        writer
+            .write_all(&vec![0; self.header.entry_size as usize])
+            .expect("memory write failed");
+        writer.flush().expect("memory write failed");
     }
 
-    fn write_entry_bytes(&mut self, record_number: u32, buf: Vec<u8>) -> Result<(), StorageError> {
+    fn write_entry_bytes(&mut self, record_number: u32, buf: &[u8]) -> Result<(), StorageError> {
         if buf.len() > self.candid_entry_size_limit() {
             return Err(StorageError::EntrySizeLimitExceeded(buf.len()));
         }
@@ -549,7 +1012,7 @@ impl<M: Memory + Clone> Storage<M> {
         writer
             .write_all(&(buf.len() as u16).to_le_bytes())
             .expect("memory write failed");
-        writer.write_all(&buf).expect("memory write failed");
+        writer.write_all(buf).expect("memory write failed");
         writer.flush().expect("memory write failed");
         Ok(())
     }
@@ -557,11 +1020,27 @@ impl<M: Memory + Clone> Storage<M> {
     /// Reads the data of the specified anchor from stable memory.
     pub fn read(&self, anchor_number: AnchorNumber) -> Result<Anchor, StorageError> {
         let record_number = self.anchor_number_to_record(anchor_number)?;
-        let data_buf = self.read_entry_bytes(record_number);
-        candid::decode_one(&data_buf).map_err(StorageError::DeserializationError)
+        let data_buf = if self.header.version == 8 {
+            read_anchor_bytes(self.anchor_chunks(), anchor_number)
+        } else {
+            self.read_entry_bytes(record_number)?
+        };
+        if data_buf.is_empty() {
+            // empty records belong to deleted anchors (see [Storage::delete])
+            return Err(StorageError::AnchorDeleted(anchor_number));
+        }
+        let anchor: Anchor =
+            candid::decode_one(&data_buf).map_err(StorageError::DeserializationError)?;
+        if let Some(canister_id) = anchor.moved_to() {
+            return Err(StorageError::AnchorMoved {
+                anchor_number,
+                canister_id,
+            });
+        }
+        Ok(anchor)
     }
 
-    fn read_entry_bytes(&self, record_number: u32) -> Vec<u8> {
+    fn read_entry_bytes(&self, record_number: u32) -> Result<Vec<u8>, StorageError> {
         let address = self.record_address(record_number);
         // the reader will check stable memory bounds
         // use buffered reader to minimize expensive stable memory operations
@@ -577,18 +1056,31 @@ impl<M: Memory + Clone> Storage<M> {
 
         // This error most likely indicates stable memory corruption.
         if len > self.candid_entry_size_limit() {
-            trap(&format!(
-                "persisted value size {} exceeds maximum size {}",
-                len,
-                self.candid_entry_size_limit()
-            ))
+            return Err(StorageError::PersistedEntrySizeExceeded {
+                length: len,
+                limit: self.candid_entry_size_limit(),
+            });
         }
 
         let mut data_buf = vec![0; len];
         reader
             .read_exact(data_buf.as_mut_slice())
             .expect("failed to read memory");
-        data_buf
+        Ok(data_buf)
+    }
+
+    /// Reads the whole record, including the length of the candid encoded entry.
+    fn read_record_bytes(&self, record_number: u32) -> Vec<u8> {
+        let address = self.record_address(record_number);
+        let reader_cell = self
+            .anchor_memory
+            .get_reader(address, self.header.entry_size as usize);
+        let mut buf = vec![0; self.header.entry_size as usize];
+        reader_cell
+            .borrow_mut()
+            .read_exact(buf.as_mut_slice())
+            .expect("failed to read memory");
+        buf
     }
 
     /// Make sure all the required metadata is recorded to stable memory.
@@ -691,9 +1183,96 @@ impl<M: Memory + Clone> Storage<M> {
         self.record_address(self.header.num_anchors)
     }
 
-    /// Writes the persistent state to stable memory just outside of the space allocated to the highest anchor number.
-    /// This is only used to _temporarily_ save state during upgrades. It will be overwritten on next anchor registration.
+    /// Returns true if the persistent state is written to its dedicated memory, in which case it
+    /// should be written after changes rather than only in `pre_upgrade`.
+    pub fn has_persistent_state_memory(&self) -> bool {
+        self.maybe_persistent_state_memory.is_some()
+    }
+
+    /// Writes the persistent state to its dedicated virtual memory (layout version 7 and 8) or to
+    /// stable memory just outside of the space allocated to the highest anchor number (layout
+    /// version 6).
+    /// In the latter case, this is only used to _temporarily_ save state during upgrades. It will
+    /// be overwritten on next anchor registration.
     pub fn write_persistent_state(&mut self, state: &PersistentState) {
+        let buffer_size = self.header.entry_size as usize;
+        let Some(memory) = self.maybe_persistent_state_memory.as_mut() else {
+            self.write_persistent_state_after_anchors(state);
+            return;
+        };
+        write_upgrade_data(memory, PERSISTENT_STATE_MAGIC, state, buffer_size);
+    }
+
+    /// With layout version 7, copies the persistent state from its dedicated memory to after the
+    /// anchor records, where the previous version reads it from. The already encoded state is copied
+    /// as is, i.e. it is not serialized again. Only to be used in `pre_upgrade` to keep a rollback to
+    /// the previous version possible (see module docs).
+    pub fn copy_persistent_state_for_rollback(&mut self) {
+        if self.header.version != 7 {
+            return;
+        }
+        let Some(memory) = self.maybe_persistent_state_memory.as_ref() else {
+            return;
+        };
+        if memory.size() == 0 {
+            // the persistent state has never been written
+            return;
+        }
+        let mut header_buf = [0u8; 12];
+        memory.read(0, &mut header_buf);
+        if header_buf[0..4] != PERSISTENT_STATE_MAGIC {
+            return;
+        }
+        let size = u64::from_le_bytes(header_buf[4..12].try_into().unwrap());
+        let mut data_buf = vec![0; header_buf.len() + size as usize];
+        memory.read(0, &mut data_buf);
+
+        let address = self.unused_memory_start();
+        let writer_cell = self
+            .anchor_memory
+            .get_writer(address, self.header.entry_size as usize);
+        writer_cell.borrow_mut().write_all(&data_buf).unwrap();
+    }
+
+    /// With layout version 7, discards the copy of the persistent state after the anchor records
+    /// (see [Storage::copy_persistent_state_for_rollback]) once it has been read in `post_upgrade`.
+    /// From then on, the persistent state in its dedicated memory is the up to date one and a stale
+    /// copy must not take precedence should the next `pre_upgrade` not write a new one.
+    pub fn discard_persistent_state_after_anchors(&mut self) {
+        if self.header.version != 7 || !self.has_persistent_state_memory() {
+            return;
+        }
+        if let Err(PersistentStateError::NotFound) = self.read_persistent_state_after_anchors() {
+            return;
+        }
+        let address = self.unused_memory_start();
+        let writer_cell = self
+            .anchor_memory
+            .get_writer(address, self.header.entry_size as usize);
+        writer_cell.borrow_mut().write_all(&[0u8; 4]).unwrap();
+    }
+
+    /// Reads the persistent state written using [Storage::write_persistent_state].
+    pub fn read_persistent_state(&self) -> Result<PersistentState, PersistentStateError> {
+        let Some(memory) = self.maybe_persistent_state_memory.as_ref() else {
+            return self.read_persistent_state_after_anchors();
+        };
+        if self.header.version == 7 {
+            // a persistent state after the anchors has been written by the last `pre_upgrade`, possibly
+            // of a previous version (i.e. after a rollback)
+            match self.read_persistent_state_after_anchors() {
+                Err(PersistentStateError::NotFound) => {}
+                result => return result,
+            }
+        }
+        read_upgrade_data(
+            memory,
+            PERSISTENT_STATE_MAGIC,
+            self.header.entry_size as usize,
+        )
+    }
+
+    fn write_persistent_state_after_anchors(&mut self, state: &PersistentState) {
         let address = self.unused_memory_start();
 
         // In practice, candid encoding is infallible. The Result is an artifact of the serde API.
@@ -712,9 +1291,7 @@ impl<M: Memory + Clone> Storage<M> {
         writer.write_all(&encoded_state).unwrap();
     }
 
-    /// Reads the persistent state from stable memory just outside of the space allocated to the highest anchor number.
-    /// This is only used to restore state in `post_upgrade`.
-    pub fn read_persistent_state(&self) -> Result<PersistentState, PersistentStateError> {
+    fn read_persistent_state_after_anchors(&self) -> Result<PersistentState, PersistentStateError> {
         const WASM_PAGE_SIZE: u64 = 65536;
         let address = self.unused_memory_start();
         if address > self.anchor_memory.size() * WASM_PAGE_SIZE {
@@ -753,9 +1330,132 @@ impl<M: Memory + Clone> Storage<M> {
         candid::decode_one(&data_buf).map_err(PersistentStateError::CandidError)
     }
 
+    pub fn tentative_device_registrations(&self) -> &TentativeDeviceRegistrations<M> {
+        &self.tentative_device_registrations
+    }
+
+    pub fn tentative_device_registrations_mut(&mut self) -> &mut TentativeDeviceRegistrations<M> {
+        &mut self.tentative_device_registrations
+    }
+
+    pub fn inflight_challenges(&self) -> &InflightChallenges<M> {
+        &self.inflight_challenges
+    }
+
+    pub fn inflight_challenges_mut(&mut self) -> &mut InflightChallenges<M> {
+        &mut self.inflight_challenges
+    }
+
+    pub fn temp_keys(&self) -> &TempKeys<M> {
+        &self.temp_keys
+    }
+
+    pub fn temp_keys_mut(&mut self) -> &mut TempKeys<M> {
+        &mut self.temp_keys
+    }
+
+    pub fn recovery_queue(&self) -> &RecoveryQueue<M> {
+        &self.recovery_queue
+    }
+
+    pub fn recovery_queue_mut(&mut self) -> &mut RecoveryQueue<M> {
+        &mut self.recovery_queue
+    }
+
+    pub fn guardian_recovery_requests(&self) -> &GuardianRecoveryRequests<M> {
+        &self.guardian_recovery_requests
+    }
+
+    pub fn guardian_recovery_requests_mut(&mut self) -> &mut GuardianRecoveryRequests<M> {
+        &mut self.guardian_recovery_requests
+    }
+
+    pub fn guardian_wards(&self) -> &GuardianWards<M> {
+        &self.guardian_wards
+    }
+
+    pub fn guardian_wards_mut(&mut self) -> &mut GuardianWards<M> {
+        &mut self.guardian_wards
+    }
+
+    pub fn delegation_sessions(&self) -> &DelegationSessions<M> {
+        &self.delegation_sessions
+    }
+
+    pub fn delegation_sessions_mut(&mut self) -> &mut DelegationSessions<M> {
+        &mut self.delegation_sessions
+    }
+
+    pub fn revocation_epochs(&self) -> &RevocationEpochs<M> {
+        &self.revocation_epochs
+    }
+
+    pub fn revocation_epochs_mut(&mut self) -> &mut RevocationEpochs<M> {
+        &mut self.revocation_epochs
+    }
+
     pub fn version(&self) -> u8 {
         self.header.version
     }
+
+    /// Returns the limits of the anchors that fit into this storage (see [AnchorLimits]).
+    pub fn anchor_limits(&self) -> AnchorLimits {
+        if self.header.version == 8 {
+            AnchorLimits::CHUNKED
+        } else {
+            AnchorLimits::RECORD
+        }
+    }
+}
+
+/// Writes the candid encoded `data` prefixed by the magic bytes and its length to the start of the
+/// given memory (i.e. the same format as the [PersistentState]).
+fn write_upgrade_data<M: Memory, T: CandidType + ?Sized>(
+    memory: &mut M,
+    magic: [u8; 4],
+    data: &T,
+    buffer_size: usize,
+) {
+    // In practice, candid encoding is infallible. The Result is an artifact of the serde API.
+    let encoded_data = candid::encode_one(data).unwrap();
+
+    let mut writer = BufferedMemoryWriter::new(memory, 0, buffer_size);
+    writer.write_all(&magic).unwrap();
+    writer
+        .write_all(&(encoded_data.len() as u64).to_le_bytes())
+        .unwrap();
+    writer.write_all(&encoded_data).unwrap();
+    writer.flush().unwrap();
+}
+
+/// Reads data written using [write_upgrade_data], e.g. by previous versions in `pre_upgrade`.
+fn read_upgrade_data<M: Memory, T: CandidType + DeserializeOwned>(
+    memory: &M,
+    magic: [u8; 4],
+    buffer_size: usize,
+) -> Result<T, PersistentStateError> {
+    let mut reader = BufferedMemoryReader::new(memory, 0, buffer_size);
+    let mut magic_buf: [u8; 4] = [0; 4];
+    reader
+        .read_exact(&mut magic_buf)
+        // out of bounds means that the data has never been written
+        .map_err(|_| PersistentStateError::NotFound)?;
+    if magic_buf != magic {
+        return Err(PersistentStateError::NotFound);
+    }
+
+    let mut size_buf: [u8; 8] = [0; 8];
+    reader
+        .read_exact(&mut size_buf)
+        .map_err(PersistentStateError::ReadError)?;
+
+    let size = u64::from_le_bytes(size_buf);
+    let mut data_buf = vec![0; size as usize];
+    reader
+        .read_exact(data_buf.as_mut_slice())
+        .map_err(PersistentStateError::ReadError)?;
+
+    candid::decode_one(&data_buf).map_err(PersistentStateError::CandidError)
 }
 
 #[derive(Debug)]
@@ -772,9 +1472,21 @@ pub enum StorageError {
         range: (AnchorNumber, AnchorNumber),
     },
     BadAnchorNumber(u64),
+    AnchorDeleted(AnchorNumber),
+    // The anchor has been moved to another canister, only a tombstone is left.
+    AnchorMoved {
+        anchor_number: AnchorNumber,
+        canister_id: Principal,
+    },
     DeserializationError(candid::error::Error),
     SerializationError(candid::error::Error),
     EntrySizeLimitExceeded(usize),
+    // The size of a persisted entry exceeds the maximum entry size, most likely indicating stable
+    // memory corruption.
+    PersistedEntrySizeExceeded {
+        length: usize,
+        limit: usize,
+    },
 }
 
 impl fmt::Display for StorageError {
@@ -789,6 +1501,14 @@ impl fmt::Display for StorageError {
                 anchor_number, range.0, range.1
             ),
             Self::BadAnchorNumber(n) => write!(f, "bad Identity Anchor {n}"),
+            Self::AnchorDeleted(n) => write!(f, "Identity Anchor {n} has been deleted"),
+            Self::AnchorMoved {
+                anchor_number,
+                canister_id,
+            } => write!(
+                f,
+                "Identity Anchor {anchor_number} has moved to canister {canister_id}"
+            ),
             Self::DeserializationError(err) => {
                 write!(f, "failed to deserialize a Candid value: {err}")
             }
@@ -800,6 +1520,10 @@ impl fmt::Display for StorageError {
                 "attempted to store an entry of size {n} \
                  which is larger then the max allowed entry size"
             ),
+            Self::PersistedEntrySizeExceeded { length, limit } => write!(
+                f,
+                "persisted value size {length} exceeds maximum size {limit}"
+            ),
         }
     }
 }
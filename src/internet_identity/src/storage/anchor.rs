@@ -3,6 +3,8 @@ use crate::{IC0_APP_ORIGIN, INTERNETCOMPUTER_ORG_ORIGIN};
 use candid::{CandidType, Deserialize, Principal};
 use internet_identity_interface::archive::types::DeviceDataWithoutAlias;
 use internet_identity_interface::internet_identity::types::*;
+use serde::Serialize;
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::{fmt, iter};
 
@@ -13,9 +15,44 @@ mod tests;
 /// The anchor has limited visibility for the constructor to make sure it is loaded from storage.
 /// The devices can only be modified by the exposed functions which keeps invariant checking local
 /// to this module.
-#[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Anchor {
     devices: Vec<Device>,
+    // If set, destructive operations authenticated by a recovery device are delayed by the given
+    // amount of time (see [recovery_delay](crate::anchor_management::recovery_delay)).
+    recovery_delay_ns: Option<u64>,
+    // If set, the given guardians can jointly add a device to the anchor
+    // (see [guardian_recovery](crate::anchor_management::guardian_recovery)).
+    guardians: Option<Guardians>,
+    // If set, the anchor has been moved to the given canister and this anchor is only a tombstone
+    // without any devices (see [transfer](crate::anchor_management::transfer)).
+    moved_to: Option<Principal>,
+}
+
+/// Other anchors that can jointly recover the anchor, i.e. add a new device to it.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Guardians {
+    pub guardians: Vec<AnchorNumber>,
+    // number of guardians required to approve a recovery
+    pub threshold: u8,
+}
+
+impl From<GuardianConfig> for Guardians {
+    fn from(config: GuardianConfig) -> Self {
+        Self {
+            guardians: config.guardians,
+            threshold: config.threshold,
+        }
+    }
+}
+
+impl From<Guardians> for GuardianConfig {
+    fn from(guardians: Guardians) -> Self {
+        Self {
+            guardians: guardians.guardians,
+            threshold: guardians.threshold,
+        }
+    }
 }
 
 impl Device {
@@ -100,16 +137,29 @@ impl Anchor {
     /// Creation of new anchors is restricted in order to make sure that the device checks are
     /// not accidentally bypassed.
     pub(super) fn new() -> Anchor {
-        Self { devices: vec![] }
+        Self {
+            devices: vec![],
+            recovery_delay_ns: None,
+            guardians: None,
+            moved_to: None,
+        }
     }
 
-    pub fn add_device(&mut self, device: Device) -> Result<(), AnchorError> {
-        if self.devices.iter().any(|e| e.pubkey == device.pubkey) {
-            return Err(AnchorError::DuplicateDevice {
-                device_key: device.pubkey,
-            });
+    /// Returns a tombstone for an anchor that has been moved to the given canister.
+    pub fn moved(canister_id: Principal) -> Anchor {
+        Self {
+            moved_to: Some(canister_id),
+            ..Self::new()
         }
-        check_device_invariants(&device)?;
+    }
+
+    /// Returns the canister the anchor has been moved to, if it is a tombstone.
+    pub fn moved_to(&self) -> Option<Principal> {
+        self.moved_to
+    }
+
+    pub fn add_device(&mut self, device: Device) -> Result<(), AnchorError> {
+        check_device_addition(&self.devices, &device)?;
         check_anchor_invariants(&self.devices.iter().chain(iter::once(&device)).collect())?;
         self.devices.push(device);
         Ok(())
@@ -120,7 +170,7 @@ impl Anchor {
     /// violated by removing a device. See also the documentation on
     /// [check_invariants](Anchor::check_invariants).
     pub fn remove_device(&mut self, device_key: &DeviceKey) -> Result<(), AnchorError> {
-        let index = self.device_index(device_key)?;
+        let index = device_index(&self.devices, device_key)?;
         check_mutation_allowed(&self.devices[index])?;
 
         self.devices.remove(index);
@@ -137,12 +187,7 @@ impl Anchor {
         device_key: &DeviceKey,
         modified_device: Device,
     ) -> Result<(), AnchorError> {
-        if device_key != &modified_device.pubkey {
-            return Err(AnchorError::CannotModifyDeviceKey);
-        }
-        check_device_invariants(&modified_device)?;
-        let index = self.device_index(device_key)?;
-        check_mutation_allowed(&self.devices[index])?;
+        let index = check_device_modification(&self.devices, device_key, &modified_device)?;
         check_anchor_invariants(
             &self
                 .devices
@@ -158,11 +203,13 @@ impl Anchor {
         Ok(())
     }
 
-    fn device_index(&self, device_key: &DeviceKey) -> Result<usize, AnchorError> {
-        let Some(index) = self.devices.iter().position(|e| e.pubkey == device_key) else {
-            return Err(AnchorError::NotFound {device_key: device_key.clone()});
-        };
-        Ok(index)
+    /// Starts a batch of device modifications that is applied to this anchor as a whole,
+    /// see [AnchorBatch].
+    pub fn batch(&mut self) -> AnchorBatch<'_> {
+        AnchorBatch {
+            devices: self.devices.clone(),
+            anchor: self,
+        }
     }
 
     /// Returns a reference to the device given the key.
@@ -175,6 +222,24 @@ impl Anchor {
         &self.devices
     }
 
+    /// Returns the delay applied to destructive operations authenticated by a recovery device, if any.
+    pub fn recovery_delay_ns(&self) -> Option<u64> {
+        self.recovery_delay_ns
+    }
+
+    pub fn set_recovery_delay_ns(&mut self, recovery_delay_ns: Option<u64>) {
+        self.recovery_delay_ns = recovery_delay_ns;
+    }
+
+    /// Returns the guardians that can jointly recover the anchor, if any.
+    pub fn guardians(&self) -> Option<&Guardians> {
+        self.guardians.as_ref()
+    }
+
+    pub fn set_guardians(&mut self, guardians: Option<Guardians>) {
+        self.guardians = guardians;
+    }
+
     /// Consumes self and exposes the devices.
     pub fn into_devices(self) -> Vec<Device> {
         self.devices
@@ -196,6 +261,47 @@ impl Anchor {
         Ok(())
     }
 
+    /// Checks the device and anchor invariants (see [check_device_invariants] and
+    /// [check_anchor_invariants]) on the anchor as a whole.
+    ///
+    /// Mutations only check the invariants on the devices they affect, so anchors written before
+    /// an invariant was introduced might still violate it (e.g. anchors with multiple recovery
+    /// phrases).
+    pub fn check_invariants(&self) -> InvariantViolations {
+        InvariantViolations {
+            devices: self
+                .devices
+                .iter()
+                .filter_map(|device| {
+                    check_device_invariants(device)
+                        .err()
+                        .map(|err| (device.pubkey.clone(), err))
+                })
+                .collect(),
+            anchor: check_anchor_invariants(&self.devices.iter().collect()).err(),
+        }
+    }
+
+    /// Truncates the aliases exceeding the length limit (at a character boundary) and returns
+    /// the keys of the modified devices.
+    /// **Note:** Does not check invariants, based on the assumption that no invariant can be
+    /// violated by shortening an alias.
+    pub fn truncate_aliases(&mut self) -> Vec<DeviceKey> {
+        let mut truncated = vec![];
+        for device in self.devices.iter_mut() {
+            if device.alias.len() <= ALIAS_LEN_LIMIT {
+                continue;
+            }
+            let mut len = ALIAS_LEN_LIMIT;
+            while !device.alias.is_char_boundary(len) {
+                len -= 1;
+            }
+            device.alias.truncate(len);
+            truncated.push(device.pubkey.clone());
+        }
+        truncated
+    }
+
     /// Returns the timestamp of the last known activity, if any.
     pub fn last_activity(&self) -> Option<Timestamp> {
         let mut timestamps: Vec<Option<Timestamp>> = self
@@ -278,7 +384,7 @@ pub enum DomainActivity {
 /// backwards compatible level between device data stored in stable memory.
 /// It is similar to `DeviceDataInternal` but with redundant options removed
 /// (which is possible due to the stable memory candid schema migration).
-#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Device {
     pub pubkey: DeviceKey,
     pub alias: String,
@@ -322,13 +428,115 @@ impl Device {
     }
 }
 
+/// Device modifications that are applied to an anchor as a whole.
+///
+/// The device operations check the same conditions as the corresponding operations on [Anchor],
+/// except for the anchor invariants which are checked only once on the resulting set of devices
+/// when the batch is committed. This allows for example to add a device to an anchor that has
+/// reached the device limit, as long as another device is removed within the same batch.
+///
+/// The anchor is only modified by [commit](AnchorBatch::commit). Dropping the batch discards
+/// all modifications.
+pub struct AnchorBatch<'a> {
+    anchor: &'a mut Anchor,
+    devices: Vec<Device>,
+}
+
+impl AnchorBatch<'_> {
+    /// Returns a reference to the device given the key, taking into account the modifications
+    /// made so far.
+    pub fn device(&self, device_key: &DeviceKey) -> Option<&Device> {
+        self.devices.iter().find(|e| e.pubkey == device_key)
+    }
+
+    pub fn add_device(&mut self, device: Device) -> Result<(), AnchorError> {
+        check_device_addition(&self.devices, &device)?;
+        self.devices.push(device);
+        Ok(())
+    }
+
+    pub fn remove_device(&mut self, device_key: &DeviceKey) -> Result<(), AnchorError> {
+        let index = device_index(&self.devices, device_key)?;
+        check_mutation_allowed(&self.devices[index])?;
+        self.devices.remove(index);
+        Ok(())
+    }
+
+    pub fn modify_device(
+        &mut self,
+        device_key: &DeviceKey,
+        modified_device: Device,
+    ) -> Result<(), AnchorError> {
+        let index = check_device_modification(&self.devices, device_key, &modified_device)?;
+        self.devices[index] = modified_device;
+        Ok(())
+    }
+
+    /// Checks the anchor invariants on the resulting set of devices and applies the modifications
+    /// to the anchor. The anchor is left unchanged if an error is returned.
+    pub fn commit(self) -> Result<(), AnchorError> {
+        check_anchor_invariants(&self.devices.iter().collect())?;
+        self.anchor.devices = self.devices;
+        Ok(())
+    }
+}
+
+fn device_index(devices: &[Device], device_key: &DeviceKey) -> Result<usize, AnchorError> {
+    let Some(index) = devices.iter().position(|e| e.pubkey == device_key) else {
+        return Err(AnchorError::NotFound {device_key: device_key.clone()});
+    };
+    Ok(index)
+}
+
+/// Checks that the device can be added to the given devices (not considering the anchor invariants).
+fn check_device_addition(devices: &[Device], device: &Device) -> Result<(), AnchorError> {
+    if devices.iter().any(|e| e.pubkey == device.pubkey) {
+        return Err(AnchorError::DuplicateDevice {
+            device_key: device.pubkey.clone(),
+        });
+    }
+    check_device_invariants(device)
+}
+
+/// Checks that the device with the given key can be replaced by `modified_device` (not considering
+/// the anchor invariants) and returns the index of the device to be modified.
+fn check_device_modification(
+    devices: &[Device],
+    device_key: &DeviceKey,
+    modified_device: &Device,
+) -> Result<usize, AnchorError> {
+    if device_key != &modified_device.pubkey {
+        return Err(AnchorError::CannotModifyDeviceKey);
+    }
+    check_device_invariants(modified_device)?;
+    let index = device_index(devices, device_key)?;
+    check_mutation_allowed(&devices[index])?;
+    Ok(index)
+}
+
+thread_local! {
+    // Principal on whose behalf anchors are modified instead of the caller, see [with_acting_principal]
+    static ACTING_PRINCIPAL: Cell<Option<Principal>> = Cell::new(None);
+}
+
+/// Runs `f` checking the modifications of protected devices against the given principal instead
+/// of the caller. Used to apply operations requested by the given principal in a previous call,
+/// e.g. from a canister timer (where the caller is the canister itself).
+pub fn with_acting_principal<R>(principal: Principal, f: impl FnOnce() -> R) -> R {
+    ACTING_PRINCIPAL.with(|acting| acting.set(Some(principal)));
+    let result = f();
+    ACTING_PRINCIPAL.with(|acting| acting.set(None));
+    result
+}
+
 fn check_mutation_allowed(device: &Device) -> Result<(), AnchorError> {
     match device.protection {
         DeviceProtection::Unprotected => (),
         DeviceProtection::Protected => {
-            if caller() != Principal::self_authenticating(&device.pubkey) {
+            let actual_principal = ACTING_PRINCIPAL.with(Cell::get).unwrap_or_else(caller);
+            if actual_principal != Principal::self_authenticating(&device.pubkey) {
                 return Err(AnchorError::MutationNotAllowed {
-                    actual_principal: caller(),
+                    actual_principal,
                     authorized_principal: Principal::self_authenticating(&device.pubkey),
                 });
             }
@@ -348,9 +556,58 @@ fn caller() -> Principal {
     tests::test_caller()
 }
 
+/// Limits on the devices of an anchor checked by [check_anchor_invariants]. They depend on the
+/// storage layout, as anchors need to fit into the fixed size records of layout versions 6 and 7
+/// (see [crate::storage]).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AnchorLimits {
+    /// The number of devices is limited. The front-end limits the devices further
+    /// by only allowing 8 devices with purpose `authentication` to make sure there is always
+    /// a slot for the recovery devices.
+    /// Note however, that a free device slot does not guarantee that it will fit the the anchor
+    /// due to the `variable_fields_limit`.
+    max_devices: usize,
+
+    /// Single devices can use >500 bytes for the variable length fields alone.
+    /// In order to not give away all the anchor space to the device vector, we limit the sum of the
+    /// size of all variable fields of all devices. This ensures that we have the flexibility to expand
+    /// or change anchors in the future.
+    variable_fields_limit: usize,
+}
+
+impl AnchorLimits {
+    /// Limits of anchors stored in 4 KB records (layout versions 6 and 7).
+    /// The variable fields limit of 2500 was chosen so to accommodate pre-memory-migration anchors
+    /// (limited to 2048 bytes) plus an additional 452 bytes to fit new fields introduced since.
+    pub const RECORD: AnchorLimits = AnchorLimits {
+        max_devices: 10,
+        variable_fields_limit: 2500,
+    };
+
+    /// Limits of anchors stored in chunks (layout version 8). Anchors at these limits use about
+    /// half of [MAX_ANCHOR_SIZE](crate::storage::anchor_chunks::MAX_ANCHOR_SIZE), leaving room for
+    /// future fields.
+    pub const CHUNKED: AnchorLimits = AnchorLimits {
+        max_devices: 32,
+        variable_fields_limit: 32_000,
+    };
+}
+
+thread_local! {
+    // Limits of the anchors, set according to the layout of the storage, see [set_anchor_limits]
+    static ANCHOR_LIMITS: Cell<AnchorLimits> = Cell::new(AnchorLimits::RECORD);
+}
+
+/// Sets the limits checked by [check_anchor_invariants] on all subsequent modifications of anchors.
+/// Needs to be called whenever the storage layout changes, see
+/// [Storage::anchor_limits](crate::storage::Storage::anchor_limits).
+pub fn set_anchor_limits(limits: AnchorLimits) {
+    ANCHOR_LIMITS.with(|anchor_limits| anchor_limits.set(limits));
+}
+
 /// This checks anchor invariants, in particular:
-///   * Max number of devices
-///   * Sum of sizes of all variable length fields does not exceed limit
+///   * Max number of devices (see [AnchorLimits])
+///   * Sum of sizes of all variable length fields does not exceed limit (see [AnchorLimits])
 ///   * There can only be one recovery phrase
 ///
 /// **Important:**
@@ -360,25 +617,12 @@ fn caller() -> Principal {
 /// To allow that transition, [remove_device](Anchor::remove_device) does _not_ check the invariants based on the assumption
 /// that the state of an anchor cannot get worse by removing a device.
 fn check_anchor_invariants(devices: &Vec<&Device>) -> Result<(), AnchorError> {
-    /// The number of devices is limited. The front-end limits the devices further
-    /// by only allowing 8 devices with purpose `authentication` to make sure there is always
-    /// a slot for the recovery devices.
-    /// Note however, that a free device slot does not guarantee that it will fit the the anchor
-    /// due to the `VARIABLE_FIELDS_LIMIT`.
-    const MAX_DEVICES_PER_ANCHOR: usize = 10;
+    let limits = ANCHOR_LIMITS.with(Cell::get);
 
-    /// Single devices can use >500 bytes for the variable length fields alone.
-    /// In order to not give away all the anchor space to the device vector, we limit the sum of the
-    /// size of all variable fields of all devices. This ensures that we have the flexibility to expand
-    /// or change anchors in the future.
-    /// The value 2500 was chosen so to accommodate pre-memory-migration anchors (limited to 2048 bytes)
-    /// plus an additional 452 bytes to fit new fields introduced since.
-    const VARIABLE_FIELDS_LIMIT: usize = 2500;
-
-    if devices.len() > MAX_DEVICES_PER_ANCHOR {
+    if devices.len() > limits.max_devices {
         return Err(AnchorError::TooManyDevices {
             num_devices: devices.len(),
-            limit: MAX_DEVICES_PER_ANCHOR,
+            limit: limits.max_devices,
         });
     }
 
@@ -388,10 +632,10 @@ fn check_anchor_invariants(devices: &Vec<&Device>) -> Result<(), AnchorError> {
         .map(|device| device.variable_fields_len())
         .sum();
 
-    if existing_variable_size > VARIABLE_FIELDS_LIMIT {
+    if existing_variable_size > limits.variable_fields_limit {
         return Err(AnchorError::CumulativeDataLimitExceeded {
             length: existing_variable_size,
-            limit: VARIABLE_FIELDS_LIMIT,
+            limit: limits.variable_fields_limit,
         });
     }
 
@@ -453,10 +697,13 @@ fn check_device_invariants(device: &Device) -> Result<(), AnchorError> {
     Ok(())
 }
 
+/// Maximum length of the device alias (in bytes).
+const ALIAS_LEN_LIMIT: usize = 64;
+/// Maximum length of the device public key (in bytes).
+pub const PK_LEN_LIMIT: usize = 300;
+
 fn check_device_limits(device: &Device) -> Result<(), AnchorError> {
     const ORIGIN_LEN_LIMIT: usize = 50;
-    const ALIAS_LEN_LIMIT: usize = 64;
-    const PK_LEN_LIMIT: usize = 300;
     const CREDENTIAL_ID_LEN_LIMIT: usize = 200;
 
     let n = device.alias.len();
@@ -505,6 +752,20 @@ fn check_device_limits(device: &Device) -> Result<(), AnchorError> {
     Ok(())
 }
 
+/// The invariant violations of an anchor, see [Anchor::check_invariants].
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct InvariantViolations {
+    /// The first violated device invariant of every device violating any.
+    pub devices: Vec<(DeviceKey, AnchorError)>,
+    pub anchor: Option<AnchorError>,
+}
+
+impl InvariantViolations {
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty() && self.anchor.is_none()
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub enum AnchorError {
     TooManyDevices {
@@ -577,3 +838,55 @@ impl fmt::Display for AnchorError {
         }
     }
 }
+
+impl From<AnchorError> for AuthnMethodError {
+    fn from(err: AnchorError) -> Self {
+        match err {
+            AnchorError::TooManyDevices { limit, num_devices } => {
+                AuthnMethodError::TooManyAuthnMethods {
+                    limit: limit as u64,
+                    num_authn_methods: num_devices as u64,
+                }
+            }
+            AnchorError::DeviceLimitExceeded {
+                field,
+                length,
+                limit,
+            } => AuthnMethodError::FieldLimitExceeded {
+                field,
+                length: length as u64,
+                limit: limit as u64,
+            },
+            AnchorError::CumulativeDataLimitExceeded { length, limit } => {
+                AuthnMethodError::CumulativeDataLimitExceeded {
+                    length: length as u64,
+                    limit: limit as u64,
+                }
+            }
+            AnchorError::InvalidDeviceProtection { key_type } => {
+                AuthnMethodError::InvalidProtection { key_type }
+            }
+            AnchorError::RecoveryPhraseCredentialIdMismatch => {
+                AuthnMethodError::RecoveryPhraseCredentialIdMismatch
+            }
+            AnchorError::MutationNotAllowed {
+                authorized_principal,
+                actual_principal,
+            } => AuthnMethodError::MutationNotAllowed {
+                authorized_principal,
+                actual_principal,
+            },
+            AnchorError::MultipleRecoveryPhrases => AuthnMethodError::MultipleRecoveryPhrases,
+            AnchorError::CannotModifyDeviceKey => AuthnMethodError::CannotModifyPublicKey,
+            AnchorError::NotFound { device_key } => AuthnMethodError::NotFound {
+                public_key: device_key,
+            },
+            AnchorError::DuplicateDevice { device_key } => AuthnMethodError::DuplicatePublicKey {
+                public_key: device_key,
+            },
+            AnchorError::ReservedMetadataKey { key } => {
+                AuthnMethodError::ReservedMetadataKey { key }
+            }
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use crate::storage::anchor::{Anchor, AnchorError, Device};
+use crate::storage::anchor::{
+    set_anchor_limits, with_acting_principal, Anchor, AnchorError, AnchorLimits, Device,
+};
 use candid::Principal;
 use internet_identity_interface::internet_identity::types::{
     DeviceData, DeviceProtection, KeyType, MetadataEntry, Purpose, Timestamp,
@@ -54,6 +56,20 @@ fn should_enforce_max_number_of_devices() {
     assert_eq!(anchor.devices().len(), 10);
 }
 
+#[test]
+fn should_allow_more_and_larger_devices_with_chunked_limits() {
+    set_anchor_limits(AnchorLimits::CHUNKED);
+    let mut anchor = Anchor::new();
+    for i in 0..32 {
+        anchor.add_device(large_device(i)).unwrap();
+    }
+
+    let result = anchor.add_device(device(32));
+
+    assert!(matches!(result, Err(AnchorError::TooManyDevices { .. })));
+    assert_eq!(anchor.devices().len(), 32);
+}
+
 #[test]
 fn should_enforce_pubkey_limit() {
     let mut anchor = Anchor::new();
@@ -185,6 +201,9 @@ fn should_prevent_mutation_when_invariants_are_violated() {
             device1.clone(),
             recovery_phrase(2, DeviceProtection::Unprotected),
         ],
+        recovery_delay_ns: None,
+        guardians: None,
+        moved_to: None,
     };
 
     device1.alias = "new alias".to_string();
@@ -200,6 +219,9 @@ fn should_prevent_addition_when_invariants_are_violated() {
             recovery_phrase(1, DeviceProtection::Unprotected),
             recovery_phrase(2, DeviceProtection::Unprotected),
         ],
+        recovery_delay_ns: None,
+        guardians: None,
+        moved_to: None,
     };
 
     let result = anchor.add_device(sample_device());
@@ -215,6 +237,9 @@ fn should_allow_removal_when_invariants_are_violated() {
             device1.clone(),
             recovery_phrase(2, DeviceProtection::Unprotected),
         ],
+        recovery_delay_ns: None,
+        guardians: None,
+        moved_to: None,
     };
 
     anchor.remove_device(&device1.pubkey).unwrap();
@@ -284,6 +309,25 @@ fn should_allow_modification_of_protected_device_with_matching_caller() {
     assert_eq!(anchor.devices()[0].alias, "new alias");
 }
 
+#[test]
+fn should_check_protected_devices_against_acting_principal() {
+    let device1 = recovery_phrase(1, DeviceProtection::Protected);
+    let mut anchor = Anchor::new();
+    anchor.add_device(device1.clone()).unwrap();
+
+    let result = with_acting_principal(test_caller(), || anchor.remove_device(&device1.pubkey));
+    assert!(matches!(
+        result,
+        Err(AnchorError::MutationNotAllowed { .. })
+    ));
+
+    with_acting_principal(Principal::self_authenticating(&device1.pubkey), || {
+        anchor.remove_device(&device1.pubkey)
+    })
+    .unwrap();
+    assert!(anchor.devices.is_empty());
+}
+
 #[test]
 fn should_not_remove_unknown_device() {
     let mut anchor = Anchor::new();
@@ -435,6 +479,130 @@ fn should_not_allow_reserved_metadata_key() {
     }
 }
 
+#[test]
+fn should_check_anchor_invariants_only_on_batch_commit() {
+    let mut anchor = Anchor::new();
+    for i in 0..10 {
+        anchor.add_device(device(i)).unwrap();
+    }
+
+    let mut batch = anchor.batch();
+    batch.add_device(device(10)).unwrap();
+    batch.remove_device(&device(0).pubkey).unwrap();
+    batch.commit().unwrap();
+
+    assert_eq!(anchor.devices().len(), 10);
+    assert!(anchor.device(&device(0).pubkey).is_none());
+    assert!(anchor.device(&device(10).pubkey).is_some());
+}
+
+#[test]
+fn should_not_modify_anchor_if_batch_commit_fails() {
+    let mut anchor = Anchor::new();
+    for i in 0..10 {
+        anchor.add_device(device(i)).unwrap();
+    }
+
+    let mut batch = anchor.batch();
+    batch.remove_device(&device(0).pubkey).unwrap();
+    batch.add_device(device(10)).unwrap();
+    batch.add_device(device(11)).unwrap();
+    let result = batch.commit();
+
+    assert!(matches!(result, Err(AnchorError::TooManyDevices { .. })));
+    assert_eq!(anchor.devices, (0..10).map(device).collect::<Vec<_>>());
+}
+
+#[test]
+fn should_not_modify_anchor_if_batch_is_not_committed() {
+    let mut anchor = Anchor::new();
+    anchor.add_device(sample_device()).unwrap();
+
+    let mut batch = anchor.batch();
+    batch.remove_device(&sample_device().pubkey).unwrap();
+    drop(batch);
+
+    assert_eq!(anchor.devices, vec![sample_device()]);
+}
+
+#[test]
+fn should_check_device_operations_within_batch() {
+    let mut anchor = Anchor::new();
+    anchor.add_device(sample_device()).unwrap();
+
+    let mut batch = anchor.batch();
+    assert!(matches!(
+        batch.add_device(sample_device()),
+        Err(AnchorError::DuplicateDevice { .. })
+    ));
+    assert!(matches!(
+        batch.remove_device(&device(1).pubkey),
+        Err(AnchorError::NotFound { .. })
+    ));
+    assert!(matches!(
+        batch.modify_device(&sample_device().pubkey, device(1)),
+        Err(AnchorError::CannotModifyDeviceKey)
+    ));
+}
+
+#[test]
+fn should_report_invariant_violations() {
+    let mut long_alias_device = device(3);
+    long_alias_device.alias = "a".repeat(65);
+    let anchor = Anchor {
+        devices: vec![
+            recovery_phrase(1, DeviceProtection::Unprotected),
+            recovery_phrase(2, DeviceProtection::Unprotected),
+            long_alias_device.clone(),
+        ],
+        recovery_delay_ns: None,
+        guardians: None,
+        moved_to: None,
+    };
+
+    let violations = anchor.check_invariants();
+
+    assert!(!violations.is_empty());
+    assert_eq!(
+        violations.anchor,
+        Some(AnchorError::MultipleRecoveryPhrases)
+    );
+    assert_eq!(violations.devices.len(), 1);
+    assert_eq!(violations.devices[0].0, long_alias_device.pubkey);
+    assert!(matches!(
+        violations.devices[0].1,
+        AnchorError::DeviceLimitExceeded { .. }
+    ));
+}
+
+#[test]
+fn should_not_report_invariant_violations_on_valid_anchor() {
+    let mut anchor = Anchor::new();
+    anchor.add_device(sample_device()).unwrap();
+
+    assert!(anchor.check_invariants().is_empty());
+}
+
+#[test]
+fn should_truncate_aliases() {
+    let mut long_alias_device = device(1);
+    // multi-byte characters must not be split
+    long_alias_device.alias = format!("{}€€", "a".repeat(62));
+    let mut anchor = Anchor {
+        devices: vec![long_alias_device.clone(), device(2)],
+        recovery_delay_ns: None,
+        guardians: None,
+        moved_to: None,
+    };
+
+    let truncated = anchor.truncate_aliases();
+
+    assert_eq!(truncated, vec![long_alias_device.pubkey.clone()]);
+    assert_eq!(anchor.devices[0].alias, "a".repeat(62));
+    assert_eq!(anchor.devices[1], device(2));
+    assert!(anchor.check_invariants().is_empty());
+}
+
 fn sample_device() -> Device {
     Device {
         pubkey: ByteBuf::from("public key of some sample device"),
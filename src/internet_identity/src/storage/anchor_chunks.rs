@@ -0,0 +1,146 @@
+//! Storage of the candid encoded anchors of layout version 8.
+//!
+//! The anchors are split into chunks of at most [ANCHOR_CHUNK_SIZE] bytes which are stored in a
+//! [StableBTreeMap] keyed by (anchor number, chunk index). The map reserves the maximum value size
+//! for every entry, so with small chunks, small anchors only use a few chunks while large anchors
+//! are no longer limited by a fixed record size.
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap, Storable};
+use internet_identity_interface::internet_identity::types::AnchorNumber;
+use std::borrow::Cow;
+
+/// Maximum number of bytes stored in a single chunk.
+pub const ANCHOR_CHUNK_SIZE: usize = 512;
+/// Maximum number of chunks per anchor, i.e. anchors can be at most 64 KiB.
+const MAX_ANCHOR_CHUNKS: usize = 128;
+/// Maximum size of the candid encoded anchor.
+pub const MAX_ANCHOR_SIZE: usize = ANCHOR_CHUNK_SIZE * MAX_ANCHOR_CHUNKS;
+
+pub type AnchorChunks<M> = StableBTreeMap<AnchorChunkKey, AnchorChunk, M>;
+
+/// Key of a chunk of an anchor.
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug, Clone, Ord, PartialOrd)]
+pub struct AnchorChunkKey {
+    // Attention: order of fields MUST NOT be changed because Ord is derived!
+    anchor_number: AnchorNumber,
+    index: u8,
+}
+
+impl AnchorChunkKey {
+    fn new(anchor_number: AnchorNumber, index: u8) -> Self {
+        Self {
+            anchor_number,
+            index,
+        }
+    }
+
+    /// Returns the key of the first chunk of the anchor.
+    fn first(anchor_number: AnchorNumber) -> Self {
+        Self::new(anchor_number, 0)
+    }
+
+    /// Returns the highest possible chunk key of the anchor.
+    fn last(anchor_number: AnchorNumber) -> Self {
+        Self::new(anchor_number, u8::MAX)
+    }
+}
+
+/// Note: byte ordering is very important as the keys are sorted on a byte level
+/// --> use big endian to ensure that the most significant bytes are compared first
+impl Storable for AnchorChunkKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::MAX_SIZE as usize);
+        buf.extend(self.anchor_number.to_be_bytes());
+        buf.push(self.index);
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        AnchorChunkKey {
+            anchor_number: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[0..8]).expect("failed to read anchor number"),
+            ),
+            index: bytes[8],
+        }
+    }
+}
+
+impl BoundedStorable for AnchorChunkKey {
+    const MAX_SIZE: u32 = 9;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// A chunk of the candid encoded anchor.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct AnchorChunk(Vec<u8>);
+
+impl Storable for AnchorChunk {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        AnchorChunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for AnchorChunk {
+    const MAX_SIZE: u32 = ANCHOR_CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Reads the candid encoded anchor. Returns an empty vector if the anchor has no chunks, i.e. it
+/// has never been written or has been deleted.
+pub fn read_anchor_bytes<M: Memory>(
+    chunks: &AnchorChunks<M>,
+    anchor_number: AnchorNumber,
+) -> Vec<u8> {
+    chunks
+        .range(AnchorChunkKey::first(anchor_number)..=AnchorChunkKey::last(anchor_number))
+        .flat_map(|(_, chunk)| chunk.0)
+        .collect()
+}
+
+/// Writes the candid encoded anchor, replacing all the chunks previously written for it.
+/// The caller has to make sure that `buf` is at most [MAX_ANCHOR_SIZE] bytes.
+pub fn write_anchor_bytes<M: Memory>(
+    chunks: &mut AnchorChunks<M>,
+    anchor_number: AnchorNumber,
+    buf: &[u8],
+) {
+    assert!(buf.len() <= MAX_ANCHOR_SIZE, "bug: anchor too large");
+    let num_chunks = buf.chunks(ANCHOR_CHUNK_SIZE).len();
+    for (index, chunk) in buf.chunks(ANCHOR_CHUNK_SIZE).enumerate() {
+        chunks.insert(
+            AnchorChunkKey::new(anchor_number, index as u8),
+            AnchorChunk(chunk.to_vec()),
+        );
+    }
+    remove_chunks_from(chunks, anchor_number, num_chunks);
+}
+
+/// Removes all the chunks of the anchor.
+pub fn delete_anchor_bytes<M: Memory>(chunks: &mut AnchorChunks<M>, anchor_number: AnchorNumber) {
+    remove_chunks_from(chunks, anchor_number, 0);
+}
+
+/// Removes the chunks of the anchor starting at chunk index `first`, e.g. the chunks left over
+/// from a previous, larger version of the anchor.
+fn remove_chunks_from<M: Memory>(
+    chunks: &mut AnchorChunks<M>,
+    anchor_number: AnchorNumber,
+    first: usize,
+) {
+    if first >= MAX_ANCHOR_CHUNKS {
+        return;
+    }
+    let stale_keys: Vec<AnchorChunkKey> = chunks
+        .range(
+            AnchorChunkKey::new(anchor_number, first as u8)..=AnchorChunkKey::last(anchor_number),
+        )
+        .map(|(key, _)| key)
+        .collect();
+    for key in stale_keys {
+        chunks.remove(&key);
+    }
+}
@@ -1,17 +1,26 @@
 use crate::archive::{ArchiveData, ArchiveState};
-use crate::state::PersistentState;
-use crate::storage::anchor::{Anchor, Device};
-use crate::storage::{Header, PersistentStateError, StableMemory, StorageError};
+use crate::state::revocation_epochs::RevocationScope;
+use crate::state::temp_keys::TempKey;
+use crate::state::{
+    ChallengeInfo, PersistentState, RegistrationState, TentativeDeviceRegistration,
+};
+use crate::storage::anchor::{set_anchor_limits, Anchor, AnchorLimits, Device};
+use crate::storage::anchor_chunks::{
+    delete_anchor_bytes, read_anchor_bytes, write_anchor_bytes, ANCHOR_CHUNK_SIZE, MAX_ANCHOR_SIZE,
+};
+use crate::storage::{BoundedKey, Header, PersistentStateError, StableMemory, StorageError};
 use crate::Storage;
 use candid::Principal;
 use ic_stable_structures::{Memory, VectorMemory};
 use internet_identity_interface::internet_identity::types::{
-    ActiveAnchorCounter, ActiveAnchorStatistics, ArchiveConfig, CompletedActiveAnchorStats,
-    DeviceProtection, KeyType, OngoingActiveAnchorStats, Purpose,
+    ActiveAnchorCounter, ActiveAnchorStatistics, AnchorNumber, ArchiveConfig, ChallengeKey,
+    ChallengeProviderConfig, CompletedActiveAnchorStats, DeviceData, DeviceKey, DeviceProtection,
+    KeyType, OngoingActiveAnchorStats, ProofOfWorkParams, Purpose,
 };
 use serde_bytes::ByteBuf;
 use std::borrow::Borrow;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 const WASM_PAGE_SIZE: u64 = 1 << 16;
@@ -111,21 +120,6 @@ fn range_as_hex(memory: &RefCell<Vec<u8>>, offset: u64, length: usize) -> String
     answer
 }
 
-fn assert_memory_page_eq(memory_1: &VectorMemory, memory_2: &VectorMemory, page_no: u64) {
-    let d: u64 = 64;
-    for i in 0..(WASM_PAGE_SIZE / d) {
-        let offset = WASM_PAGE_SIZE * page_no + i * d;
-        assert_eq!(
-            range_as_hex(memory_1.borrow(), offset, d as usize),
-            range_as_hex(memory_2.borrow(), offset, d as usize),
-            "Storage's memory differ at page # {}, i = {}, offset = {}",
-            page_no,
-            i,
-            offset
-        );
-    }
-}
-
 fn test_migrate_memory_from_v6_to_v7(number_of_anchors: usize) {
     let (id_range_lo, id_range_hi) = (12345, 678910);
     let memory_v6 = VectorMemory::default();
@@ -155,10 +149,19 @@ fn test_migrate_memory_from_v6_to_v7(number_of_anchors: usize) {
         storage_v7.anchor_memory.size()
     );
     assert_eq!(memory_v7.size(), memory_v6.size());
-    for i in 0..memory_v7.size() {
-        assert_memory_page_eq(&memory_v7, &memory_v6, i);
+    // The buckets of the maps in virtual memories 1 to 3 are allocated when the storage is
+    // initialized, i.e. before the anchor memory buckets with version 7 and after them when
+    // migrating. Hence, only the anchor memory and the headers are the same.
+    assert_eq!(
+        range_as_hex(memory_v7.borrow(), 0, WASM_PAGE_SIZE as usize),
+        range_as_hex(memory_v6.borrow(), 0, WASM_PAGE_SIZE as usize)
+    );
+    for anchor_number in id_range_lo..id_range_lo + number_of_anchors as u64 {
+        assert_eq!(
+            storage_migrated_v7.read(anchor_number).unwrap(),
+            storage_v7.read(anchor_number).unwrap()
+        );
     }
-    assert_eq!(memory_v6, memory_v7);
 }
 
 #[test]
@@ -200,17 +203,21 @@ fn should_allocate_new_bucket_after_2048_anchors_v7() {
         wrap_memory(memory_v7.clone(), SupportedVersion::V7),
     );
 
-    // The 1st anchor allocates 1st bucket.
+    // 2 header pages plus 1 bucket of 128 pages for each of the maps in virtual memories 1 to 3
+    // and 6 to 10.
+    assert_eq!(1026, memory_v7.size());
+
+    // The 1st anchor allocates 1st bucket of the anchor memory.
     add_test_anchor_data(&mut storage_v7, 1);
-    assert_eq!(130, memory_v7.size()); // 2 header pages plus 1st bucket of 128 pages.
+    assert_eq!(1154, memory_v7.size());
 
     // With a total of 2048 anchors, we still have only one bucket.
     add_test_anchor_data(&mut storage_v7, 2047);
-    assert_eq!(130, memory_v7.size()); // 2 header pages plus 1st bucket of 128 pages.
+    assert_eq!(1154, memory_v7.size());
 
     // For the next anchor a new bucket of 128 pages will be allocated.
     add_test_anchor_data(&mut storage_v7, 1);
-    assert_eq!(258, memory_v7.size()); // 2 header pages plus two buckets of 128 pages each.
+    assert_eq!(1282, memory_v7.size());
 }
 
 #[test]
@@ -220,15 +227,326 @@ fn should_not_migrate_if_wrong_memory() {
     assert!(Storage::from_memory_v6_to_v7(memory).is_none());
 }
 
+#[test]
+fn should_serialize_header_v8() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((1, 2), StableMemory::StableBTreeMap(memory.clone()));
+    storage.update_salt([5u8; 32]);
+    storage.flush();
+
+    let mut buf = vec![0; HEADER_SIZE];
+    memory.read(0, &mut buf);
+    assert_eq!(buf, hex::decode("494943080000000001000000000000000200000000000000001005050505050505050505050505050505050505050505050505050505050505050000020000000000").unwrap());
+}
+
+#[test]
+fn should_store_large_anchors_in_chunks_v8() {
+    let memory = VectorMemory::default();
+    let storage = Storage::new((1, 2), StableMemory::StableBTreeMap(memory));
+    let mut chunks = storage.maybe_anchor_chunks.unwrap();
+
+    let large = vec![7u8; 3 * ANCHOR_CHUNK_SIZE + 1];
+    write_anchor_bytes(&mut chunks, 1, &large);
+    write_anchor_bytes(&mut chunks, 2, &[8u8; 10]);
+    assert_eq!(chunks.len(), 5);
+    assert_eq!(read_anchor_bytes(&chunks, 1), large);
+
+    // the chunks no longer needed are removed
+    write_anchor_bytes(&mut chunks, 1, &[9u8; 10]);
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(read_anchor_bytes(&chunks, 1), vec![9u8; 10]);
+    assert_eq!(read_anchor_bytes(&chunks, 2), vec![8u8; 10]);
+
+    delete_anchor_bytes(&mut chunks, 1);
+    assert!(read_anchor_bytes(&chunks, 1).is_empty());
+    assert_eq!(read_anchor_bytes(&chunks, 2), vec![8u8; 10]);
+}
+
+#[test]
+fn should_reject_anchor_exceeding_max_size_v8() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((1, 2), StableMemory::StableBTreeMap(memory));
+    let (anchor_number, _) = storage.allocate_anchor().unwrap();
+
+    let result = storage.write_anchor_chunks(anchor_number, &vec![0u8; MAX_ANCHOR_SIZE + 1]);
+    assert!(matches!(
+        result,
+        Err(StorageError::EntrySizeLimitExceeded(_))
+    ));
+}
+
+#[test]
+fn should_migrate_anchors_from_v7_to_v8_incrementally() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 20_000), StableMemory::Managed(memory.clone()));
+    add_test_anchor_data(&mut storage, 10);
+    storage.delete(10_003).unwrap();
+    let anchors: Vec<Anchor> = (10_000..10_010)
+        .filter(|anchor_number| *anchor_number != 10_003)
+        .map(|anchor_number| storage.read(anchor_number).unwrap())
+        .collect();
+
+    storage.start_migration_to_v8();
+    assert!(storage.is_migrating_to_v8());
+    assert!(!storage.migrate_anchors_to_v8(4));
+    assert_eq!(storage.version(), 7);
+
+    // anchors modified during the migration are kept up to date in both locations
+    let mut migrated_anchor = storage.read(10_001).unwrap();
+    migrated_anchor.add_device(second_device()).unwrap();
+    storage.write(10_001, migrated_anchor.clone()).unwrap();
+    let mut pending_anchor = storage.read(10_007).unwrap();
+    pending_anchor.add_device(second_device()).unwrap();
+    storage.write(10_007, pending_anchor.clone()).unwrap();
+    storage.delete(10_002).unwrap();
+    add_test_anchor_data(&mut storage, 1);
+
+    // the anchor records remain the source of truth until the migration is complete
+    let storage_v7 = Storage::from_memory(memory.clone()).unwrap();
+    assert_eq!(storage_v7.version(), 7);
+    assert_eq!(storage_v7.read(10_001).unwrap(), migrated_anchor);
+
+    assert!(!storage.migrate_anchors_to_v8(4));
+    assert!(storage.migrate_anchors_to_v8(4));
+    assert!(!storage.is_migrating_to_v8());
+    assert_eq!(storage.version(), 8);
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.version(), 8);
+    assert_eq!(storage.anchor_count(), 11);
+    assert_eq!(storage.read(10_000).unwrap(), anchors[0]);
+    assert_eq!(storage.read(10_001).unwrap(), migrated_anchor);
+    assert_eq!(storage.read(10_007).unwrap(), pending_anchor);
+    assert_eq!(storage.read(10_009).unwrap(), anchors[8]);
+    assert!(storage.read(10_010).is_ok());
+    for deleted in [10_002, 10_003] {
+        assert!(matches!(
+            storage.read(deleted),
+            Err(StorageError::AnchorDeleted(_))
+        ));
+    }
+}
+
+#[test]
+fn should_skip_corrupted_records_when_migrating_from_v7_to_v8() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 20_000), StableMemory::Managed(memory.clone()));
+    add_test_anchor_data(&mut storage, 5);
+    let anchor = storage.read(10_003).unwrap();
+
+    // corrupt the record of anchor 10_002 by writing a length exceeding the record size
+    let address = storage.record_address(2);
+    {
+        let writer = storage.anchor_memory.get_writer(address, 2);
+        let mut writer = writer.borrow_mut();
+        writer.write_all(&u16::MAX.to_le_bytes()).unwrap();
+        writer.flush().unwrap();
+    }
+    assert!(matches!(
+        storage.read(10_002),
+        Err(StorageError::PersistedEntrySizeExceeded { .. })
+    ));
+
+    storage.start_migration_to_v8();
+    assert!(!storage.migrate_anchors_to_v8(3));
+    let progress = storage.v8_migration_progress().unwrap();
+    assert_eq!(progress.migrated_anchors, 3);
+    assert_eq!(progress.total_anchors, 5);
+    assert_eq!(progress.num_skipped_anchors, 1);
+    assert_eq!(progress.skipped_anchors, vec![10_002]);
+
+    assert!(storage.migrate_anchors_to_v8(3));
+    assert_eq!(storage.version(), 8);
+    let progress = storage.v8_migration_progress().unwrap();
+    assert_eq!(progress.migrated_anchors, 5);
+    assert_eq!(progress.skipped_anchors, vec![10_002]);
+    assert_eq!(storage.read(10_003).unwrap(), anchor);
+    // the corrupted record is kept rather than reading as deleted
+    assert!(matches!(
+        storage.read(10_002),
+        Err(StorageError::DeserializationError(_))
+    ));
+}
+
+#[test]
+fn should_only_allow_anchors_larger_than_a_record_with_v8() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 20_000), StableMemory::Managed(memory));
+    add_test_anchor_data(&mut storage, 1);
+    assert_eq!(storage.anchor_limits(), AnchorLimits::RECORD);
+    let mut anchor = storage.read(10_000).unwrap();
+    set_anchor_limits(AnchorLimits::CHUNKED);
+    for i in 0..12 {
+        anchor
+            .add_device(Device {
+                pubkey: ByteBuf::from([i; 300]),
+                credential_id: Some(ByteBuf::from([i; 200])),
+                ..sample_device()
+            })
+            .unwrap();
+    }
+    assert!(matches!(
+        storage.write(10_000, anchor.clone()),
+        Err(StorageError::EntrySizeLimitExceeded(_))
+    ));
+
+    storage.start_migration_to_v8();
+    assert!(storage.migrate_anchors_to_v8(1));
+    assert_eq!(storage.anchor_limits(), AnchorLimits::CHUNKED);
+    storage.write(10_000, anchor.clone()).unwrap();
+    assert_eq!(storage.read(10_000).unwrap(), anchor);
+}
+
+#[test]
+fn should_restart_migration_from_v7_to_v8_after_reload() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 20_000), StableMemory::Managed(memory.clone()));
+    add_test_anchor_data(&mut storage, 5);
+    storage.start_migration_to_v8();
+    assert!(!storage.migrate_anchors_to_v8(2));
+
+    // e.g. a rollback modifying an anchor that has already been copied
+    let mut anchor = storage.read(10_000).unwrap();
+    anchor.add_device(second_device()).unwrap();
+    let buf = candid::encode_one(&anchor).unwrap();
+    storage.write_entry_bytes(0, &buf).unwrap();
+
+    let mut storage = Storage::from_memory(memory.clone()).unwrap();
+    assert!(storage.is_migrating_to_v8());
+    assert!(storage.migrate_anchors_to_v8(10));
+    assert_eq!(storage.version(), 8);
+    assert_eq!(storage.read(10_000).unwrap(), anchor);
+}
+
+fn test_should_save_persistent_state_to_dedicated_memory(version: SupportedVersion) {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 20_000), wrap_memory(memory.clone(), version));
+    add_test_anchor_data(&mut storage, 3);
+    storage.write_persistent_state(&sample_persistent_state());
+
+    // the persistent state is no longer written after the anchor records
+    let mut buf = vec![0u8; 4];
+    memory.read(RESERVED_HEADER_BYTES + 3 * 4096, &mut buf);
+    assert_ne!(buf, PERSISTENT_STATE_MAGIC);
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        sample_persistent_state()
+    );
+}
+
+#[test]
+fn should_save_persistent_state_to_dedicated_memory_v7() {
+    test_should_save_persistent_state_to_dedicated_memory(SupportedVersion::V7);
+}
+
+#[test]
+fn should_save_persistent_state_to_dedicated_memory_v8() {
+    test_should_save_persistent_state_to_dedicated_memory(SupportedVersion::V8);
+}
+
+#[test]
+fn should_read_persistent_state_written_after_anchors_by_previous_version_v7() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 20_000), StableMemory::Single(memory.clone()));
+    add_test_anchor_data(&mut storage, 3);
+    storage.write_persistent_state(&sample_persistent_state());
+
+    let mut storage = Storage::from_memory_v6_to_v7(memory.clone()).unwrap();
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        sample_persistent_state()
+    );
+
+    // the persistent state written by the previous version is kept for a rollback
+    let mut buf = vec![0u8; 4];
+    memory.read(RESERVED_HEADER_BYTES + 3 * 4096, &mut buf);
+    assert_eq!(buf, PERSISTENT_STATE_MAGIC);
+
+    // the persistent state is written on change and copied for a rollback in pre_upgrade
+    let mut new_state = sample_persistent_state();
+    new_state.canister_creation_cycles_cost = 42;
+    storage.write_persistent_state(&new_state);
+    storage.copy_persistent_state_for_rollback();
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.read_persistent_state().unwrap(), new_state);
+}
+
+#[test]
+fn should_discard_persistent_state_after_anchors_v7() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(
+        (10_000, 20_000),
+        wrap_memory(memory.clone(), SupportedVersion::V7),
+    );
+    add_test_anchor_data(&mut storage, 3);
+    storage.write_persistent_state(&sample_persistent_state());
+    storage.copy_persistent_state_for_rollback();
+    storage.discard_persistent_state_after_anchors();
+
+    // a later change must not be shadowed by the stale copy after the anchor records
+    let mut new_state = sample_persistent_state();
+    new_state.canister_creation_cycles_cost = 42;
+    storage.write_persistent_state(&new_state);
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.read_persistent_state().unwrap(), new_state);
+}
+
+#[test]
+fn should_write_persistent_state_for_rollback_v7() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(
+        (10_000, 20_000),
+        wrap_memory(memory.clone(), SupportedVersion::V7),
+    );
+    add_test_anchor_data(&mut storage, 3);
+    let mut rollback_state = sample_persistent_state();
+    rollback_state.canister_creation_cycles_cost = 42;
+    storage.write_persistent_state(&rollback_state);
+    storage.copy_persistent_state_for_rollback();
+
+    // a previous version reads the persistent state from after the anchor records
+    let mut buf = vec![0u8; 4];
+    memory.read(RESERVED_HEADER_BYTES + 3 * 4096, &mut buf);
+    assert_eq!(buf, PERSISTENT_STATE_MAGIC);
+
+    let storage = Storage::from_memory(memory.clone()).unwrap();
+    assert_eq!(
+        storage.read_persistent_state_after_anchors().unwrap(),
+        rollback_state
+    );
+}
+
+#[test]
+fn should_not_write_persistent_state_for_rollback_v8() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(
+        (10_000, 20_000),
+        wrap_memory(memory.clone(), SupportedVersion::V8),
+    );
+    storage.write_persistent_state(&sample_persistent_state());
+    storage.copy_persistent_state_for_rollback();
+
+    assert!(matches!(
+        storage.read_persistent_state_after_anchors(),
+        Err(PersistentStateError::NotFound)
+    ));
+}
+
 enum SupportedVersion {
     V6,
     V7,
+    V8,
 }
 
 fn wrap_memory<M: Memory>(memory: M, version: SupportedVersion) -> StableMemory<M> {
     match version {
         SupportedVersion::V6 => StableMemory::Single(memory),
         SupportedVersion::V7 => StableMemory::Managed(memory),
+        SupportedVersion::V8 => StableMemory::StableBTreeMap(memory),
     }
 }
 
@@ -254,8 +572,13 @@ fn should_read_previous_write_v7() {
     test_should_read_previous_write(SupportedVersion::V7);
 }
 
+#[test]
+fn should_read_previous_write_v8() {
+    test_should_read_previous_write(SupportedVersion::V8);
+}
+
 fn test_should_serialize_first_record(version: SupportedVersion) {
-    const EXPECTED_LENGTH: usize = 253;
+    const EXPECTED_LENGTH: usize = 294;
     let memory = VectorMemory::default();
     let mut storage = Storage::new((123, 456), wrap_memory(memory.clone(), version));
     let (anchor_number, mut anchor) = storage.allocate_anchor().unwrap();
@@ -281,7 +604,7 @@ fn should_serialize_first_record_v7() {
 }
 
 fn test_should_serialize_subsequent_record_to_expected_memory_location(version: SupportedVersion) {
-    const EXPECTED_LENGTH: usize = 253;
+    const EXPECTED_LENGTH: usize = 294;
     const EXPECTED_RECORD_OFFSET: u64 = 409_600; // 100 * max anchor size
     let memory = VectorMemory::default();
     let mut storage = Storage::new((123, 456), wrap_memory(memory.clone(), version));
@@ -329,6 +652,11 @@ fn should_not_write_using_anchor_number_outside_allocated_range_v7() {
     test_should_not_write_using_anchor_number_outside_allocated_range(SupportedVersion::V7);
 }
 
+#[test]
+fn should_not_write_using_anchor_number_outside_allocated_range_v8() {
+    test_should_not_write_using_anchor_number_outside_allocated_range(SupportedVersion::V8);
+}
+
 #[test]
 fn should_deserialize_first_record_v6() {
     let memory = VectorMemory::default();
@@ -389,6 +717,120 @@ fn should_not_read_using_anchor_number_outside_allocated_range_v7() {
     test_should_not_read_using_anchor_number_outside_allocated_range(SupportedVersion::V7);
 }
 
+#[test]
+fn should_not_read_using_anchor_number_outside_allocated_range_v8() {
+    test_should_not_read_using_anchor_number_outside_allocated_range(SupportedVersion::V8);
+}
+
+fn test_should_delete_anchor(version: SupportedVersion) {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), wrap_memory(memory, version));
+    let (anchor_number, mut anchor) = storage.allocate_anchor().unwrap();
+    anchor.add_device(sample_device()).unwrap();
+    storage.write(anchor_number, anchor).unwrap();
+    let (other_anchor_number, mut other_anchor) = storage.allocate_anchor().unwrap();
+    other_anchor.add_device(sample_device()).unwrap();
+    storage
+        .write(other_anchor_number, other_anchor.clone())
+        .unwrap();
+
+    storage.delete(anchor_number).unwrap();
+
+    assert!(matches!(
+        storage.read(anchor_number),
+        Err(StorageError::AnchorDeleted(_))
+    ));
+    assert_eq!(storage.read(other_anchor_number).unwrap(), other_anchor);
+    // deleted anchor numbers are not reused
+    assert_eq!(
+        storage.allocate_anchor().unwrap().0,
+        other_anchor_number + 1
+    );
+}
+
+#[test]
+fn should_delete_anchor_v6() {
+    test_should_delete_anchor(SupportedVersion::V6);
+}
+
+#[test]
+fn should_delete_anchor_v7() {
+    test_should_delete_anchor(SupportedVersion::V7);
+}
+
+#[test]
+fn should_delete_anchor_v8() {
+    test_should_delete_anchor(SupportedVersion::V8);
+}
+
+#[test]
+fn should_report_moved_anchor() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), wrap_memory(memory, SupportedVersion::V8));
+    let (anchor_number, mut anchor) = storage.allocate_anchor().unwrap();
+    anchor.add_device(sample_device()).unwrap();
+    storage.write(anchor_number, anchor).unwrap();
+    let canister_id = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+
+    storage
+        .write(anchor_number, Anchor::moved(canister_id))
+        .unwrap();
+
+    assert!(matches!(
+        storage.read(anchor_number),
+        Err(StorageError::AnchorMoved {
+            anchor_number: 123,
+            canister_id: id,
+        }) if id == canister_id
+    ));
+}
+
+#[test]
+fn should_allocate_anchors_up_to_given_anchor_number() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), wrap_memory(memory, SupportedVersion::V7));
+    storage.allocate_anchor().unwrap();
+
+    storage.allocate_anchors_up_to(130).unwrap();
+    let mut anchor = Anchor::new();
+    anchor.add_device(sample_device()).unwrap();
+    storage.write(130, anchor.clone()).unwrap();
+
+    assert_eq!(storage.anchor_count(), 8);
+    assert_eq!(storage.read(130).unwrap(), anchor);
+    // skipped anchor numbers read as deleted
+    assert!(matches!(
+        storage.read(129),
+        Err(StorageError::AnchorDeleted(129))
+    ));
+    // allocating an anchor number that is already allocated does nothing
+    storage.allocate_anchors_up_to(125).unwrap();
+    assert_eq!(storage.allocate_anchor().unwrap().0, 131);
+    assert!(matches!(
+        storage.allocate_anchors_up_to(456),
+        Err(StorageError::AnchorNumberOutOfRange { .. })
+    ));
+
+    // the skipped records must not contain stale data, like a persistent state written after the
+    // anchors
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), wrap_memory(memory, SupportedVersion::V6));
+    storage.allocate_anchor().unwrap();
+    storage.write_persistent_state(&sample_persistent_state());
+
+    storage.allocate_anchors_up_to(130).unwrap();
+    for anchor_number in 124..=130 {
+        assert!(matches!(
+            storage.read(anchor_number),
+            Err(StorageError::AnchorDeleted(number)) if number == anchor_number
+        ));
+    }
+    assert!(matches!(
+        storage.read_persistent_state(),
+        Err(PersistentStateError::NotFound)
+    ));
+}
+
 fn test_should_save_and_restore_persistent_state(version: SupportedVersion) {
     let memory = VectorMemory::default();
     let mut storage = Storage::new((123, 456), wrap_memory(memory, version));
@@ -411,6 +853,11 @@ fn should_save_and_restore_persistent_state_v7() {
     test_should_save_and_restore_persistent_state(SupportedVersion::V7);
 }
 
+#[test]
+fn should_save_and_restore_persistent_state_v8() {
+    test_should_save_and_restore_persistent_state(SupportedVersion::V8);
+}
+
 fn test_should_save_persistent_state_at_expected_memory_address(version: SupportedVersion) {
     let memory = VectorMemory::default();
     let mut storage = Storage::new((10_000, 3_784_873), wrap_memory(memory.clone(), version));
@@ -428,11 +875,6 @@ fn should_save_persistent_state_at_expected_memory_address_v6() {
     test_should_save_persistent_state_at_expected_memory_address(SupportedVersion::V6);
 }
 
-#[test]
-fn should_save_persistent_state_at_expected_memory_address_v7() {
-    test_should_save_persistent_state_at_expected_memory_address(SupportedVersion::V7);
-}
-
 fn test_should_not_find_persistent_state(version: SupportedVersion) {
     let memory = VectorMemory::default();
     let mut storage = Storage::new((10_000, 3_784_873), wrap_memory(memory, version));
@@ -452,6 +894,11 @@ fn should_not_find_persistent_state_v7() {
     test_should_not_find_persistent_state(SupportedVersion::V7);
 }
 
+#[test]
+fn should_not_find_persistent_state_v8() {
+    test_should_not_find_persistent_state(SupportedVersion::V8);
+}
+
 #[test]
 fn should_not_find_persistent_state_on_magic_bytes_mismatch_v6() {
     let memory = VectorMemory::default();
@@ -491,11 +938,6 @@ fn should_save_persistent_state_at_expected_memory_address_with_anchors_v6() {
     test_should_save_persistent_state_at_expected_memory_address_with_anchors(SupportedVersion::V6);
 }
 
-#[test]
-fn should_save_persistent_state_at_expected_memory_address_with_anchors_v7() {
-    test_should_save_persistent_state_at_expected_memory_address_with_anchors(SupportedVersion::V7);
-}
-
 /// This tests verifies that address calculation is correct for 64bit addresses.
 /// Note: this test takes about 8GB of memory.
 #[test]
@@ -570,9 +1012,31 @@ fn should_overwrite_persistent_state_with_next_anchor_v6() {
     test_should_overwrite_persistent_state_with_next_anchor(SupportedVersion::V6);
 }
 
+fn test_should_keep_persistent_state_on_next_anchor(version: SupportedVersion) {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), wrap_memory(memory, version));
+    storage.flush();
+
+    storage.allocate_anchor().unwrap();
+    storage.write_persistent_state(&sample_persistent_state());
+
+    let (anchor_number, anchor) = storage.allocate_anchor().unwrap();
+    storage.write(anchor_number, anchor).unwrap();
+
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        sample_persistent_state()
+    );
+}
+
 #[test]
-fn should_overwrite_persistent_state_with_next_anchor_v7() {
-    test_should_overwrite_persistent_state_with_next_anchor(SupportedVersion::V7);
+fn should_keep_persistent_state_on_next_anchor_v7() {
+    test_should_keep_persistent_state_on_next_anchor(SupportedVersion::V7);
+}
+
+#[test]
+fn should_keep_persistent_state_on_next_anchor_v8() {
+    test_should_keep_persistent_state_on_next_anchor(SupportedVersion::V8);
 }
 
 #[test]
@@ -619,6 +1083,235 @@ fn sample_device() -> Device {
     }
 }
 
+fn second_device() -> Device {
+    Device {
+        pubkey: ByteBuf::from("hello world, I am another public key"),
+        credential_id: Some(ByteBuf::from("this is another credential id")),
+        ..sample_device()
+    }
+}
+
+fn test_should_keep_tentative_device_registrations(version: SupportedVersion) {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), wrap_memory(memory.clone(), version));
+    let (anchor_number, mut anchor) = storage.allocate_anchor().unwrap();
+    anchor.add_device(sample_device()).unwrap();
+    storage.write(anchor_number, anchor.clone()).unwrap();
+
+    let registrations = sample_tentative_device_registrations();
+    for (anchor_number, registration) in registrations.clone() {
+        storage
+            .tentative_device_registrations_mut()
+            .insert(anchor_number, registration);
+    }
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(tentative_device_registrations(&storage), registrations);
+    assert_eq!(storage.read(anchor_number).unwrap(), anchor);
+}
+
+#[test]
+fn should_keep_tentative_device_registrations_v7() {
+    test_should_keep_tentative_device_registrations(SupportedVersion::V7);
+}
+
+#[test]
+fn should_keep_tentative_device_registrations_v8() {
+    test_should_keep_tentative_device_registrations(SupportedVersion::V8);
+}
+
+#[test]
+fn should_not_keep_tentative_device_registrations_v6() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(
+        (123, 456),
+        wrap_memory(memory.clone(), SupportedVersion::V6),
+    );
+
+    for (anchor_number, registration) in sample_tentative_device_registrations() {
+        storage
+            .tentative_device_registrations_mut()
+            .insert(anchor_number, registration);
+    }
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert!(storage.tentative_device_registrations().is_empty());
+}
+
+fn test_should_keep_revocation_epochs(version: SupportedVersion) {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), wrap_memory(memory.clone(), version));
+    storage
+        .revocation_epochs_mut()
+        .revoke(RevocationScope::anchor(123), 100, 0);
+    storage
+        .revocation_epochs_mut()
+        .revoke(RevocationScope::frontend(124, [1; 32]), 100, 0);
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert!(storage.revocation_epochs().revoked_since(123, [2; 32], 100));
+    assert!(storage.revocation_epochs().revoked_since(124, [1; 32], 100));
+    assert!(!storage.revocation_epochs().revoked_since(124, [2; 32], 100));
+}
+
+#[test]
+fn should_keep_revocation_epochs_v7() {
+    test_should_keep_revocation_epochs(SupportedVersion::V7);
+}
+
+#[test]
+fn should_keep_revocation_epochs_v8() {
+    test_should_keep_revocation_epochs(SupportedVersion::V8);
+}
+
+fn tentative_device_registrations<M: Memory + Clone>(
+    storage: &Storage<M>,
+) -> HashMap<AnchorNumber, TentativeDeviceRegistration> {
+    storage.tentative_device_registrations().iter().collect()
+}
+
+fn test_should_keep_inflight_challenges_and_temp_keys(version: SupportedVersion) {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), wrap_memory(memory.clone(), version));
+    let challenges = sample_inflight_challenges();
+    for (key, challenge) in challenges.clone() {
+        storage
+            .inflight_challenges_mut()
+            .insert(BoundedKey::new(key.as_bytes()).unwrap(), challenge);
+    }
+    let temp_keys = sample_temp_keys();
+    insert_temp_keys(&mut storage, &temp_keys);
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(inflight_challenges(&storage), challenges);
+    assert_temp_keys_eq(&storage, &temp_keys);
+}
+
+#[test]
+fn should_keep_inflight_challenges_and_temp_keys_v7() {
+    test_should_keep_inflight_challenges_and_temp_keys(SupportedVersion::V7);
+}
+
+#[test]
+fn should_keep_inflight_challenges_and_temp_keys_v8() {
+    test_should_keep_inflight_challenges_and_temp_keys(SupportedVersion::V8);
+}
+
+#[test]
+fn should_not_keep_inflight_challenges_and_temp_keys_v6() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new(
+        (123, 456),
+        wrap_memory(memory.clone(), SupportedVersion::V6),
+    );
+    for (key, challenge) in sample_inflight_challenges() {
+        storage
+            .inflight_challenges_mut()
+            .insert(BoundedKey::new(key.as_bytes()).unwrap(), challenge);
+    }
+    insert_temp_keys(&mut storage, &sample_temp_keys());
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert!(storage.inflight_challenges().is_empty());
+    assert_eq!(storage.temp_keys().num_temp_keys(), 0);
+}
+
+fn inflight_challenges<M: Memory + Clone>(
+    storage: &Storage<M>,
+) -> HashMap<ChallengeKey, ChallengeInfo> {
+    storage
+        .inflight_challenges()
+        .iter()
+        .map(|(key, challenge)| {
+            (
+                String::from_utf8(key.to_bytes().to_vec()).unwrap(),
+                challenge,
+            )
+        })
+        .collect()
+}
+
+fn insert_temp_keys<M: Memory + Clone>(
+    storage: &mut Storage<M>,
+    temp_keys: &[(DeviceKey, TempKey)],
+) {
+    for (device_key, temp_key) in temp_keys {
+        storage
+            .temp_keys_mut()
+            .insert_temp_key(device_key, temp_key.clone());
+    }
+}
+
+fn assert_temp_keys_eq<M: Memory + Clone>(
+    storage: &Storage<M>,
+    temp_keys: &[(DeviceKey, TempKey)],
+) {
+    assert_eq!(storage.temp_keys().num_temp_keys(), temp_keys.len());
+    for (device_key, temp_key) in temp_keys {
+        assert_eq!(
+            storage.temp_keys().temp_key(device_key).as_ref(),
+            Some(temp_key)
+        );
+    }
+}
+
+fn sample_inflight_challenges() -> HashMap<ChallengeKey, ChallengeInfo> {
+    HashMap::from([
+        (
+            "challenge1".to_string(),
+            ChallengeInfo {
+                created: 1_234_567,
+                chars: "a1b2c".to_string(),
+                provider: None,
+            },
+        ),
+        (
+            "challenge2".to_string(),
+            ChallengeInfo {
+                created: 7_654_321,
+                chars: String::new(),
+                provider: Some(ChallengeProviderConfig::ProofOfWork(ProofOfWorkParams {
+                    difficulty: 20,
+                })),
+            },
+        ),
+    ])
+}
+
+fn sample_temp_keys() -> Vec<(DeviceKey, TempKey)> {
+    vec![(
+        sample_device().pubkey,
+        TempKey::new(
+            Principal::from_text("2h5ob-7aaaa-aaaad-aacya-cai").unwrap(),
+            123,
+            1_234_567,
+        ),
+    )]
+}
+
+fn sample_tentative_device_registrations() -> HashMap<AnchorNumber, TentativeDeviceRegistration> {
+    HashMap::from([
+        (
+            123,
+            TentativeDeviceRegistration {
+                expiration: 1_234_567,
+                state: RegistrationState::DeviceRegistrationModeActive,
+            },
+        ),
+        (
+            124,
+            TentativeDeviceRegistration {
+                expiration: 7_654_321,
+                state: RegistrationState::DeviceTentativelyAdded {
+                    tentative_device: DeviceData::from(sample_device()),
+                    verification_code: "123456".to_string(),
+                    failed_attempts: 1,
+                },
+            },
+        ),
+    ])
+}
+
 fn sample_persistent_state() -> PersistentState {
     PersistentState {
         archive_state: ArchiveState::Created {
@@ -658,5 +1351,10 @@ fn sample_persistent_state() -> PersistentState {
         domain_active_anchor_stats: None,
         latest_delegation_origins: None,
         max_num_latest_delegation_origins: None,
+        delegation_ttl_policy: None,
+        recovery_queue_next_id: None,
+        challenge_provider: None,
+        captcha_config: None,
+        method_rate_limits: None,
     }
 }
@@ -0,0 +1,43 @@
+//! Timer driven migration of the anchors from storage layout version 7 to layout version 8 (see
+//! [crate::storage]).
+//!
+//! The migration is started by an upgrade with `migrate_storage_to_stable_btree_map` set. Anchors
+//! are then copied per timer invocation until all anchors have been copied, at which point the
+//! storage switches to layout version 8. The canister stays fully operational during the
+//! migration.
+use crate::state;
+use crate::storage::anchor;
+use ic_cdk::api::performance_counter;
+use ic_cdk_timers::set_timer;
+use std::time::Duration;
+
+/// Number of anchors copied between checks of the instruction counter. Copying an anchor takes at
+/// most 8 chunk inserts (for a full version 7 record), so a step is small compared to the budget.
+const MIGRATION_STEP_SIZE: u32 = 50;
+/// Number of instructions after which no further step is started in the same timer invocation.
+/// Together with the last step, this stays well within the instruction limit of a message.
+const MIGRATION_INSTRUCTION_BUDGET: u64 = 2_000_000_000;
+
+/// Starts the timer migrating the anchors, if a migration is in progress. Timers do not survive
+/// upgrades, so this needs to be called on `post_upgrade`.
+pub fn start_timer() {
+    if state::storage_borrow(|storage| storage.is_migrating_to_v8()) {
+        set_timer(Duration::ZERO, migrate_batch);
+    }
+}
+
+fn migrate_batch() {
+    loop {
+        let complete =
+            state::storage_borrow_mut(|storage| storage.migrate_anchors_to_v8(MIGRATION_STEP_SIZE));
+        if complete {
+            // the anchors no longer need to fit into the records of layout version 7
+            anchor::set_anchor_limits(state::storage_borrow(|storage| storage.anchor_limits()));
+            return;
+        }
+        if performance_counter(0) > MIGRATION_INSTRUCTION_BUDGET {
+            set_timer(Duration::ZERO, migrate_batch);
+            return;
+        }
+    }
+}
@@ -1,6 +1,7 @@
 //! Tests related to the last_usage_timestamp.
 
 use canister_tests::api::internet_identity as api;
+use canister_tests::api::internet_identity::api_v2;
 use canister_tests::flows;
 use canister_tests::framework::*;
 use ic_test_state_machine_client::CallError;
@@ -333,3 +334,57 @@ fn should_update_last_usage_on_exit_device_registration_mode() -> Result<(), Cal
 
     Ok(())
 }
+
+/// Tests that identity_recovery_info updates the last usage timestamp.
+#[test]
+fn should_update_last_usage_on_identity_recovery_info() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    api::add(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        &device_data_2(),
+    )?;
+
+    env.advance_time(Duration::from_secs(1));
+    let expected_timestamp = time(&env);
+
+    api_v2::identity_recovery_info(&env, canister_id, principal_1(), user_number)?;
+
+    // use the device2 to get the info, otherwise getting the info will update the timestamp we want to verify
+    let anchor_info = api::get_anchor_info(&env, canister_id, principal_2(), user_number)?;
+    assert_device_last_used(&anchor_info, &device_data_1().pubkey, expected_timestamp);
+
+    Ok(())
+}
+
+/// Tests that identity_guardians_info updates the last usage timestamp.
+#[test]
+fn should_update_last_usage_on_identity_guardians_info() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    api::add(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        &device_data_2(),
+    )?;
+
+    env.advance_time(Duration::from_secs(1));
+    let expected_timestamp = time(&env);
+
+    api_v2::identity_guardians_info(&env, canister_id, principal_1(), user_number)?;
+
+    // use the device2 to get the info, otherwise getting the info will update the timestamp we want to verify
+    let anchor_info = api::get_anchor_info(&env, canister_id, principal_2(), user_number)?;
+    assert_device_last_used(&anchor_info, &device_data_1().pubkey, expected_timestamp);
+
+    Ok(())
+}
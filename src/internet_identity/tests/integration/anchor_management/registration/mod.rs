@@ -2,6 +2,7 @@
 //! 1. create_challenge: retrieve a captcha
 //! 2. register: submit the captcha solution and device information to create a new anchor
 
+mod proof_of_work;
 mod temp_keys;
 
 use candid::Principal;
@@ -194,7 +195,19 @@ fn should_not_allow_expired_captcha() -> Result<(), CallError> {
 #[test]
 fn should_limit_captcha_creation() -> Result<(), CallError> {
     let env = env();
-    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    // disable the rate limit to reach the maximum number of inflight captchas
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            method_rate_limits: Some(vec![MethodRateLimit {
+                method: RateLimitedMethod::CreateChallenge,
+                global: None,
+                per_anchor: None,
+            }]),
+            ..Default::default()
+        }),
+    );
 
     for _ in 0..500 {
         api::create_challenge(&env, canister_id)?;
@@ -209,6 +222,80 @@ fn should_limit_captcha_creation() -> Result<(), CallError> {
     Ok(())
 }
 
+/// Tests that the captcha parameters can be configured and are reported in the stats.
+#[test]
+fn should_apply_captcha_config() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    assert_eq!(api::stats(&env, canister_id)?.captcha_config.length, 5);
+
+    let config = CaptchaConfig {
+        length: 6,
+        width: 300,
+        height: 150,
+        wave_frequency: 3,
+        wave_amplitude: 10,
+        challenge_lifetime_ns: 60 * 1_000_000_000,
+        max_inflight_challenges: 3,
+    };
+    upgrade_ii_canister_with_arg(
+        &env,
+        canister_id,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            captcha_config: Some(config.clone()),
+            ..Default::default()
+        }),
+    )?;
+    assert_eq!(api::stats(&env, canister_id)?.captcha_config, config);
+
+    for _ in 0..3 {
+        api::create_challenge(&env, canister_id)?;
+    }
+    let result = api::create_challenge(&env, canister_id);
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("too many inflight captchas").unwrap(),
+    );
+
+    // the challenges expire after the configured lifetime
+    env.advance_time(Duration::from_secs(61));
+    api::create_challenge(&env, canister_id)?;
+    Ok(())
+}
+
+/// Tests that invalid captcha parameters are rejected.
+#[test]
+fn should_reject_invalid_captcha_config() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = upgrade_ii_canister_with_arg(
+        &env,
+        canister_id,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            captcha_config: Some(CaptchaConfig {
+                length: 0,
+                width: 220,
+                height: 120,
+                wave_frequency: 2,
+                wave_amplitude: 20,
+                challenge_lifetime_ns: 300 * 1_000_000_000,
+                max_inflight_challenges: 500,
+            }),
+            ..Default::default()
+        }),
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("invalid captcha config: length must be between 1 and 10, got 0").unwrap(),
+    );
+}
+
 /// Tests that the `register` call will hit the rate limit on too many calls and that the limit
 /// will allow new calls after some time.
 #[test]
@@ -266,6 +353,27 @@ fn should_rate_limit_register_calls() -> Result<(), CallError> {
     Ok(())
 }
 
+/// Tests that `create_challenge` is rate limited globally without any rate limit being configured.
+#[test]
+fn should_rate_limit_create_challenge_by_default() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    for _ in 0..100 {
+        api::create_challenge(&env, canister_id)?;
+    }
+    let result = api::create_challenge(&env, canister_id);
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("rate limit reached, try again later").unwrap(),
+    );
+
+    env.advance_time(Duration::from_secs(1));
+    api::create_challenge(&env, canister_id)?;
+    Ok(())
+}
+
 /// Tests that the `register` rate limit does not replenish tokens to more than max_tokens.
 #[test]
 fn should_not_allow_more_than_max_tokens_calls_on_rate_limit() -> Result<(), CallError> {
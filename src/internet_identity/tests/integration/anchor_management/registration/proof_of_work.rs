@@ -0,0 +1,150 @@
+//! Tests for the registration with proof of work challenges (instead of image CAPTCHAs).
+
+use canister_tests::api::internet_identity as api;
+use canister_tests::framework::*;
+use ic_test_state_machine_client::CallError;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use internet_identity_interface::internet_identity::types::*;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+const DIFFICULTY: u8 = 8;
+
+fn proof_of_work_arg(difficulty: u8) -> Option<InternetIdentityInit> {
+    Some(InternetIdentityInit {
+        challenge_provider: Some(ChallengeProviderConfig::ProofOfWork(ProofOfWorkParams {
+            difficulty,
+        })),
+        ..Default::default()
+    })
+}
+
+fn leading_zero_bits(challenge_key: &str, solution: &str) -> u32 {
+    let hash = Sha256::digest(format!("{challenge_key}:{solution}").as_bytes());
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn find_solution(challenge_key: &str, is_solution: impl Fn(u32) -> bool) -> String {
+    (0u64..)
+        .map(|nonce| nonce.to_string())
+        .find(|candidate| is_solution(leading_zero_bits(challenge_key, candidate)))
+        .unwrap()
+}
+
+/// Tests that an anchor can be registered by solving a proof of work challenge.
+#[test]
+fn should_register_with_proof_of_work() -> Result<(), CallError> {
+    let env = env();
+    let canister_id =
+        install_ii_canister_with_arg(&env, II_WASM.clone(), proof_of_work_arg(DIFFICULTY));
+
+    let challenge = api::create_challenge(&env, canister_id)?;
+    assert_eq!(
+        challenge.proof_of_work,
+        Some(ProofOfWorkParams {
+            difficulty: DIFFICULTY
+        })
+    );
+    assert!(challenge.png_base64.is_empty());
+
+    let solution = find_solution(&challenge.challenge_key, |bits| bits >= DIFFICULTY as u32);
+    let result = api::register(
+        &env,
+        canister_id,
+        principal_1(),
+        &device_data_1(),
+        &ChallengeAttempt {
+            chars: solution,
+            key: challenge.challenge_key,
+        },
+        None,
+    )?;
+    assert!(matches!(result, RegisterResponse::Registered { .. }));
+    Ok(())
+}
+
+/// Tests that insufficient proofs of work are rejected.
+#[test]
+fn should_reject_insufficient_proof_of_work() -> Result<(), CallError> {
+    let env = env();
+    let canister_id =
+        install_ii_canister_with_arg(&env, II_WASM.clone(), proof_of_work_arg(DIFFICULTY));
+
+    let challenge = api::create_challenge(&env, canister_id)?;
+    let solution = find_solution(&challenge.challenge_key, |bits| bits < DIFFICULTY as u32);
+    let result = api::register(
+        &env,
+        canister_id,
+        principal_1(),
+        &device_data_1(),
+        &ChallengeAttempt {
+            chars: solution,
+            key: challenge.challenge_key,
+        },
+        None,
+    )?;
+    assert!(matches!(result, RegisterResponse::BadChallenge));
+    Ok(())
+}
+
+/// Tests that challenges are checked by the provider that created them, even if the provider
+/// changed in the meantime.
+#[test]
+fn should_check_challenge_with_provider_that_created_it() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            migrate_storage_to_memory_manager: Some(true),
+            ..Default::default()
+        }),
+    );
+
+    let challenge = api::create_challenge(&env, canister_id)?;
+    assert_eq!(challenge.proof_of_work, None);
+
+    upgrade_ii_canister_with_arg(
+        &env,
+        canister_id,
+        II_WASM.clone(),
+        proof_of_work_arg(DIFFICULTY),
+    )?;
+
+    let result = api::register(
+        &env,
+        canister_id,
+        principal_1(),
+        &device_data_1(),
+        &ChallengeAttempt {
+            chars: "a".to_string(),
+            key: challenge.challenge_key,
+        },
+        None,
+    )?;
+    assert!(matches!(result, RegisterResponse::Registered { .. }));
+    Ok(())
+}
+
+/// Tests that invalid difficulties are rejected.
+#[test]
+fn should_reject_invalid_difficulty() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result =
+        upgrade_ii_canister_with_arg(&env, canister_id, II_WASM.clone(), proof_of_work_arg(33));
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("invalid challenge provider: the proof of work difficulty must be between 1 and 32, got 33").unwrap(),
+    );
+}
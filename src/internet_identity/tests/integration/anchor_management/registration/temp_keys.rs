@@ -5,12 +5,13 @@ use canister_tests::api::internet_identity as api;
 use canister_tests::flows;
 use canister_tests::framework::{
     assert_metric, device_data_1, device_data_2, env, expect_user_error_with_message, get_metrics,
-    install_ii_canister, test_principal, II_WASM,
+    install_ii_canister, install_ii_canister_with_arg, test_principal, upgrade_ii_canister,
+    II_WASM,
 };
 use ic_cdk::api::management_canister::main::CanisterId;
 use ic_test_state_machine_client::{CallError, ErrorCode, StateMachine};
 use internet_identity_interface::internet_identity::types::{
-    AnchorNumber, Challenge, ChallengeAttempt, DeviceData, RegisterResponse,
+    AnchorNumber, Challenge, ChallengeAttempt, DeviceData, InternetIdentityInit, RegisterResponse,
 };
 use regex::Regex;
 use serde_bytes::ByteBuf;
@@ -145,6 +146,36 @@ fn should_not_allow_temp_key_for_different_anchor() -> Result<(), CallError> {
     Ok(())
 }
 
+/// Tests that both the CAPTCHA challenges and the temp keys survive upgrades.
+#[test]
+fn should_keep_challenge_and_temp_key_across_upgrade() -> Result<(), CallError> {
+    let env = env();
+    let arg = InternetIdentityInit {
+        migrate_storage_to_memory_manager: Some(true),
+        ..Default::default()
+    };
+    let canister_id = install_ii_canister_with_arg(&env, II_WASM.clone(), Some(arg));
+    let temp_key = test_principal(1);
+
+    let anchor = register_with_temp_key(&env, canister_id, temp_key, &device_data_1());
+    let challenge = api::create_challenge(&env, canister_id)?;
+
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+    api::get_anchor_info(&env, canister_id, temp_key, anchor)?;
+    let device = device_data_2();
+    let response = api::register(
+        &env,
+        canister_id,
+        device.principal(),
+        &device,
+        &challenge_solution(challenge),
+        None,
+    )?;
+    assert!(matches!(response, RegisterResponse::Registered { .. }));
+    Ok(())
+}
+
 /// Tests that the number of temp keys is exposed as a metric.
 #[test]
 fn should_provide_temp_keys_metric() -> Result<(), CallError> {
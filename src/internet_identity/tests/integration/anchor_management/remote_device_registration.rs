@@ -7,6 +7,7 @@
 //! Additionally, there are the following bounds on the registration flow:
 //! 1. registration mode expires after 15 minutes
 //! 2. there is a limit of 3 attempts for step 3 in the above process
+//! 3. step 2 and step 3 are rate limited (see [MethodRateLimit])
 
 use canister_tests::api::internet_identity as api;
 use canister_tests::flows;
@@ -243,3 +244,295 @@ fn reject_verification_with_wrong_code() -> Result<(), CallError> {
     ));
     Ok(())
 }
+
+/// Tests that the device registration flow survives an upgrade (with layout version 7).
+#[test]
+fn can_register_remote_device_across_upgrade() -> Result<(), CallError> {
+    let env = env();
+    let arg = InternetIdentityInit {
+        migrate_storage_to_memory_manager: Some(true),
+        ..Default::default()
+    };
+    let canister_id = install_ii_canister_with_arg(&env, II_WASM.clone(), Some(arg));
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    let add_response = api::add_tentative_device(&env, canister_id, user_number, &device_data_2())?;
+    let verification_code = match add_response {
+        AddTentativeDeviceResponse::AddedTentatively {
+            verification_code, ..
+        } => verification_code,
+        err => panic!("failed to add tentative device: {err:?}"),
+    };
+
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+    let verification_response = api::verify_tentative_device(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        &verification_code,
+    )?;
+    assert!(matches!(
+        verification_response,
+        VerifyTentativeDeviceResponse::Verified
+    ));
+    Ok(())
+}
+
+fn arg_with_method_rate_limit(limit: MethodRateLimit) -> Option<InternetIdentityInit> {
+    Some(InternetIdentityInit {
+        method_rate_limits: Some(vec![limit]),
+        ..Default::default()
+    })
+}
+
+fn add_per_anchor_rate_limit_arg() -> Option<InternetIdentityInit> {
+    arg_with_method_rate_limit(MethodRateLimit {
+        method: RateLimitedMethod::AddTentativeDevice,
+        global: None,
+        per_anchor: Some(RateLimitConfig {
+            time_per_token_ns: Duration::from_secs(60).as_nanos() as u64,
+            max_tokens: 1,
+        }),
+    })
+}
+
+/// Tests that add_tentative_device is rate limited per anchor.
+#[test]
+fn should_rate_limit_add_tentative_device_per_anchor() -> Result<(), CallError> {
+    let env = env();
+    let canister_id =
+        install_ii_canister_with_arg(&env, II_WASM.clone(), add_per_anchor_rate_limit_arg());
+    let user_number_1 = flows::register_anchor(&env, canister_id);
+    let user_number_2 = flows::register_anchor_with_device(&env, canister_id, &device_data_2());
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number_1)?;
+    api::enter_device_registration_mode(&env, canister_id, principal_2(), user_number_2)?;
+
+    let add_response =
+        api::add_tentative_device(&env, canister_id, user_number_1, &device_data_2())?;
+    let AddTentativeDeviceResponse::AddedTentatively {
+        verification_code, ..
+    } = add_response
+    else {
+        panic!("failed to add tentative device: {add_response:?}");
+    };
+    // calls not adding a tentative device do not use a token
+    let result = api::add_tentative_device(&env, canister_id, user_number_1, &device_data_1())?;
+    assert!(matches!(
+        result,
+        AddTentativeDeviceResponse::AnotherDeviceTentativelyAdded
+    ));
+    api::verify_tentative_device(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number_1,
+        &verification_code,
+    )?;
+
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number_1)?;
+    let result = api::add_tentative_device(&env, canister_id, user_number_1, &device_data_1());
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("rate limit reached, try again later").unwrap(),
+    );
+
+    // other anchors are not affected
+    let result = api::add_tentative_device(&env, canister_id, user_number_2, &device_data_1())?;
+    assert!(matches!(
+        result,
+        AddTentativeDeviceResponse::AddedTentatively { .. }
+    ));
+    assert_metric(
+        &get_metrics(&env, canister_id),
+        "internet_identity_rate_limit_exhausted_anchors{method=\"add_tentative_device\"}",
+        2.0,
+    );
+
+    // the token is replenished after some time
+    env.advance_time(Duration::from_secs(60));
+    let result = api::add_tentative_device(&env, canister_id, user_number_1, &device_data_1())?;
+    assert!(matches!(
+        result,
+        AddTentativeDeviceResponse::AddedTentatively { .. }
+    ));
+    Ok(())
+}
+
+/// Tests that the anchor owner can discard a device added tentatively by someone else, without the
+/// per anchor rate limit keeping the owner's device from being added.
+#[test]
+fn should_discard_tentative_device_on_exit() -> Result<(), CallError> {
+    let env = env();
+    let canister_id =
+        install_ii_canister_with_arg(&env, II_WASM.clone(), add_per_anchor_rate_limit_arg());
+    let user_number = flows::register_anchor(&env, canister_id);
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+
+    // someone else adds a device before the owner's device
+    api::add_tentative_device(&env, canister_id, user_number, &device_data_2())?;
+    let result =
+        api::add_tentative_device(&env, canister_id, user_number, &recovery_device_data_1())?;
+    assert!(matches!(
+        result,
+        AddTentativeDeviceResponse::AnotherDeviceTentativelyAdded
+    ));
+
+    api::exit_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    let result =
+        api::add_tentative_device(&env, canister_id, user_number, &recovery_device_data_1())?;
+    assert!(matches!(
+        result,
+        AddTentativeDeviceResponse::AddedTentatively { .. }
+    ));
+    Ok(())
+}
+
+/// Tests that, with the default rate limits, a caller adding its own device to an anchor in
+/// registration mode over and over again can neither use up the tokens of the anchor nor keep the
+/// owner's device from being added.
+#[test]
+fn should_not_let_unauthenticated_caller_block_owner_device() -> Result<(), CallError> {
+    const SQUATTING_ATTEMPTS: u64 = 20; // more than the default per anchor burst of 10
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    for _ in 0..SQUATTING_ATTEMPTS {
+        // the attacker polls for the registration mode ...
+        let result =
+            api::add_tentative_device(&env, canister_id, user_number, &recovery_device_data_2())?;
+        assert!(matches!(
+            result,
+            AddTentativeDeviceResponse::DeviceRegistrationModeOff
+        ));
+        // ... and adds its device as soon as the owner enters it
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let result =
+            api::add_tentative_device(&env, canister_id, user_number, &recovery_device_data_2())?;
+        assert!(matches!(
+            result,
+            AddTentativeDeviceResponse::AddedTentatively { .. }
+        ));
+        let result = api::add_tentative_device(&env, canister_id, user_number, &device_data_2())?;
+        assert!(matches!(
+            result,
+            AddTentativeDeviceResponse::AnotherDeviceTentativelyAdded
+        ));
+        // the owner discards the device of the attacker
+        api::exit_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    }
+
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    let add_response = api::add_tentative_device(&env, canister_id, user_number, &device_data_2())?;
+    let AddTentativeDeviceResponse::AddedTentatively {
+        verification_code, ..
+    } = add_response
+    else {
+        panic!("failed to add tentative device: {add_response:?}");
+    };
+    let verification_response = api::verify_tentative_device(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        &verification_code,
+    )?;
+    assert!(matches!(
+        verification_response,
+        VerifyTentativeDeviceResponse::Verified
+    ));
+
+    // all the calls were subject to the default global rate limit
+    assert_metric(
+        &get_metrics(&env, canister_id),
+        "internet_identity_rate_limit_current_tokens{method=\"add_tentative_device\"}",
+        (100 - 3 * SQUATTING_ATTEMPTS - 1) as f64,
+    );
+    Ok(())
+}
+
+/// Tests that verify_tentative_device is rate limited across all anchors and that the current
+/// tokens are reported in the metrics.
+#[test]
+fn should_rate_limit_verify_tentative_device_globally() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_method_rate_limit(MethodRateLimit {
+            method: RateLimitedMethod::VerifyTentativeDevice,
+            global: Some(RateLimitConfig {
+                time_per_token_ns: Duration::from_secs(60).as_nanos() as u64,
+                max_tokens: 2,
+            }),
+            per_anchor: None,
+        }),
+    );
+    let user_number = flows::register_anchor(&env, canister_id);
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    api::add_tentative_device(&env, canister_id, user_number, &device_data_2())?;
+
+    for _ in 0..2 {
+        api::verify_tentative_device(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "invalid code",
+        )?;
+    }
+    assert_metric(
+        &get_metrics(&env, canister_id),
+        "internet_identity_rate_limit_current_tokens{method=\"verify_tentative_device\"}",
+        0.0,
+    );
+
+    let result = api::verify_tentative_device(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        "invalid code",
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("rate limit reached, try again later").unwrap(),
+    );
+    Ok(())
+}
+
+/// Tests that invalid method rate limits are rejected.
+#[test]
+fn should_reject_per_anchor_rate_limit_for_create_challenge() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = upgrade_ii_canister_with_arg(
+        &env,
+        canister_id,
+        II_WASM.clone(),
+        arg_with_method_rate_limit(MethodRateLimit {
+            method: RateLimitedMethod::CreateChallenge,
+            global: None,
+            per_anchor: Some(RateLimitConfig {
+                time_per_token_ns: 1,
+                max_tokens: 1,
+            }),
+        }),
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new(
+            "invalid method rate limits: create_challenge cannot be rate limited per anchor",
+        )
+        .unwrap(),
+    );
+}
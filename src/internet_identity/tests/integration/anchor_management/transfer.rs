@@ -0,0 +1,499 @@
+//! Tests for transferring anchors between canisters (`export_anchors`, `import_anchors`,
+//! `mark_anchors_moved` and `anchor_moved_to`).
+
+use candid::Principal;
+use canister_tests::api::internet_identity as api;
+use canister_tests::api::internet_identity::api_v2;
+use canister_tests::flows;
+use canister_tests::framework::*;
+use ic_cdk::api::management_canister::main::CanisterId;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use ic_test_state_machine_client::{CallError, StateMachine};
+use internet_identity_interface::internet_identity::types::*;
+use regex::Regex;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Registers two anchors (using the devices of principal 1 and 2), the second being the guardian of
+/// the first one. Returns the numbers of the ward and of the guardian.
+fn ward_with_guardian(
+    env: &StateMachine,
+    canister_id: CanisterId,
+) -> Result<(AnchorNumber, AnchorNumber), CallError> {
+    let ward = flows::register_anchor(env, canister_id);
+    let guardian = flows::register_anchor_with(env, canister_id, principal_2(), &device_data_2());
+    let result = api_v2::identity_guardians_set(
+        env,
+        canister_id,
+        principal_1(),
+        ward,
+        Some(GuardianConfig {
+            guardians: vec![guardian],
+            threshold: 1,
+        }),
+    )?;
+    assert_eq!(result, Some(IdentityGuardiansSetResponse::Ok));
+    Ok((ward, guardian))
+}
+
+fn guardian_config(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    ward: AnchorNumber,
+) -> Result<Option<GuardianConfig>, CallError> {
+    let Some(IdentityGuardiansInfoResponse::Ok(info)) =
+        api_v2::identity_guardians_info(env, canister_id, principal_1(), ward)? else {
+        panic!("expected guardians info to be returned");
+    };
+    Ok(info.config)
+}
+
+fn import_arg(anchors: Vec<ExportedAnchor>) -> ImportAnchorsArg {
+    ImportAnchorsArg {
+        anchors,
+        acknowledge_principal_change: true,
+        replace_deleted: None,
+    }
+}
+
+/// Verifies that anchors exported page by page can be imported by another canister, preserving
+/// the last usage timestamps and the metadata of the devices.
+#[test]
+fn should_transfer_anchors_to_another_canister() -> Result<(), CallError> {
+    let env = env();
+    let source_id = install_ii_canister(&env, II_WASM.clone());
+    let anchor_number = flows::register_anchor(&env, source_id);
+    let other_anchor_number = flows::register_anchor(&env, source_id);
+    let device = DeviceData {
+        metadata: Some(HashMap::from([(
+            "some_key".to_string(),
+            MetadataEntry::String("some data".to_string()),
+        )])),
+        ..device_data_2()
+    };
+    api::add(&env, source_id, principal_1(), anchor_number, &device)?;
+    // sets the last usage timestamp of device 1
+    let anchor_info = api::get_anchor_info(&env, source_id, principal_1(), anchor_number)?;
+    env.advance_time(Duration::from_secs(1));
+
+    let page = api::export_anchors(
+        &env,
+        source_id,
+        Principal::anonymous(),
+        &ExportAnchorsArg {
+            from: 0,
+            to: None,
+            limit: Some(1),
+        },
+    )?;
+    assert_eq!(page.anchors.len(), 1);
+    assert_eq!(page.next, Some(other_anchor_number));
+    let last_page = api::export_anchors(
+        &env,
+        source_id,
+        Principal::anonymous(),
+        &ExportAnchorsArg {
+            from: other_anchor_number,
+            to: None,
+            limit: None,
+        },
+    )?;
+    assert_eq!(last_page.anchors.len(), 1);
+    assert_eq!(last_page.next, None);
+
+    let target_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_anchor_range((anchor_number, anchor_number + 100)),
+    );
+    api::import_anchors(
+        &env,
+        target_id,
+        Principal::anonymous(),
+        &import_arg(page.anchors),
+    )?;
+    api::import_anchors(
+        &env,
+        target_id,
+        Principal::anonymous(),
+        &import_arg(last_page.anchors),
+    )?;
+
+    // use device 2, otherwise getting the info would update the timestamp we want to verify
+    let imported_info = api::get_anchor_info(&env, target_id, principal_2(), anchor_number)?;
+    let device_1 = |info: &IdentityAnchorInfo| {
+        info.devices
+            .iter()
+            .find(|device| device.pubkey == device_data_1().pubkey)
+            .cloned()
+            .expect("device 1 not found")
+    };
+    assert_eq!(device_1(&imported_info), device_1(&anchor_info));
+    assert!(imported_info
+        .devices
+        .iter()
+        .any(|imported| imported.pubkey == device.pubkey && imported.metadata == device.metadata));
+    assert_eq!(
+        api::get_anchor_info(&env, target_id, principal_1(), other_anchor_number)?
+            .into_device_data(),
+        vec![device_data_1()]
+    );
+    // new anchors are allocated after the imported ones
+    assert_eq!(
+        flows::register_anchor(&env, target_id),
+        other_anchor_number + 1
+    );
+    Ok(())
+}
+
+/// Verifies that moved anchors are replaced by tombstones telling clients where they moved to.
+#[test]
+fn should_mark_anchors_as_moved() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let anchor_number = flows::register_anchor(&env, canister_id);
+    let other_anchor_number = flows::register_anchor(&env, canister_id);
+    let target_id = env.create_canister(None);
+
+    api::mark_anchors_moved(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &MarkAnchorsMovedArg {
+            anchor_numbers: vec![anchor_number],
+            canister_id: target_id,
+        },
+    )?;
+
+    assert_eq!(
+        api::anchor_moved_to(&env, canister_id, anchor_number)?,
+        Some(target_id)
+    );
+    assert_eq!(
+        api::anchor_moved_to(&env, canister_id, other_anchor_number)?,
+        None
+    );
+    let result = api::get_anchor_info(&env, canister_id, principal_1(), anchor_number);
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new(&format!("has moved to canister {target_id}")).unwrap(),
+    );
+    // moved anchors are no longer exported
+    let exported = api::export_anchors(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &ExportAnchorsArg {
+            from: 0,
+            to: None,
+            limit: None,
+        },
+    )?;
+    let exported_numbers: Vec<AnchorNumber> = exported
+        .anchors
+        .iter()
+        .map(|anchor| anchor.anchor_number)
+        .collect();
+    assert_eq!(exported_numbers, vec![other_anchor_number]);
+    Ok(())
+}
+
+/// Verifies that moved anchors are no longer guardians of the anchors staying on the canister.
+#[test]
+fn should_remove_moved_anchor_as_guardian() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (ward, guardian) = ward_with_guardian(&env, canister_id)?;
+
+    api::mark_anchors_moved(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &MarkAnchorsMovedArg {
+            anchor_numbers: vec![guardian],
+            canister_id: env.create_canister(None),
+        },
+    )?;
+
+    assert_eq!(guardian_config(&env, canister_id, ward)?, None);
+    Ok(())
+}
+
+/// Verifies that guardians outside of the range of the importing canister are removed from the
+/// imported anchors.
+#[test]
+fn should_remove_guardians_outside_of_range_on_import() -> Result<(), CallError> {
+    let env = env();
+    let source_id = install_ii_canister(&env, II_WASM.clone());
+    let (ward, _) = ward_with_guardian(&env, source_id)?;
+    let exported = api::export_anchors(
+        &env,
+        source_id,
+        Principal::anonymous(),
+        &ExportAnchorsArg {
+            from: ward,
+            to: Some(ward + 1),
+            limit: None,
+        },
+    )?;
+    assert_eq!(exported.anchors.len(), 1);
+
+    // the guardian is not in the range of the target canister
+    let target_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_anchor_range((ward, ward + 1)),
+    );
+    api::import_anchors(
+        &env,
+        target_id,
+        Principal::anonymous(),
+        &import_arg(exported.anchors),
+    )?;
+
+    assert_eq!(guardian_config(&env, target_id, ward)?, None);
+    Ok(())
+}
+
+/// Verifies that imports do not overwrite existing anchors.
+#[test]
+fn should_not_import_over_existing_anchor() -> Result<(), CallError> {
+    let env = env();
+    let source_id = install_ii_canister(&env, II_WASM.clone());
+    let target_id = install_ii_canister(&env, II_WASM.clone());
+    flows::register_anchor(&env, source_id);
+    let anchor_number =
+        flows::register_anchor_with(&env, target_id, principal_2(), &device_data_2());
+
+    let exported = api::export_anchors(
+        &env,
+        source_id,
+        Principal::anonymous(),
+        &ExportAnchorsArg {
+            from: 0,
+            to: None,
+            limit: None,
+        },
+    )?;
+    let result = api::import_anchors(
+        &env,
+        target_id,
+        Principal::anonymous(),
+        &import_arg(exported.anchors),
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new(&format!("anchor {anchor_number} already exists")).unwrap(),
+    );
+    assert_eq!(
+        api::get_anchor_info(&env, target_id, principal_2(), anchor_number)?.into_device_data(),
+        vec![device_data_2()]
+    );
+    Ok(())
+}
+
+/// Verifies that deleted anchors are only replaced if explicitly listed, as anchor numbers of
+/// deleted anchors must not be reused otherwise.
+#[test]
+fn should_only_import_over_deleted_anchor_if_listed() -> Result<(), CallError> {
+    let env = env();
+    let source_id = install_ii_canister(&env, II_WASM.clone());
+    let anchor_number = flows::register_anchor(&env, source_id);
+    let other_anchor_number = flows::register_anchor(&env, source_id);
+    let mut exported = api::export_anchors(
+        &env,
+        source_id,
+        Principal::anonymous(),
+        &ExportAnchorsArg {
+            from: 0,
+            to: None,
+            limit: None,
+        },
+    )?
+    .anchors;
+    let target_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_anchor_range((anchor_number, anchor_number + 100)),
+    );
+    // importing the second anchor only leaves the first anchor number deleted
+    let other_anchor = exported.pop().expect("anchor not exported");
+    api::import_anchors(
+        &env,
+        target_id,
+        Principal::anonymous(),
+        &import_arg(vec![other_anchor]),
+    )?;
+
+    let result = api::import_anchors(
+        &env,
+        target_id,
+        Principal::anonymous(),
+        &import_arg(exported.clone()),
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new(&format!("anchor {anchor_number} has been deleted")).unwrap(),
+    );
+
+    api::import_anchors(
+        &env,
+        target_id,
+        Principal::anonymous(),
+        &ImportAnchorsArg {
+            replace_deleted: Some(vec![anchor_number]),
+            ..import_arg(exported)
+        },
+    )?;
+    assert_eq!(
+        api::get_anchor_info(&env, target_id, principal_1(), anchor_number)?.into_device_data(),
+        vec![device_data_1()]
+    );
+    assert_eq!(
+        api::get_anchor_info(&env, target_id, principal_1(), other_anchor_number)?
+            .into_device_data(),
+        vec![device_data_1()]
+    );
+    Ok(())
+}
+
+/// Verifies that anchors that cannot be read are reported instead of failing the whole export.
+#[test]
+fn should_report_unreadable_anchors_on_export() -> Result<(), CallError> {
+    const ALIAS: &str = "alias of the corrupted anchor";
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let corrupted_anchor_number = flows::register_anchor_with(
+        &env,
+        canister_id,
+        principal_1(),
+        &DeviceData {
+            alias: ALIAS.to_string(),
+            ..device_data_1()
+        },
+    );
+    let anchor_number = flows::register_anchor(&env, canister_id);
+
+    // replace the alias by invalid UTF-8 so that the anchor can no longer be decoded
+    let mut memory = env.get_stable_memory(canister_id);
+    let alias = ALIAS.as_bytes();
+    let mut corrupted = false;
+    for i in 0..=memory.len() - alias.len() {
+        if &memory[i..i + alias.len()] == alias {
+            memory[i..i + alias.len()].fill(0xff);
+            corrupted = true;
+        }
+    }
+    assert!(corrupted);
+    env.set_stable_memory(canister_id, ByteBuf::from(memory));
+
+    let exported = api::export_anchors(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &ExportAnchorsArg {
+            from: 0,
+            to: None,
+            limit: None,
+        },
+    )?;
+    let exported_numbers: Vec<AnchorNumber> = exported
+        .anchors
+        .iter()
+        .map(|anchor| anchor.anchor_number)
+        .collect();
+    assert_eq!(exported_numbers, vec![anchor_number]);
+    assert_eq!(exported.unreadable, vec![corrupted_anchor_number]);
+    assert_eq!(exported.next, None);
+    Ok(())
+}
+
+/// Verifies that only controllers can transfer anchors.
+#[test]
+fn should_only_allow_controllers_to_transfer_anchors() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = api::export_anchors(
+        &env,
+        canister_id,
+        principal_1(),
+        &ExportAnchorsArg {
+            from: 0,
+            to: None,
+            limit: None,
+        },
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("is not allowed to export anchors").unwrap(),
+    );
+
+    let result = api::import_anchors(&env, canister_id, principal_1(), &import_arg(vec![]));
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("is not allowed to import anchors").unwrap(),
+    );
+
+    let result = api::mark_anchors_moved(
+        &env,
+        canister_id,
+        principal_1(),
+        &MarkAnchorsMovedArg {
+            anchor_numbers: vec![],
+            canister_id: Principal::anonymous(),
+        },
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("is not allowed to mark anchors as moved").unwrap(),
+    );
+}
+
+/// Verifies that the change of principals must be acknowledged to import anchors.
+#[test]
+fn should_require_acknowledging_principal_change() -> Result<(), CallError> {
+    let env = env();
+    let source_id = install_ii_canister(&env, II_WASM.clone());
+    let anchor_number = flows::register_anchor(&env, source_id);
+    let exported = api::export_anchors(
+        &env,
+        source_id,
+        Principal::anonymous(),
+        &ExportAnchorsArg {
+            from: 0,
+            to: None,
+            limit: None,
+        },
+    )?;
+    let target_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_anchor_range((anchor_number, anchor_number + 100)),
+    );
+
+    let result = api::import_anchors(
+        &env,
+        target_id,
+        Principal::anonymous(),
+        &ImportAnchorsArg {
+            anchors: exported.anchors,
+            acknowledge_principal_change: false,
+            replace_deleted: None,
+        },
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("importing anchors changes their principals").unwrap(),
+    );
+    assert!(api::lookup(&env, target_id, anchor_number)?.is_empty());
+    Ok(())
+}
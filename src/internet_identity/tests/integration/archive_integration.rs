@@ -51,6 +51,11 @@ mod deployment_tests {
                 register_rate_limit: None,
                 max_num_latest_delegation_origins: None,
                 migrate_storage_to_memory_manager: None,
+                delegation_ttl_policy: None,
+                challenge_provider: None,
+                captcha_config: None,
+                method_rate_limits: None,
+                migrate_storage_to_stable_btree_map: None,
             }),
         );
         env.add_cycles(ii_canister, 150_000_000_000);
@@ -186,6 +191,11 @@ mod deployment_tests {
                 register_rate_limit: None,
                 max_num_latest_delegation_origins: None,
                 migrate_storage_to_memory_manager: None,
+                delegation_ttl_policy: None,
+                challenge_provider: None,
+                captcha_config: None,
+                method_rate_limits: None,
+                migrate_storage_to_stable_btree_map: None,
             }),
         )
         .unwrap();
@@ -603,6 +613,11 @@ mod pull_entries_tests {
             register_rate_limit: None,
             max_num_latest_delegation_origins: None,
             migrate_storage_to_memory_manager: None,
+            delegation_ttl_policy: None,
+            challenge_provider: None,
+            captcha_config: None,
+            method_rate_limits: None,
+            migrate_storage_to_stable_btree_map: None,
         };
 
         let ii_canister = install_ii_canister_with_arg(&env, II_WASM.clone(), Some(init_arg));
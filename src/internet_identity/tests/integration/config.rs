@@ -0,0 +1,241 @@
+//! Tests for the runtime configuration API (`config` and `config_update`).
+
+use candid::Principal;
+use canister_tests::api::internet_identity as api;
+use canister_tests::flows;
+use canister_tests::framework::{
+    arg_with_anchor_range, env, expect_user_error_with_message, install_ii_canister,
+    install_ii_canister_with_arg, principal_1, II_WASM,
+};
+use ic_test_state_machine_client::CallError;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use internet_identity_interface::internet_identity::types::{
+    ChallengeProviderConfig, InternetIdentityInit, ProofOfWorkParams, RateLimitConfig,
+};
+use regex::Regex;
+
+/// Verifies that the current configuration can be retrieved.
+#[test]
+fn should_return_config() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_anchor_range((10_000, 10_100)),
+    );
+
+    let config = api::config(&env, canister_id)?;
+    assert_eq!(config.assigned_user_number_range, Some((10_000, 10_100)));
+    assert_eq!(
+        config.challenge_provider,
+        Some(ChallengeProviderConfig::Captcha)
+    );
+    assert_eq!(
+        config.captcha_config,
+        Some(api::stats(&env, canister_id)?.captcha_config)
+    );
+    assert_eq!(config.migrate_storage_to_memory_manager, None);
+    Ok(())
+}
+
+/// Verifies that a controller can update the configuration without an upgrade.
+#[test]
+fn should_update_config() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let rate_limit = RateLimitConfig {
+        time_per_token_ns: 1_000_000_000,
+        max_tokens: 5,
+    };
+
+    api::config_update(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &InternetIdentityInit {
+            register_rate_limit: Some(rate_limit.clone()),
+            max_num_latest_delegation_origins: Some(42),
+            ..Default::default()
+        },
+    )?;
+
+    let config = api::config(&env, canister_id)?;
+    assert_eq!(config.register_rate_limit, Some(rate_limit));
+    assert_eq!(config.max_num_latest_delegation_origins, Some(42));
+    assert_eq!(
+        api::stats(&env, canister_id)?.max_num_latest_delegation_origins,
+        42
+    );
+    Ok(())
+}
+
+/// Verifies that only controllers can update the configuration.
+#[test]
+fn should_only_allow_controllers_to_update_config() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = api::config_update(
+        &env,
+        canister_id,
+        principal_1(),
+        &InternetIdentityInit {
+            max_num_latest_delegation_origins: Some(42),
+            ..Default::default()
+        },
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("is not allowed to update the configuration").unwrap(),
+    );
+}
+
+/// Verifies that the configuration changes are validated and not applied partially.
+#[test]
+fn should_reject_invalid_config() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_anchor_range((10_000, 10_100)),
+    );
+    flows::register_anchor(&env, canister_id);
+    flows::register_anchor(&env, canister_id);
+
+    // shrinking the range below the number of allocated anchors is not allowed
+    let result = api::config_update(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &InternetIdentityInit {
+            assigned_user_number_range: Some((10_000, 10_001)),
+            ..Default::default()
+        },
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("does not accommodate all 2 anchors").unwrap(),
+    );
+
+    // the valid changes are rolled back if any of the changes is invalid
+    let result = api::config_update(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &InternetIdentityInit {
+            max_num_latest_delegation_origins: Some(42),
+            challenge_provider: Some(ChallengeProviderConfig::ProofOfWork(ProofOfWorkParams {
+                difficulty: 0,
+            })),
+            ..Default::default()
+        },
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("invalid challenge provider").unwrap(),
+    );
+
+    let config = api::config(&env, canister_id)?;
+    assert_eq!(config.assigned_user_number_range, Some((10_000, 10_100)));
+    assert_ne!(config.max_num_latest_delegation_origins, Some(42));
+    assert_eq!(
+        config.challenge_provider,
+        Some(ChallengeProviderConfig::Captcha)
+    );
+    Ok(())
+}
+
+/// Verifies that register rate limits without replenishing tokens are rejected.
+#[test]
+fn should_reject_invalid_register_rate_limit() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    for rate_limit in [
+        RateLimitConfig {
+            time_per_token_ns: 0,
+            max_tokens: 5,
+        },
+        RateLimitConfig {
+            time_per_token_ns: 1_000_000_000,
+            max_tokens: 0,
+        },
+    ] {
+        let result = api::config_update(
+            &env,
+            canister_id,
+            Principal::anonymous(),
+            &InternetIdentityInit {
+                register_rate_limit: Some(rate_limit.clone()),
+                ..Default::default()
+            },
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("invalid register rate limit").unwrap(),
+        );
+        assert_ne!(
+            api::config(&env, canister_id)?.register_rate_limit,
+            Some(rate_limit)
+        );
+    }
+
+    // registrations still work
+    flows::register_anchor(&env, canister_id);
+    Ok(())
+}
+
+/// Verifies that the number of latest delegation origins cannot be set to 0.
+#[test]
+fn should_reject_zero_max_num_latest_delegation_origins() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = api::config_update(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &InternetIdentityInit {
+            max_num_latest_delegation_origins: Some(0),
+            ..Default::default()
+        },
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("max_num_latest_delegation_origins must be greater than 0").unwrap(),
+    );
+    assert_ne!(
+        api::config(&env, canister_id)?.max_num_latest_delegation_origins,
+        Some(0)
+    );
+    Ok(())
+}
+
+/// Verifies that the storage migration cannot be triggered outside of an upgrade.
+#[test]
+fn should_reject_storage_migration_in_config_update() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = api::config_update(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &InternetIdentityInit {
+            migrate_storage_to_memory_manager: Some(true),
+            ..Default::default()
+        },
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("the storage can only be migrated on upgrade").unwrap(),
+    );
+}
@@ -4,9 +4,11 @@ use candid::Principal;
 use canister_tests::api::internet_identity as api;
 use canister_tests::flows;
 use canister_tests::framework::*;
-use ic_test_state_machine_client::CallError;
 use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
-use internet_identity_interface::internet_identity::types::GetDelegationResponse;
+use ic_test_state_machine_client::{call_candid_as, CallError};
+use internet_identity_interface::internet_identity::types::{
+    DelegationTtlRule, GetDelegationResponse, InternetIdentityInit,
+};
 use regex::Regex;
 use serde_bytes::ByteBuf;
 use std::ops::Add;
@@ -150,6 +152,93 @@ fn should_shorten_expiration_greater_max_ttl() -> Result<(), CallError> {
     Ok(())
 }
 
+/// Verifies that the delegation lifetime policy is applied to matching frontends.
+#[test]
+fn should_apply_delegation_ttl_policy() -> Result<(), CallError> {
+    const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+    let env = env();
+    let policy = vec![DelegationTtlRule {
+        frontend: "https://*.wallet.com".to_string(),
+        max_time_to_live_ns: HOUR_NS,
+        default_time_to_live_ns: 10 * 60 * 1_000_000_000, // 10 minutes
+    }];
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            delegation_ttl_policy: Some(policy.clone()),
+            ..Default::default()
+        }),
+    );
+    let user_number = flows::register_anchor(&env, canister_id);
+    let pub_session_key = ByteBuf::from("session public key");
+    let now = time(&env);
+
+    let (_, expiration) = api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        "https://app.wallet.com",
+        &pub_session_key,
+        Some(24 * HOUR_NS),
+    )?;
+    assert_eq!(expiration, now + HOUR_NS);
+
+    let (_, expiration) = api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        "https://app.wallet.com",
+        &pub_session_key,
+        None,
+    )?;
+    assert_eq!(expiration, now + 10 * 60 * 1_000_000_000);
+
+    // frontends not matching the policy are not affected
+    let (_, expiration) = api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        "https://forum.com",
+        &pub_session_key,
+        Some(24 * HOUR_NS),
+    )?;
+    assert_eq!(expiration, now + 24 * HOUR_NS);
+
+    assert_eq!(api::stats(&env, canister_id)?.delegation_ttl_policy, policy);
+    Ok(())
+}
+
+/// Verifies that invalid delegation lifetime policies are rejected.
+#[test]
+fn should_reject_invalid_delegation_ttl_policy() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = upgrade_ii_canister_with_arg(
+        &env,
+        canister_id,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            delegation_ttl_policy: Some(vec![DelegationTtlRule {
+                frontend: "https://wallet.com".to_string(),
+                max_time_to_live_ns: 60 * 1_000_000_000,
+                default_time_to_live_ns: 120 * 1_000_000_000,
+            }]),
+            ..Default::default()
+        }),
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("invalid delegation TTL policy: https://wallet.com: default time to live exceeds the max time to live").unwrap(),
+    );
+}
+
 /// Verifies that delegations can be requested in parallel.
 #[test]
 fn should_get_multiple_valid_delegations() -> Result<(), CallError> {
@@ -234,6 +323,361 @@ fn should_get_multiple_valid_delegations() -> Result<(), CallError> {
     Ok(())
 }
 
+/// Verifies that delegations restricted to target canisters are issued.
+#[test]
+fn should_get_valid_delegation_with_targets() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    let frontend_hostname = "https://some-dapp.com";
+    let pub_session_key = ByteBuf::from("session public key");
+    let targets = Some(vec![
+        Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap(),
+        Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+    ]);
+
+    let (canister_sig_key, expiration) = api::prepare_delegation_with_targets(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        None,
+        &targets,
+    )?;
+
+    let signed_delegation = match api::get_delegation_with_targets(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        expiration,
+        &targets,
+    )? {
+        GetDelegationResponse::SignedDelegation(delegation) => delegation,
+        GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+    };
+
+    verify_delegation(&env, canister_sig_key, &signed_delegation, &env.root_key());
+    assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+    assert_eq!(signed_delegation.delegation.expiration, expiration);
+    assert_eq!(signed_delegation.delegation.targets, targets);
+    Ok(())
+}
+
+/// Verifies that a delegation can only be retrieved with the same targets it was prepared with.
+#[test]
+fn should_not_get_delegation_with_different_targets() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    let frontend_hostname = "https://some-dapp.com";
+    let pub_session_key = ByteBuf::from("session public key");
+    let targets = Some(vec![
+        Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap()
+    ]);
+
+    let (_, expiration) = api::prepare_delegation_with_targets(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        None,
+        &targets,
+    )?;
+
+    // a delegation without targets was never prepared
+    match api::get_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        expiration,
+    )? {
+        GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+        GetDelegationResponse::NoSuchDelegation => {}
+    };
+
+    match api::get_delegation_with_targets(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        expiration,
+        &Some(vec![
+            Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()
+        ]),
+    )? {
+        GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+        GetDelegationResponse::NoSuchDelegation => {}
+    };
+    Ok(())
+}
+
+/// Verifies that delegations are issued without further checks if the origin matches the frontend hostname.
+#[test]
+fn should_get_valid_delegation_for_same_origin() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    let frontend_hostname = "https://some-dapp.com";
+    let pub_session_key = ByteBuf::from("session public key");
+
+    let (canister_sig_key, expiration) = api::prepare_delegation_with_origin(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        None,
+        frontend_hostname,
+    )?;
+
+    let signed_delegation = match api::get_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        expiration,
+    )? {
+        GetDelegationResponse::SignedDelegation(delegation) => delegation,
+        GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+    };
+
+    verify_delegation(&env, canister_sig_key, &signed_delegation, &env.root_key());
+    Ok(())
+}
+
+/// Verifies that delegations for a canister's legacy domain (ic0.app) are issued to the same
+/// canister served on the official domain (icp0.io), as requested by the II frontend.
+#[test]
+fn should_get_valid_delegation_for_official_domain_origin() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    let frontend_hostname = "https://rdmx6-jaaaa-aaaaa-aaadq-cai.ic0.app";
+    let pub_session_key = ByteBuf::from("session public key");
+
+    let (canister_sig_key, expiration) = api::prepare_delegation_with_origin(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        None,
+        "https://rdmx6-jaaaa-aaaaa-aaadq-cai.icp0.io",
+    )?;
+
+    let signed_delegation = match api::get_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        expiration,
+    )? {
+        GetDelegationResponse::SignedDelegation(delegation) => delegation,
+        GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+    };
+
+    verify_delegation(&env, canister_sig_key, &signed_delegation, &env.root_key());
+    Ok(())
+}
+
+/// Verifies that no delegation is issued if the requesting origin is not provided.
+#[test]
+fn should_not_prepare_delegation_without_origin() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    let result: Result<(ByteBuf, u64), CallError> = call_candid_as(
+        &env,
+        canister_id,
+        principal_1(),
+        "prepare_delegation",
+        (
+            user_number,
+            "https://some-dapp.com",
+            ByteBuf::from("session key"),
+            None::<u64>,
+        ),
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("the requesting origin must be provided").unwrap(),
+    );
+}
+
+/// Verifies that no delegation is issued for a derivation origin that cannot list alternative origins.
+#[test]
+fn should_not_prepare_delegation_for_invalid_derivation_origin() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    let result = api::prepare_delegation_with_origin(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        "https://some-dapp.com",
+        &ByteBuf::from("session key"),
+        None,
+        "https://malicious-dapp.com",
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("invalid derivation origin: derivation origin https://some-dapp.com is not a valid canister origin").unwrap(),
+    );
+}
+
+/// Verifies that revoked delegations can no longer be retrieved.
+#[test]
+fn should_not_get_delegation_after_revocation() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    let frontend_hostname = "https://some-dapp.com";
+    let pub_session_key = ByteBuf::from("session public key");
+
+    let (_, expiration) = api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        None,
+    )?;
+
+    api::revoke_delegations(&env, canister_id, principal_1(), user_number, None)?;
+
+    match api::get_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname,
+        &pub_session_key,
+        expiration,
+    )? {
+        GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+        GetDelegationResponse::NoSuchDelegation => {}
+    };
+    Ok(())
+}
+
+/// Verifies that revoking the delegations of one frontend does not affect other frontends.
+#[test]
+fn should_only_revoke_delegations_of_given_frontend() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    let frontend_hostname_1 = "https://some-dapp-1.com";
+    let frontend_hostname_2 = "https://some-dapp-2.com";
+    let pub_session_key = ByteBuf::from("session public key");
+
+    let (_, expiration_1) = api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname_1,
+        &pub_session_key,
+        None,
+    )?;
+    let (canister_sig_key_2, expiration_2) = api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname_2,
+        &pub_session_key,
+        None,
+    )?;
+
+    api::revoke_delegations(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        Some(frontend_hostname_1),
+    )?;
+
+    match api::get_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname_1,
+        &pub_session_key,
+        expiration_1,
+    )? {
+        GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+        GetDelegationResponse::NoSuchDelegation => {}
+    };
+    let signed_delegation = match api::get_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        frontend_hostname_2,
+        &pub_session_key,
+        expiration_2,
+    )? {
+        GetDelegationResponse::SignedDelegation(delegation) => delegation,
+        GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+    };
+    verify_delegation(
+        &env,
+        canister_sig_key_2,
+        &signed_delegation,
+        &env.root_key(),
+    );
+    Ok(())
+}
+
+/// Verifies that delegations can only be revoked by the matching user.
+#[test]
+fn can_not_revoke_delegations_for_different_user() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    let result = api::revoke_delegations(
+        &env,
+        canister_id,
+        principal_2(),
+        user_number, // belongs to principal_1
+        None,
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+    );
+}
+
 /// Verifies that an anchor that was registered using II_WASM_PREVIOUS gets valid delegations after upgrading to the current version.
 #[test]
 fn should_get_valid_delegation_for_old_anchor_after_ii_upgrade() -> Result<(), CallError> {
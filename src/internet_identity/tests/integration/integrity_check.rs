@@ -0,0 +1,172 @@
+//! Tests for the integrity scan (`integrity_scan_start` and `integrity_scan_report`).
+
+use candid::Principal;
+use canister_tests::api::internet_identity as api;
+use canister_tests::flows;
+use canister_tests::framework::{
+    env, expect_user_error_with_message, install_ii_canister, principal_1,
+    restore_compressed_stable_memory, upgrade_ii_canister, EMPTY_WASM, II_WASM,
+};
+use ic_test_state_machine_client::CallError;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use internet_identity_interface::internet_identity::types::{
+    IntegrityScanArg, IntegrityViolationCounts, IntegrityViolationKind,
+};
+use regex::Regex;
+
+/// Verifies that a scan over valid anchors completes without violations.
+#[test]
+fn should_scan_anchors_without_violations() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    flows::register_anchor(&env, canister_id);
+    flows::register_anchor(&env, canister_id);
+
+    assert_eq!(
+        api::integrity_scan_report(&env, canister_id, Principal::anonymous())?,
+        None
+    );
+    api::integrity_scan_start(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &IntegrityScanArg::default(),
+    )?;
+    env.tick();
+
+    let report = api::integrity_scan_report(&env, canister_id, Principal::anonymous())?
+        .expect("no integrity scan report");
+    assert!(report.completed_at.is_some());
+    assert!(!report.repair);
+    assert_eq!(report.scanned_anchors, 2);
+    assert_eq!(report.anchors_with_violations, 0);
+    assert_eq!(report.violation_counts, IntegrityViolationCounts::default());
+    assert!(report.violations.is_empty());
+    Ok(())
+}
+
+/// Verifies that anchors violating invariants introduced after they were written are reported,
+/// but not modified, as multiple recovery phrases cannot be fixed safely.
+#[test]
+fn should_report_legacy_anchor_with_multiple_recovery_phrases() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, EMPTY_WASM.clone());
+    restore_compressed_stable_memory(
+        &env,
+        canister_id,
+        "stable_memory/multiple-recovery-phrases-v7.bin.gz",
+    );
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+    let devices_before =
+        api::get_anchor_info(&env, canister_id, principal_1(), 10_000)?.into_device_data();
+
+    api::integrity_scan_start(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &IntegrityScanArg {
+            repair: Some(true),
+            ..Default::default()
+        },
+    )?;
+    env.tick();
+
+    let report = api::integrity_scan_report(&env, canister_id, Principal::anonymous())?
+        .expect("no integrity scan report");
+    assert!(report.completed_at.is_some());
+    assert!(report.repair);
+    assert!(report.violation_counts.anchor_invariant >= 1);
+    assert_eq!(report.repaired_anchors, 0);
+    let violation = report
+        .violations
+        .iter()
+        .find(|violation| violation.anchor_number == 10_000)
+        .expect("anchor 10000 not reported");
+    assert_eq!(violation.kind, IntegrityViolationKind::AnchorInvariant);
+    assert!(!violation.repaired);
+    assert!(violation.message.contains("recovery phrase"));
+
+    let devices_after =
+        api::get_anchor_info(&env, canister_id, principal_1(), 10_000)?.into_device_data();
+    assert_eq!(devices_after, devices_before);
+    Ok(())
+}
+
+/// Verifies that a running scan can only be replaced if it is explicitly restarted, e.g. because
+/// it stalled.
+#[test]
+fn should_only_replace_running_scan_on_restart() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, EMPTY_WASM.clone());
+    // more anchors than checked in a single batch
+    restore_compressed_stable_memory(
+        &env,
+        canister_id,
+        "stable_memory/persistent_state_full_bucket_v7_2047_ids.bin.gz",
+    );
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+    api::integrity_scan_start(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &IntegrityScanArg::default(),
+    )?;
+    let result = api::integrity_scan_start(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &IntegrityScanArg::default(),
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("an integrity scan is already running").unwrap(),
+    );
+
+    api::integrity_scan_start(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &IntegrityScanArg {
+            restart: Some(true),
+            ..Default::default()
+        },
+    )?;
+    for _ in 0..5 {
+        env.tick();
+    }
+
+    // the batches of the aborted scan are not counted
+    let report = api::integrity_scan_report(&env, canister_id, Principal::anonymous())?
+        .expect("no integrity scan report");
+    assert!(report.completed_at.is_some());
+    assert_eq!(report.scanned_anchors, 2047);
+    Ok(())
+}
+
+/// Verifies that only controllers can start a scan and read the report.
+#[test]
+fn should_only_allow_controllers_to_scan() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = api::integrity_scan_start(
+        &env,
+        canister_id,
+        principal_1(),
+        &IntegrityScanArg::default(),
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("is not allowed to start an integrity scan").unwrap(),
+    );
+
+    let result = api::integrity_scan_report(&env, canister_id, principal_1());
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("is not allowed to read the integrity scan report").unwrap(),
+    );
+}
@@ -7,8 +7,10 @@
 mod active_anchor_stats;
 mod anchor_management;
 mod archive_integration;
+mod config;
 mod delegation;
 mod http;
+mod integrity_check;
 mod latest_delegation_origins;
 mod rollback;
 mod stable_memory;
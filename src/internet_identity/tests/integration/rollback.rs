@@ -6,6 +6,7 @@ use canister_tests::api::internet_identity as api;
 use canister_tests::flows;
 use canister_tests::framework::*;
 use ic_test_state_machine_client::CallError;
+use internet_identity_interface::internet_identity::types::InternetIdentityInit;
 use serde_bytes::ByteBuf;
 
 /// Tests simple upgrade and downgrade.
@@ -93,3 +94,33 @@ fn should_keep_new_anchor_across_rollback() -> Result<(), CallError> {
     )?;
     Ok(())
 }
+
+/// Verifies that the persistent state of a storage with managed memory (layout version 7) survives
+/// a rollback to the previous version, which reads it only from after the anchor records.
+#[test]
+fn should_keep_persistent_state_v7_across_rollback() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            canister_creation_cycles_cost: Some(0),
+            migrate_storage_to_memory_manager: Some(true),
+            ..Default::default()
+        }),
+    );
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    // roll back
+    upgrade_ii_canister(&env, canister_id, II_WASM_PREVIOUS.clone());
+    api::health_check(&env, canister_id);
+    let devices =
+        api::get_anchor_info(&env, canister_id, principal_1(), user_number)?.into_device_data();
+    assert_eq!(devices, vec![device_data_1()]);
+
+    // and upgrade again
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+    api::health_check(&env, canister_id);
+    flows::register_anchor(&env, canister_id);
+    Ok(())
+}
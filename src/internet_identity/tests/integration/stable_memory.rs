@@ -12,6 +12,7 @@ use rand::Rng;
 use regex::Regex;
 use serde_bytes::ByteBuf;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[allow(dead_code)]
 mod test_setup_helpers;
@@ -67,6 +68,18 @@ fn known_devices() -> [DeviceData; 6] {
     [device1, device2, device3, device4, device5, device6]
 }
 
+/// Tests that fresh installations use the memory manager (layout version 7), so that the stable maps
+/// are persisted across upgrades.
+#[test]
+fn should_use_memory_manager_for_new_installations() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let stats = api::stats(&env, canister_id)?;
+    assert_eq!(7, stats.storage_layout_version);
+    Ok(())
+}
+
 /// Tests that some known anchors with their respective devices are available after stable memory restore.
 /// Uses the same data initially created using the genesis layout and then migrated until v6.
 #[test]
@@ -81,6 +94,7 @@ fn should_load_genesis_migrated_to_v6_backup() -> Result<(), CallError> {
         canister_id,
         "stable_memory/genesis-layout-migrated-to-v6.bin.gz",
     );
+    // storages with layout version 6 are migrated to the memory manager without arguments
     upgrade_ii_canister(&env, canister_id, II_WASM.clone());
 
     // check known anchors in the backup
@@ -103,7 +117,7 @@ fn should_load_genesis_migrated_to_v6_backup() -> Result<(), CallError> {
     assert_eq!(devices, vec![device5, device6]);
 
     let stats = api::stats(&env, canister_id)?;
-    assert_eq!(6, stats.storage_layout_version);
+    assert_eq!(7, stats.storage_layout_version);
     Ok(())
 }
 
@@ -309,7 +323,8 @@ fn should_allow_modification_after_deleting_second_recovery_phrase() -> Result<(
     Ok(())
 }
 
-/// Verifies that a stable memory backup with persistent state can be used for an upgrade.
+/// Verifies that a stable memory backup with persistent state can be used for an upgrade (and is
+/// migrated to layout version 7).
 #[test]
 fn should_read_persistent_state_v6() -> Result<(), CallError> {
     let env = env();
@@ -329,7 +344,7 @@ fn should_read_persistent_state_v6() -> Result<(), CallError> {
     let stats = api::stats(&env, canister_id)?;
     assert!(stats.archive_info.archive_canister.is_none());
     assert!(stats.archive_info.archive_config.is_none());
-    assert_eq!(6, stats.storage_layout_version);
+    assert_eq!(7, stats.storage_layout_version);
     Ok(())
 }
 
@@ -384,6 +399,110 @@ fn should_read_persistent_state_v7() -> Result<(), CallError> {
     Ok(())
 }
 
+/// Tests that the persistent state is written to its dedicated memory when it changes, i.e. it
+/// survives both new anchors (which used to overwrite it) and upgrades.
+#[test]
+fn should_keep_persistent_state_v7_across_registrations_and_upgrades() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, EMPTY_WASM.clone());
+
+    restore_compressed_stable_memory(
+        &env,
+        canister_id,
+        "stable_memory/persistent_state_no_archive_v7.bin.gz",
+    );
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+    api::config_update(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        &InternetIdentityInit {
+            max_num_latest_delegation_origins: Some(42),
+            ..Default::default()
+        },
+    )?;
+    flows::register_anchor(&env, canister_id);
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+    flows::register_anchor(&env, canister_id);
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+    let stats = api::stats(&env, canister_id)?;
+    assert_eq!(stats.max_num_latest_delegation_origins, 42);
+    assert_eq!(7, stats.storage_layout_version);
+    Ok(())
+}
+
+/// Tests that the persistent state is written by every call changing it (e.g. a login) and that the
+/// instructions used to write it with a full archive buffer are reported.
+#[test]
+fn should_write_persistent_state_on_change() -> Result<(), CallError> {
+    const WRITES: &str = "internet_identity_persistent_state_writes";
+    const WRITE_INSTRUCTIONS: &str = "internet_identity_persistent_state_write_instructions";
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            archive_config: Some(ArchiveConfig {
+                module_hash: archive_wasm_hash(&ARCHIVE_WASM),
+                entries_buffer_limit: 100,
+                polling_interval_ns: Duration::from_secs(24 * 60 * 60).as_nanos() as u64,
+                entries_fetch_limit: 10,
+            }),
+            canister_creation_cycles_cost: Some(0),
+            migrate_storage_to_memory_manager: Some(true),
+            ..Default::default()
+        }),
+    );
+    deploy_archive_via_ii(&env, canister_id);
+    let anchor_number = flows::register_anchor(&env, canister_id);
+
+    // fill the archive buffer
+    loop {
+        if api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            anchor_number,
+            &device_data_2(),
+        )
+        .is_err()
+            || api::remove(
+                &env,
+                canister_id,
+                principal_1(),
+                anchor_number,
+                &device_data_2().pubkey,
+            )
+            .is_err()
+        {
+            break;
+        }
+    }
+    assert_metric(
+        &get_metrics(&env, canister_id),
+        "internet_identity_buffered_archive_entries",
+        100f64,
+    );
+    let (writes, _) = parse_metric(&get_metrics(&env, canister_id), WRITES);
+
+    api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        anchor_number,
+        "https://some-dapp.com",
+        &ByteBuf::from("session key"),
+        None,
+    )?;
+    let metrics = get_metrics(&env, canister_id);
+    assert!(parse_metric(&metrics, WRITES).0 > writes);
+    let (instructions, _) = parse_metric(&metrics, WRITE_INSTRUCTIONS);
+    assert!(instructions > 0.0);
+    Ok(())
+}
+
 #[test]
 fn should_grow_persistent_state_v7_by_bucket_size() -> Result<(), CallError> {
     let env = env();
@@ -406,10 +525,14 @@ fn should_grow_persistent_state_v7_by_bucket_size() -> Result<(), CallError> {
     let stats = api::stats(&env, canister_id)?;
     assert_eq!(7, stats.storage_layout_version);
 
-    // Check the number of allocated memory pages before expansion.
+    // Check the number of allocated memory pages before expansion: 2 header pages, the bucket of
+    // the anchors and one bucket each for the maps of the tentative device registrations, the
+    // inflight challenges, the temp keys, the recovery queue (2 maps), the guardian recovery
+    // requests, the guardian index and the delegation sessions and for the persistent state, all
+    // allocated on upgrade.
     let metrics = get_metrics(&env, canister_id);
     let (stable_memory_pages, _) = parse_metric(&metrics, "internet_identity_stable_memory_pages");
-    assert_eq!(stable_memory_pages, 130f64);
+    assert_eq!(stable_memory_pages, 1282f64);
 
     // Verify a random existing anchor.
     let random_anchor_offset = rand::thread_rng().gen_range(0..anchor_count);
@@ -444,7 +567,7 @@ fn should_grow_persistent_state_v7_by_bucket_size() -> Result<(), CallError> {
     // Verify the number of allocated memory pages didn't grow yet.
     let metrics = get_metrics(&env, canister_id);
     let (stable_memory_pages, _) = parse_metric(&metrics, "internet_identity_stable_memory_pages");
-    assert_eq!(stable_memory_pages, 130f64);
+    assert_eq!(stable_memory_pages, 1282f64);
 
     // Add another anchor -- this DOES trigger an allocation of a new managed memory bucket.
     let anchor_offset = anchor_count + 1;
@@ -469,7 +592,7 @@ fn should_grow_persistent_state_v7_by_bucket_size() -> Result<(), CallError> {
     assert_eq!(next_anchor, new_anchor_number);
     let metrics = get_metrics(&env, canister_id);
     let (stable_memory_pages, _) = parse_metric(&metrics, "internet_identity_stable_memory_pages");
-    assert_eq!(stable_memory_pages, 258f64);
+    assert_eq!(stable_memory_pages, 1410f64);
 
     // Verify another random existing anchor (after addition of a new one).
     let random_anchor_offset = rand::thread_rng().gen_range(0..anchor_count);
@@ -520,6 +643,89 @@ fn should_read_persistent_state_with_archive() -> Result<(), CallError> {
     Ok(())
 }
 
+/// Verifies that a v7 stable memory backup is migrated to v8 and that the anchors and the persistent
+/// state are retained.
+#[test]
+fn should_migrate_v7_to_v8() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, EMPTY_WASM.clone());
+
+    restore_compressed_stable_memory(
+        &env,
+        canister_id,
+        "stable_memory/persistent_state_no_archive_v7.bin.gz",
+    );
+    let arg = InternetIdentityInit {
+        migrate_storage_to_stable_btree_map: Some(true),
+        ..Default::default()
+    };
+    upgrade_ii_canister_with_arg(&env, canister_id, II_WASM.clone(), Some(arg))
+        .expect("II upgrade failed.");
+    // the migration is driven by a timer
+    env.advance_time(Duration::from_secs(1));
+    env.tick();
+
+    let stats = api::stats(&env, canister_id)?;
+    assert_eq!(8, stats.storage_layout_version);
+    assert!(stats.storage_migration.is_none());
+    let devices = api::get_anchor_info(&env, canister_id, principal_1(), 127)?.into_device_data();
+    assert_eq!(devices.len(), 7);
+
+    // the persistent state is kept when upgrading the migrated storage
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+    let stats = api::stats(&env, canister_id)?;
+    assert_eq!(8, stats.storage_layout_version);
+    assert!(stats.archive_info.archive_config.is_none());
+    let devices = api::get_anchor_info(&env, canister_id, principal_1(), 127)?.into_device_data();
+    assert_eq!(devices.len(), 7);
+    Ok(())
+}
+
+/// Verifies that II can be installed with stable memory layout v8.
+#[test]
+fn should_install_with_layout_v8() -> Result<(), CallError> {
+    let env = env();
+    let arg = InternetIdentityInit {
+        migrate_storage_to_stable_btree_map: Some(true),
+        ..Default::default()
+    };
+    let canister_id = install_ii_canister_with_arg(&env, II_WASM.clone(), Some(arg));
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+    let devices =
+        api::get_anchor_info(&env, canister_id, principal_1(), user_number)?.into_device_data();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(api::stats(&env, canister_id)?.storage_layout_version, 8);
+    Ok(())
+}
+
+/// Verifies that anchors can grow beyond the limits of layout version 7 with layout v8.
+#[test]
+fn should_allow_more_and_larger_devices_with_layout_v8() -> Result<(), CallError> {
+    let env = env();
+    let arg = InternetIdentityInit {
+        migrate_storage_to_stable_btree_map: Some(true),
+        ..Default::default()
+    };
+    let canister_id = install_ii_canister_with_arg(&env, II_WASM.clone(), Some(arg));
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    // exceeds both the number of devices and the size of the records of layout version 7
+    for i in 0..11u8 {
+        let mut device = large_size_device();
+        device.pubkey = ByteBuf::from([i; 300]);
+        api::add(&env, canister_id, principal_1(), user_number, &device)?;
+    }
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+    let devices =
+        api::get_anchor_info(&env, canister_id, principal_1(), user_number)?.into_device_data();
+    assert_eq!(devices.len(), 12);
+    Ok(())
+}
+
 /// Tests that II will refuse to install on a stable memory layout that is no longer supported.
 #[test]
 fn should_trap_on_old_stable_memory() -> Result<(), CallError> {
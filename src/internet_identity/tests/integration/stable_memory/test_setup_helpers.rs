@@ -16,6 +16,8 @@ pub(crate) fn prepare_persistent_state_v7(anchor_count: usize) {
     assert_eq!(7, stats.storage_layout_version);
 
     for i in 0..anchor_count {
+        // stay within the default rate limit of create_challenge
+        env.advance_time(Duration::from_secs(1));
         let anchor_number =
             flows::register_anchor_with(&env, canister_id, principal(i), &sample_unique_device(i));
         api::add(
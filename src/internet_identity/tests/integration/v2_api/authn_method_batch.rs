@@ -0,0 +1,169 @@
+use crate::v2_api::authn_method_test_helpers::{
+    create_identity_with_authn_method, eq_ignoring_last_authentication, sample_authn_method,
+};
+use candid::Principal;
+use canister_tests::api::internet_identity::api_v2;
+use canister_tests::framework::{
+    env, expect_user_error_with_message, install_ii_canister, II_WASM,
+};
+use ic_cdk::api::management_canister::main::CanisterId;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use ic_test_state_machine_client::{CallError, StateMachine};
+use internet_identity_interface::internet_identity::types::{
+    AuthnMethodBatchResponse, AuthnMethodData, AuthnMethodError, AuthnMethodOperation,
+    IdentityInfoResponse, IdentityNumber,
+};
+use regex::Regex;
+
+#[test]
+fn should_apply_batch() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method_1 = sample_authn_method(1);
+    let principal = authn_method_1.principal();
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, authn_method_1.clone());
+
+    let operations = vec![
+        AuthnMethodOperation::Add(sample_authn_method(2)),
+        AuthnMethodOperation::Add(sample_authn_method(3)),
+        AuthnMethodOperation::Remove(sample_authn_method(2).public_key()),
+    ];
+    let result =
+        api_v2::authn_method_batch(&env, canister_id, principal, identity_number, &operations)?;
+    assert_eq!(result, Some(AuthnMethodBatchResponse::Ok));
+
+    let authn_methods = authn_methods(&env, canister_id, principal, identity_number)?;
+    assert_eq!(authn_methods.len(), 2);
+    assert!(eq_ignoring_last_authentication(
+        &authn_methods[0],
+        &authn_method_1
+    ));
+    assert!(eq_ignoring_last_authentication(
+        &authn_methods[1],
+        &sample_authn_method(3)
+    ));
+    Ok(())
+}
+
+#[test]
+fn should_check_limits_only_on_resulting_authn_methods() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method_1 = sample_authn_method(1);
+    let principal = authn_method_1.principal();
+    let identity_number = create_identity_with_authn_method(&env, canister_id, authn_method_1);
+
+    // fill the identity up to the limit of 10 authn methods
+    let operations = (2..=10)
+        .map(|i| AuthnMethodOperation::Add(sample_authn_method(i)))
+        .collect::<Vec<_>>();
+    let result =
+        api_v2::authn_method_batch(&env, canister_id, principal, identity_number, &operations)?;
+    assert_eq!(result, Some(AuthnMethodBatchResponse::Ok));
+
+    // adding first would exceed the limit if the operations were applied one by one
+    let operations = vec![
+        AuthnMethodOperation::Add(sample_authn_method(11)),
+        AuthnMethodOperation::Remove(sample_authn_method(2).public_key()),
+    ];
+    let result =
+        api_v2::authn_method_batch(&env, canister_id, principal, identity_number, &operations)?;
+    assert_eq!(result, Some(AuthnMethodBatchResponse::Ok));
+
+    let authn_methods = authn_methods(&env, canister_id, principal, identity_number)?;
+    assert_eq!(authn_methods.len(), 10);
+    Ok(())
+}
+
+#[test]
+fn should_not_apply_batch_if_operation_fails() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method_1 = sample_authn_method(1);
+    let principal = authn_method_1.principal();
+    let identity_number = create_identity_with_authn_method(&env, canister_id, authn_method_1);
+
+    let operations = vec![
+        AuthnMethodOperation::Add(sample_authn_method(2)),
+        AuthnMethodOperation::Remove(sample_authn_method(3).public_key()),
+    ];
+    let result =
+        api_v2::authn_method_batch(&env, canister_id, principal, identity_number, &operations)?;
+    assert_eq!(
+        result,
+        Some(AuthnMethodBatchResponse::OperationError {
+            index: 1,
+            error: AuthnMethodError::NotFound {
+                public_key: sample_authn_method(3).public_key()
+            }
+        })
+    );
+
+    let authn_methods = authn_methods(&env, canister_id, principal, identity_number)?;
+    assert_eq!(authn_methods.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn should_not_apply_batch_exceeding_limits() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method_1 = sample_authn_method(1);
+    let principal = authn_method_1.principal();
+    let identity_number = create_identity_with_authn_method(&env, canister_id, authn_method_1);
+
+    let operations = (2..=11)
+        .map(|i| AuthnMethodOperation::Add(sample_authn_method(i)))
+        .collect::<Vec<_>>();
+    let result =
+        api_v2::authn_method_batch(&env, canister_id, principal, identity_number, &operations)?;
+    assert_eq!(
+        result,
+        Some(AuthnMethodBatchResponse::AuthnMethodError(
+            AuthnMethodError::TooManyAuthnMethods {
+                limit: 10,
+                num_authn_methods: 11
+            }
+        ))
+    );
+
+    let authn_methods = authn_methods(&env, canister_id, principal, identity_number)?;
+    assert_eq!(authn_methods.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn should_require_authentication_to_apply_batch() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, sample_authn_method(1));
+
+    let result = api_v2::authn_method_batch(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        identity_number,
+        &[AuthnMethodOperation::Add(sample_authn_method(2))],
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+    );
+}
+
+fn authn_methods(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    principal: Principal,
+    identity_number: IdentityNumber,
+) -> Result<Vec<AuthnMethodData>, CallError> {
+    let Some(IdentityInfoResponse::Ok(identity_info)) =
+        api_v2::identity_info(env, canister_id, principal, identity_number)? else {
+        panic!("Expected identity info to be returned");
+    };
+    Ok(identity_info.authn_methods)
+}
@@ -0,0 +1,149 @@
+use crate::v2_api::authn_method_test_helpers::{
+    create_identity_with_authn_method, sample_authn_method,
+};
+use candid::Principal;
+use canister_tests::api::internet_identity as api;
+use canister_tests::api::internet_identity::api_v2;
+use canister_tests::framework::{
+    env, expect_user_error_with_message, install_ii_canister, II_WASM,
+};
+use ic_test_state_machine_client::CallError;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use internet_identity_interface::internet_identity::types::{
+    AuthnMethodMetadataReplaceResponse, IdentityInfoResponse, KeyType, MetadataEntry,
+};
+use regex::Regex;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+
+#[test]
+fn should_replace_authn_method_metadata() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method = sample_authn_method(1);
+    let principal = authn_method.principal();
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, authn_method.clone());
+    let metadata = HashMap::from([
+        (
+            "alias".to_string(),
+            MetadataEntry::String("new alias".to_string()),
+        ),
+        (
+            "some_key".to_string(),
+            MetadataEntry::Bytes(ByteBuf::from("some value")),
+        ),
+    ]);
+
+    let result = api_v2::authn_method_metadata_replace(
+        &env,
+        canister_id,
+        principal,
+        identity_number,
+        &authn_method.public_key(),
+        &metadata,
+    )?;
+    assert!(matches!(
+        result,
+        Some(AuthnMethodMetadataReplaceResponse::Ok)
+    ));
+
+    let Some(IdentityInfoResponse::Ok(identity_info)) =
+        api_v2::identity_info(&env, canister_id, principal, identity_number)? else {
+        panic!("Expected identity info to be returned");
+    };
+    assert_eq!(identity_info.authn_methods[0].metadata, metadata);
+    Ok(())
+}
+
+#[test]
+fn should_keep_key_type_if_not_specified() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method = sample_authn_method(1);
+    let principal = authn_method.principal();
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, authn_method.clone());
+    let mut device = api::get_anchor_info(&env, canister_id, principal, identity_number)?
+        .into_device_data()
+        .remove(0);
+    device.key_type = KeyType::SeedPhrase;
+    api::update(
+        &env,
+        canister_id,
+        principal,
+        identity_number,
+        &device.pubkey,
+        &device,
+    )?;
+
+    let result = api_v2::authn_method_metadata_replace(
+        &env,
+        canister_id,
+        principal,
+        identity_number,
+        &authn_method.public_key(),
+        &HashMap::new(),
+    )?;
+    assert!(matches!(
+        result,
+        Some(AuthnMethodMetadataReplaceResponse::Ok)
+    ));
+
+    let devices =
+        api::get_anchor_info(&env, canister_id, principal, identity_number)?.into_device_data();
+    assert_eq!(devices[0].key_type, KeyType::SeedPhrase);
+    Ok(())
+}
+
+#[test]
+fn should_require_authentication_to_replace_authn_method_metadata() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method = sample_authn_method(1);
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, authn_method.clone());
+
+    let result = api_v2::authn_method_metadata_replace(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        identity_number,
+        &authn_method.public_key(),
+        &HashMap::new(),
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+    );
+}
+
+#[test]
+fn should_report_error_on_failed_conversion() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method = sample_authn_method(1);
+    let principal = authn_method.principal();
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, authn_method.clone());
+
+    let result = api_v2::authn_method_metadata_replace(
+        &env,
+        canister_id,
+        principal,
+        identity_number,
+        &authn_method.public_key(),
+        &HashMap::from([(
+            "origin".to_string(),
+            MetadataEntry::Bytes(ByteBuf::from("invalid")),
+        )]),
+    )?;
+
+    assert!(matches!(
+        result,
+        Some(AuthnMethodMetadataReplaceResponse::InvalidMetadata(_))
+    ));
+    Ok(())
+}
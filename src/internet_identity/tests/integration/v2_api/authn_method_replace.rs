@@ -0,0 +1,160 @@
+use crate::v2_api::authn_method_test_helpers::{
+    create_identity_with_authn_method, eq_ignoring_last_authentication, sample_authn_method,
+};
+use candid::Principal;
+use canister_tests::api::internet_identity::api_v2;
+use canister_tests::framework::{
+    env, expect_user_error_with_message, install_ii_canister, II_WASM,
+};
+use ic_test_state_machine_client::CallError;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use internet_identity_interface::internet_identity::types::{
+    AuthnMethodAddResponse, AuthnMethodError, AuthnMethodReplaceResponse, IdentityInfoResponse,
+    MetadataEntry,
+};
+use regex::Regex;
+use serde_bytes::ByteBuf;
+
+#[test]
+fn should_replace_authn_method() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method_1 = sample_authn_method(1);
+    let principal = authn_method_1.principal();
+    let authn_method_2 = sample_authn_method(2);
+    let authn_method_3 = sample_authn_method(3);
+
+    let identity_number = create_identity_with_authn_method(&env, canister_id, authn_method_1);
+    let result = api_v2::authn_method_add(
+        &env,
+        canister_id,
+        principal,
+        identity_number,
+        &authn_method_2,
+    )?;
+    assert!(matches!(result, Some(AuthnMethodAddResponse::Ok)));
+
+    let result = api_v2::authn_method_replace(
+        &env,
+        canister_id,
+        principal,
+        identity_number,
+        &authn_method_2.public_key(),
+        &authn_method_3,
+    )?;
+    assert!(matches!(result, Some(AuthnMethodReplaceResponse::Ok)));
+
+    let Some(IdentityInfoResponse::Ok(identity_info)) =
+        api_v2::identity_info(&env, canister_id, principal, identity_number)? else {
+        panic!("Expected identity info to be returned");
+    };
+    assert_eq!(identity_info.authn_methods.len(), 2);
+    assert!(eq_ignoring_last_authentication(
+        &identity_info.authn_methods[1],
+        &authn_method_3
+    ));
+    Ok(())
+}
+
+#[test]
+fn should_require_authentication_to_replace_authn_method() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method = sample_authn_method(1);
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, authn_method.clone());
+
+    let result = api_v2::authn_method_replace(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        identity_number,
+        &authn_method.public_key(),
+        &sample_authn_method(2),
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+    );
+}
+
+#[test]
+fn should_report_error_on_failed_conversion() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method_1 = sample_authn_method(1);
+    let principal = authn_method_1.principal();
+    let mut authn_method_2 = sample_authn_method(2);
+    authn_method_2.metadata.insert(
+        "alias".to_string(),
+        MetadataEntry::Bytes(ByteBuf::from("invalid")),
+    );
+
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, authn_method_1.clone());
+    let result = api_v2::authn_method_replace(
+        &env,
+        canister_id,
+        principal,
+        identity_number,
+        &authn_method_1.public_key(),
+        &authn_method_2,
+    )?;
+
+    assert!(matches!(
+        result,
+        Some(AuthnMethodReplaceResponse::InvalidMetadata(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn should_keep_authn_method_if_replacement_is_rejected() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method_1 = sample_authn_method(1);
+    let principal = authn_method_1.principal();
+    let authn_method_2 = sample_authn_method(2);
+
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, authn_method_1.clone());
+    let result = api_v2::authn_method_add(
+        &env,
+        canister_id,
+        principal,
+        identity_number,
+        &authn_method_2,
+    )?;
+    assert!(matches!(result, Some(AuthnMethodAddResponse::Ok)));
+
+    // replacing authn_method_1 with authn_method_2 must fail because it is already registered
+    let result = api_v2::authn_method_replace(
+        &env,
+        canister_id,
+        principal,
+        identity_number,
+        &authn_method_1.public_key(),
+        &authn_method_2,
+    )?;
+    assert_eq!(
+        result,
+        Some(AuthnMethodReplaceResponse::AuthnMethodError(
+            AuthnMethodError::DuplicatePublicKey {
+                public_key: authn_method_2.public_key()
+            }
+        ))
+    );
+
+    let Some(IdentityInfoResponse::Ok(identity_info)) =
+        api_v2::identity_info(&env, canister_id, principal, identity_number)? else {
+        panic!("Expected identity info to be returned");
+    };
+    assert_eq!(identity_info.authn_methods.len(), 2);
+    assert!(eq_ignoring_last_authentication(
+        &identity_info.authn_methods[0],
+        &authn_method_1
+    ));
+    Ok(())
+}
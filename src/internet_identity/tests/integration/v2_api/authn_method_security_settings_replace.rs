@@ -0,0 +1,77 @@
+use crate::v2_api::authn_method_test_helpers::{
+    create_identity_with_authn_method, sample_authn_method,
+};
+use candid::Principal;
+use canister_tests::api::internet_identity::api_v2;
+use canister_tests::framework::{
+    env, expect_user_error_with_message, install_ii_canister, II_WASM,
+};
+use ic_test_state_machine_client::CallError;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use internet_identity_interface::internet_identity::types::{
+    AuthnMethodProtection, AuthnMethodSecuritySettings, AuthnMethodSecuritySettingsReplaceResponse,
+    IdentityInfoResponse, Purpose,
+};
+use regex::Regex;
+
+#[test]
+fn should_replace_authn_method_security_settings() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method = sample_authn_method(1);
+    let principal = authn_method.principal();
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, authn_method.clone());
+    // only recovery phrases can be protected
+    let security_settings = AuthnMethodSecuritySettings {
+        protection: AuthnMethodProtection::Unprotected,
+        purpose: Purpose::Recovery,
+    };
+
+    let result = api_v2::authn_method_security_settings_replace(
+        &env,
+        canister_id,
+        principal,
+        identity_number,
+        &authn_method.public_key(),
+        &security_settings,
+    )?;
+    assert!(matches!(
+        result,
+        Some(AuthnMethodSecuritySettingsReplaceResponse::Ok)
+    ));
+
+    let Some(IdentityInfoResponse::Ok(identity_info)) =
+        api_v2::identity_info(&env, canister_id, principal, identity_number)? else {
+        panic!("Expected identity info to be returned");
+    };
+    assert_eq!(identity_info.authn_methods[0].purpose, Purpose::Recovery);
+    Ok(())
+}
+
+#[test]
+fn should_require_authentication_to_replace_authn_method_security_settings() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let authn_method = sample_authn_method(1);
+    let identity_number =
+        create_identity_with_authn_method(&env, canister_id, authn_method.clone());
+
+    let result = api_v2::authn_method_security_settings_replace(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        identity_number,
+        &authn_method.public_key(),
+        &AuthnMethodSecuritySettings {
+            protection: AuthnMethodProtection::Unprotected,
+            purpose: Purpose::Authentication,
+        },
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+    );
+}
@@ -0,0 +1,610 @@
+//! Tests for the recovery of identities with the approval of guardian identities.
+
+use crate::v2_api::authn_method_test_helpers::{
+    create_identity_with_authn_method, eq_ignoring_last_authentication, sample_authn_method,
+};
+use candid::Principal;
+use canister_tests::api::internet_identity::api_v2;
+use canister_tests::framework::{
+    env, expect_user_error_with_message, install_ii_canister, II_WASM,
+};
+use ic_cdk::api::management_canister::main::CanisterId;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use ic_test_state_machine_client::{CallError, StateMachine};
+use internet_identity_interface::internet_identity::types::{
+    AuthnMethodData, DeviceData, DeviceWithUsage, GuardianConfig, GuardianRecoveryApproveResponse,
+    GuardianRecoveryRequestResponse, IdentityDeleteResponse, IdentityGuardiansInfoResponse,
+    IdentityGuardiansSetResponse, IdentityInfoResponse, IdentityNumber,
+    IdentityRecoveryDelaySetResponse, IdentityRecoveryInfoResponse, PublicKey,
+};
+use regex::Regex;
+use std::time::Duration;
+
+fn principal(authn_method: &AuthnMethodData) -> Principal {
+    DeviceData::from(DeviceWithUsage::try_from(authn_method.clone()).unwrap()).principal()
+}
+
+fn public_key(authn_method: &AuthnMethodData) -> PublicKey {
+    DeviceData::from(DeviceWithUsage::try_from(authn_method.clone()).unwrap()).pubkey
+}
+
+/// Creates an identity (using sample authn method 1) with three guardians (using sample authn
+/// methods 2, 3 and 4) and a threshold of 2.
+fn identity_with_guardians(
+    env: &StateMachine,
+    canister_id: CanisterId,
+) -> Result<(IdentityNumber, Vec<IdentityNumber>), CallError> {
+    let identity_number =
+        create_identity_with_authn_method(env, canister_id, sample_authn_method(1));
+    let guardians: Vec<IdentityNumber> = (2..=4)
+        .map(|i| create_identity_with_authn_method(env, canister_id, sample_authn_method(i)))
+        .collect();
+
+    let result = api_v2::identity_guardians_set(
+        env,
+        canister_id,
+        principal(&sample_authn_method(1)),
+        identity_number,
+        Some(GuardianConfig {
+            guardians: guardians.clone(),
+            threshold: 2,
+        }),
+    )?;
+    assert_eq!(result, Some(IdentityGuardiansSetResponse::Ok));
+    Ok((identity_number, guardians))
+}
+
+#[test]
+fn should_recover_identity_with_guardian_approvals() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, guardians) = identity_with_guardians(&env, canister_id)?;
+    let new_authn_method = sample_authn_method(5);
+
+    let result = api_v2::guardian_recovery_request(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+        &new_authn_method,
+    )?;
+    assert!(matches!(
+        result,
+        Some(GuardianRecoveryRequestResponse::Ok { .. })
+    ));
+
+    let result = api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(2)),
+        guardians[0],
+        identity_number,
+        &public_key(&new_authn_method),
+    )?;
+    assert_eq!(
+        result,
+        Some(GuardianRecoveryApproveResponse::Ok {
+            approvals: 1,
+            threshold: 2
+        })
+    );
+
+    let result = api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(4)),
+        guardians[2],
+        identity_number,
+        &public_key(&new_authn_method),
+    )?;
+    assert_eq!(result, Some(GuardianRecoveryApproveResponse::Recovered));
+
+    // the new authn method can be used to access the identity
+    let Some(IdentityInfoResponse::Ok(identity_info)) = api_v2::identity_info(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+    )? else {
+        panic!("expected identity info to be returned");
+    };
+    assert_eq!(identity_info.authn_methods.len(), 2);
+    assert!(eq_ignoring_last_authentication(
+        &identity_info.authn_methods[1],
+        &new_authn_method
+    ));
+
+    let Some(IdentityGuardiansInfoResponse::Ok(guardians_info)) = api_v2::identity_guardians_info(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+    )? else {
+        panic!("expected guardians info to be returned");
+    };
+    assert_eq!(guardians_info.requests, vec![]);
+    Ok(())
+}
+
+#[test]
+fn should_require_guardian_for_approval() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, _) = identity_with_guardians(&env, canister_id)?;
+    let other_identity =
+        create_identity_with_authn_method(&env, canister_id, sample_authn_method(6));
+    let new_authn_method = sample_authn_method(5);
+
+    api_v2::guardian_recovery_request(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+        &new_authn_method,
+    )?;
+
+    let result = api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(6)),
+        other_identity,
+        identity_number,
+        &public_key(&new_authn_method),
+    )?;
+    assert_eq!(result, Some(GuardianRecoveryApproveResponse::NotAGuardian));
+    Ok(())
+}
+
+#[test]
+fn should_only_approve_requested_public_key() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, guardians) = identity_with_guardians(&env, canister_id)?;
+    let new_authn_method = sample_authn_method(5);
+
+    api_v2::guardian_recovery_request(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+        &new_authn_method,
+    )?;
+
+    let result = api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(2)),
+        guardians[0],
+        identity_number,
+        &public_key(&sample_authn_method(7)),
+    )?;
+    assert_eq!(
+        result,
+        Some(GuardianRecoveryApproveResponse::RequestNotFound)
+    );
+    Ok(())
+}
+
+#[test]
+fn should_require_caller_to_match_requested_public_key() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, _) = identity_with_guardians(&env, canister_id)?;
+
+    let result = api_v2::guardian_recovery_request(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(7)),
+        identity_number,
+        &sample_authn_method(5),
+    )?;
+    assert_eq!(
+        result,
+        Some(GuardianRecoveryRequestResponse::PublicKeyMismatch)
+    );
+    Ok(())
+}
+
+#[test]
+fn should_reject_invalid_guardian_config() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, guardians) = identity_with_guardians(&env, canister_id)?;
+
+    for config in [
+        GuardianConfig {
+            guardians: guardians.clone(),
+            threshold: 4,
+        },
+        GuardianConfig {
+            guardians: vec![guardians[0], guardians[0]],
+            threshold: 1,
+        },
+        GuardianConfig {
+            guardians: vec![identity_number],
+            threshold: 1,
+        },
+    ] {
+        let result = api_v2::identity_guardians_set(
+            &env,
+            canister_id,
+            principal(&sample_authn_method(1)),
+            identity_number,
+            Some(config),
+        )?;
+        assert!(matches!(
+            result,
+            Some(IdentityGuardiansSetResponse::InvalidConfig(_))
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn should_not_replace_approved_request() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, guardians) = identity_with_guardians(&env, canister_id)?;
+    let new_authn_method = sample_authn_method(5);
+
+    let Some(GuardianRecoveryRequestResponse::Ok { expiration }) =
+        api_v2::guardian_recovery_request(
+            &env,
+            canister_id,
+            principal(&new_authn_method),
+            identity_number,
+            &new_authn_method,
+        )? else {
+        panic!("expected the request to be accepted");
+    };
+    api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(2)),
+        guardians[0],
+        identity_number,
+        &public_key(&new_authn_method),
+    )?;
+
+    // the approved request is not renewed
+    let result = api_v2::guardian_recovery_request(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+        &new_authn_method,
+    )?;
+    assert_eq!(
+        result,
+        Some(GuardianRecoveryRequestResponse::RequestPending { expiration })
+    );
+
+    // nor replaced by (more than the maximum number of) other requests
+    for i in 10..20 {
+        let other_authn_method = sample_authn_method(i);
+        let result = api_v2::guardian_recovery_request(
+            &env,
+            canister_id,
+            principal(&other_authn_method),
+            identity_number,
+            &other_authn_method,
+        )?;
+        assert!(matches!(
+            result,
+            Some(GuardianRecoveryRequestResponse::Ok { .. })
+        ));
+    }
+
+    // the approved request can still be completed
+    let result = api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(3)),
+        guardians[1],
+        identity_number,
+        &public_key(&new_authn_method),
+    )?;
+    assert_eq!(result, Some(GuardianRecoveryApproveResponse::Recovered));
+    Ok(())
+}
+
+#[test]
+fn should_remove_deleted_guardian() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, guardians) = identity_with_guardians(&env, canister_id)?;
+    let new_authn_method = sample_authn_method(5);
+
+    api_v2::guardian_recovery_request(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+        &new_authn_method,
+    )?;
+    api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(2)),
+        guardians[0],
+        identity_number,
+        &public_key(&new_authn_method),
+    )?;
+
+    // delete the first guardian, confirming the deletion with a second authn method
+    api_v2::authn_method_add(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(2)),
+        guardians[0],
+        &sample_authn_method(6),
+    )?;
+    let Some(IdentityDeleteResponse::ConfirmationRequired { confirmation_token, .. }) =
+        api_v2::identity_delete(
+            &env,
+            canister_id,
+            principal(&sample_authn_method(2)),
+            guardians[0],
+            None,
+        )? else {
+        panic!("expected confirmation to be required");
+    };
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(6)),
+        guardians[0],
+        Some(&confirmation_token),
+    )?;
+    assert_eq!(result, Some(IdentityDeleteResponse::Ok));
+
+    let Some(IdentityGuardiansInfoResponse::Ok(guardians_info)) = api_v2::identity_guardians_info(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(1)),
+        identity_number,
+    )? else {
+        panic!("expected guardians info to be returned");
+    };
+    assert_eq!(
+        guardians_info.config,
+        Some(GuardianConfig {
+            guardians: guardians[1..].to_vec(),
+            threshold: 2,
+        })
+    );
+    assert_eq!(guardians_info.requests[0].approvals, vec![]);
+
+    // the remaining guardians can still recover the identity
+    for (guardian, authn_method) in [(guardians[1], 3), (guardians[2], 4)] {
+        api_v2::guardian_recovery_approve(
+            &env,
+            canister_id,
+            principal(&sample_authn_method(authn_method)),
+            guardian,
+            identity_number,
+            &public_key(&new_authn_method),
+        )?;
+    }
+    let Some(IdentityInfoResponse::Ok(info)) = api_v2::identity_info(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+    )? else {
+        panic!("expected identity info to be returned");
+    };
+    assert_eq!(info.authn_methods.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn should_keep_several_requests() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, guardians) = identity_with_guardians(&env, canister_id)?;
+    let new_authn_method = sample_authn_method(5);
+    let other_authn_method = sample_authn_method(7);
+
+    for authn_method in [&new_authn_method, &other_authn_method] {
+        let result = api_v2::guardian_recovery_request(
+            &env,
+            canister_id,
+            principal(authn_method),
+            identity_number,
+            authn_method,
+        )?;
+        assert!(matches!(
+            result,
+            Some(GuardianRecoveryRequestResponse::Ok { .. })
+        ));
+    }
+
+    let Some(IdentityGuardiansInfoResponse::Ok(guardians_info)) = api_v2::identity_guardians_info(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(1)),
+        identity_number,
+    )? else {
+        panic!("expected guardians info to be returned");
+    };
+    assert_eq!(guardians_info.requests.len(), 2);
+
+    // the request made first can still be approved
+    let result = api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(2)),
+        guardians[0],
+        identity_number,
+        &public_key(&new_authn_method),
+    )?;
+    assert_eq!(
+        result,
+        Some(GuardianRecoveryApproveResponse::Ok {
+            approvals: 1,
+            threshold: 2
+        })
+    );
+    Ok(())
+}
+
+#[test]
+fn should_replace_oldest_unapproved_request() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, guardians) = identity_with_guardians(&env, canister_id)?;
+
+    // one more request than the maximum number of pending requests
+    for i in 10..=15 {
+        let authn_method = sample_authn_method(i);
+        api_v2::guardian_recovery_request(
+            &env,
+            canister_id,
+            principal(&authn_method),
+            identity_number,
+            &authn_method,
+        )?;
+        env.advance_time(Duration::from_secs(1));
+    }
+
+    let Some(IdentityGuardiansInfoResponse::Ok(guardians_info)) = api_v2::identity_guardians_info(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(1)),
+        identity_number,
+    )? else {
+        panic!("expected guardians info to be returned");
+    };
+    assert_eq!(guardians_info.requests.len(), 5);
+
+    let result = api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(2)),
+        guardians[0],
+        identity_number,
+        &public_key(&sample_authn_method(10)),
+    )?;
+    assert_eq!(
+        result,
+        Some(GuardianRecoveryApproveResponse::RequestNotFound)
+    );
+    let result = api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(2)),
+        guardians[0],
+        identity_number,
+        &public_key(&sample_authn_method(11)),
+    )?;
+    assert!(matches!(
+        result,
+        Some(GuardianRecoveryApproveResponse::Ok { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn should_not_rate_limit_recovery_requests() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, _) = identity_with_guardians(&env, canister_id)?;
+    let new_authn_method = sample_authn_method(5);
+
+    // requests made by others cannot keep the legitimate requester from renewing their request
+    for _ in 0..10 {
+        let result = api_v2::guardian_recovery_request(
+            &env,
+            canister_id,
+            principal(&new_authn_method),
+            identity_number,
+            &new_authn_method,
+        )?;
+        assert!(matches!(
+            result,
+            Some(GuardianRecoveryRequestResponse::Ok { .. })
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn should_delay_recovery_of_identity_with_recovery_delay() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let (identity_number, guardians) = identity_with_guardians(&env, canister_id)?;
+    let result = api_v2::identity_recovery_delay_set(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(1)),
+        identity_number,
+        Some(Duration::from_secs(24 * 60 * 60).as_nanos() as u64),
+    )?;
+    assert_eq!(result, Some(IdentityRecoveryDelaySetResponse::Ok));
+    let new_authn_method = sample_authn_method(5);
+
+    api_v2::guardian_recovery_request(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+        &new_authn_method,
+    )?;
+    api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(2)),
+        guardians[0],
+        identity_number,
+        &public_key(&new_authn_method),
+    )?;
+    let result = api_v2::guardian_recovery_approve(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(3)),
+        guardians[1],
+        identity_number,
+        &public_key(&new_authn_method),
+    )?;
+    assert!(matches!(
+        result,
+        Some(GuardianRecoveryApproveResponse::RecoveryDelayed { .. })
+    ));
+    let Some(IdentityRecoveryInfoResponse::Ok(recovery_info)) = api_v2::identity_recovery_info(
+        &env,
+        canister_id,
+        principal(&sample_authn_method(1)),
+        identity_number,
+    )? else {
+        panic!("expected recovery info to be returned");
+    };
+    assert_eq!(recovery_info.queued_operations.len(), 1);
+
+    // the new authn method can only be used once the delay has passed
+    let result = api_v2::identity_info(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("could not be authenticated").unwrap(),
+    );
+
+    env.advance_time(Duration::from_secs(24 * 60 * 60 + 10 * 60));
+    env.tick();
+
+    let Some(IdentityInfoResponse::Ok(identity_info)) = api_v2::identity_info(
+        &env,
+        canister_id,
+        principal(&new_authn_method),
+        identity_number,
+    )? else {
+        panic!("expected identity info to be returned");
+    };
+    assert_eq!(identity_info.authn_methods.len(), 2);
+    Ok(())
+}
@@ -0,0 +1,353 @@
+//! Tests for the deletion of identities using `identity_delete`.
+
+use candid::Principal;
+use canister_tests::api::internet_identity as api;
+use canister_tests::api::internet_identity::api_v2;
+use canister_tests::flows;
+use canister_tests::framework::{
+    device_data_1, device_data_2, env, expect_user_error_with_message, install_ii_canister,
+    principal_1, principal_2, principal_recovery_1, recovery_device_data_1, test_principal,
+    II_WASM,
+};
+use ic_cdk::api::management_canister::main::CanisterId;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use ic_test_state_machine_client::{CallError, StateMachine};
+use internet_identity_interface::internet_identity::types::{
+    ChallengeAttempt, DeviceData, DeviceProtection, IdentityDeleteResponse, IdentityNumber,
+    RegisterResponse,
+};
+use regex::Regex;
+use serde_bytes::ByteBuf;
+use std::time::Duration;
+
+/// Creates an identity with two devices (of principal 1 and 2).
+fn identity_with_two_devices(
+    env: &StateMachine,
+    canister_id: CanisterId,
+) -> Result<IdentityNumber, CallError> {
+    let identity_number = flows::register_anchor(env, canister_id);
+    api::add(
+        env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        &device_data_2(),
+    )?;
+    Ok(identity_number)
+}
+
+/// Requests the deletion of the identity using the device of principal 1 and returns the
+/// confirmation token.
+fn request_deletion(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    identity_number: IdentityNumber,
+) -> Result<ByteBuf, CallError> {
+    let result = api_v2::identity_delete(env, canister_id, principal_1(), identity_number, None)?;
+    let Some(IdentityDeleteResponse::ConfirmationRequired { confirmation_token, .. }) = result else {
+        panic!("expected confirmation to be required, got {result:?}");
+    };
+    Ok(confirmation_token)
+}
+
+#[test]
+fn should_delete_identity_after_confirmation() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_two_devices(&env, canister_id)?;
+
+    let confirmation_token = request_deletion(&env, canister_id, identity_number)?;
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal_2(),
+        identity_number,
+        Some(&confirmation_token),
+    )?;
+    assert_eq!(result, Some(IdentityDeleteResponse::Ok));
+    assert!(api::lookup(&env, canister_id, identity_number)?.is_empty());
+
+    let result = api_v2::identity_info(&env, canister_id, principal_1(), identity_number);
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("Identity Anchor \\d+ has been deleted").unwrap(),
+    );
+    Ok(())
+}
+
+#[test]
+fn should_expire_deletion_request() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_two_devices(&env, canister_id)?;
+
+    let confirmation_token = request_deletion(&env, canister_id, identity_number)?;
+
+    env.advance_time(Duration::from_secs(6 * 60));
+
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal_2(),
+        identity_number,
+        Some(&confirmation_token),
+    )?;
+    assert_eq!(result, Some(IdentityDeleteResponse::InvalidConfirmation));
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn should_require_confirmation_token_from_other_device() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_two_devices(&env, canister_id)?;
+
+    let confirmation_token = request_deletion(&env, canister_id, identity_number)?;
+
+    // the device that requested the deletion cannot confirm it, not even after the waiting period
+    for _ in 0..2 {
+        let result = api_v2::identity_delete(
+            &env,
+            canister_id,
+            principal_1(),
+            identity_number,
+            Some(&confirmation_token),
+        )?;
+        assert_eq!(
+            result,
+            Some(IdentityDeleteResponse::AuthnMethodNotAllowedToConfirm)
+        );
+        env.advance_time(Duration::from_secs(61 * 60));
+    }
+    let confirmation_token = request_deletion(&env, canister_id, identity_number)?;
+
+    // the confirmation token must match
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal_2(),
+        identity_number,
+        Some(&ByteBuf::from(vec![0; 32])),
+    )?;
+    assert_eq!(result, Some(IdentityDeleteResponse::InvalidConfirmation));
+
+    // repeating the request replaces the pending request instead of confirming it
+    let new_confirmation_token = request_deletion(&env, canister_id, identity_number)?;
+    assert_ne!(new_confirmation_token, confirmation_token);
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal_2(),
+        identity_number,
+        Some(&confirmation_token),
+    )?;
+    assert_eq!(result, Some(IdentityDeleteResponse::InvalidConfirmation));
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn should_delete_single_device_identity_after_waiting_period() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+
+    let result = api_v2::identity_delete(&env, canister_id, principal_1(), identity_number, None)?;
+    let Some(IdentityDeleteResponse::ConfirmationRequired {
+        confirmation_token,
+        delayed_confirmation_start: Some(delayed_confirmation_start),
+        ..
+    }) = result else {
+        panic!("expected confirmation to be required, got {result:?}");
+    };
+
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        Some(&confirmation_token),
+    )?;
+    assert_eq!(
+        result,
+        Some(IdentityDeleteResponse::ConfirmationNotYetAllowed {
+            delayed_confirmation_start
+        })
+    );
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 1);
+
+    env.advance_time(Duration::from_secs(61 * 60));
+
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        Some(&confirmation_token),
+    )?;
+    assert_eq!(result, Some(IdentityDeleteResponse::Ok));
+    assert!(api::lookup(&env, canister_id, identity_number)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn should_not_delete_single_device_identity_if_other_device_used_since_request(
+) -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+
+    let confirmation_token = request_deletion(&env, canister_id, identity_number)?;
+    api::add(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        &device_data_2(),
+    )?;
+    env.advance_time(Duration::from_secs(10 * 60));
+    api::get_anchor_info(&env, canister_id, principal_2(), identity_number)?;
+
+    env.advance_time(Duration::from_secs(51 * 60));
+
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        Some(&confirmation_token),
+    )?;
+    assert_eq!(result, Some(IdentityDeleteResponse::InvalidConfirmation));
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn should_not_confirm_deletion_using_temp_key() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let temp_key = test_principal(1);
+    let challenge = api::create_challenge(&env, canister_id)?;
+    let RegisterResponse::Registered { user_number: identity_number } = api::register(
+        &env,
+        canister_id,
+        temp_key,
+        &device_data_2(),
+        &ChallengeAttempt {
+            chars: "a".to_string(),
+            key: challenge.challenge_key,
+        },
+        Some(temp_key),
+    )? else {
+        panic!("expected the identity to be registered");
+    };
+    api::add(
+        &env,
+        canister_id,
+        principal_2(),
+        identity_number,
+        &device_data_1(),
+    )?;
+
+    let confirmation_token = request_deletion(&env, canister_id, identity_number)?;
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        temp_key,
+        identity_number,
+        Some(&confirmation_token),
+    )?;
+    assert_eq!(
+        result,
+        Some(IdentityDeleteResponse::AuthnMethodNotAllowedToConfirm)
+    );
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn should_expire_delayed_deletion_confirmation() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+
+    let confirmation_token = request_deletion(&env, canister_id, identity_number)?;
+
+    env.advance_time(Duration::from_secs(121 * 60));
+
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        Some(&confirmation_token),
+    )?;
+    assert_eq!(result, Some(IdentityDeleteResponse::InvalidConfirmation));
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn should_require_protected_recovery_method_if_present() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+    let protected_recovery_phrase = DeviceData {
+        protection: DeviceProtection::Protected,
+        ..recovery_device_data_1()
+    };
+    api::add(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        &protected_recovery_phrase,
+    )?;
+
+    for _ in 0..2 {
+        let result =
+            api_v2::identity_delete(&env, canister_id, principal_1(), identity_number, None)?;
+        assert_eq!(
+            result,
+            Some(IdentityDeleteResponse::ProtectedRecoveryMethodRequired)
+        );
+    }
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+
+    // no confirmation required when using the protected recovery method
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        None,
+    )?;
+    assert_eq!(result, Some(IdentityDeleteResponse::Ok));
+    assert!(api::lookup(&env, canister_id, identity_number)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn should_require_authentication_to_delete_identity() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        Principal::anonymous(),
+        identity_number,
+        None,
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+    );
+}
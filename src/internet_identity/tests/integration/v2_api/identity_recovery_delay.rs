@@ -0,0 +1,788 @@
+//! Tests for the time-locked recovery of identities with a recovery delay.
+
+use canister_tests::api::internet_identity as api;
+use canister_tests::api::internet_identity::api_v2;
+use canister_tests::flows;
+use canister_tests::framework::{
+    device_data_1, device_data_2, env, expect_user_error_with_message, install_ii_canister,
+    principal_1, principal_2, principal_recovery_1, recovery_device_data_1, upgrade_ii_canister,
+    II_WASM,
+};
+use ic_cdk::api::management_canister::main::CanisterId;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use ic_test_state_machine_client::{CallError, StateMachine};
+use internet_identity_interface::internet_identity::types::{
+    AddTentativeDeviceResponse, AnchorNumber, AuthnMethodAddResponse, AuthnMethodData,
+    AuthnMethodError, AuthnMethodMetadataReplaceResponse, AuthnMethodProtection,
+    AuthnMethodRemoveResponse, AuthnMethodSecuritySettings,
+    AuthnMethodSecuritySettingsReplaceResponse, DeviceData, DeviceProtection, DeviceWithUsage,
+    DroppedRecoveryOperation, IdentityDeleteResponse, IdentityRecoveryDelaySetResponse,
+    IdentityRecoveryInfoResponse, MetadataEntry, Purpose, QueuedRecoveryOperation,
+    RecoveryOperation, RecoveryOperationCancelResponse,
+};
+use regex::Regex;
+use std::time::Duration;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Registers an identity with a recovery phrase and a recovery delay of one day.
+fn identity_with_recovery_delay(
+    env: &StateMachine,
+    canister_id: CanisterId,
+) -> Result<AnchorNumber, CallError> {
+    let identity_number = flows::register_anchor(env, canister_id);
+    api::add(
+        env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        &recovery_device_data_1(),
+    )?;
+    let result = api_v2::identity_recovery_delay_set(
+        env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        Some(DAY_NS),
+    )?;
+    assert_eq!(result, Some(IdentityRecoveryDelaySetResponse::Ok));
+    Ok(identity_number)
+}
+
+/// Returns the operations queued for the given identity.
+fn queued_operations(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    identity_number: AnchorNumber,
+) -> Result<Vec<QueuedRecoveryOperation>, CallError> {
+    let Some(IdentityRecoveryInfoResponse::Ok(info)) =
+        api_v2::identity_recovery_info(env, canister_id, principal_1(), identity_number)? else {
+        panic!("expected recovery info to be returned");
+    };
+    Ok(info.queued_operations)
+}
+
+/// Returns the operations of the given identity that have been dropped.
+fn dropped_operations(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    identity_number: AnchorNumber,
+) -> Result<Vec<DroppedRecoveryOperation>, CallError> {
+    let Some(IdentityRecoveryInfoResponse::Ok(info)) =
+        api_v2::identity_recovery_info(env, canister_id, principal_2(), identity_number)? else {
+        panic!("expected recovery info to be returned");
+    };
+    Ok(info.dropped_operations)
+}
+
+/// Asserts that a v1 call was rejected because the operation has been queued and returns the id
+/// of the queued operation.
+fn expect_queued(result: Result<(), CallError>) -> u64 {
+    let Err(CallError::Reject(message)) = result else {
+        panic!("expected the call to be rejected, got {result:?}");
+    };
+    Regex::new("queued as recovery operation ([0-9]+)")
+        .unwrap()
+        .captures(&message)
+        .unwrap_or_else(|| panic!("unexpected reject message: {message}"))[1]
+        .parse()
+        .unwrap()
+}
+
+fn authn_method(device: DeviceData) -> AuthnMethodData {
+    AuthnMethodData::from(DeviceWithUsage::from(device))
+}
+
+#[test]
+fn should_delay_removal_by_recovery_method() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    let result = api_v2::authn_method_remove(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_1().pubkey,
+    )?;
+    let Some(AuthnMethodRemoveResponse::RecoveryDelayed { id, .. }) = result else {
+        panic!("expected the removal to be delayed, got {result:?}");
+    };
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+
+    let Some(IdentityRecoveryInfoResponse::Ok(info)) =
+        api_v2::identity_recovery_info(&env, canister_id, principal_1(), identity_number)? else {
+        panic!("expected recovery info to be returned");
+    };
+    assert_eq!(info.recovery_delay_ns, Some(DAY_NS));
+    assert_eq!(info.queued_operations.len(), 1);
+    assert_eq!(info.queued_operations[0].id, id);
+    assert_eq!(
+        info.queued_operations[0].operation,
+        RecoveryOperation::AuthnMethodRemove {
+            public_key: device_data_1().pubkey
+        }
+    );
+
+    // the queued operation is applied by a timer once the delay has passed
+    env.advance_time(Duration::from_secs(24 * 60 * 60 + 10 * 60));
+    env.tick();
+
+    let devices = api::lookup(&env, canister_id, identity_number)?;
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].pubkey, recovery_device_data_1().pubkey);
+    Ok(())
+}
+
+#[test]
+fn should_keep_queued_operations_across_upgrades() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    let result = api_v2::authn_method_remove(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_1().pubkey,
+    )?;
+    assert!(matches!(
+        result,
+        Some(AuthnMethodRemoveResponse::RecoveryDelayed { .. })
+    ));
+
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+    assert_eq!(
+        queued_operations(&env, canister_id, identity_number)?.len(),
+        1
+    );
+
+    env.advance_time(Duration::from_secs(24 * 60 * 60 + 10 * 60));
+    env.tick();
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn should_apply_delayed_operation_on_protected_recovery_phrase() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+    api::add(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        &DeviceData {
+            protection: DeviceProtection::Protected,
+            ..recovery_device_data_1()
+        },
+    )?;
+    let result = api_v2::identity_recovery_delay_set(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        Some(DAY_NS),
+    )?;
+    assert_eq!(result, Some(IdentityRecoveryDelaySetResponse::Ok));
+
+    // unprotecting the recovery phrase is delayed and later applied by the canister timer
+    let result = api_v2::authn_method_security_settings_replace(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &recovery_device_data_1().pubkey,
+        &AuthnMethodSecuritySettings {
+            protection: AuthnMethodProtection::Unprotected,
+            purpose: Purpose::Recovery,
+        },
+    )?;
+    assert!(matches!(
+        result,
+        Some(AuthnMethodSecuritySettingsReplaceResponse::RecoveryDelayed { .. })
+    ));
+
+    env.advance_time(Duration::from_secs(24 * 60 * 60 + 10 * 60));
+    env.tick();
+
+    let devices = api::lookup(&env, canister_id, identity_number)?;
+    let recovery_phrase = devices
+        .iter()
+        .find(|device| device.pubkey == recovery_device_data_1().pubkey)
+        .expect("recovery phrase not found");
+    assert_eq!(recovery_phrase.protection, DeviceProtection::Unprotected);
+    let Some(IdentityRecoveryInfoResponse::Ok(info)) =
+        api_v2::identity_recovery_info(&env, canister_id, principal_1(), identity_number)? else {
+        panic!("expected recovery info to be returned");
+    };
+    assert!(info.queued_operations.is_empty());
+    assert!(info.dropped_operations.is_empty());
+    Ok(())
+}
+
+#[test]
+fn should_report_dropped_operation() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    let Some(AuthnMethodRemoveResponse::RecoveryDelayed { id, .. }) = api_v2::authn_method_remove(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_1().pubkey,
+    )? else {
+        panic!("expected the removal to be delayed");
+    };
+    // the device is removed before the queued removal is due
+    api::add(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        &device_data_2(),
+    )?;
+    api::remove(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        &device_data_1().pubkey,
+    )?;
+
+    env.advance_time(Duration::from_secs(24 * 60 * 60 + 10 * 60));
+    env.tick();
+
+    let Some(IdentityRecoveryInfoResponse::Ok(info)) =
+        api_v2::identity_recovery_info(&env, canister_id, principal_2(), identity_number)? else {
+        panic!("expected recovery info to be returned");
+    };
+    assert!(info.queued_operations.is_empty());
+    assert_eq!(info.dropped_operations.len(), 1);
+    assert_eq!(info.dropped_operations[0].operation.id, id);
+    assert!(info.dropped_operations[0].error.contains("not found"));
+
+    // dropped operations can be removed from the recovery info
+    let result = api_v2::identity_recovery_operation_cancel(
+        &env,
+        canister_id,
+        principal_2(),
+        identity_number,
+        id,
+    )?;
+    assert_eq!(result, Some(RecoveryOperationCancelResponse::Ok));
+    assert!(dropped_operations(&env, canister_id, identity_number)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn should_drop_operations_of_removed_recovery_method() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    let Some(AuthnMethodAddResponse::RecoveryDelayed { id, .. }) = api_v2::authn_method_add(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &authn_method(device_data_2()),
+    )? else {
+        panic!("expected the addition to be delayed");
+    };
+    // the owner removes the (leaked) recovery method
+    api::remove(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        &recovery_device_data_1().pubkey,
+    )?;
+
+    assert!(queued_operations(&env, canister_id, identity_number)?.is_empty());
+    let Some(IdentityRecoveryInfoResponse::Ok(info)) =
+        api_v2::identity_recovery_info(&env, canister_id, principal_1(), identity_number)? else {
+        panic!("expected recovery info to be returned");
+    };
+    let dropped = info.dropped_operations;
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(dropped[0].operation.id, id);
+    assert!(dropped[0].error.contains("has been removed"));
+
+    env.advance_time(Duration::from_secs(24 * 60 * 60 + 10 * 60));
+    env.tick();
+
+    let devices = api::lookup(&env, canister_id, identity_number)?;
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].pubkey, device_data_1().pubkey);
+    Ok(())
+}
+
+#[test]
+fn should_drop_operations_when_recovery_delay_is_removed() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    let Some(AuthnMethodRemoveResponse::RecoveryDelayed { id, .. }) = api_v2::authn_method_remove(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_1().pubkey,
+    )? else {
+        panic!("expected the removal to be delayed");
+    };
+    let result = api_v2::identity_recovery_delay_set(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        None,
+    )?;
+    assert_eq!(result, Some(IdentityRecoveryDelaySetResponse::Ok));
+
+    assert!(queued_operations(&env, canister_id, identity_number)?.is_empty());
+    let Some(IdentityRecoveryInfoResponse::Ok(info)) =
+        api_v2::identity_recovery_info(&env, canister_id, principal_1(), identity_number)? else {
+        panic!("expected recovery info to be returned");
+    };
+    let dropped = info.dropped_operations;
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(dropped[0].operation.id, id);
+
+    env.advance_time(Duration::from_secs(24 * 60 * 60 + 10 * 60));
+    env.tick();
+
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn should_not_queue_more_than_max_operations() -> Result<(), CallError> {
+    const MAX_QUEUED_OPERATIONS: u64 = 10;
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+    let metadata_replace = |alias: String| {
+        api_v2::authn_method_metadata_replace(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            identity_number,
+            &device_data_1().pubkey,
+            &[("alias".to_string(), MetadataEntry::String(alias))]
+                .into_iter()
+                .collect(),
+        )
+    };
+
+    for i in 0..MAX_QUEUED_OPERATIONS {
+        let result = metadata_replace(format!("alias {i}"))?;
+        assert!(matches!(
+            result,
+            Some(AuthnMethodMetadataReplaceResponse::RecoveryDelayed { .. })
+        ));
+    }
+    let result = metadata_replace("one too many".to_string())?;
+    assert_eq!(
+        result,
+        Some(AuthnMethodMetadataReplaceResponse::AuthnMethodError(
+            AuthnMethodError::TooManyQueuedOperations {
+                limit: MAX_QUEUED_OPERATIONS
+            }
+        ))
+    );
+    assert_eq!(
+        queued_operations(&env, canister_id, identity_number)?.len(),
+        MAX_QUEUED_OPERATIONS as usize
+    );
+    Ok(())
+}
+
+#[test]
+fn should_cancel_delayed_operation() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    let Some(AuthnMethodRemoveResponse::RecoveryDelayed { id, .. }) = api_v2::authn_method_remove(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_1().pubkey,
+    )? else {
+        panic!("expected the removal to be delayed");
+    };
+
+    let result = api_v2::identity_recovery_operation_cancel(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        id,
+    )?;
+    assert_eq!(
+        result,
+        Some(RecoveryOperationCancelResponse::AuthenticationMethodRequired)
+    );
+
+    let result = api_v2::identity_recovery_operation_cancel(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        id,
+    )?;
+    assert_eq!(result, Some(RecoveryOperationCancelResponse::Ok));
+
+    env.advance_time(Duration::from_secs(24 * 60 * 60 + 10 * 60));
+    env.tick();
+
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn should_not_allow_recovery_method_to_reduce_delay() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    for delay in [None, Some(DAY_NS / 2)] {
+        let result = api_v2::identity_recovery_delay_set(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            identity_number,
+            delay,
+        )?;
+        assert_eq!(
+            result,
+            Some(IdentityRecoveryDelaySetResponse::AuthenticationMethodRequired)
+        );
+    }
+
+    let result = api_v2::identity_recovery_delay_set(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        Some(2 * DAY_NS),
+    )?;
+    assert_eq!(result, Some(IdentityRecoveryDelaySetResponse::Ok));
+
+    let result = api_v2::identity_recovery_delay_set(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        Some(31 * DAY_NS),
+    )?;
+    assert!(matches!(
+        result,
+        Some(IdentityRecoveryDelaySetResponse::InvalidDelay { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn should_not_allow_recovery_method_to_delete_identity() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    let result = api_v2::identity_delete(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        None,
+    );
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("the identity has a recovery delay").unwrap(),
+    );
+
+    let result = api_v2::identity_delete(&env, canister_id, principal_1(), identity_number, None)?;
+    assert!(matches!(
+        result,
+        Some(IdentityDeleteResponse::ConfirmationRequired { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn should_delay_v1_modifications_by_recovery_method() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+    let renamed_device = DeviceData {
+        alias: "renamed".to_string(),
+        ..device_data_1()
+    };
+
+    let add_id = expect_queued(api::add(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_2(),
+    ));
+    let update_id = expect_queued(api::update(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_1().pubkey,
+        &renamed_device,
+    ));
+    let replace_id = expect_queued(api::replace(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_1().pubkey,
+        &device_data_2(),
+    ));
+    let remove_id = expect_queued(api::remove(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_1().pubkey,
+    ));
+
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+    let queued = queued_operations(&env, canister_id, identity_number)?;
+    assert_eq!(
+        queued.iter().map(|queued| queued.id).collect::<Vec<_>>(),
+        vec![add_id, update_id, replace_id, remove_id]
+    );
+    assert_eq!(
+        queued[0].operation,
+        RecoveryOperation::AuthnMethodAdd {
+            authn_method: authn_method(device_data_2())
+        }
+    );
+    assert!(matches!(
+        queued[1].operation,
+        RecoveryOperation::AuthnMethodUpdate { .. }
+    ));
+    assert!(matches!(
+        queued[2].operation,
+        RecoveryOperation::AuthnMethodReplace { .. }
+    ));
+    assert!(matches!(
+        queued[3].operation,
+        RecoveryOperation::AuthnMethodRemove { .. }
+    ));
+    Ok(())
+}
+
+#[test]
+fn should_apply_v1_addition_by_recovery_method_after_delay() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    expect_queued(api::add(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_2(),
+    ));
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+
+    env.advance_time(Duration::from_secs(24 * 60 * 60 + 10 * 60));
+    env.tick();
+
+    let devices = api::lookup(&env, canister_id, identity_number)?;
+    assert_eq!(devices.len(), 3);
+    assert!(devices
+        .iter()
+        .any(|device| device.pubkey == device_data_2().pubkey));
+    Ok(())
+}
+
+#[test]
+fn should_delay_v2_addition_by_recovery_method() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    let result = api_v2::authn_method_add(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &authn_method(device_data_2()),
+    )?;
+    let Some(AuthnMethodAddResponse::RecoveryDelayed { id, .. }) = result else {
+        panic!("expected the addition to be delayed, got {result:?}");
+    };
+
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+    let queued = queued_operations(&env, canister_id, identity_number)?;
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].id, id);
+    Ok(())
+}
+
+#[test]
+fn should_delay_v2_metadata_replace_of_other_method_by_recovery_method() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+    let metadata = [(
+        "alias".to_string(),
+        MetadataEntry::String("renamed".to_string()),
+    )]
+    .into_iter()
+    .collect();
+
+    let result = api_v2::authn_method_metadata_replace(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_1().pubkey,
+        &metadata,
+    )?;
+    let Some(AuthnMethodMetadataReplaceResponse::RecoveryDelayed { id, .. }) = result else {
+        panic!("expected the metadata replacement to be delayed, got {result:?}");
+    };
+
+    let queued = queued_operations(&env, canister_id, identity_number)?;
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].id, id);
+    let devices = api::lookup(&env, canister_id, identity_number)?;
+    assert!(devices.iter().all(|device| device.alias != "renamed"));
+    Ok(())
+}
+
+#[test]
+fn should_delay_v2_security_settings_replace_by_recovery_method() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    // turning another method into a recovery method
+    let result = api_v2::authn_method_security_settings_replace(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &device_data_1().pubkey,
+        &AuthnMethodSecuritySettings {
+            protection: AuthnMethodProtection::Unprotected,
+            purpose: Purpose::Recovery,
+        },
+    )?;
+    assert!(matches!(
+        result,
+        Some(AuthnMethodSecuritySettingsReplaceResponse::RecoveryDelayed { .. })
+    ));
+
+    // changing the purpose of the recovery method itself
+    let result = api_v2::authn_method_security_settings_replace(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &recovery_device_data_1().pubkey,
+        &AuthnMethodSecuritySettings {
+            protection: AuthnMethodProtection::Unprotected,
+            purpose: Purpose::Authentication,
+        },
+    )?;
+    assert!(matches!(
+        result,
+        Some(AuthnMethodSecuritySettingsReplaceResponse::RecoveryDelayed { .. })
+    ));
+
+    assert_eq!(
+        queued_operations(&env, canister_id, identity_number)?.len(),
+        2
+    );
+    let devices = api::lookup(&env, canister_id, identity_number)?;
+    let recovery_devices = devices
+        .iter()
+        .filter(|device| device.purpose == Purpose::Recovery)
+        .collect::<Vec<_>>();
+    assert_eq!(recovery_devices.len(), 1);
+    assert_eq!(recovery_devices[0].pubkey, recovery_device_data_1().pubkey);
+    Ok(())
+}
+
+#[test]
+fn should_delay_tentative_device_verification_by_recovery_method() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    api::enter_device_registration_mode(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+    )?;
+    let AddTentativeDeviceResponse::AddedTentatively { verification_code, .. } =
+        api::add_tentative_device(&env, canister_id, identity_number, &device_data_2())? else {
+        panic!("expected the device to be added tentatively");
+    };
+    let result = api::verify_tentative_device(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &verification_code,
+    );
+    let Err(CallError::Reject(message)) = result else {
+        panic!("expected the verification to be rejected, got {result:?}");
+    };
+    assert!(message.contains("queued as recovery operation"));
+
+    assert_eq!(api::lookup(&env, canister_id, identity_number)?.len(), 2);
+    let queued = queued_operations(&env, canister_id, identity_number)?;
+    assert_eq!(queued.len(), 1);
+    assert_eq!(
+        queued[0].operation,
+        RecoveryOperation::AuthnMethodAdd {
+            authn_method: authn_method(device_data_2())
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn should_allow_recovery_method_to_update_own_alias() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = identity_with_recovery_delay(&env, canister_id)?;
+
+    api::update(
+        &env,
+        canister_id,
+        principal_recovery_1(),
+        identity_number,
+        &recovery_device_data_1().pubkey,
+        &DeviceData {
+            alias: "renamed".to_string(),
+            ..recovery_device_data_1()
+        },
+    )?;
+
+    assert!(queued_operations(&env, canister_id, identity_number)?.is_empty());
+    let devices = api::lookup(&env, canister_id, identity_number)?;
+    assert!(devices.iter().any(|device| device.alias == "renamed"));
+    Ok(())
+}
@@ -0,0 +1,209 @@
+//! Tests that `identity_sessions` returns the delegations prepared for an identity.
+
+use candid::Principal;
+use canister_tests::api::internet_identity as api;
+use canister_tests::api::internet_identity::api_v2;
+use canister_tests::flows;
+use canister_tests::framework::{
+    device_data_1, env, expect_user_error_with_message, install_ii_canister, principal_1,
+    upgrade_ii_canister, II_WASM,
+};
+use ic_test_state_machine_client::CallError;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use internet_identity_interface::internet_identity::types::{
+    DelegationSession, IdentitySessionsResponse,
+};
+use regex::Regex;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+#[test]
+fn should_list_sessions() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+    let session_key = ByteBuf::from("session public key");
+
+    let (_, expiration_1) = api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        "https://some-dapp-1.com",
+        &session_key,
+        None,
+    )?;
+    let (_, expiration_2) = api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        "https://some-dapp-2.com",
+        &session_key,
+        Some(Duration::from_secs(2 * 60 * 60).as_nanos() as u64), // 2 hours
+    )?;
+
+    let Some(IdentitySessionsResponse::Ok(mut sessions)) =
+        api_v2::identity_sessions(&env, canister_id, principal_1(), identity_number)? else {
+        panic!("Expected identity sessions to be returned");
+    };
+    sessions.sort_by_key(|session| session.expiration);
+
+    let fingerprint = ByteBuf::from(Sha256::digest(&session_key).to_vec());
+    assert_eq!(
+        sessions,
+        vec![
+            DelegationSession {
+                frontend: "https://some-dapp-1.com".to_string(),
+                session_key_fingerprint: fingerprint.clone(),
+                expiration: expiration_1,
+                authn_method_pubkey: device_data_1().pubkey,
+            },
+            DelegationSession {
+                frontend: "https://some-dapp-2.com".to_string(),
+                session_key_fingerprint: fingerprint,
+                expiration: expiration_2,
+                authn_method_pubkey: device_data_1().pubkey,
+            }
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn should_not_list_expired_sessions() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+    let session_key = ByteBuf::from("session public key");
+
+    api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        "https://some-dapp-1.com",
+        &session_key,
+        None, // default expiration: 30 minutes
+    )?;
+    let (_, expiration) = api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        "https://some-dapp-2.com",
+        &session_key,
+        Some(Duration::from_secs(2 * 60 * 60).as_nanos() as u64), // 2 hours
+    )?;
+
+    env.advance_time(Duration::from_secs(60 * 60));
+
+    let Some(IdentitySessionsResponse::Ok(sessions)) =
+        api_v2::identity_sessions(&env, canister_id, principal_1(), identity_number)? else {
+        panic!("Expected identity sessions to be returned");
+    };
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].frontend, "https://some-dapp-2.com");
+    assert_eq!(sessions[0].expiration, expiration);
+    Ok(())
+}
+
+#[test]
+fn should_keep_sessions_across_upgrades() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+
+    let (_, expiration) = api::prepare_delegation(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        "https://some-dapp-1.com",
+        &ByteBuf::from("session public key"),
+        None,
+    )?;
+
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+    let Some(IdentitySessionsResponse::Ok(sessions)) =
+        api_v2::identity_sessions(&env, canister_id, principal_1(), identity_number)? else {
+        panic!("Expected identity sessions to be returned");
+    };
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].frontend, "https://some-dapp-1.com");
+    assert_eq!(sessions[0].expiration, expiration);
+    Ok(())
+}
+
+#[test]
+fn should_not_list_revoked_sessions() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+    let session_key = ByteBuf::from("session public key");
+
+    for frontend in [
+        "https://some-dapp-1.com",
+        "https://some-dapp-2.com",
+        "https://some-dapp-3.com",
+    ] {
+        api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            identity_number,
+            frontend,
+            &session_key,
+            None,
+        )?;
+    }
+
+    api::revoke_delegations(
+        &env,
+        canister_id,
+        principal_1(),
+        identity_number,
+        Some("https://some-dapp-1.com"),
+    )?;
+
+    let Some(IdentitySessionsResponse::Ok(mut sessions)) =
+        api_v2::identity_sessions(&env, canister_id, principal_1(), identity_number)? else {
+        panic!("Expected identity sessions to be returned");
+    };
+    sessions.sort_by(|a, b| a.frontend.cmp(&b.frontend));
+    let frontends: Vec<&str> = sessions
+        .iter()
+        .map(|session| session.frontend.as_str())
+        .collect();
+    assert_eq!(
+        frontends,
+        vec!["https://some-dapp-2.com", "https://some-dapp-3.com"]
+    );
+
+    api::revoke_delegations(&env, canister_id, principal_1(), identity_number, None)?;
+
+    let Some(IdentitySessionsResponse::Ok(sessions)) =
+        api_v2::identity_sessions(&env, canister_id, principal_1(), identity_number)? else {
+        panic!("Expected identity sessions to be returned");
+    };
+    assert!(sessions.is_empty());
+    Ok(())
+}
+
+#[test]
+fn should_require_authentication_for_identity_sessions() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let identity_number = flows::register_anchor(&env, canister_id);
+
+    let result =
+        api_v2::identity_sessions(&env, canister_id, Principal::anonymous(), identity_number);
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+    );
+}
@@ -1,3 +1,12 @@
 mod authn_method_add;
+mod authn_method_batch;
+mod authn_method_metadata_replace;
+mod authn_method_remove;
+mod authn_method_replace;
+mod authn_method_security_settings_replace;
 mod authn_method_test_helpers;
+mod guardian_recovery;
+mod identity_delete;
 mod identity_info;
+mod identity_recovery_delay;
+mod identity_sessions;
@@ -1,4 +1,4 @@
-use crate::archive::types::DeviceDataWithoutAlias;
+use crate::archive::types::{DeviceDataWithoutAlias, Operation, OperationType};
 use crate::internet_identity::types::DeviceData;
 
 impl From<DeviceData> for DeviceDataWithoutAlias {
@@ -17,3 +17,21 @@ impl From<DeviceData> for DeviceDataWithoutAlias {
         }
     }
 }
+
+impl From<&Operation> for OperationType {
+    fn from(operation: &Operation) -> Self {
+        match operation {
+            Operation::RegisterAnchor { .. } => OperationType::RegisterAnchor,
+            Operation::AddDevice { .. } => OperationType::AddDevice,
+            Operation::UpdateDevice { .. } => OperationType::UpdateDevice,
+            Operation::ReplaceDevice { .. } => OperationType::ReplaceDevice,
+            Operation::RemoveDevice { .. } => OperationType::RemoveDevice,
+            Operation::DeleteAnchor => OperationType::DeleteAnchor,
+            Operation::RequestIdentityDeletion { .. } => OperationType::RequestIdentityDeletion,
+            Operation::Batch { .. } => OperationType::Batch,
+            Operation::SetGuardians { .. } => OperationType::SetGuardians,
+            Operation::RequestGuardianRecovery { .. } => OperationType::RequestGuardianRecovery,
+            Operation::ApproveGuardianRecovery { .. } => OperationType::ApproveGuardianRecovery,
+        }
+    }
+}
@@ -23,6 +23,56 @@ pub enum Operation {
     },
     #[serde(rename = "remove_device")]
     RemoveDevice { device: PublicKey },
+    #[serde(rename = "delete_anchor")]
+    DeleteAnchor,
+    // The deletion of the anchor has been requested using the given device and awaits confirmation.
+    #[serde(rename = "request_identity_deletion")]
+    RequestIdentityDeletion { device: PublicKey },
+    // Operations applied atomically as part of a single call (never nested).
+    #[serde(rename = "batch")]
+    Batch { operations: Vec<Operation> },
+    // Guardian recovery has been disabled if the list of guardians is empty.
+    #[serde(rename = "set_guardians")]
+    SetGuardians {
+        guardians: Vec<AnchorNumber>,
+        threshold: u8,
+    },
+    #[serde(rename = "request_guardian_recovery")]
+    RequestGuardianRecovery { device: DeviceDataWithoutAlias },
+    // Once the threshold is reached, the approval is archived together with the addition of
+    // the device as a batch.
+    #[serde(rename = "approve_guardian_recovery")]
+    ApproveGuardianRecovery {
+        guardian: AnchorNumber,
+        device: PublicKey,
+    },
+}
+
+// The type of an operation, used to filter entries.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum OperationType {
+    #[serde(rename = "register_anchor")]
+    RegisterAnchor,
+    #[serde(rename = "add_device")]
+    AddDevice,
+    #[serde(rename = "update_device")]
+    UpdateDevice,
+    #[serde(rename = "replace_device")]
+    ReplaceDevice,
+    #[serde(rename = "remove_device")]
+    RemoveDevice,
+    #[serde(rename = "delete_anchor")]
+    DeleteAnchor,
+    #[serde(rename = "request_identity_deletion")]
+    RequestIdentityDeletion,
+    #[serde(rename = "batch")]
+    Batch,
+    #[serde(rename = "set_guardians")]
+    SetGuardians,
+    #[serde(rename = "request_guardian_recovery")]
+    RequestGuardianRecovery,
+    #[serde(rename = "approve_guardian_recovery")]
+    ApproveGuardianRecovery,
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
@@ -74,6 +124,17 @@ pub enum Private {
 pub struct Entries {
     // make this a vec of options to keep Entry extensible
     pub entries: Vec<Option<Entry>>,
+    // index of the next entry not included in this response, if any
+    pub next_index: Option<u64>,
+}
+
+// Restricts the entries returned. Entries have to match all the criteria given.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct EntryFilter {
+    // Only entries with one of the given operation types (or batches containing such an operation).
+    pub operation_types: Option<Vec<OperationType>>,
+    // Only entries made by the given caller.
+    pub caller: Option<Principal>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -84,6 +145,65 @@ pub struct AnchorEntries {
     pub cursor: Option<Cursor>,
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TimeRangeEntries {
+    // make this a vec of options to keep Entry extensible
+    pub entries: Vec<Option<Entry>>,
+    // cursor pointing to the next entry not included in this response, if any
+    pub cursor: Option<Cursor>,
+    // range of entries archived before the timestamp index was introduced that have not been
+    // indexed yet, if any. These entries are not included in the response.
+    pub unindexed_entries: Option<LogRange>,
+}
+
+// Range of log indices of archived entries.
+#[derive(Clone, Debug, Eq, PartialEq, CandidType, Deserialize)]
+pub struct LogRange {
+    pub from: u64,
+    // exclusive
+    pub to: u64,
+}
+
+// Head of the hash chain and root of the Merkle tree over all archived entries.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HashChainHead {
+    // number of entries in the hash chain and the Merkle tree
+    pub length: u64,
+    // hash of the last entry in the hash chain (32 zero bytes if the chain is empty)
+    pub head: ByteBuf,
+    // root of the Merkle tree (RFC 9162) over the entries
+    pub merkle_root: ByteBuf,
+    // certificate of SHA-256(length || head || merkle_root), with length as 8 bytes big endian,
+    // which is the certified data of the archive
+    pub certificate: Option<ByteBuf>,
+}
+
+// Result of verifying a range of the hash chain against the archived entries.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RangeVerification {
+    pub from: u64,
+    // end of the verified range (exclusive), which is smaller than requested if the range is too large
+    pub to: u64,
+    // index of the first entry not matching the hash chain, if any
+    pub first_mismatch: Option<u64>,
+}
+
+// Proves that an archived entry is included in the certified Merkle tree (RFC 9162, section 2.1.3).
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EntryProof {
+    pub index: u64,
+    // the entry as archived, i.e. the candid encoded Entry
+    pub entry: ByteBuf,
+    // number of entries in the Merkle tree the proof refers to
+    pub length: u64,
+    // hashes of the siblings on the path from the leaf of the entry to the Merkle root
+    pub path: Vec<ByteBuf>,
+    // head of the hash chain over the first length entries
+    pub head: ByteBuf,
+    // certificate of SHA-256(length || head || merkle_root), see HashChainHead
+    pub certificate: Option<ByteBuf>,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum Cursor {
     // timestamp of the next entry not included in this response, if any
@@ -1,4 +1,6 @@
-use crate::internet_identity::types::{AuthnMethod, AuthnMethodData, PublicKeyAuthn, WebAuthn};
+use crate::internet_identity::types::{
+    AuthnMethod, AuthnMethodData, PublicKey, PublicKeyAuthn, WebAuthn,
+};
 use candid::Principal;
 
 impl AuthnMethodData {
@@ -11,4 +13,12 @@ impl AuthnMethodData {
         };
         Principal::self_authenticating(pubkey)
     }
+
+    /// Returns the public key of this authentication method.
+    pub fn public_key(&self) -> PublicKey {
+        match self.authn_method {
+            AuthnMethod::WebAuthn(WebAuthn { ref pubkey, .. }) => pubkey.clone(),
+            AuthnMethod::PubKey(PublicKeyAuthn { ref pubkey }) => pubkey.clone(),
+        }
+    }
 }
@@ -1,4 +1,5 @@
 use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
 use serde_bytes::ByteBuf;
 use std::collections::HashMap;
 
@@ -51,7 +52,7 @@ pub struct DeviceWithUsage {
     pub metadata: Option<HashMap<String, MetadataEntry>>,
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub enum Purpose {
     #[serde(rename = "recovery")]
     Recovery,
@@ -69,7 +70,7 @@ pub enum RegisterResponse {
     BadChallenge,
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub enum KeyType {
     #[serde(rename = "unknown")]
     Unknown,
@@ -81,7 +82,7 @@ pub enum KeyType {
     SeedPhrase,
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub enum DeviceProtection {
     #[serde(rename = "protected")]
     Protected,
@@ -89,7 +90,7 @@ pub enum DeviceProtection {
     Unprotected,
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub enum MetadataEntry {
     #[serde(rename = "string")]
     String(String),
@@ -103,6 +104,36 @@ pub enum MetadataEntry {
 pub struct Challenge {
     pub png_base64: String,
     pub challenge_key: ChallengeKey,
+    // Set for proof of work challenges, in which case `png_base64` is empty.
+    pub proof_of_work: Option<ProofOfWorkParams>,
+}
+
+/// Parameters of a proof of work challenge: the solution must be chosen such that the SHA-256 hash
+/// of `<challenge key>:<solution>` starts with at least `difficulty` zero bits.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ProofOfWorkParams {
+    pub difficulty: u8,
+}
+
+/// Parameters of the image CAPTCHA and of the inflight challenges.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct CaptchaConfig {
+    pub length: u8,
+    pub width: u32,
+    pub height: u32,
+    pub wave_frequency: u32,
+    pub wave_amplitude: u32,
+    pub challenge_lifetime_ns: u64,
+    pub max_inflight_challenges: u64,
+}
+
+/// The kind of challenge that has to be solved to register a new anchor.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum ChallengeProviderConfig {
+    #[serde(rename = "captcha")]
+    Captcha,
+    #[serde(rename = "proof_of_work")]
+    ProofOfWork(ProofOfWorkParams),
 }
 
 pub type ChallengeKey = String;
@@ -192,7 +223,14 @@ pub struct InternetIdentityInit {
     pub canister_creation_cycles_cost: Option<u64>,
     pub register_rate_limit: Option<RateLimitConfig>,
     pub max_num_latest_delegation_origins: Option<u64>,
+    // Ignored: the storage always uses the memory manager (i.e. layout version 6 is always
+    // migrated to 7), kept for compatibility.
     pub migrate_storage_to_memory_manager: Option<bool>,
+    pub delegation_ttl_policy: Option<Vec<DelegationTtlRule>>,
+    pub challenge_provider: Option<ChallengeProviderConfig>,
+    pub captcha_config: Option<CaptchaConfig>,
+    pub method_rate_limits: Option<Vec<MethodRateLimit>>,
+    pub migrate_storage_to_stable_btree_map: Option<bool>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
@@ -202,10 +240,26 @@ pub struct InternetIdentityStats {
     pub archive_info: ArchiveInfo,
     pub canister_creation_cycles_cost: u64,
     pub storage_layout_version: u8,
+    pub storage_migration: Option<StorageMigrationProgress>,
     pub active_anchor_stats: Option<ActiveAnchorStatistics<ActiveAnchorCounter>>,
     pub domain_active_anchor_stats: Option<ActiveAnchorStatistics<DomainActiveAnchorCounter>>,
     pub max_num_latest_delegation_origins: u64,
     pub latest_delegation_origins: Vec<FrontendHostname>,
+    pub delegation_ttl_policy: Vec<DelegationTtlRule>,
+    pub captcha_config: CaptchaConfig,
+}
+
+/// Progress of the migration to storage layout version 8.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct StorageMigrationProgress {
+    /// Number of anchors processed by the migration (including skipped anchors).
+    pub migrated_anchors: u32,
+    pub total_anchors: u32,
+    /// Number of anchors that could not be migrated because their records are corrupted. Their
+    /// records are copied as they are, i.e. they cannot be read after the migration either.
+    pub num_skipped_anchors: u32,
+    /// The first (up to 100) anchors that could not be migrated.
+    pub skipped_anchors: Vec<AnchorNumber>,
 }
 
 /// Information about the archive.
@@ -225,6 +279,39 @@ pub struct RateLimitConfig {
     pub max_tokens: u64,
 }
 
+/// Methods that can be rate limited individually (see [MethodRateLimit]).
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Eq, PartialEq, Hash)]
+pub enum RateLimitedMethod {
+    #[serde(rename = "create_challenge")]
+    CreateChallenge,
+    #[serde(rename = "add_tentative_device")]
+    AddTentativeDevice,
+    #[serde(rename = "verify_tentative_device")]
+    VerifyTentativeDevice,
+    #[serde(rename = "prepare_delegation")]
+    PrepareDelegation,
+}
+
+/// Rate limits of a method, both across all calls and per anchor.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct MethodRateLimit {
+    pub method: RateLimitedMethod,
+    pub global: Option<RateLimitConfig>,
+    pub per_anchor: Option<RateLimitConfig>,
+}
+
+/// Delegation lifetime policy for the frontend hostnames matching `frontend`.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct DelegationTtlRule {
+    // Frontend hostname (e.g. "https://wallet.example.com") or pattern starting with "*." to match
+    // all subdomains (e.g. "https://*.example.com").
+    pub frontend: String,
+    // Upper bound for the time to live (in ns) of delegations.
+    pub max_time_to_live_ns: u64,
+    // Time to live (in ns) of delegations if none is requested.
+    pub default_time_to_live_ns: u64,
+}
+
 /// Configuration parameters of the archive to be used on the next deployment.
 #[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct ArchiveConfig {
@@ -286,3 +373,106 @@ pub enum DeployArchiveResult {
     #[serde(rename = "failed")]
     Failed(String),
 }
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
+pub struct IntegrityScanArg {
+    // If set to true, violations that can be fixed safely (i.e. over-long aliases) are repaired
+    // and the repairs are archived.
+    pub repair: Option<bool>,
+    // If set to true, a running scan is aborted and replaced by the new one, e.g. if the running
+    // scan does not make progress anymore because one of its batches failed.
+    pub restart: Option<bool>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct IntegrityScanReport {
+    pub started_at: Timestamp,
+    // Not set while the scan is still running.
+    pub completed_at: Option<Timestamp>,
+    pub repair: bool,
+    pub scanned_anchors: u64,
+    // Number of anchors with at least one violation.
+    pub anchors_with_violations: u64,
+    pub repaired_anchors: u64,
+    pub violation_counts: IntegrityViolationCounts,
+    // The violations found, up to a limit (the counts are always complete).
+    pub violations: Vec<IntegrityViolation>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
+pub struct IntegrityViolationCounts {
+    pub undecodable: u64,
+    pub size_overflow: u64,
+    pub anchor_invariant: u64,
+    pub device_invariant: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct IntegrityViolation {
+    pub anchor_number: AnchorNumber,
+    pub kind: IntegrityViolationKind,
+    pub message: String,
+    pub repaired: bool,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum IntegrityViolationKind {
+    // The anchor record cannot be decoded.
+    #[serde(rename = "undecodable")]
+    Undecodable,
+    // The size of the anchor record exceeds the maximum size.
+    #[serde(rename = "size_overflow")]
+    SizeOverflow,
+    // The devices of the anchor as a whole violate an invariant (e.g. multiple recovery phrases).
+    #[serde(rename = "anchor_invariant")]
+    AnchorInvariant,
+    // A device of the anchor violates a device invariant (e.g. an over-long alias).
+    #[serde(rename = "device_invariant")]
+    DeviceInvariant,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ExportAnchorsArg {
+    // First anchor number to export.
+    pub from: AnchorNumber,
+    // Anchor number to stop at (exclusive). If not set, the export continues up to the last
+    // allocated anchor.
+    pub to: Option<AnchorNumber>,
+    // Maximum number of anchors to return, capped by the canister.
+    pub limit: Option<u32>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ExportedAnchor {
+    pub anchor_number: AnchorNumber,
+    // The candid encoded anchor as stored by the canister, i.e. including the metadata and the
+    // last usage timestamps of the devices.
+    pub data: ByteBuf,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ImportAnchorsArg {
+    pub anchors: Vec<ExportedAnchor>,
+    // Must be set to confirm that the principals of the imported anchors change (see the candid
+    // interface for details).
+    pub acknowledge_principal_change: bool,
+    // Numbers of deleted anchors that may be replaced by the import. Other deleted anchors are
+    // never replaced.
+    pub replace_deleted: Option<Vec<AnchorNumber>>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ExportAnchorsResponse {
+    pub anchors: Vec<ExportedAnchor>,
+    // Anchors that could not be read (e.g. corrupted records) and have been skipped.
+    pub unreadable: Vec<AnchorNumber>,
+    // The anchor number to continue the export from, if the range has not been exported completely.
+    pub next: Option<AnchorNumber>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct MarkAnchorsMovedArg {
+    pub anchor_numbers: Vec<AnchorNumber>,
+    // The canister now serving the anchors.
+    pub canister_id: Principal,
+}
@@ -1,5 +1,8 @@
-use crate::internet_identity::types::{CredentialId, MetadataEntry, PublicKey, Purpose, Timestamp};
-use candid::{CandidType, Deserialize};
+use crate::internet_identity::types::{
+    CredentialId, FrontendHostname, KeyType, MetadataEntry, PublicKey, Purpose, Timestamp,
+};
+use candid::{CandidType, Deserialize, Principal};
+use serde_bytes::ByteBuf;
 use std::collections::HashMap;
 
 pub type IdentityNumber = u64;
@@ -64,10 +67,350 @@ pub enum IdentityInfoResponse {
     Ok(IdentityInfo),
 }
 
+/// Reasons for an operation on the authentication methods of an identity to be rejected.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AuthnMethodError {
+    #[serde(rename = "too_many_authn_methods")]
+    TooManyAuthnMethods { limit: u64, num_authn_methods: u64 },
+    #[serde(rename = "field_limit_exceeded")]
+    FieldLimitExceeded {
+        field: String,
+        length: u64,
+        limit: u64,
+    },
+    #[serde(rename = "cumulative_data_limit_exceeded")]
+    CumulativeDataLimitExceeded { length: u64, limit: u64 },
+    // only recovery phrases can be protected
+    #[serde(rename = "invalid_protection")]
+    InvalidProtection { key_type: KeyType },
+    #[serde(rename = "recovery_phrase_credential_id_mismatch")]
+    RecoveryPhraseCredentialIdMismatch,
+    // protected authn methods can only be modified when authenticated with the same authn method
+    #[serde(rename = "mutation_not_allowed")]
+    MutationNotAllowed {
+        authorized_principal: Principal,
+        actual_principal: Principal,
+    },
+    #[serde(rename = "multiple_recovery_phrases")]
+    MultipleRecoveryPhrases,
+    #[serde(rename = "cannot_modify_public_key")]
+    CannotModifyPublicKey,
+    #[serde(rename = "not_found")]
+    NotFound { public_key: PublicKey },
+    #[serde(rename = "duplicate_public_key")]
+    DuplicatePublicKey { public_key: PublicKey },
+    #[serde(rename = "reserved_metadata_key")]
+    ReservedMetadataKey { key: String },
+    // the identity already has the maximum number of operations queued because of its recovery
+    // delay
+    #[serde(rename = "too_many_queued_operations")]
+    TooManyQueuedOperations { limit: u64 },
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub enum AuthnMethodAddResponse {
     #[serde(rename = "ok")]
     Ok,
+    // the operation was requested using a recovery method and will only be applied after the
+    // recovery delay of the identity, unless cancelled
+    #[serde(rename = "recovery_delayed")]
+    RecoveryDelayed { id: u64, execution_time: Timestamp },
+    #[serde(rename = "authn_method_error")]
+    AuthnMethodError(AuthnMethodError),
+    #[serde(rename = "invalid_metadata")]
+    InvalidMetadata(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AuthnMethodRemoveResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    // the operation was requested using a recovery method and will only be applied after the
+    // recovery delay of the identity, unless cancelled
+    #[serde(rename = "recovery_delayed")]
+    RecoveryDelayed { id: u64, execution_time: Timestamp },
+    #[serde(rename = "authn_method_error")]
+    AuthnMethodError(AuthnMethodError),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AuthnMethodReplaceResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    // the operation was requested using a recovery method and will only be applied after the
+    // recovery delay of the identity, unless cancelled
+    #[serde(rename = "recovery_delayed")]
+    RecoveryDelayed { id: u64, execution_time: Timestamp },
+    #[serde(rename = "authn_method_error")]
+    AuthnMethodError(AuthnMethodError),
+    #[serde(rename = "invalid_metadata")]
+    InvalidMetadata(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AuthnMethodMetadataReplaceResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    // the operation was requested using a recovery method and will only be applied after the
+    // recovery delay of the identity, unless cancelled
+    #[serde(rename = "recovery_delayed")]
+    RecoveryDelayed { id: u64, execution_time: Timestamp },
+    #[serde(rename = "authn_method_error")]
+    AuthnMethodError(AuthnMethodError),
     #[serde(rename = "invalid_metadata")]
     InvalidMetadata(String),
 }
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AuthnMethodSecuritySettings {
+    pub protection: AuthnMethodProtection,
+    pub purpose: Purpose,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AuthnMethodSecuritySettingsReplaceResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    // the operation was requested using a recovery method and will only be applied after the
+    // recovery delay of the identity, unless cancelled
+    #[serde(rename = "recovery_delayed")]
+    RecoveryDelayed { id: u64, execution_time: Timestamp },
+    #[serde(rename = "authn_method_error")]
+    AuthnMethodError(AuthnMethodError),
+}
+
+/// Modification of the authentication methods of an identity as part of a batch.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AuthnMethodOperation {
+    #[serde(rename = "add")]
+    Add(AuthnMethodData),
+    // the public key of the authentication method must not change
+    #[serde(rename = "update")]
+    Update {
+        public_key: PublicKey,
+        authn_method: AuthnMethodData,
+    },
+    #[serde(rename = "replace")]
+    Replace {
+        public_key: PublicKey,
+        authn_method: AuthnMethodData,
+    },
+    #[serde(rename = "remove")]
+    Remove(PublicKey),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AuthnMethodBatchResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "invalid_metadata")]
+    InvalidMetadata { index: u64, message: String },
+    // the operation at the given index was rejected
+    #[serde(rename = "operation_error")]
+    OperationError { index: u64, error: AuthnMethodError },
+    // the resulting set of authentication methods was rejected
+    #[serde(rename = "authn_method_error")]
+    AuthnMethodError(AuthnMethodError),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum IdentityDeleteResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    // the deletion must be confirmed by calling identity_delete again with the confirmation token,
+    // either before the expiration using another authentication method, or, if the identity has a
+    // single authentication method, between delayed_confirmation_start and
+    // delayed_confirmation_expiration using the authentication method that requested the deletion
+    #[serde(rename = "confirmation_required")]
+    ConfirmationRequired {
+        confirmation_token: ByteBuf,
+        expiration: Timestamp,
+        delayed_confirmation_start: Option<Timestamp>,
+        delayed_confirmation_expiration: Option<Timestamp>,
+    },
+    // the identity has a protected recovery method which must be used to delete the identity
+    #[serde(rename = "protected_recovery_method_required")]
+    ProtectedRecoveryMethodRequired,
+    // the confirmation token is unknown or expired
+    #[serde(rename = "invalid_confirmation")]
+    InvalidConfirmation,
+    // the caller cannot confirm the deletion: it is authenticated using a temporary key or, unless
+    // the identity has a single authentication method, using the authentication method that
+    // requested the deletion
+    #[serde(rename = "authn_method_not_allowed_to_confirm")]
+    AuthnMethodNotAllowedToConfirm,
+    // the authentication method that requested the deletion can only confirm it after the
+    // waiting period, i.e. from delayed_confirmation_start on
+    #[serde(rename = "confirmation_not_yet_allowed")]
+    ConfirmationNotYetAllowed {
+        delayed_confirmation_start: Timestamp,
+    },
+}
+
+/// Operation requested using a recovery method on an identity with a recovery delay.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum RecoveryOperation {
+    #[serde(rename = "authn_method_remove")]
+    AuthnMethodRemove { public_key: PublicKey },
+    #[serde(rename = "authn_method_replace")]
+    AuthnMethodReplace {
+        public_key: PublicKey,
+        authn_method: AuthnMethodData,
+    },
+    #[serde(rename = "authn_method_add")]
+    AuthnMethodAdd { authn_method: AuthnMethodData },
+    // the authn method with the given public key is updated to the given authn method
+    #[serde(rename = "authn_method_update")]
+    AuthnMethodUpdate {
+        public_key: PublicKey,
+        authn_method: AuthnMethodData,
+    },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct QueuedRecoveryOperation {
+    pub id: u64,
+    pub operation: RecoveryOperation,
+    // public key of the recovery method used to request the operation or, for guardian
+    // recoveries, of the authentication method to be added
+    pub requested_by: PublicKey,
+    pub execution_time: Timestamp,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct DroppedRecoveryOperation {
+    pub operation: QueuedRecoveryOperation,
+    // reason why the operation could not be applied
+    pub error: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct IdentityRecoveryInfo {
+    pub recovery_delay_ns: Option<u64>,
+    pub queued_operations: Vec<QueuedRecoveryOperation>,
+    pub dropped_operations: Vec<DroppedRecoveryOperation>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum IdentityRecoveryInfoResponse {
+    #[serde(rename = "ok")]
+    Ok(IdentityRecoveryInfo),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum IdentityRecoveryDelaySetResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "invalid_delay")]
+    InvalidDelay { max_delay_ns: u64 },
+    // reducing or removing the recovery delay requires authentication with an authentication
+    // method that is not a recovery method
+    #[serde(rename = "authentication_method_required")]
+    AuthenticationMethodRequired,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum RecoveryOperationCancelResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "not_found")]
+    NotFound,
+    // queued recovery operations can only be cancelled using an authentication method that is
+    // not a recovery method
+    #[serde(rename = "authentication_method_required")]
+    AuthenticationMethodRequired,
+}
+
+/// Guardians are other identities that can jointly approve the addition of a new authentication
+/// method to the identity (i.e. recover the identity). At least `threshold` guardians must approve.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct GuardianConfig {
+    pub guardians: Vec<IdentityNumber>,
+    pub threshold: u8,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct GuardianRecoveryRequest {
+    pub authn_method: AuthnMethodData,
+    // the guardians that approved the request so far
+    pub approvals: Vec<IdentityNumber>,
+    pub expiration: Timestamp,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct IdentityGuardiansInfo {
+    pub config: Option<GuardianConfig>,
+    // the pending recovery requests, oldest first
+    pub requests: Vec<GuardianRecoveryRequest>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum IdentityGuardiansInfoResponse {
+    #[serde(rename = "ok")]
+    Ok(IdentityGuardiansInfo),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum IdentityGuardiansSetResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "invalid_config")]
+    InvalidConfig(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum GuardianRecoveryRequestResponse {
+    #[serde(rename = "ok")]
+    Ok { expiration: Timestamp },
+    // the identity does not have any guardians
+    #[serde(rename = "no_guardians")]
+    NoGuardians,
+    // the caller must be the principal derived from the public key of the authentication method
+    #[serde(rename = "public_key_mismatch")]
+    PublicKeyMismatch,
+    // another request has already been approved by a guardian and is pending until its expiration
+    #[serde(rename = "request_pending")]
+    RequestPending { expiration: Timestamp },
+    #[serde(rename = "invalid_metadata")]
+    InvalidMetadata(String),
+    #[serde(rename = "authn_method_error")]
+    AuthnMethodError(AuthnMethodError),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum GuardianRecoveryApproveResponse {
+    // the approval has been recorded but more approvals are required
+    #[serde(rename = "ok")]
+    Ok { approvals: u8, threshold: u8 },
+    // the threshold has been reached and the authentication method has been added
+    #[serde(rename = "recovered")]
+    Recovered,
+    // the threshold has been reached but the authentication method will only be added after the
+    // recovery delay of the identity, unless cancelled
+    #[serde(rename = "recovery_delayed")]
+    RecoveryDelayed { id: u64, execution_time: Timestamp },
+    #[serde(rename = "not_a_guardian")]
+    NotAGuardian,
+    // there is no pending request for the given public key
+    #[serde(rename = "request_not_found")]
+    RequestNotFound,
+    #[serde(rename = "authn_method_error")]
+    AuthnMethodError(AuthnMethodError),
+}
+
+/// A delegation prepared for an identity that has not yet expired.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct DelegationSession {
+    pub frontend: FrontendHostname,
+    // SHA-256 hash of the session key
+    pub session_key_fingerprint: ByteBuf,
+    pub expiration: Timestamp,
+    // public key of the authentication method that was used to prepare the delegation
+    pub authn_method_pubkey: PublicKey,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum IdentitySessionsResponse {
+    #[serde(rename = "ok")]
+    Ok(Vec<DelegationSession>),
+}